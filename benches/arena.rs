@@ -0,0 +1,142 @@
+//! Compares the number of heap allocations and the time taken by decoding many small messages
+//! into owned, borrowed and arena-allocated values.
+//!
+//! Run with `cargo bench --bench arena --features arena`.
+use std::alloc;
+use std::hint;
+use std::sync::atomic;
+use std::time;
+
+use bumpalo::Bump;
+use serde_protobuf::descriptor::{
+    Descriptors, FieldDescriptor, FieldLabel, InternalFieldType, MessageDescriptor,
+};
+use serde_protobuf::value::{ArenaMessage, BorrowedMessage, Message};
+
+const ITERATIONS: u32 = 10000;
+
+static ALLOCATIONS: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+/// The system allocator, counting the number of allocations made through it.
+struct CountingAllocator;
+
+unsafe impl alloc::GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: alloc::Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, atomic::Ordering::Relaxed);
+        alloc::System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: alloc::Layout) {
+        alloc::System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: alloc::Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, atomic::Ordering::Relaxed);
+        alloc::System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn main() {
+    let field = |name: &str, number, label, field_type| {
+        FieldDescriptor::builder(name)
+            .number(number)
+            .label(label)
+            .field_type(field_type)
+            .build()
+    };
+    let mut message = MessageDescriptor::new(".bench.Event");
+    message.add_field(field(
+        "id",
+        1,
+        FieldLabel::Optional,
+        InternalFieldType::UInt64,
+    ));
+    message.add_field(field(
+        "name",
+        2,
+        FieldLabel::Optional,
+        InternalFieldType::String,
+    ));
+    message.add_field(field(
+        "samples",
+        3,
+        FieldLabel::Repeated,
+        InternalFieldType::SInt32,
+    ));
+    message.add_field(field(
+        "tags",
+        4,
+        FieldLabel::Repeated,
+        InternalFieldType::UnresolvedMessage(".bench.Tag".into()),
+    ));
+    let mut tag = MessageDescriptor::new(".bench.Tag");
+    tag.add_field(field(
+        "key",
+        1,
+        FieldLabel::Optional,
+        InternalFieldType::String,
+    ));
+    tag.add_field(field(
+        "value",
+        2,
+        FieldLabel::Optional,
+        InternalFieldType::String,
+    ));
+    let mut descriptors = Descriptors::new();
+    descriptors.add_message(message);
+    descriptors.add_message(tag);
+    descriptors.resolve_refs();
+    let message = descriptors.message_by_name(".bench.Event").unwrap();
+
+    // id: 42, name: "event", samples: [0, 1, ..., 15] (packed), tags: [{key: "k", value: "v"} * 4]
+    let mut data = vec![8, 42, 18, 5];
+    data.extend_from_slice(b"event");
+    data.extend_from_slice(&[26, 16]);
+    data.extend((0..16).map(|i| i * 2));
+    for _ in 0..4 {
+        data.extend_from_slice(&[34, 6, 10, 1, b'k', 18, 1, b'v']);
+    }
+
+    let owned = measure(|| {
+        let mut value = Message::new(message);
+        let mut input = protobuf::CodedInputStream::from_bytes(&data);
+        value.merge_from(&descriptors, message, &mut input).unwrap();
+        hint::black_box(value);
+    });
+    let borrowed = measure(|| {
+        let value = BorrowedMessage::decode(&descriptors, message, &data).unwrap();
+        hint::black_box(value);
+    });
+    let mut bump = Bump::new();
+    let arena = measure(|| {
+        let value = ArenaMessage::decode(&bump, &descriptors, message, &data).unwrap();
+        hint::black_box(value);
+        bump.reset();
+    });
+
+    for (name, (allocations, time)) in &[("owned", owned), ("borrowed", borrowed), ("arena", arena)]
+    {
+        println!(
+            "{:8}: {:6.1} allocations, {:>8.1?} per message",
+            name, allocations, time
+        );
+    }
+}
+
+fn measure<F>(mut f: F) -> (f64, time::Duration)
+where
+    F: FnMut(),
+{
+    f();
+    let allocations = ALLOCATIONS.load(atomic::Ordering::Relaxed);
+    let start = time::Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let elapsed = start.elapsed() / ITERATIONS;
+    let allocations = ALLOCATIONS.load(atomic::Ordering::Relaxed) - allocations;
+    (allocations as f64 / f64::from(ITERATIONS), elapsed)
+}
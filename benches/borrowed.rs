@@ -0,0 +1,72 @@
+//! Compares decoding messages with large `bytes` fields into owned and borrowed values.
+//!
+//! Run with `cargo bench --bench borrowed`.
+use std::hint;
+use std::time;
+
+use serde_protobuf::descriptor::{
+    Descriptors, FieldDescriptor, FieldLabel, InternalFieldType, MessageDescriptor,
+};
+use serde_protobuf::value::{BorrowedMessage, Message};
+
+const ITERATIONS: u32 = 1000;
+
+fn main() {
+    let mut message = MessageDescriptor::new(".bench.Blobs");
+    message.add_field(
+        FieldDescriptor::builder("blobs")
+            .number(1)
+            .label(FieldLabel::Repeated)
+            .field_type(InternalFieldType::Bytes)
+            .build(),
+    );
+    let mut descriptors = Descriptors::new();
+    descriptors.add_message(message);
+    let message = descriptors.message_by_name(".bench.Blobs").unwrap();
+
+    for &(count, size) in &[(1000, 64), (64, 64 * 1024), (4, 1024 * 1024)] {
+        // blobs: [size bytes] * count, where size is encoded in at most three varint bytes
+        let mut data = Vec::new();
+        for _ in 0..count {
+            data.push(10);
+            let mut len = size as u64;
+            while len >= 0x80 {
+                data.push(len as u8 | 0x80);
+                len >>= 7;
+            }
+            data.push(len as u8);
+            data.resize(data.len() + size, 0xab);
+        }
+
+        let owned = measure(|| {
+            let mut value = Message::new(message);
+            let mut input = protobuf::CodedInputStream::from_bytes(&data);
+            value.merge_from(&descriptors, message, &mut input).unwrap();
+            hint::black_box(value);
+        });
+        let borrowed = measure(|| {
+            let value = BorrowedMessage::decode(&descriptors, message, &data).unwrap();
+            hint::black_box(value);
+        });
+        println!(
+            "{:4} x {:7} bytes: owned {:>10.1?}, borrowed {:>10.1?} per message ({:.1}x)",
+            count,
+            size,
+            owned,
+            borrowed,
+            owned.as_secs_f64() / borrowed.as_secs_f64()
+        );
+    }
+}
+
+fn measure<F>(mut f: F) -> time::Duration
+where
+    F: FnMut(),
+{
+    f();
+    let start = time::Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    start.elapsed() / ITERATIONS
+}
@@ -0,0 +1,136 @@
+//! Compares the number of heap allocations and the time taken by decoding large packed
+//! repeated `int32` and `sfixed32` fields before and after reserving capacity for their values up
+//! front.
+//!
+//! "Before" decodes the payload the way it was decoded before: reading the values straight from
+//! the input stream and appending them one at a time.  "After" is `Message::merge_from`.
+//!
+//! Run with `cargo bench --bench packed`.
+use std::alloc;
+use std::hint;
+use std::sync::atomic;
+use std::time;
+
+use serde_protobuf::descriptor::{
+    Descriptors, FieldDescriptor, FieldLabel, InternalFieldType, MessageDescriptor,
+};
+use serde_protobuf::value::{Message, Value};
+
+const ITERATIONS: u32 = 100;
+const VALUES: i32 = 100_000;
+
+static ALLOCATIONS: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+/// The system allocator, counting the number of allocations made through it.
+struct CountingAllocator;
+
+unsafe impl alloc::GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: alloc::Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, atomic::Ordering::Relaxed);
+        alloc::System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: alloc::Layout) {
+        alloc::System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: alloc::Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, atomic::Ordering::Relaxed);
+        alloc::System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn main() {
+    let mut message = MessageDescriptor::new(".bench.Samples");
+    for (name, number, field_type) in [
+        ("repeated_int32", 1, InternalFieldType::Int32),
+        ("repeated_sfixed32", 2, InternalFieldType::SFixed32),
+    ] {
+        message.add_field(
+            FieldDescriptor::builder(name)
+                .number(number)
+                .label(FieldLabel::Repeated)
+                .field_type(field_type)
+                .build(),
+        );
+    }
+    let mut descriptors = Descriptors::new();
+    descriptors.add_message(message);
+    let message = descriptors.message_by_name(".bench.Samples").unwrap();
+
+    // [-50000, ..., 49999], with varints of mixed lengths
+    let encode = |number, write: fn(&mut protobuf::CodedOutputStream, i32)| {
+        let mut payload = Vec::new();
+        let mut output = protobuf::CodedOutputStream::vec(&mut payload);
+        for i in 0..VALUES {
+            write(&mut output, i - VALUES / 2);
+        }
+        output.flush().unwrap();
+        drop(output);
+
+        let mut data = Vec::new();
+        let mut output = protobuf::CodedOutputStream::vec(&mut data);
+        output.write_bytes(number, &payload).unwrap();
+        output.flush().unwrap();
+        drop(output);
+        data
+    };
+    let int32 = encode(1, |o, v| o.write_int32_no_tag(v).unwrap());
+    let sfixed32 = encode(2, |o, v| o.write_sfixed32_no_tag(v).unwrap());
+
+    let before = |data: &[u8], read: fn(&mut protobuf::CodedInputStream) -> i32| {
+        let mut input = protobuf::CodedInputStream::from_bytes(data);
+        input.read_raw_varint32().unwrap();
+        let len = input.read_raw_varint32().unwrap();
+        let old_limit = input.push_limit(u64::from(len)).unwrap();
+        let mut values = Vec::new();
+        while !input.eof().unwrap() {
+            values.push(Value::I32(read(&mut input)));
+        }
+        input.pop_limit(old_limit);
+        hint::black_box(values);
+    };
+    let after = |data: &[u8]| {
+        let mut value = Message::new(message);
+        let mut input = protobuf::CodedInputStream::from_bytes(data);
+        value.merge_from(&descriptors, message, &mut input).unwrap();
+        hint::black_box(value);
+    };
+    let results = [
+        (
+            "int32 before",
+            measure(|| before(&int32, |i| i.read_int32().unwrap())),
+        ),
+        ("int32 after", measure(|| after(&int32))),
+        (
+            "sfixed32 before",
+            measure(|| before(&sfixed32, |i| i.read_sfixed32().unwrap())),
+        ),
+        ("sfixed32 after", measure(|| after(&sfixed32))),
+    ];
+
+    for (name, (allocations, time)) in &results {
+        println!(
+            "{:15}: {:6.1} allocations, {:>8.1?} per message",
+            name, allocations, time
+        );
+    }
+}
+
+fn measure<F>(mut f: F) -> (f64, time::Duration)
+where
+    F: FnMut(),
+{
+    f();
+    let allocations = ALLOCATIONS.load(atomic::Ordering::Relaxed);
+    let start = time::Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let elapsed = start.elapsed() / ITERATIONS;
+    let allocations = ALLOCATIONS.load(atomic::Ordering::Relaxed) - allocations;
+    (allocations as f64 / f64::from(ITERATIONS), elapsed)
+}
@@ -63,22 +63,78 @@
 //! #   foo().unwrap();
 //! # }
 //! ```
+//!
+//! Since the deserializer maps fields to their names and enum values to the names of the values,
+//! messages can also be deserialized directly into a type that derives `Deserialize`.  Repeated
+//! fields become sequences, and optional fields without any value are presented as none, so such
+//! fields should use `Option` unless they have a default value.
+//!
+//! For very large messages, a `FieldReader` can instead be used to pull individual field values
+//! out of the stream one at a time, without materializing the whole message:
+//!
+//! ```
+//! use serde_protobuf::de::FieldReader;
+//! use serde_protobuf::descriptor::*;
+//!
+//! let mut message = MessageDescriptor::new(".mypackage.Numbers");
+//! message.add_field(FieldDescriptor::builder("value").number(1).label(FieldLabel::Repeated).build());
+//! let mut descriptors = Descriptors::new();
+//! descriptors.add_message(message);
+//!
+//! let data = &[8, 1, 8, 2, 8, 3];
+//! let input = protobuf::CodedInputStream::from_bytes(data);
+//! let reader = FieldReader::for_named_message(&descriptors, ".mypackage.Numbers", input).unwrap();
+//! for result in reader {
+//!     let (field, value) = result.unwrap();
+//!     println!("{} = {:?}", field.name(), value);
+//! }
+//! ```
 
 use crate::descriptor;
 use crate::error;
 
 use crate::value;
+use crate::wire;
 use protobuf;
 use serde;
 use std::collections;
 use std::fmt;
+use std::io;
+use std::iter;
+use std::ops;
 use std::vec;
 
+/// The numbers of the top-level fields of a message that were present on the wire, as returned by
+/// `decode_with_presence`.
+pub type FieldPresence = collections::HashSet<i32>;
+
 /// A deserializer that can deserialize a single message type.
 pub struct Deserializer<'de> {
     descriptors: &'de descriptor::Descriptors,
     descriptor: &'de descriptor::MessageDescriptor,
     input: protobuf::CodedInputStream<'de>,
+    options: value::DecodeOptions,
+    field_filter: Option<collections::BTreeSet<i32>>,
+}
+
+/// A pull-based reader that yields the fields of a single message one value at a time.
+///
+/// Every value of a repeated field is yielded separately, in the order that the values appear on
+/// the wire, so that interleaved and packed repeated fields can be processed without buffering
+/// the whole field.  Unknown fields are skipped.
+pub struct FieldReader<'de> {
+    descriptors: &'de descriptor::Descriptors,
+    descriptor: &'de descriptor::MessageDescriptor,
+    input: protobuf::CodedInputStream<'de>,
+    options: value::DecodeOptions,
+    pending: collections::VecDeque<(&'de descriptor::FieldDescriptor, value::Value)>,
+    seen: collections::BTreeSet<i32>,
+    /// The number of values read so far for each repeated field, used to index element errors.
+    repeated_counts: collections::BTreeMap<i32, usize>,
+    /// The number of field occurrences that may still be read; see
+    /// `DecodeOptions::max_total_fields`.
+    remaining_fields: Option<u64>,
+    done: bool,
 }
 
 struct MessageVisitor<'de> {
@@ -124,6 +180,8 @@ impl<'de> Deserializer<'de> {
             descriptors,
             descriptor,
             input,
+            options: value::DecodeOptions::default(),
+            field_filter: None,
         }
     }
 
@@ -144,6 +202,322 @@ impl<'de> Deserializer<'de> {
             })
         }
     }
+
+    /// Sets the options used to decode the input, for example to bound the size of the fields
+    /// read from untrusted input.
+    pub fn with_options(mut self, options: value::DecodeOptions) -> Deserializer<'de> {
+        self.options = options;
+        self
+    }
+
+    /// Registers a hook that transforms every decoded value of the specified field of the
+    /// specified message type; see `DecodeOptions::field_hook`.
+    pub fn with_field_hook<S, F>(
+        mut self,
+        message_name: S,
+        field_number: i32,
+        hook: F,
+    ) -> Deserializer<'de>
+    where
+        S: Into<String>,
+        F: Fn(value::Value) -> error::Result<value::Value> + Send + Sync + 'static,
+    {
+        self.options = self.options.field_hook(message_name, field_number, hook);
+        self
+    }
+
+    /// Sets whether all scalar fields are decoded as strings; see
+    /// `DecodeOptions::stringify_scalars`.
+    pub fn stringify_scalars(mut self, stringify_scalars: bool) -> Deserializer<'de> {
+        self.options = self.options.stringify_scalars(stringify_scalars);
+        self
+    }
+
+    /// Sets the maximum total number of field occurrences decoded from the message; see
+    /// `DecodeOptions::max_total_fields`.
+    pub fn max_total_fields(mut self, max_total_fields: u64) -> Deserializer<'de> {
+        self.options = self.options.max_total_fields(max_total_fields);
+        self
+    }
+
+    /// Sets whether decoding fails if a singular scalar field occurs more than once on the wire;
+    /// see `DecodeOptions::reject_duplicate_scalars`.
+    pub fn reject_duplicate_scalars(mut self, reject_duplicate_scalars: bool) -> Deserializer<'de> {
+        self.options = self
+            .options
+            .reject_duplicate_scalars(reject_duplicate_scalars);
+        self
+    }
+
+    /// Sets whether fields whose message type isn't in the descriptor registry are decoded without
+    /// a schema instead of failing; see `DecodeOptions::schemaless_unresolved_messages`.
+    ///
+    /// Since unknown fields are omitted, such messages are deserialized as empty maps.
+    pub fn schemaless_unresolved_messages(
+        mut self,
+        schemaless_unresolved_messages: bool,
+    ) -> Deserializer<'de> {
+        self.options = self
+            .options
+            .schemaless_unresolved_messages(schemaless_unresolved_messages);
+        self
+    }
+
+    /// Restricts decoding of the top-level message to the fields with the specified numbers.
+    ///
+    /// All other fields are skipped on the wire without being decoded or allocated, and don't
+    /// appear in the deserialized result, which makes projecting a few fields out of a large
+    /// message cheap.
+    pub fn with_field_filter(mut self, field_numbers: &[i32]) -> Deserializer<'de> {
+        self.field_filter = Some(field_numbers.iter().cloned().collect());
+        self
+    }
+
+    /// Scans the top-level message without decoding it, returning the number and byte range of
+    /// every field in the order that they appear on the wire.
+    ///
+    /// Each range covers both the tag and the value of the field, and is relative to the start of
+    /// the input stream, so a field can later be re-read by decoding that slice of the original
+    /// buffer as a message of the same type.  Repeated fields that are not packed have one entry
+    /// per value, and unknown fields are included too.
+    pub fn field_offsets(&mut self) -> error::Result<Vec<(i32, ops::Range<usize>)>> {
+        let mut offsets = Vec::new();
+        while !self.input.eof()? {
+            let start = self.input.pos() as usize;
+            let (number, wire_type) = value::read_tag(&mut self.input)?;
+            value::skip_field(&mut self.input, wire_type, &self.options)?;
+            offsets.push((number as i32, start..self.input.pos() as usize));
+        }
+        Ok(offsets)
+    }
+
+    /// Decodes the top-level message leniently, returning whatever could be decoded together with
+    /// the errors that were encountered.
+    ///
+    /// Unlike deserializing through `serde`, which fails on the first error, every field that
+    /// can't be decoded is skipped with its error recorded, and decoding continues with the next
+    /// field.  Decoding only stops early if the input is malformed in a way that makes it
+    /// impossible to find the next field, such as a bad tag.  The field filter and the decode
+    /// options are honored.
+    pub fn deserialize_lenient(&mut self) -> (value::Value, Vec<error::Error>) {
+        let mut message = value::Message::new(self.descriptor);
+        let errors = message.merge_lenient(
+            self.descriptors,
+            self.descriptor,
+            &mut self.input,
+            &self.options,
+            self.field_filter.as_ref(),
+        );
+        (value::Value::Message(message), errors)
+    }
+}
+
+/// Reads a single frame of a delimited stream, where each message is prefixed with its length as a
+/// varint, and decodes it as a message of the specified type.
+///
+/// Returns `None` if the reader is at the end of the stream, and an `Error::EndOfStream` error if
+/// the stream ends in the middle of a frame.  Frames can be written using `ser::write_delimited`.
+pub fn read_delimited<R>(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    reader: &mut R,
+) -> Option<error::Result<value::Value>>
+where
+    R: io::Read,
+{
+    let len = wire::try_read_varint(reader)?;
+    let result = (|| {
+        let bytes = wire::read_bytes(reader, len?)?;
+        let mut message = value::Message::new(descriptor);
+        let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+        message.merge_from(descriptors, descriptor, &mut input)?;
+        Ok(value::Value::Message(message))
+    })();
+    Some(result)
+}
+
+/// Decodes a single frame of a delimited stream, like `read_delimited`, from a byte slice that
+/// must contain exactly that frame.
+///
+/// Returns an `Error::EndOfStream` error if the slice ends before the frame does, and an
+/// `Error::TrailingData` error if there are bytes after the end of the frame, which usually
+/// indicates a framing bug on the writing side.
+pub fn decode_exact(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    bytes: &[u8],
+) -> error::Result<value::Value> {
+    let mut reader = bytes;
+    let value = read_delimited(descriptors, descriptor, &mut reader)
+        .unwrap_or(Err(error::Error::EndOfStream))?;
+    if !reader.is_empty() {
+        return Err(error::Error::TrailingData {
+            remaining: reader.len(),
+        });
+    }
+    Ok(value)
+}
+
+/// Decodes a binary encoded message of the specified type, also returning the numbers of all
+/// top-level fields that were present on the wire.
+///
+/// A decoded `value::Value` can't tell a field that was explicitly set to its default value apart
+/// from an absent field without presence tracking, but the presence set contains every field that
+/// occurred on the wire regardless of its value, including unknown fields and fields whose type
+/// has no presence semantics.  This is useful for diffing and merging messages.
+pub fn decode_with_presence(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    bytes: &[u8],
+) -> error::Result<(value::Value, FieldPresence)> {
+    let mut message = value::Message::new(descriptor);
+    let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+    let presence = message
+        .merge_with_presence(descriptors, descriptor, &mut input)?
+        .into_iter()
+        .collect();
+    Ok((value::Value::Message(message), presence))
+}
+
+/// Decodes a single value of the specified field from its raw encoding, without a message frame.
+///
+/// The bytes are the value as it appears on the wire after the field's tag, so length-delimited
+/// values include their length prefix; the ranges returned by `Deserializer::field_offsets` cover
+/// the tag too, which has to be skipped first.  The wire type must be the one that the field's
+/// type uses for a single value, so packed repeated values are rejected with an
+/// `Error::BadWireType` error, and all of the bytes must be consumed by the value.  Groups aren't
+/// supported.
+pub fn decode_field_value(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    wire_type: protobuf::rt::WireType,
+    bytes: &[u8],
+) -> error::Result<value::Value> {
+    let field_type = field.field_type(descriptors);
+    if protobuf::rt::WireType::StartGroup == value::wire_type(&field_type) {
+        return Err(error::Error::BadField {
+            field: field.name().to_owned(),
+            reason: "groups are not supported".to_owned(),
+        });
+    }
+    if value::wire_type(&field_type) != wire_type {
+        return Err(error::Error::BadWireType { wire_type });
+    }
+
+    let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+    let mut decoded = value::Field::Singular(None);
+    decoded.merge_from(descriptors, field, &mut input, wire_type)?;
+    if !input.eof()? {
+        return Err(error::Error::BadField {
+            field: field.name().to_owned(),
+            reason: "trailing bytes after the value".to_owned(),
+        });
+    }
+    match decoded {
+        value::Field::Singular(Some(value)) => Ok(value),
+        _ => Err(error::Error::EndOfStream),
+    }
+}
+
+/// Decodes a binary encoded message of the specified named type directly into its canonical JSON
+/// form, as produced by `json::to_value`.
+///
+/// The message type name must be fully qualified.  Fields are converted as they are read instead
+/// of first decoding the whole message into a `value::Value`, although singular message fields
+/// and map fields are still decoded into values before they are converted.  This requires the
+/// `json` feature.
+#[cfg(feature = "json")]
+pub fn to_json(
+    descriptors: &descriptor::Descriptors,
+    message_name: &str,
+    bytes: &[u8],
+) -> error::Result<serde_json::Value> {
+    let descriptor =
+        descriptors
+            .message_by_name(message_name)
+            .ok_or_else(|| error::Error::UnknownMessage {
+                name: message_name.to_owned(),
+            })?;
+    let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+    crate::json::decode_to_json(descriptors, descriptor, &mut input)
+}
+
+/// Decodes a binary encoded message of the specified named type into a flat map from dotted field
+/// paths to scalar values, for example for indexing messages in a key-value store.
+///
+/// The message type name must be fully qualified.  Fields of nested messages are keyed by the
+/// field names along the path separated by `.`, and elements of repeated fields by the field name
+/// followed by the index in brackets, such as `person.name` or `tags[0]`.  Map fields are treated
+/// like the repeated messages that they are encoded as, so their entries become for example
+/// `labels[0].key` and `labels[0].value`.  Unset fields and unknown fields are omitted.
+pub fn to_flat_map(
+    descriptors: &descriptor::Descriptors,
+    message_name: &str,
+    bytes: &[u8],
+) -> error::Result<collections::BTreeMap<String, value::Value>> {
+    let descriptor =
+        descriptors
+            .message_by_name(message_name)
+            .ok_or_else(|| error::Error::UnknownMessage {
+                name: message_name.to_owned(),
+            })?;
+    let mut message = value::Message::new(descriptor);
+    let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+    message.merge_from(descriptors, descriptor, &mut input)?;
+
+    let mut map = collections::BTreeMap::new();
+    flatten_message(descriptors, descriptor, message, "", &mut map);
+    Ok(map)
+}
+
+fn flatten_message(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    message: value::Message,
+    prefix: &str,
+    map: &mut collections::BTreeMap<String, value::Value>,
+) {
+    for (number, field) in message.fields {
+        let field_descriptor = match descriptor.field_by_number(number) {
+            Some(f) => f,
+            None => continue,
+        };
+        let key = if prefix.is_empty() {
+            field_descriptor.name().to_owned()
+        } else {
+            format!("{}.{}", prefix, field_descriptor.name())
+        };
+        match field {
+            value::Field::Singular(Some(v)) => {
+                flatten_value(descriptors, field_descriptor, v, key, map)
+            }
+            value::Field::Singular(None) => (),
+            value::Field::Repeated(vs) => {
+                for (i, v) in vs.into_iter().enumerate() {
+                    let key = format!("{}[{}]", key, i);
+                    flatten_value(descriptors, field_descriptor, v, key, map);
+                }
+            }
+        }
+    }
+}
+
+fn flatten_value(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    value: value::Value,
+    key: String,
+    map: &mut collections::BTreeMap<String, value::Value>,
+) {
+    match (field.field_type(descriptors), value) {
+        (descriptor::FieldType::Message(m), value::Value::Message(message))
+        | (descriptor::FieldType::Group(m), value::Value::Message(message)) => {
+            flatten_message(descriptors, m, message, &key, map)
+        }
+        (_, value) => {
+            map.insert(key, value);
+        }
+    }
 }
 
 impl<'de> fmt::Debug for Deserializer<'de> {
@@ -152,7 +526,7 @@ impl<'de> fmt::Debug for Deserializer<'de> {
     }
 }
 
-impl<'de, 'b> serde::Deserializer<'de> for &'b mut Deserializer<'de> {
+impl<'de> serde::Deserializer<'de> for &mut Deserializer<'de> {
     type Error = error::CompatError;
 
     forward_to_deserialize_any! {
@@ -167,7 +541,21 @@ impl<'de, 'b> serde::Deserializer<'de> for &'b mut Deserializer<'de> {
         V: serde::de::Visitor<'de>,
     {
         let mut message = value::Message::new(self.descriptor);
-        message.merge_from(self.descriptors, self.descriptor, &mut self.input)?;
+        match self.field_filter {
+            Some(ref wanted) => message.merge_wanted_fields(
+                self.descriptors,
+                self.descriptor,
+                &mut self.input,
+                &self.options,
+                wanted,
+            )?,
+            None => message.merge_from_with_options(
+                self.descriptors,
+                self.descriptor,
+                &mut self.input,
+                &self.options,
+            )?,
+        }
         visitor.visit_map(MessageVisitor::new(
             self.descriptors,
             self.descriptor,
@@ -176,6 +564,142 @@ impl<'de, 'b> serde::Deserializer<'de> for &'b mut Deserializer<'de> {
     }
 }
 
+impl<'de> FieldReader<'de> {
+    /// Constructs a new field reader for the specified message type.
+    ///
+    /// The caller must ensure that all of the information needed by the specified message
+    /// descriptor is available in the associated descriptors registry.
+    pub fn new(
+        descriptors: &'de descriptor::Descriptors,
+        descriptor: &'de descriptor::MessageDescriptor,
+        input: protobuf::CodedInputStream<'de>,
+    ) -> FieldReader<'de> {
+        FieldReader {
+            descriptors,
+            descriptor,
+            input,
+            options: value::DecodeOptions::default(),
+            pending: collections::VecDeque::new(),
+            seen: collections::BTreeSet::new(),
+            repeated_counts: collections::BTreeMap::new(),
+            remaining_fields: None,
+            done: false,
+        }
+    }
+
+    /// Constructs a new field reader for the specified named message type.
+    ///
+    /// The message type name must be fully quailified (for example
+    /// `".google.protobuf.FileDescriptorSet"`).
+    pub fn for_named_message(
+        descriptors: &'de descriptor::Descriptors,
+        message_name: &str,
+        input: protobuf::CodedInputStream<'de>,
+    ) -> error::Result<FieldReader<'de>> {
+        if let Some(message) = descriptors.message_by_name(message_name) {
+            Ok(FieldReader::new(descriptors, message, input))
+        } else {
+            Err(error::Error::UnknownMessage {
+                name: message_name.to_owned(),
+            })
+        }
+    }
+
+    /// Sets the options used to decode the input, for example to bound the size of the fields
+    /// read from untrusted input.
+    pub fn with_options(mut self, options: value::DecodeOptions) -> FieldReader<'de> {
+        self.remaining_fields = options.max_total_fields;
+        self.options = options;
+        self
+    }
+
+    fn read_next(&mut self) -> error::Result<bool> {
+        let context = value::DecodeContext::resume(&self.options, self.remaining_fields);
+        while !self.input.eof()? {
+            let (number, wire_type) = value::read_tag(&mut self.input)?;
+            context.count_field()?;
+
+            if let Some(field) = self.descriptor.field_by_number(number as i32) {
+                self.options
+                    .mark_seen(self.descriptors, field, &mut self.seen)?;
+                let mut value = value::Field::new(field);
+                let count = self.repeated_counts.entry(field.number()).or_insert(0);
+                let result = value.merge_value(
+                    self.descriptors,
+                    field,
+                    &mut self.input,
+                    wire_type,
+                    &context,
+                );
+                result.map_err(|e| match e {
+                    error::Error::InField {
+                        field,
+                        index,
+                        source,
+                    } => error::Error::InField {
+                        field,
+                        index: *count + index,
+                        source,
+                    },
+                    e => e,
+                })?;
+                context.check_nested_required()?;
+                if self.options.stringify_scalars {
+                    value.stringify_scalars(self.descriptors, field);
+                }
+                match value {
+                    value::Field::Singular(v) => self.pending.extend(v.map(|v| (field, v))),
+                    value::Field::Repeated(vs) => {
+                        *count += vs.len();
+                        self.pending.extend(vs.into_iter().map(|v| (field, v)))
+                    }
+                }
+
+                if !self.pending.is_empty() {
+                    self.remaining_fields = context.remaining_fields();
+                    return Ok(true);
+                }
+            } else {
+                value::skip_field(&mut self.input, wire_type, &self.options)?;
+            }
+        }
+        self.options.check_required(self.descriptor, &self.seen)?;
+        Ok(false)
+    }
+}
+
+impl<'de> Iterator for FieldReader<'de> {
+    type Item = error::Result<(&'de descriptor::FieldDescriptor, value::Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(entry) = self.pending.pop_front() {
+            return Some(Ok(entry));
+        }
+
+        if self.done {
+            return None;
+        }
+
+        match self.read_next() {
+            Ok(true) => self.pending.pop_front().map(Ok),
+            Ok(false) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<'de> fmt::Debug for FieldReader<'de> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FieldReader").finish()
+    }
+}
+
 impl<'de> MessageVisitor<'de> {
     #[inline]
     fn new(
@@ -274,13 +798,60 @@ impl<'de> MessageFieldDeserializer<'de> {
     }
 }
 
+impl<'de> MessageFieldDeserializer<'de> {
+    /// Deserializes the field like `deserialize_any`, except that present optional values are
+    /// visited directly instead of being wrapped in `Some`, for use with non-self-describing
+    /// deserialization targets.
+    #[inline]
+    fn deserialize_unwrapped<V>(mut self, visitor: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        match self.field.take() {
+            Some(value::Field::Singular(Some(v))) => {
+                visit_value(self.descriptors, self.descriptor, v, visitor)
+            }
+            field => {
+                self.field = field;
+                serde::Deserializer::deserialize_any(self, visitor)
+            }
+        }
+    }
+}
+
+/// Forwards typed deserialization methods to `deserialize_unwrapped`.
+macro_rules! forward_to_deserialize_unwrapped {
+    ($($func:ident($($arg:ident: $ty:ty),*))*) => {
+        $(
+            #[inline]
+            fn $func<V>(self, $($arg: $ty,)* visitor: V) -> error::CompatResult<V::Value>
+            where
+                V: serde::de::Visitor<'de>,
+            {
+                $(let _ = $arg;)*
+                self.deserialize_unwrapped(visitor)
+            }
+        )*
+    };
+}
+
 impl<'de> serde::Deserializer<'de> for MessageFieldDeserializer<'de> {
     type Error = error::CompatError;
 
     forward_to_deserialize_any! {
-        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
-        byte_buf option unit unit_struct newtype_struct seq tuple
-        tuple_struct map struct enum identifier ignored_any
+        identifier ignored_any
+    }
+
+    forward_to_deserialize_unwrapped! {
+        deserialize_bool() deserialize_i8() deserialize_i16() deserialize_i32()
+        deserialize_i64() deserialize_u8() deserialize_u16() deserialize_u32()
+        deserialize_u64() deserialize_f32() deserialize_f64() deserialize_char()
+        deserialize_str() deserialize_string() deserialize_bytes() deserialize_byte_buf()
+        deserialize_unit() deserialize_unit_struct(name: &'static str)
+        deserialize_newtype_struct(name: &'static str) deserialize_seq()
+        deserialize_tuple(len: usize) deserialize_tuple_struct(name: &'static str, len: usize)
+        deserialize_map()
+        deserialize_struct(name: &'static str, fields: &'static [&'static str])
     }
 
     #[inline]
@@ -311,6 +882,47 @@ impl<'de> serde::Deserializer<'de> for MessageFieldDeserializer<'de> {
             None => Err(error::Error::EndOfStream.into()),
         }
     }
+
+    #[inline]
+    fn deserialize_option<V>(mut self, visitor: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        let ds = self.descriptors;
+        let d = self.descriptor;
+        match self.field.take() {
+            Some(value::Field::Singular(None)) => visitor.visit_none(),
+            Some(value::Field::Singular(Some(v))) => {
+                visitor.visit_some(ValueDeserializer::new(ds, d, v))
+            }
+            Some(field @ value::Field::Repeated(_)) => {
+                visitor.visit_some(MessageFieldDeserializer::new(ds, d, field))
+            }
+            None => Err(error::Error::EndOfStream.into()),
+        }
+    }
+
+    #[inline]
+    fn deserialize_enum<V>(
+        mut self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        match self.field.take() {
+            Some(value::Field::Singular(Some(v))) => {
+                ValueDeserializer::new(self.descriptors, self.descriptor, v)
+                    .deserialize_enum(name, variants, visitor)
+            }
+            field => {
+                self.field = field;
+                serde::Deserializer::deserialize_any(self, visitor)
+            }
+        }
+    }
 }
 
 impl<'de> RepeatedValueVisitor<'de> {
@@ -371,8 +983,45 @@ impl<'de> serde::Deserializer<'de> for ValueDeserializer<'de> {
 
     forward_to_deserialize_any! {
         bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
-        byte_buf option unit unit_struct newtype_struct seq tuple
-        tuple_struct map struct enum identifier ignored_any
+        byte_buf unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+
+    #[inline]
+    fn deserialize_option<V>(self, visitor: V) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        visitor.visit_some(self)
+    }
+
+    #[inline]
+    fn deserialize_enum<V>(
+        mut self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> error::CompatResult<V::Value>
+    where
+        V: serde::de::Visitor<'de>,
+    {
+        use serde::de::IntoDeserializer;
+
+        match self.value.take() {
+            Some(value::Value::Enum(e)) => {
+                if let descriptor::FieldType::Enum(d) = self.descriptor.field_type(self.descriptors)
+                {
+                    match d.value_by_number(e) {
+                        Some(v) => visitor.visit_enum(v.name().into_deserializer()),
+                        None => Err(error::Error::UnknownEnumValue { value: e }.into()),
+                    }
+                } else {
+                    panic!("A field with an enum value doesn't have an enum type!")
+                }
+            }
+            Some(value) => visit_value(self.descriptors, self.descriptor, value, visitor),
+            None => Err(error::Error::EndOfStream.into()),
+        }
     }
 
     #[inline]
@@ -408,18 +1057,1258 @@ where
         value::Value::Bytes(v) => visitor.visit_byte_buf(v),
         value::Value::String(v) => visitor.visit_string(v),
         value::Value::Message(m) => {
-            if let descriptor::FieldType::Message(d) = descriptor.field_type(descriptors) {
-                visitor.visit_map(MessageVisitor::new(descriptors, d, m))
-            } else {
-                panic!("A field with a message value doesn't have a message type!")
+            match descriptor.field_type(descriptors) {
+                descriptor::FieldType::Message(d) | descriptor::FieldType::Group(d) => {
+                    visitor.visit_map(MessageVisitor::new(descriptors, d, m))
+                }
+                // Messages of unresolved types are decoded without a schema, so all of their fields
+                // are unknown fields, which are omitted
+                descriptor::FieldType::UnresolvedMessage(_) => visitor.visit_map(
+                    serde::de::value::MapDeserializer::<_, error::CompatError>::new(
+                        iter::empty::<(i32, i32)>(),
+                    ),
+                ),
+                _ => panic!("A field with a message value doesn't have a message type!"),
             }
         }
         value::Value::Enum(e) => {
             if let descriptor::FieldType::Enum(d) = descriptor.field_type(descriptors) {
-                visitor.visit_str(d.value_by_number(e).unwrap().name())
+                match d.name_for(e) {
+                    Some(name) => visitor.visit_str(name),
+                    // Unknown enum values are preserved as their raw numbers
+                    None => visitor.visit_i32(e),
+                }
             } else {
                 panic!("A field with an enum value doesn't have an enum type!")
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::descriptor::test::load_descriptors;
+    use crate::descriptor::{Descriptors, FieldDescriptor, FieldLabel, InternalFieldType};
+
+    fn numbers_descriptors() -> Descriptors {
+        let mut message = descriptor::MessageDescriptor::new(".test.Numbers");
+        message.add_field(
+            FieldDescriptor::builder("value")
+                .number(1)
+                .label(FieldLabel::Repeated)
+                .build(),
+        );
+        message.add_field(
+            FieldDescriptor::builder("name")
+                .number(2)
+                .field_type(InternalFieldType::String)
+                .build(),
+        );
+        let mut descriptors = Descriptors::new();
+        descriptors.add_message(message);
+        descriptors
+    }
+
+    fn read_all(data: &[u8]) -> Vec<error::Result<(String, value::Value)>> {
+        let descriptors = numbers_descriptors();
+        let input = protobuf::CodedInputStream::from_bytes(data);
+        FieldReader::for_named_message(&descriptors, ".test.Numbers", input)
+            .unwrap()
+            .map(|r| r.map(|(f, v)| (f.name().to_owned(), v)))
+            .collect()
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    enum Color {
+        #[serde(rename = "RED")]
+        Red,
+        #[serde(rename = "BLUE")]
+        Blue,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Inner {
+        id: i64,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Outer {
+        name: String,
+        nickname: Option<String>,
+        color: Color,
+        colors: Vec<Color>,
+        inner: Inner,
+        inners: Vec<Inner>,
+        values: Vec<u32>,
+    }
+
+    fn outer_descriptors() -> Descriptors {
+        let mut color = descriptor::EnumDescriptor::new(".test.Color");
+        color.add_value(descriptor::EnumValueDescriptor::new("RED", 0));
+        color.add_value(descriptor::EnumValueDescriptor::new("BLUE", 1));
+
+        let mut inner = descriptor::MessageDescriptor::new(".test.Inner");
+        inner.add_field(
+            FieldDescriptor::builder("id")
+                .number(1)
+                .label(FieldLabel::Required)
+                .field_type(InternalFieldType::Int64)
+                .build(),
+        );
+
+        let message_type = || InternalFieldType::UnresolvedMessage(".test.Inner".into());
+        let enum_type = || InternalFieldType::UnresolvedEnum(".test.Color".into());
+        let mut outer = descriptor::MessageDescriptor::new(".test.Outer");
+        outer.add_field(
+            FieldDescriptor::builder("name")
+                .number(1)
+                .field_type(InternalFieldType::String)
+                .build(),
+        );
+        outer.add_field(
+            FieldDescriptor::builder("nickname")
+                .number(2)
+                .field_type(InternalFieldType::String)
+                .build(),
+        );
+        outer.add_field(
+            FieldDescriptor::builder("color")
+                .number(3)
+                .field_type(enum_type())
+                .build(),
+        );
+        outer.add_field(
+            FieldDescriptor::builder("colors")
+                .number(4)
+                .label(FieldLabel::Repeated)
+                .field_type(enum_type())
+                .build(),
+        );
+        outer.add_field(
+            FieldDescriptor::builder("inner")
+                .number(5)
+                .field_type(message_type())
+                .build(),
+        );
+        outer.add_field(
+            FieldDescriptor::builder("inners")
+                .number(6)
+                .label(FieldLabel::Repeated)
+                .field_type(message_type())
+                .build(),
+        );
+        outer.add_field(
+            FieldDescriptor::builder("values")
+                .number(7)
+                .label(FieldLabel::Repeated)
+                .field_type(InternalFieldType::UInt32)
+                .build(),
+        );
+
+        let mut descriptors = Descriptors::new();
+        descriptors.add_enum(color);
+        descriptors.add_message(inner);
+        descriptors.add_message(outer);
+        descriptors.resolve_refs();
+        descriptors
+    }
+
+    #[test]
+    fn deserialize_derived_struct() {
+        use serde::Deserialize;
+
+        let descriptors = outer_descriptors();
+        let data = [
+            10, 3, b'b', b'o', b'b', // name: "bob"
+            24, 1, // color: BLUE
+            32, 0, 32, 1, // colors: [RED, BLUE]
+            42, 2, 8, 7, // inner: { id: 7 }
+            50, 2, 8, 8, 50, 2, 8, 9, // inners: [{ id: 8 }, { id: 9 }]
+            58, 2, 1, 2, // values: [1, 2] (packed)
+        ];
+        let input = protobuf::CodedInputStream::from_bytes(&data);
+        let mut deserializer =
+            Deserializer::for_named_message(&descriptors, ".test.Outer", input).unwrap();
+        let outer = Outer::deserialize(&mut deserializer).unwrap();
+
+        assert_eq!(
+            outer,
+            Outer {
+                name: "bob".to_owned(),
+                nickname: None,
+                color: Color::Blue,
+                colors: vec![Color::Red, Color::Blue],
+                inner: Inner { id: 7 },
+                inners: vec![Inner { id: 8 }, Inner { id: 9 }],
+                values: vec![1, 2],
+            }
+        );
+    }
+
+    #[test]
+    fn unknown_enum_values() {
+        use serde::Deserialize;
+
+        let descriptors = outer_descriptors();
+        let color = descriptors.enum_by_name(".test.Color").unwrap();
+        assert_eq!(color.name_for(1), Some("BLUE"));
+        assert_eq!(color.name_for(7), None);
+
+        let data = [
+            24, 7, // color: 7
+            32, 1, 32, 9, // colors: [BLUE, 9]
+        ];
+        let input = protobuf::CodedInputStream::from_bytes(&data);
+        let mut deserializer =
+            Deserializer::for_named_message(&descriptors, ".test.Outer", input).unwrap();
+        let value = serde_value::Value::deserialize(&mut deserializer).unwrap();
+
+        let field = |name: &str| match value {
+            serde_value::Value::Map(ref m) => m
+                .get(&serde_value::Value::String(name.to_owned()))
+                .cloned()
+                .unwrap(),
+            ref v => panic!("Expected a map, got {:?}", v),
+        };
+        assert_eq!(
+            field("color"),
+            serde_value::Value::Option(Some(Box::new(serde_value::Value::I32(7))))
+        );
+        assert_eq!(
+            field("colors"),
+            serde_value::Value::Seq(vec![
+                serde_value::Value::String("BLUE".to_owned()),
+                serde_value::Value::I32(9),
+            ])
+        );
+    }
+
+    #[test]
+    fn max_total_fields() {
+        use serde::Deserialize;
+
+        fn too_many_fields(e: &error::Error) -> bool {
+            match e {
+                error::Error::TooManyFields { max_fields } => *max_fields == 11,
+                error::Error::DecodeAt { source, .. } | error::Error::InField { source, .. } => {
+                    too_many_fields(source)
+                }
+                _ => false,
+            }
+        }
+
+        let descriptors = numbers_descriptors();
+        let options = |max| value::DecodeOptions::new().max_total_fields(max);
+        // name: "ab", value: [1, ..., 10] (packed), which counts the tags and every element
+        let mut data = vec![18, 2, b'a', b'b', 10, 10];
+        data.extend(1..=10);
+        let deserialize = |max| {
+            let input = protobuf::CodedInputStream::from_bytes(&data);
+            let mut deserializer =
+                Deserializer::for_named_message(&descriptors, ".test.Numbers", input)
+                    .unwrap()
+                    .max_total_fields(max);
+            serde::de::IgnoredAny::deserialize(&mut deserializer).map_err(|e| e.into_error())
+        };
+        assert!(deserialize(12).is_ok());
+        match deserialize(11) {
+            Err(ref e) if too_many_fields(e) => (),
+            r => panic!("Expected too many fields error, got {:?}", r),
+        }
+
+        let descriptor = descriptors.message_by_name(".test.Numbers").unwrap();
+        match value::BorrowedMessage::decode_with_options(
+            &descriptors,
+            descriptor,
+            &data,
+            &options(11),
+        ) {
+            Err(ref e) if too_many_fields(e) => (),
+            r => panic!("Expected too many fields error, got {:?}", r),
+        }
+
+        // The limit applies to the reader as a whole, not to each field
+        let data = [
+            8, 1, 8, 2, 8, 3, 8, 4, 8, 5, 8, 6, 8, 7, 8, 8, 8, 9, 8, 10, 8, 11, 8, 12,
+        ];
+        let input = protobuf::CodedInputStream::from_bytes(&data);
+        let results = FieldReader::for_named_message(&descriptors, ".test.Numbers", input)
+            .unwrap()
+            .with_options(options(11))
+            .collect::<Vec<_>>();
+        assert_eq!(results.len(), 12);
+        assert!(results[..11].iter().all(Result::is_ok));
+        assert!(too_many_fields(results[11].as_ref().unwrap_err()));
+
+        // Fields of nested messages count towards the limit of the outer message
+        let descriptors = load_descriptors();
+        let descriptor = descriptors
+            .message_by_name(".protobuf_unittest.TestAllTypes")
+            .unwrap();
+        // repeated_nested_message: [{ bb: 1 }] * 6
+        let data = [130, 3, 2, 8, 1].repeat(6);
+        let decode = |max| {
+            let mut message = value::Message::new(descriptor);
+            let mut input = protobuf::CodedInputStream::from_bytes(&data);
+            message.merge_from_with_options(&descriptors, descriptor, &mut input, &options(max))
+        };
+        assert!(decode(12).is_ok());
+        match decode(11) {
+            Err(ref e) if too_many_fields(e) => (),
+            r => panic!("Expected too many fields error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn max_field_size() {
+        use serde::Deserialize;
+
+        let descriptors = numbers_descriptors();
+        let options = || value::DecodeOptions::new().max_field_size(4);
+
+        // name: "abcd"
+        let data = [18, 4, b'a', b'b', b'c', b'd'];
+        let input = protobuf::CodedInputStream::from_bytes(&data);
+        let mut deserializer =
+            Deserializer::for_named_message(&descriptors, ".test.Numbers", input)
+                .unwrap()
+                .with_options(options());
+        assert!(serde::de::IgnoredAny::deserialize(&mut deserializer).is_ok());
+
+        // name: a bogus length prefix of 2^32 - 1 bytes
+        let data = [18, 0xff, 0xff, 0xff, 0xff, 0x0f, b'a'];
+        let input = protobuf::CodedInputStream::from_bytes(&data);
+        let mut deserializer =
+            Deserializer::for_named_message(&descriptors, ".test.Numbers", input)
+                .unwrap()
+                .with_options(options());
+        match serde::de::IgnoredAny::deserialize(&mut deserializer) {
+            Err(e) => match e.into_error() {
+                error::Error::DecodeAt { offset: 0, source } => match *source {
+                    error::Error::FieldTooLarge { size, max_size } => {
+                        assert_eq!(size, 0xffff_ffff);
+                        assert_eq!(max_size, 4);
+                    }
+                    e => panic!("Expected field too large error, got {:?}", e),
+                },
+                e => panic!("Expected field too large error, got {:?}", e),
+            },
+            Ok(_) => panic!("Expected field too large error"),
+        }
+
+        // value: packed with a bogus length prefix, and an unknown field with one
+        for data in &[&[10, 0x80, 0x80, 0x04][..], &[122, 0x80, 0x80, 0x04][..]] {
+            let input = protobuf::CodedInputStream::from_bytes(data);
+            let results = FieldReader::for_named_message(&descriptors, ".test.Numbers", input)
+                .unwrap()
+                .with_options(options())
+                .collect::<Vec<_>>();
+            match results.as_slice() {
+                [Err(error::Error::FieldTooLarge { size: 65536, .. })] => (),
+                r => panic!("Expected field too large error, got {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn read_delimited_frames() {
+        let descriptors = outer_descriptors();
+        let outer = descriptors.message_by_name(".test.Outer").unwrap();
+        let data = [
+            5, 10, 3, b'b', b'o', b'b', // { name: "bob" }
+            0,    // {}
+            4, 10, 3, b'a', // truncated
+        ];
+        let mut reader = &data[..];
+
+        match read_delimited(&descriptors, outer, &mut reader) {
+            Some(Ok(value::Value::Message(m))) => match m.fields.get(&1) {
+                Some(value::Field::Singular(Some(value::Value::String(s)))) => assert_eq!(s, "bob"),
+                f => panic!("Expected a name, got {:?}", f),
+            },
+            r => panic!("Expected a message, got {:?}", r),
+        }
+        match read_delimited(&descriptors, outer, &mut reader) {
+            Some(Ok(value::Value::Message(m))) => {
+                assert!(matches!(
+                    m.fields.get(&1),
+                    Some(value::Field::Singular(None))
+                ))
+            }
+            r => panic!("Expected a message, got {:?}", r),
+        }
+        match read_delimited(&descriptors, outer, &mut reader) {
+            Some(Err(error::Error::EndOfStream)) => (),
+            r => panic!("Expected end of stream error, got {:?}", r),
+        }
+        assert!(read_delimited(&descriptors, outer, &mut reader).is_none());
+    }
+
+    #[test]
+    fn decode_exact_frame() {
+        let descriptors = outer_descriptors();
+        let outer = descriptors.message_by_name(".test.Outer").unwrap();
+        let data = [5, 10, 3, b'b', b'o', b'b', 0];
+
+        match decode_exact(&descriptors, outer, &data[..6]) {
+            Ok(value::Value::Message(m)) => match m.fields.get(&1) {
+                Some(value::Field::Singular(Some(value::Value::String(s)))) => assert_eq!(s, "bob"),
+                f => panic!("Expected a name, got {:?}", f),
+            },
+            r => panic!("Expected a message, got {:?}", r),
+        }
+        match decode_exact(&descriptors, outer, &data) {
+            Err(error::Error::TrailingData { remaining: 1 }) => (),
+            r => panic!("Expected trailing data error, got {:?}", r),
+        }
+        for data in &[&data[..0], &data[..4]] {
+            match decode_exact(&descriptors, outer, data) {
+                Err(error::Error::EndOfStream) => (),
+                r => panic!("Expected end of stream error, got {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn decode_with_presence() {
+        let descriptors = numbers_descriptors();
+        let numbers = descriptors.message_by_name(".test.Numbers").unwrap();
+
+        // name = "", then an unknown field 3 = 0
+        let (value, presence) =
+            super::decode_with_presence(&descriptors, numbers, &[18, 0, 24, 0]).unwrap();
+        assert_eq!(presence, [2, 3].iter().cloned().collect::<FieldPresence>());
+        match value {
+            value::Value::Message(m) => match m.fields.get(&2) {
+                Some(value::Field::Singular(Some(value::Value::String(s)))) => {
+                    assert!(s.is_empty())
+                }
+                f => panic!("Expected an empty name, got {:?}", f),
+            },
+            v => panic!("Expected a message, got {:?}", v),
+        }
+
+        // value = [0], an explicit zero
+        let (_, presence) = super::decode_with_presence(&descriptors, numbers, &[8, 0]).unwrap();
+        assert!(presence.contains(&1) && !presence.contains(&2));
+
+        // value = [0, 1], in two occurrences
+        let (_, presence) =
+            super::decode_with_presence(&descriptors, numbers, &[8, 0, 8, 1]).unwrap();
+        assert_eq!(presence, [1].iter().cloned().collect::<FieldPresence>());
+
+        let (_, presence) = super::decode_with_presence(&descriptors, numbers, &[]).unwrap();
+        assert!(presence.is_empty());
+        assert!(super::decode_with_presence(&descriptors, numbers, &[8]).is_err());
+
+        // Only the fields of the top-level message are recorded
+        let descriptors = outer_descriptors();
+        let outer = descriptors.message_by_name(".test.Outer").unwrap();
+        // inner { id: 30 }
+        let (_, presence) =
+            super::decode_with_presence(&descriptors, outer, &[42, 2, 8, 30]).unwrap();
+        assert_eq!(presence, [5].iter().cloned().collect::<FieldPresence>());
+    }
+
+    #[test]
+    fn to_flat_map() {
+        let descriptors = outer_descriptors();
+        let data = [
+            10, 3, b'B', b'o', b'b', // name: "Bob"
+            24, 1, // color: BLUE
+            42, 2, 8, 30, // inner { id: 30 }
+            50, 2, 8, 1, 50, 2, 8, 2, // inners: [{ id: 1 }, { id: 2 }]
+            56, 7, 56, 8, // values: [7, 8]
+        ];
+
+        let map = super::to_flat_map(&descriptors, ".test.Outer", &data).unwrap();
+        assert_eq!(
+            map.keys().map(String::as_str).collect::<Vec<_>>(),
+            vec![
+                "color",
+                "inner.id",
+                "inners[0].id",
+                "inners[1].id",
+                "name",
+                "values[0]",
+                "values[1]",
+            ]
+        );
+        assert!(matches!(map["name"], value::Value::String(ref s) if s == "Bob"));
+        assert!(matches!(map["color"], value::Value::Enum(1)));
+        assert!(matches!(map["inner.id"], value::Value::I64(30)));
+        assert!(matches!(map["inners[1].id"], value::Value::I64(2)));
+        assert!(matches!(map["values[0]"], value::Value::U32(7)));
+
+        match super::to_flat_map(&descriptors, ".test.Missing", &data) {
+            Err(error::Error::UnknownMessage { name }) => assert_eq!(name, ".test.Missing"),
+            r => panic!("Expected unknown message error, got {:?}", r),
+        }
+
+        // Groups are flattened like messages, and declared defaults that aren't on the wire are
+        // omitted
+        let mut group = descriptor::MessageDescriptor::new(".test.Message.Group");
+        group.add_field(FieldDescriptor::builder("a").number(1).build());
+        let mut message = descriptor::MessageDescriptor::new(".test.Message");
+        message.add_field(
+            FieldDescriptor::builder("group")
+                .number(1)
+                .field_type(InternalFieldType::UnresolvedGroup(
+                    ".test.Message.Group".into(),
+                ))
+                .build(),
+        );
+        message.add_field(
+            FieldDescriptor::builder("count")
+                .number(2)
+                .default_value(value::Value::I32(3))
+                .build(),
+        );
+        let mut descriptors = Descriptors::new();
+        descriptors.add_message(group);
+        descriptors.add_message(message);
+        descriptors.resolve_refs();
+        // group { a: 5 }
+        let data = [11, 8, 5, 12];
+        let map = super::to_flat_map(&descriptors, ".test.Message", &data).unwrap();
+        assert_eq!(
+            map.keys().map(String::as_str).collect::<Vec<_>>(),
+            vec!["group.a"]
+        );
+        assert!(matches!(map["group.a"], value::Value::I32(5)));
+    }
+
+    #[test]
+    fn decode_field_value() {
+        use protobuf::rt::WireType;
+
+        let descriptors = outer_descriptors();
+        let outer = descriptors.message_by_name(".test.Outer").unwrap();
+        let field = |name| outer.field_by_name(name).unwrap();
+
+        match super::decode_field_value(
+            &descriptors,
+            field("name"),
+            WireType::LengthDelimited,
+            &[3, b'b', b'o', b'b'],
+        ) {
+            Ok(value::Value::String(s)) => assert_eq!(s, "bob"),
+            r => panic!("Expected a string, got {:?}", r),
+        }
+        match super::decode_field_value(&descriptors, field("values"), WireType::Varint, &[150, 1])
+        {
+            Ok(value::Value::U32(150)) => (),
+            r => panic!("Expected an integer, got {:?}", r),
+        }
+        match super::decode_field_value(
+            &descriptors,
+            field("inner"),
+            WireType::LengthDelimited,
+            &[2, 8, 7],
+        ) {
+            Ok(value::Value::Message(m)) => match m.fields.get(&1) {
+                Some(value::Field::Singular(Some(value::Value::I64(7)))) => (),
+                f => panic!("Expected an id, got {:?}", f),
+            },
+            r => panic!("Expected a message, got {:?}", r),
+        }
+
+        // Packed values and mismatched wire types are rejected
+        match super::decode_field_value(
+            &descriptors,
+            field("values"),
+            WireType::LengthDelimited,
+            &[2, 1, 2],
+        ) {
+            Err(error::Error::BadWireType {
+                wire_type: WireType::LengthDelimited,
+            }) => (),
+            r => panic!("Expected a bad wire type error, got {:?}", r),
+        }
+        match super::decode_field_value(&descriptors, field("name"), WireType::Varint, &[1]) {
+            Err(error::Error::BadWireType {
+                wire_type: WireType::Varint,
+            }) => (),
+            r => panic!("Expected a bad wire type error, got {:?}", r),
+        }
+        match super::decode_field_value(&descriptors, field("color"), WireType::Varint, &[1, 1]) {
+            Err(error::Error::BadField { .. }) => (),
+            r => panic!("Expected a bad field error, got {:?}", r),
+        }
+        match super::decode_field_value(&descriptors, field("color"), WireType::Varint, &[]) {
+            Err(_) => (),
+            r => panic!("Expected an error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn field_hook() {
+        use serde::Deserialize;
+
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Names {
+            name: String,
+            nickname: String,
+        }
+
+        let descriptors = outer_descriptors();
+        let data = [10, 3, b'b', b'o', b'b', 18, 2, b'a', b'l']; // name: "bob", nickname: "al"
+        let input = protobuf::CodedInputStream::from_bytes(&data);
+        let mut deserializer = Deserializer::for_named_message(&descriptors, ".test.Outer", input)
+            .unwrap()
+            .with_field_hook(".test.Outer", 1, |v| match v {
+                value::Value::String(s) => Ok(value::Value::String(s.to_uppercase())),
+                v => Ok(v),
+            });
+
+        assert_eq!(
+            Names::deserialize(&mut deserializer).unwrap(),
+            Names {
+                name: "BOB".to_owned(),
+                nickname: "al".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn deserialize_lenient() {
+        use serde::Deserialize;
+
+        let descriptors = outer_descriptors();
+        let mut data = vec![10, 3, b'b', b'o', b'b']; // name: "bob"
+        data.extend_from_slice(&[29, 1, 0, 0, 0]); // color, but with the fixed32 wire type
+        data.extend_from_slice(&[18, 2, b'a', b'l']); // nickname: "al"
+
+        let input = protobuf::CodedInputStream::from_bytes(&data);
+        let (value, errors) = Deserializer::for_named_message(&descriptors, ".test.Outer", input)
+            .unwrap()
+            .deserialize_lenient();
+
+        match errors.as_slice() {
+            [error::Error::DecodeAt { offset: 5, source }] => match **source {
+                error::Error::BadWireType {
+                    wire_type: protobuf::rt::WireType::Fixed32,
+                } => (),
+                ref e => panic!("Expected a bad wire type error, got {:?}", e),
+            },
+            e => panic!("Expected a single bad wire type error, got {:?}", e),
+        }
+        let message = match value {
+            value::Value::Message(m) => m,
+            v => panic!("Expected a message, got {:?}", v),
+        };
+        let strings = message
+            .fields
+            .iter()
+            .filter_map(|(&number, field)| match field {
+                value::Field::Singular(Some(value::Value::String(s))) => Some((number, s.as_str())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(strings, vec![(1, "bob"), (2, "al")]);
+
+        // The strict mode fails on the same input
+        let input = protobuf::CodedInputStream::from_bytes(&data);
+        let mut deserializer =
+            Deserializer::for_named_message(&descriptors, ".test.Outer", input).unwrap();
+        assert!(serde::de::IgnoredAny::deserialize(&mut deserializer).is_err());
+    }
+
+    #[test]
+    fn field_filter() {
+        use serde::Deserialize;
+
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Projection {
+            name: String,
+            values: Vec<u32>,
+        }
+
+        let descriptors = outer_descriptors();
+        let mut data = vec![10, 3, b'b', b'o', b'b']; // name: "bob"
+        for id in 0..1000 {
+            data.extend_from_slice(&[50, 2, 8, (id % 128) as u8]); // inners: [{ id }, ...]
+        }
+        data.extend_from_slice(&[58, 2, 1, 2]); // values: [1, 2] (packed)
+        data.extend_from_slice(&[42, 0]); // inner: {} (missing its required id)
+
+        let deserializer = || {
+            let input = protobuf::CodedInputStream::from_bytes(&data);
+            Deserializer::for_named_message(&descriptors, ".test.Outer", input)
+                .unwrap()
+                .with_field_filter(&[1, 7])
+        };
+        let projection = Projection::deserialize(&mut deserializer()).unwrap();
+        assert_eq!(
+            projection,
+            Projection {
+                name: "bob".to_owned(),
+                values: vec![1, 2],
+            }
+        );
+
+        let value = serde_value::Value::deserialize(&mut deserializer()).unwrap();
+        match value {
+            serde_value::Value::Map(m) => assert_eq!(
+                m.keys().cloned().collect::<Vec<_>>(),
+                vec![
+                    serde_value::Value::String("name".to_owned()),
+                    serde_value::Value::String("values".to_owned()),
+                ]
+            ),
+            v => panic!("Expected a map, got {:?}", v),
+        }
+    }
+
+    #[test]
+    fn field_offsets() {
+        use serde::Deserialize;
+
+        let descriptors = outer_descriptors();
+        let data = [
+            10, 3, b'b', b'o', b'b', // name: "bob"
+            50, 2, 8, 8, 50, 2, 8, 9, // inners: [{ id: 8 }, { id: 9 }]
+            120, 7, // unknown field 15: 7
+            24, 1, // color: BLUE
+        ];
+        let input = protobuf::CodedInputStream::from_bytes(&data);
+        let offsets = Deserializer::for_named_message(&descriptors, ".test.Outer", input)
+            .unwrap()
+            .field_offsets()
+            .unwrap();
+        assert_eq!(
+            offsets,
+            vec![(1, 0..5), (6, 5..9), (6, 9..13), (15, 13..15), (3, 15..17)]
+        );
+
+        let input = protobuf::CodedInputStream::from_bytes(&data[9..13]);
+        let mut deserializer =
+            Deserializer::for_named_message(&descriptors, ".test.Outer", input).unwrap();
+        let value = serde_value::Value::deserialize(&mut deserializer).unwrap();
+        match value {
+            serde_value::Value::Map(m) => assert_eq!(
+                m[&serde_value::Value::String("inners".to_owned())],
+                serde_value::Value::Seq(vec![serde_value::Value::Map(
+                    vec![(
+                        serde_value::Value::String("id".to_owned()),
+                        serde_value::Value::I64(9)
+                    )]
+                    .into_iter()
+                    .collect()
+                )])
+            ),
+            v => panic!("Expected a map, got {:?}", v),
+        }
+    }
+
+    #[test]
+    fn missing_required_field() {
+        use serde::Deserialize;
+
+        let mut message = descriptor::MessageDescriptor::new(".test.Required");
+        message.add_field(
+            FieldDescriptor::builder("id")
+                .number(1)
+                .label(FieldLabel::Required)
+                .build(),
+        );
+        message.add_field(FieldDescriptor::builder("extra").number(2).build());
+        let mut descriptors = Descriptors::new();
+        descriptors.add_message(message);
+
+        // extra: 3
+        let data = [16, 3];
+        let deserialize = |options| {
+            let input = protobuf::CodedInputStream::from_bytes(&data);
+            let mut deserializer =
+                Deserializer::for_named_message(&descriptors, ".test.Required", input)
+                    .unwrap()
+                    .with_options(options);
+            serde_value::Value::deserialize(&mut deserializer).map_err(|e| e.into_error())
+        };
+
+        match deserialize(value::DecodeOptions::new()) {
+            Err(error::Error::MissingRequiredField { field }) => assert_eq!(field, "id"),
+            r => panic!("Expected missing required field error, got {:?}", r),
+        }
+        assert!(deserialize(value::DecodeOptions::new().allow_missing_required(true)).is_ok());
+
+        let input = protobuf::CodedInputStream::from_bytes(&data);
+        let results = FieldReader::for_named_message(&descriptors, ".test.Required", input)
+            .unwrap()
+            .collect::<Vec<_>>();
+        match results.as_slice() {
+            [Ok(_), Err(error::Error::MissingRequiredField { .. })] => (),
+            r => panic!("Expected missing required field error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn missing_required_field_split_message() {
+        let mut inner = descriptor::MessageDescriptor::new(".test.Inner");
+        inner.add_field(
+            FieldDescriptor::builder("id")
+                .number(1)
+                .label(FieldLabel::Required)
+                .build(),
+        );
+        inner.add_field(FieldDescriptor::builder("x").number(2).build());
+        let mut outer = descriptor::MessageDescriptor::new(".test.Outer");
+        let inner_type = || InternalFieldType::UnresolvedMessage(".test.Inner".into());
+        outer.add_field(
+            FieldDescriptor::builder("inner")
+                .number(1)
+                .field_type(inner_type())
+                .build(),
+        );
+        outer.add_field(
+            FieldDescriptor::builder("inners")
+                .number(2)
+                .label(FieldLabel::Repeated)
+                .field_type(inner_type())
+                .build(),
+        );
+        let mut descriptors = Descriptors::new();
+        descriptors.add_message(inner);
+        descriptors.add_message(outer);
+        descriptors.resolve_refs();
+        let outer = descriptors.message_by_name(".test.Outer").unwrap();
+
+        let decode = |data: &[u8]| -> error::Result<()> {
+            let mut message = value::Message::new(outer);
+            let mut input = protobuf::CodedInputStream::from_bytes(data);
+            message.merge_from(&descriptors, outer, &mut input)?;
+            value::BorrowedMessage::decode(&descriptors, outer, data)?;
+            #[cfg(feature = "arena")]
+            value::ArenaMessage::decode(&bumpalo::Bump::new(), &descriptors, outer, data)?;
+            Ok(())
+        };
+
+        // inner { id: 1 }, inner { x: 2 }
+        assert!(decode(&[10, 2, 8, 1, 10, 2, 16, 2]).is_ok());
+        // inner { x: 2 }, inner { id: 1 }
+        assert!(decode(&[10, 2, 16, 2, 10, 2, 8, 1]).is_ok());
+        // inner { x: 2 }, inner { x: 3 }
+        match decode(&[10, 2, 16, 2, 10, 2, 16, 3]) {
+            Err(error::Error::MissingRequiredField { field }) => assert_eq!(field, "id"),
+            r => panic!("Expected missing required field error, got {:?}", r),
+        }
+        // inners { id: 1 }, inners { x: 2 } are separate messages
+        match decode(&[18, 2, 8, 1, 18, 2, 16, 2]) {
+            Err(error::Error::MissingRequiredField { field }) => assert_eq!(field, "id"),
+            r => panic!("Expected missing required field error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn field_reader_interleaved_repeated() {
+        // value: 1, name: "a", value: 2, unknown field 15: 7, value: [3, 4] (packed)
+        let data = [8, 1, 18, 1, b'a', 8, 2, 120, 7, 10, 2, 3, 4];
+        let fields = read_all(&data)
+            .into_iter()
+            .map(|r| match r.unwrap() {
+                (n, value::Value::I32(v)) => format!("{}={}", n, v),
+                (n, value::Value::String(v)) => format!("{}={}", n, v),
+                (n, v) => panic!("unexpected value for {}: {:?}", n, v),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            vec!["value=1", "name=a", "value=2", "value=3", "value=4"]
+        );
+    }
+
+    #[test]
+    fn field_reader_surfaces_errors() {
+        // value: 1, then a truncated varint
+        let results = read_all(&[8, 1, 8, 0x80]);
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+    }
+
+    #[test]
+    fn field_reader_bad_wire_type() {
+        let results = read_all(&[13, 1, 0, 0, 0]);
+        match results.as_slice() {
+            [Err(error::Error::BadWireType { .. })] => (),
+            r => panic!("expected a bad wire type error, got {:?}", r),
+        }
+    }
+
+    /// Encodes a `TestAllTypes` message with a value of every scalar type.
+    fn all_types(descriptors: &Descriptors) -> Vec<u8> {
+        let message = descriptors
+            .message_by_name(".protobuf_unittest.TestAllTypes")
+            .unwrap();
+        let nested = descriptors
+            .message_by_name(".protobuf_unittest.TestAllTypes.NestedMessage")
+            .unwrap();
+
+        let mut inner = value::Message::new(nested);
+        inner
+            .fields
+            .insert(1, value::Field::Singular(Some(value::Value::I32(-3))));
+        let mut m = value::Message::new(message);
+        for (number, v) in vec![
+            (1, value::Value::I32(-1)),
+            (2, value::Value::I64(-9_007_199_254_740_993)),
+            (3, value::Value::U32(u32::MAX)),
+            (4, value::Value::U64(u64::MAX)),
+            (5, value::Value::I32(-5)),
+            (6, value::Value::I64(6)),
+            (7, value::Value::U32(7)),
+            (8, value::Value::U64(8)),
+            (9, value::Value::I32(-9)),
+            (10, value::Value::I64(10)),
+            (11, value::Value::F32(0.1)),
+            (12, value::Value::F64(1e20)),
+            (13, value::Value::Bool(true)),
+            (14, value::Value::String("text".to_owned())),
+            (15, value::Value::Bytes(vec![0xfb, 0xff])),
+            (18, value::Value::Message(inner)),
+            (21, value::Value::Enum(2)),
+        ] {
+            m.fields.insert(number, value::Field::Singular(Some(v)));
+        }
+        m.fields.insert(
+            31,
+            value::Field::Repeated(vec![value::Value::I32(1), value::Value::I32(2)]),
+        );
+        m.fields.insert(
+            51,
+            value::Field::Repeated(vec![value::Value::Enum(3), value::Value::Enum(42)]),
+        );
+        crate::ser::to_bytes(descriptors, message, &value::Value::Message(m)).unwrap()
+    }
+
+    #[test]
+    fn stringify_scalars() {
+        use serde::Deserialize;
+
+        let descriptors = load_descriptors();
+        let name = ".protobuf_unittest.TestAllTypes";
+        let data = all_types(&descriptors);
+        let input = protobuf::CodedInputStream::from_bytes(&data);
+        let mut deserializer = Deserializer::for_named_message(&descriptors, name, input)
+            .unwrap()
+            .stringify_scalars(true);
+        let value = serde_value::Value::deserialize(&mut deserializer).unwrap();
+
+        let string = |s: &str| serde_value::Value::String(s.to_owned());
+        let some = |v| serde_value::Value::Option(Some(Box::new(v)));
+        let field = |value: &serde_value::Value, name: &str| match *value {
+            serde_value::Value::Map(ref m) => m.get(&string(name)).cloned().unwrap(),
+            ref v => panic!("Expected a map, got {:?}", v),
+        };
+        for (name, expected) in &[
+            ("optional_int32", "-1"),
+            ("optional_int64", "-9007199254740993"),
+            ("optional_uint32", "4294967295"),
+            ("optional_uint64", "18446744073709551615"),
+            ("optional_sint32", "-5"),
+            ("optional_sint64", "6"),
+            ("optional_fixed32", "7"),
+            ("optional_fixed64", "8"),
+            ("optional_sfixed32", "-9"),
+            ("optional_sfixed64", "10"),
+            ("optional_float", "0.1"),
+            ("optional_double", "100000000000000000000"),
+            ("optional_bool", "true"),
+            ("optional_string", "text"),
+            ("optional_bytes", "+/8="),
+            ("optional_nested_enum", "BAR"),
+        ] {
+            assert_eq!(field(&value, name), some(string(expected)), "{}", name);
+        }
+        match field(&value, "optional_nested_message") {
+            serde_value::Value::Option(Some(inner)) => {
+                assert_eq!(field(&inner, "bb"), some(string("-3")))
+            }
+            v => panic!("Expected a nested message, got {:?}", v),
+        }
+        assert_eq!(
+            field(&value, "repeated_int32"),
+            serde_value::Value::Seq(vec![string("1"), string("2")])
+        );
+        // Unknown enum values become their numbers
+        assert_eq!(
+            field(&value, "repeated_nested_enum"),
+            serde_value::Value::Seq(vec![string("BAZ"), string("42")])
+        );
+        // Unset fields are left unset
+        assert_eq!(
+            field(&value, "optional_string_piece"),
+            serde_value::Value::Option(None)
+        );
+    }
+
+    #[test]
+    fn schemaless_unresolved_messages() {
+        use serde::Deserialize;
+
+        // The type of the payload and history fields is deliberately left out of the registry
+        let mut message = descriptor::MessageDescriptor::new(".test.Event");
+        message.add_field(FieldDescriptor::builder("id").number(1).build());
+        for (name, number, label) in &[
+            ("payload", 2, FieldLabel::Optional),
+            ("history", 3, FieldLabel::Repeated),
+        ] {
+            message.add_field(
+                FieldDescriptor::builder(*name)
+                    .number(*number)
+                    .label(*label)
+                    .field_type(InternalFieldType::UnresolvedMessage(".test.Missing".into()))
+                    .build(),
+            );
+        }
+        let mut descriptors = Descriptors::new();
+        descriptors.add_message(message);
+        descriptors.resolve_refs();
+        let descriptor = descriptors.message_by_name(".test.Event").unwrap();
+
+        // id: 1, payload { 1: 5, 2: "hi" }, history: [{ 1: 7 }], payload { 3: fixed32 1 }
+        let data = [
+            8, 1, 18, 6, 8, 5, 18, 2, b'h', b'i', 26, 2, 8, 7, 18, 5, 29, 1, 0, 0, 0,
+        ];
+        let decode = |options: value::DecodeOptions| {
+            let mut message = value::Message::new(descriptor);
+            let mut input = protobuf::CodedInputStream::from_bytes(&data);
+            message
+                .merge_from_with_options(&descriptors, descriptor, &mut input, &options)
+                .map(|()| message)
+        };
+
+        match decode(value::DecodeOptions::new()) {
+            Err(error::Error::DecodeAt { offset: 2, source }) => match *source {
+                error::Error::UnknownMessage { name } => assert_eq!(name, ".test.Missing"),
+                e => panic!("Expected an unknown message error, got {:?}", e),
+            },
+            r => panic!("Expected an unknown message error, got {:?}", r),
+        }
+
+        let message =
+            decode(value::DecodeOptions::new().schemaless_unresolved_messages(true)).unwrap();
+        match message.fields.get(&2) {
+            Some(value::Field::Singular(Some(value::Value::Message(payload)))) => {
+                assert!(payload.fields.is_empty());
+                let mut unknown = payload.unknown.iter().collect::<Vec<_>>();
+                unknown.sort_by_key(|&(number, _)| number);
+                assert_eq!(
+                    unknown,
+                    vec![
+                        (1, protobuf::UnknownValueRef::Varint(5)),
+                        (2, protobuf::UnknownValueRef::LengthDelimited(b"hi")),
+                        (3, protobuf::UnknownValueRef::Fixed32(1)),
+                    ]
+                );
+            }
+            f => panic!("Expected a schemaless payload, got {:?}", f),
+        }
+        match message.fields.get(&3) {
+            Some(value::Field::Repeated(vs)) => match vs.as_slice() {
+                [value::Value::Message(m)] => assert_eq!(
+                    m.unknown.iter().collect::<Vec<_>>(),
+                    vec![(1, protobuf::UnknownValueRef::Varint(7))]
+                ),
+                vs => panic!("Expected a single schemaless message, got {:?}", vs),
+            },
+            f => panic!("Expected a schemaless history, got {:?}", f),
+        }
+
+        let borrowed = value::BorrowedMessage::decode_with_options(
+            &descriptors,
+            descriptor,
+            &data,
+            &value::DecodeOptions::new().schemaless_unresolved_messages(true),
+        )
+        .unwrap();
+        match borrowed.fields.get(&2) {
+            Some(value::BorrowedField::Singular(Some(value::BorrowedValue::Message(m)))) => {
+                assert_eq!(
+                    m.unknown,
+                    vec![&[8, 5][..], &[18, 2, b'h', b'i'], &[29, 1, 0, 0, 0]]
+                )
+            }
+            f => panic!("Expected a schemaless payload, got {:?}", f),
+        }
+
+        // Unknown fields are omitted when deserializing, so the messages are empty
+        let input = protobuf::CodedInputStream::from_bytes(&data);
+        let mut deserializer =
+            Deserializer::new(&descriptors, descriptor, input).schemaless_unresolved_messages(true);
+        match serde_value::Value::deserialize(&mut deserializer).unwrap() {
+            serde_value::Value::Map(m) => assert_eq!(
+                m[&serde_value::Value::String("payload".to_owned())],
+                serde_value::Value::Option(Some(Box::new(serde_value::Value::Map(
+                    collections::BTreeMap::new()
+                ))))
+            ),
+            v => panic!("Expected a map, got {:?}", v),
+        }
+    }
+
+    #[test]
+    fn reject_duplicate_scalars() {
+        use serde::Deserialize;
+
+        let descriptors = load_descriptors();
+        // optional_int32: 1, optional_nested_message { bb: 1 }, optional_nested_message { bb: 2 },
+        // optional_int32: 2
+        let data = [8, 1, 146, 1, 2, 8, 1, 146, 1, 2, 8, 2, 8, 2];
+        let deserialize = |reject| {
+            let input = protobuf::CodedInputStream::from_bytes(&data);
+            let mut deserializer = Deserializer::for_named_message(
+                &descriptors,
+                ".protobuf_unittest.TestAllTypes",
+                input,
+            )
+            .unwrap()
+            .reject_duplicate_scalars(reject);
+            serde_value::Value::deserialize(&mut deserializer).map_err(|e| e.into_error())
+        };
+
+        match deserialize(false).unwrap() {
+            serde_value::Value::Map(m) => assert_eq!(
+                m[&serde_value::Value::String("optional_int32".to_owned())],
+                serde_value::Value::Option(Some(Box::new(serde_value::Value::I32(2))))
+            ),
+            v => panic!("Expected a map, got {:?}", v),
+        }
+        match deserialize(true) {
+            Err(error::Error::DecodeAt { offset: 12, source }) => match *source {
+                error::Error::DuplicateField { name, number } => {
+                    assert_eq!(name, "optional_int32");
+                    assert_eq!(number, 1);
+                }
+                e => panic!("Expected duplicate field error, got {:?}", e),
+            },
+            r => panic!("Expected duplicate field error, got {:?}", r),
+        }
+
+        // Duplicates within nested messages are rejected as well, but separate occurrences of a
+        // message field are merged
+        let descriptor = descriptors
+            .message_by_name(".protobuf_unittest.TestAllTypes")
+            .unwrap();
+        let options = value::DecodeOptions::new().reject_duplicate_scalars(true);
+        let merge = |data: &[u8]| {
+            let mut input = protobuf::CodedInputStream::from_bytes(data);
+            let mut message = value::Message::new(descriptor);
+            message.merge_from_with_options(&descriptors, descriptor, &mut input, &options)
+        };
+        assert!(merge(&[146, 1, 2, 8, 1, 146, 1, 2, 8, 2]).is_ok());
+        match merge(&[146, 1, 4, 8, 1, 8, 2]) {
+            Err(error::Error::DecodeAt { offset: 5, source }) => match *source {
+                error::Error::DuplicateField { name, .. } => assert_eq!(name, "bb"),
+                e => panic!("Expected duplicate field error, got {:?}", e),
+            },
+            r => panic!("Expected duplicate field error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn packed_enums() {
+        use serde::Deserialize;
+
+        let descriptors = load_descriptors();
+        let name = ".protobuf_unittest.TestAllTypes";
+        let message = descriptors.message_by_name(name).unwrap();
+        // repeated_foreign_enum: [FOREIGN_FOO, FOREIGN_BAZ, 99, -1] (packed), then
+        // repeated_foreign_enum: [FOREIGN_BAR] (unpacked)
+        let mut data = vec![162, 3, 13, 4, 6, 99];
+        data.extend(&[0xff; 9]);
+        data.extend(&[0x01, 160, 3, 5]);
+
+        let mut m = value::Message::new(message);
+        let mut input = protobuf::CodedInputStream::from_bytes(&data);
+        m.merge_from(&descriptors, message, &mut input).unwrap();
+        let numbers = match m.fields.get(&52) {
+            Some(value::Field::Repeated(vs)) => vs
+                .iter()
+                .map(|v| match *v {
+                    value::Value::Enum(n) => n,
+                    ref v => panic!("Expected an enum value, got {:?}", v),
+                })
+                .collect::<Vec<_>>(),
+            f => panic!("Expected a repeated field, got {:?}", f),
+        };
+        assert_eq!(numbers, vec![4, 6, 99, -1, 5]);
+
+        let input = protobuf::CodedInputStream::from_bytes(&data);
+        let mut deserializer = Deserializer::for_named_message(&descriptors, name, input).unwrap();
+        let value = serde_value::Value::deserialize(&mut deserializer).unwrap();
+        let field = match value {
+            serde_value::Value::Map(ref m) => m
+                .get(&serde_value::Value::String(
+                    "repeated_foreign_enum".to_owned(),
+                ))
+                .cloned()
+                .unwrap(),
+            ref v => panic!("Expected a map, got {:?}", v),
+        };
+        let string = |s: &str| serde_value::Value::String(s.to_owned());
+        assert_eq!(
+            field,
+            serde_value::Value::Seq(vec![
+                string("FOREIGN_FOO"),
+                string("FOREIGN_BAZ"),
+                serde_value::Value::I32(99),
+                serde_value::Value::I32(-1),
+                string("FOREIGN_BAR"),
+            ])
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn to_json() {
+        let descriptors = load_descriptors();
+        let name = ".protobuf_unittest.TestAllTypes";
+        let message = descriptors.message_by_name(name).unwrap();
+        // Decoding the message twice merges singular fields and appends to repeated fields
+        let mut data = all_types(&descriptors);
+        data.extend(data.clone());
+        // repeated_nested_message: [{ bb: 4 }]
+        data.extend(&[130, 3, 2, 8, 4]);
+
+        let json = super::to_json(&descriptors, name, &data).unwrap();
+        assert_eq!(json["optionalInt64"], "-9007199254740993");
+        assert_eq!(json["optionalBytes"], "+/8=");
+        assert_eq!(json["optionalNestedEnum"], "BAR");
+        assert_eq!(json["optionalNestedMessage"], serde_json::json!({"bb": -3}));
+        assert_eq!(json["repeatedInt32"], serde_json::json!([1, 2, 1, 2]));
+        assert_eq!(
+            json["repeatedNestedEnum"],
+            serde_json::json!(["BAZ", 42, "BAZ", 42])
+        );
+        assert_eq!(
+            json["repeatedNestedMessage"],
+            serde_json::json!([{"bb": 4}])
+        );
+        assert!(json.get("optionalStringPiece").is_none());
+
+        let mut m = value::Message::new(message);
+        let mut input = protobuf::CodedInputStream::from_bytes(&data);
+        m.merge_from(&descriptors, message, &mut input).unwrap();
+        let expected =
+            crate::json::to_value(&descriptors, message, &value::Value::Message(m)).unwrap();
+        assert_eq!(json, expected);
+
+        match super::to_json(&descriptors, ".protobuf_unittest.Missing", &data) {
+            Err(error::Error::UnknownMessage { .. }) => (),
+            r => panic!("Expected an unknown message error, got {:?}", r),
+        }
+        match super::to_json(&descriptors, name, &[130, 3, 2, 8]) {
+            Err(_) => (),
+            r => panic!("Expected an error for truncated data, got {:?}", r),
+        }
+        // optional_int32: 1, optional_nested_message { a tag with the invalid wire type 7 }
+        match super::to_json(&descriptors, name, &[8, 1, 146, 1, 1, 15]) {
+            Err(e) => assert_eq!(e.offset(), Some(5)),
+            r => panic!("Expected an error, got {:?}", r),
+        }
+    }
+}
@@ -48,6 +48,16 @@
 //! # }
 //! ```
 //!
+//! The file descriptor set can also be parsed without using the `protobuf` crate directly:
+//!
+//! ```
+//! use std::fs;
+//! use serde_protobuf::descriptor::Descriptors;
+//!
+//! let file = fs::File::open("testdata/descriptors.pb").unwrap();
+//! let descriptors = Descriptors::from_proto_reader(file).unwrap();
+//! ```
+//!
 //! ## Manually built schemas
 //!
 //! A descriptor can be built at run-time by incrementally adding new message types and fields:
@@ -73,6 +83,44 @@
 //! descriptors.add_enum(e);
 //! ```
 //!
+//! Field descriptors can also be created using a builder, which makes it harder to mix up the
+//! various field properties:
+//!
+//! ```
+//! use serde_protobuf::descriptor::*;
+//!
+//! let field = FieldDescriptor::builder("favorite_color")
+//!     .number(3)
+//!     .label(FieldLabel::Repeated)
+//!     .field_type(InternalFieldType::UnresolvedEnum(".mypackage.Color".into()))
+//!     .packed(true)
+//!     .build();
+//! assert_eq!("favoriteColor", field.json_name());
+//! ```
+//!
+//! To avoid repeating the package name everywhere, types can be added within a package scope,
+//! which qualifies the names of the added types and of any relative type references in their
+//! fields, like in a `.proto` file with a `package` declaration:
+//!
+//! ```
+//! use serde_protobuf::descriptor::*;
+//!
+//! let mut m = MessageDescriptor::new("Person");
+//! m.add_field(FieldDescriptor::builder("favorite_color")
+//!     .number(1)
+//!     .field_type(InternalFieldType::UnresolvedEnum("Color".into()))
+//!     .build());
+//! let mut e = EnumDescriptor::new("Color");
+//! e.add_value(EnumValueDescriptor::new("BLUE", 1));
+//!
+//! let mut descriptors = Descriptors::new();
+//! let mut package = descriptors.with_package("mypackage");
+//! package.add_message(m);
+//! package.add_enum(e);
+//! descriptors.resolve_refs();
+//! assert!(descriptors.message_by_name(".mypackage.Person").is_some());
+//! ```
+//!
 //! ## Exploring descriptors
 //!
 //! The descriptors contain various indices that can be used to quickly look up information:
@@ -126,25 +174,52 @@
 //! ```
 //!
 //! [1]: https://github.com/google/protobuf/blob/master/src/google/protobuf/descriptor.proto
+use std::collections;
 use std::f32;
 use std::f64;
+#[cfg(feature = "std")]
+use std::io;
+use std::mem;
+use std::ops;
+use std::slice;
+use std::sync;
 
-use linked_hash_map;
 use protobuf::descriptor;
+use protobuf::Message as _;
 
 use crate::error;
 use crate::value;
 
+mod cache;
+mod diff;
+mod export;
+mod file;
+mod frozen;
+mod hash;
+mod ordered;
+#[cfg(feature = "protoc")]
+mod protoc;
+mod source;
+mod subset;
+mod validate;
+
+pub use self::diff::{SchemaChange, SchemaDiff};
+pub use self::file::{FileId, FileInfo};
+pub use self::frozen::FrozenDescriptors;
+
 /// An ID used for internal tracking of resolved message descriptors.
 ///
-/// It is not possible to construct a value of this type from outside this module.
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// It is not possible to construct a value of this type from outside this module.  IDs are only
+/// meaningful for the registry that they were obtained from, and can be used as cheap keys for
+/// lookup tables instead of message names.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct MessageId(usize);
 
 /// An ID used for internal tracking of resolved enum descriptors.
 ///
-/// It is not possible to construct a value of this type from outside this module.
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// It is not possible to construct a value of this type from outside this module.  IDs are only
+/// meaningful for the registry that they were obtained from.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct EnumId(usize);
 
 /// An ID used for internal tracking of resolved enum values.
@@ -155,54 +230,105 @@ struct EnumValueId(usize);
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 struct FieldId(usize);
 
+/// Source code locations of a file, indexed by their path within the file descriptor.
+type Locations<'a> = collections::HashMap<&'a [i32], &'a descriptor::source_code_info::Location>;
+
+// The range of valid field numbers, and the range reserved for the protobuf implementation.
+pub(crate) const MIN_FIELD_NUMBER: i32 = 1;
+pub(crate) const MAX_FIELD_NUMBER: i32 = 536_870_911;
+const MIN_RESERVED_FIELD_NUMBER: i32 = 19_000;
+const MAX_RESERVED_FIELD_NUMBER: i32 = 19_999;
+
+// Field numbers used in source code info paths; see `descriptor.proto`.
+const FILE_MESSAGE_TYPE_TAG: i32 = 4;
+const FILE_ENUM_TYPE_TAG: i32 = 5;
+const MESSAGE_FIELD_TAG: i32 = 2;
+const MESSAGE_NESTED_TYPE_TAG: i32 = 3;
+const MESSAGE_ENUM_TYPE_TAG: i32 = 4;
+const ENUM_VALUE_TAG: i32 = 2;
+
+// The extendees of custom options.
+const MESSAGE_OPTIONS_NAME: &str = ".google.protobuf.MessageOptions";
+const FIELD_OPTIONS_NAME: &str = ".google.protobuf.FieldOptions";
+
+// The maximum number of similar names returned by `Descriptors::message_by_name_suggest`.
+const MAX_SUGGESTIONS: usize = 5;
+
 /// A registry for any number of protocol buffer descriptors.
 #[derive(Debug, Default)]
 pub struct Descriptors {
     // All found descriptors
     messages: Vec<MessageDescriptor>,
     enums: Vec<EnumDescriptor>,
+    files: Vec<FileInfo>,
 
     // Indices
-    messages_by_name: linked_hash_map::LinkedHashMap<String, MessageId>,
-    enums_by_name: linked_hash_map::LinkedHashMap<String, EnumId>,
+    messages_by_name: ordered::OrderedMap<sync::Arc<str>, MessageId>,
+    enums_by_name: ordered::OrderedMap<sync::Arc<str>, EnumId>,
+
+    // Reverse indices, only valid after resolve_refs
+    references_by_name: ordered::OrderedMap<sync::Arc<str>, Vec<(MessageId, FieldId)>>,
+
+    // Extension fields, by extendee name and field number
+    extensions: ordered::OrderedMap<(String, i32), FieldDescriptor>,
+    // Fully qualified names of the extension fields that were loaded from protos
+    extension_names: ordered::OrderedMap<(String, i32), String>,
+
+    // Shared storage for names, if interning is enabled
+    names: Option<collections::HashSet<sync::Arc<str>>>,
 }
 
 /// A descriptor for a single protocol buffer message type.
 // TODO: Support oneof?
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct MessageDescriptor {
-    name: String,
+    name: sync::Arc<str>,
+    file: Option<FileId>,
+    syntax: Syntax,
+    map_entry: bool,
+    message_set: bool,
+    custom_options: protobuf::UnknownFields,
+    leading_comments: Option<String>,
+    trailing_comments: Option<String>,
+    reserved_ranges: Vec<ops::Range<i32>>,
+    extension_ranges: Vec<ops::Range<i32>>,
 
     // All found descriptors
     fields: Vec<FieldDescriptor>,
 
     // Indices
-    fields_by_name: linked_hash_map::LinkedHashMap<String, FieldId>,
-    fields_by_number: linked_hash_map::LinkedHashMap<i32, FieldId>,
+    fields_by_name: ordered::OrderedMap<sync::Arc<str>, FieldId>,
+    fields_by_number: ordered::OrderedMap<i32, FieldId>,
 }
 
 /// A descriptor for a single protocol buffer enum type.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct EnumDescriptor {
-    name: String,
+    name: sync::Arc<str>,
+    file: Option<FileId>,
+    leading_comments: Option<String>,
+    trailing_comments: Option<String>,
 
     // All found descriptors
     values: Vec<EnumValueDescriptor>,
 
     // Indices
-    values_by_name: linked_hash_map::LinkedHashMap<String, EnumValueId>,
-    values_by_number: linked_hash_map::LinkedHashMap<i32, EnumValueId>,
+    values_by_name: ordered::OrderedMap<String, EnumValueId>,
+    values_by_number: ordered::OrderedMap<i32, EnumValueId>,
 }
 
 /// A descriptor for a single protocol buffer enum value.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct EnumValueDescriptor {
     name: String,
     number: i32,
+    options: descriptor::EnumValueOptions,
+    leading_comments: Option<String>,
+    trailing_comments: Option<String>,
 }
 
 /// A label that a field can be given to indicate its cardinality.
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum FieldLabel {
     /// There can be zero or one value.
     Optional,
@@ -212,6 +338,37 @@ pub enum FieldLabel {
     Repeated,
 }
 
+/// The syntax of the protocol buffer file that a message was defined in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Syntax {
+    /// The `proto2` syntax.
+    Proto2,
+    /// The `proto3` syntax.
+    Proto3,
+}
+
+/// A hint for how a C++ implementation should represent a string field.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CType {
+    /// The default string representation.
+    String,
+    /// The `Cord` representation.
+    Cord,
+    /// The `StringPiece` representation.
+    StringPiece,
+}
+
+/// A hint for how a JavaScript implementation should represent a 64-bit integer field.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum JsType {
+    /// The default representation for the field type.
+    Normal,
+    /// The value should be represented as a string.
+    String,
+    /// The value should be represented as a number.
+    Number,
+}
+
 /// The externally visible type of a field.
 ///
 /// This type representation borrows references to any referenced descriptors.
@@ -221,6 +378,8 @@ pub enum FieldType<'a> {
     UnresolvedMessage(&'a str),
     /// An enum that is yet to be resolved.
     UnresolvedEnum(&'a str),
+    /// A group whose message type is yet to be resolved.
+    UnresolvedGroup(&'a str),
     /// The `double` type.
     Double,
     /// The `float` type.
@@ -239,8 +398,8 @@ pub enum FieldType<'a> {
     Bool,
     /// The `string` type.
     String,
-    /// The `group` type.
-    Group,
+    /// A resolved group, with the implicitly defined message type of the group.
+    Group(&'a MessageDescriptor),
     /// A resolved message type.
     Message(&'a MessageDescriptor),
     /// The `bytes` type.
@@ -259,16 +418,67 @@ pub enum FieldType<'a> {
     SInt64,
 }
 
+/// The kind of a field type, without any referenced descriptors.
+///
+/// Unlike `FieldType`, this can be hashed and used as a key, for example for caches.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum FieldTypeTag {
+    /// A message that is yet to be resolved.
+    UnresolvedMessage,
+    /// An enum that is yet to be resolved.
+    UnresolvedEnum,
+    /// A group whose message type is yet to be resolved.
+    UnresolvedGroup,
+    /// The `double` type.
+    Double,
+    /// The `float` type.
+    Float,
+    /// The `int64` type.
+    Int64,
+    /// The `uint64` type.
+    UInt64,
+    /// The `int32` type.
+    Int32,
+    /// The `fixed64` type.
+    Fixed64,
+    /// The `fixed32` type.
+    Fixed32,
+    /// The `bool` type.
+    Bool,
+    /// The `string` type.
+    String,
+    /// A resolved group.
+    Group,
+    /// A resolved message type.
+    Message,
+    /// The `bytes` type.
+    Bytes,
+    /// The `uint32` type.
+    UInt32,
+    /// A resolved enum type.
+    Enum,
+    /// The `sfixed32` type.
+    SFixed32,
+    /// The `sfixed64` type.
+    SFixed64,
+    /// The `sint32` type.
+    SInt32,
+    /// The `sint64` type.
+    SInt64,
+}
+
 /// The internally tracked type of a field.
 ///
 /// The type owns all of its data, and can refer to an internally tracked ID for resolved type
 /// references.  It's by design not possible to construct those IDs from outside this module.
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum InternalFieldType {
     /// A message that is yet to be resolved.
-    UnresolvedMessage(String),
+    UnresolvedMessage(sync::Arc<str>),
     /// An enum that is yet to be resolved.
-    UnresolvedEnum(String),
+    UnresolvedEnum(sync::Arc<str>),
+    /// A group whose message type is yet to be resolved.
+    UnresolvedGroup(sync::Arc<str>),
     /// The `double` type.
     Double,
     /// The `float` type.
@@ -287,8 +497,8 @@ pub enum InternalFieldType {
     Bool,
     /// The `string` type.
     String,
-    /// The `group` type.
-    Group,
+    /// A resolved group, with the implicitly defined message type of the group.
+    Group(MessageId),
     /// A resolved message type.
     Message(MessageId),
     /// The `bytes` type.
@@ -308,14 +518,61 @@ pub enum InternalFieldType {
 }
 
 /// A descriptor for a single protocol buffer message field.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct FieldDescriptor {
+    name: sync::Arc<str>,
+    number: i32,
+    field_label: FieldLabel,
+    field_type: InternalFieldType,
+    default_value: Option<value::Value>,
+    default_value_raw: Option<String>,
+    optional: bool,
+    proto3_optional: bool,
+    json_name: String,
+    packed: Option<bool>,
+    syntax: Syntax,
+    ctype: CType,
+    jstype: JsType,
+    oneof: Option<String>,
+    oneof_index: Option<i32>,
+    custom_options: protobuf::UnknownFields,
+    leading_comments: Option<String>,
+    trailing_comments: Option<String>,
+}
+
+/// A builder for field descriptors.
+///
+/// Any properties that are not explicitly set get sensible defaults: the field number is `0`, the
+/// label is `optional`, the type is `int32`, there is no default value, the JSON name is derived
+/// from the field name, the packed encoding is not explicitly set, no representation hints are set,
+/// the field is not part of a oneof, and it has no custom options.  Unless overridden, the field is
+/// considered optional if and only if its label is `optional`.
+#[derive(Debug)]
+pub struct FieldDescriptorBuilder {
     name: String,
     number: i32,
     field_label: FieldLabel,
     field_type: InternalFieldType,
     default_value: Option<value::Value>,
-    optional: bool
+    optional: Option<bool>,
+    proto3_optional: bool,
+    json_name: Option<String>,
+    packed: Option<bool>,
+    ctype: CType,
+    jstype: JsType,
+    oneof: Option<String>,
+    custom_options: protobuf::UnknownFields,
+}
+
+/// A scope for adding manually built types to a descriptor registry within a package.
+///
+/// The names of added types, and any relative type references in the fields of added messages,
+/// are qualified with the package name.  Names that already start with a `.` are considered fully
+/// qualified and are left as-is.
+#[derive(Debug)]
+pub struct PackageBuilder<'a> {
+    descriptors: &'a mut Descriptors,
+    package: String,
 }
 
 impl Descriptors {
@@ -324,875 +581,4774 @@ impl Descriptors {
         Descriptors {
             messages: Vec::new(),
             enums: Vec::new(),
+            files: Vec::new(),
+
+            messages_by_name: ordered::OrderedMap::new(),
+            enums_by_name: ordered::OrderedMap::new(),
+
+            references_by_name: ordered::OrderedMap::new(),
+
+            extensions: ordered::OrderedMap::new(),
+            extension_names: ordered::OrderedMap::new(),
 
-            messages_by_name: linked_hash_map::LinkedHashMap::new(),
-            enums_by_name: linked_hash_map::LinkedHashMap::new(),
+            names: None,
+        }
+    }
+
+    /// Creates a new empty descriptor set that interns all names.
+    ///
+    /// Identical type and field names of the descriptors added to the registry, as well as the
+    /// names of unresolved type references, then share their storage.  The name indices always
+    /// share storage with the descriptors themselves.  This reduces the memory footprint of large
+    /// schemas with many repeated field names and type references, at the cost of a hash lookup
+    /// per name when adding descriptors.
+    ///
+    /// For the schema in `testdata/descriptors.pb`, which includes `unittest.proto`, names take up
+    /// 13,767 bytes when every name and index key is stored separately, 7,980 bytes when only the
+    /// indices share storage with the descriptors, and 5,499 bytes when interning, as measured
+    /// before resolving type references.
+    ///
+    /// ```
+    /// use serde_protobuf::descriptor::{Descriptors, InternalFieldType, FieldDescriptor,
+    ///                                  MessageDescriptor};
+    ///
+    /// let mut descriptors = Descriptors::with_interning();
+    /// for name in &[".pkg.A", ".pkg.B"] {
+    ///     let mut message = MessageDescriptor::new(*name);
+    ///     message.add_field(
+    ///         FieldDescriptor::builder("id")
+    ///             .number(1)
+    ///             .field_type(InternalFieldType::String)
+    ///             .build(),
+    ///     );
+    ///     descriptors.add_message(message);
+    /// }
+    /// assert!(descriptors.is_interning());
+    /// ```
+    pub fn with_interning() -> Descriptors {
+        Descriptors {
+            names: Some(collections::HashSet::new()),
+            ..Descriptors::new()
         }
     }
 
+    /// Whether this registry interns the names of added descriptors; see `with_interning`.
+    #[inline]
+    pub fn is_interning(&self) -> bool {
+        self.names.is_some()
+    }
+
     /// Builds a descriptor set from the specified protocol buffer file descriptor set.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the file descriptor set is malformed; use `try_from_proto` for untrusted input.
     pub fn from_proto(file_set_proto: &descriptor::FileDescriptorSet) -> Descriptors {
+        match Descriptors::try_from_proto(file_set_proto) {
+            Ok(descriptors) => descriptors,
+            Err(e) => panic!("Malformed file descriptor set: {}", e),
+        }
+    }
+
+    /// Builds a descriptor set from the specified protocol buffer file descriptor set, validating
+    /// it first.
+    ///
+    /// Returns an error if any type, field or enum value lacks a name, if a field number is
+    /// outside of the valid range (or inside the range reserved for the protobuf implementation),
+    /// or if a field's label, type and default value are inconsistent with each other.
+    pub fn try_from_proto(
+        file_set_proto: &descriptor::FileDescriptorSet,
+    ) -> error::Result<Descriptors> {
+        for file_proto in &file_set_proto.file {
+            validate_file_proto(file_proto)?;
+        }
+
         let mut descriptors = Descriptors::new();
         descriptors.add_file_set_proto(file_set_proto);
-        descriptors
+        Ok(descriptors)
     }
 
-    /// Looks up a message by its fully qualified name (i.e. `.foo.package.Message`).
+    /// Builds a descriptor set from the specified binary encoded protocol buffer file descriptor
+    /// set, validating it like `try_from_proto`.
+    pub fn from_proto_bytes(bytes: &[u8]) -> error::Result<Descriptors> {
+        let file_set_proto = descriptor::FileDescriptorSet::parse_from_bytes(bytes)?;
+        Descriptors::try_from_proto(&file_set_proto)
+    }
+
+    /// Builds a descriptor set from a binary encoded protocol buffer file descriptor set read from
+    /// the specified reader, validating it like `try_from_proto`.
+    #[cfg(feature = "std")]
+    pub fn from_proto_reader<R>(mut reader: R) -> error::Result<Descriptors>
+    where
+        R: io::Read,
+    {
+        let file_set_proto = descriptor::FileDescriptorSet::parse_from_reader(&mut reader)?;
+        Descriptors::try_from_proto(&file_set_proto)
+    }
+
+    /// The number of messages in this registry.
+    ///
+    /// ```
+    /// use serde_protobuf::descriptor::{Descriptors, EnumDescriptor, MessageDescriptor};
+    ///
+    /// let mut descriptors = Descriptors::new();
+    /// descriptors.add_message(MessageDescriptor::new(".pkg.Message"));
+    /// descriptors.add_enum(EnumDescriptor::new(".pkg.Enum"));
+    /// assert_eq!(descriptors.message_count(), 1);
+    /// assert_eq!(descriptors.enum_count(), 1);
+    /// ```
     #[inline]
-    pub fn message_by_name(&self, name: &str) -> Option<&MessageDescriptor> {
-        self.messages_by_name.get(name).map(|m| &self.messages[m.0])
+    pub fn message_count(&self) -> usize {
+        self.messages_by_name.len()
     }
 
-    /// Looks up an enum by its fully qualified name (i.e. `.foo.package.Enum`).
+    /// The number of enums in this registry.
     #[inline]
-    pub fn enum_by_name(&self, name: &str) -> Option<&EnumDescriptor> {
-        self.enums_by_name.get(name).map(|e| &self.enums[e.0])
+    pub fn enum_count(&self) -> usize {
+        self.enums_by_name.len()
     }
 
-    /// Adds all types defined in the specified protocol buffer file descriptor set to this
-    /// registry.
-    pub fn add_file_set_proto(&mut self, file_set_proto: &descriptor::FileDescriptorSet) {
-        for file_proto in &file_set_proto.file {
-            self.add_file_proto(file_proto);
-        }
+    /// Whether this registry contains neither messages nor enums.
+    ///
+    /// ```
+    /// use serde_protobuf::descriptor::{Descriptors, MessageDescriptor};
+    ///
+    /// let mut descriptors = Descriptors::new();
+    /// assert!(descriptors.is_empty());
+    /// descriptors.add_message(MessageDescriptor::new(".pkg.Message"));
+    /// assert!(!descriptors.is_empty());
+    /// ```
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.messages_by_name.is_empty() && self.enums_by_name.is_empty()
     }
 
-    /// Adds all types defined in the specified protocol buffer file descriptor to this registry.
-    pub fn add_file_proto(&mut self, file_proto: &descriptor::FileDescriptorProto) {
-        let path = if file_proto.has_package() {
-            format!(".{}", file_proto.package())
-        } else {
-            "".to_owned()
-        };
+    /// Whether this registry contains a message with the specified fully qualified name.
+    ///
+    /// Like for `message_by_name`, the leading dot may be omitted.
+    ///
+    /// ```
+    /// use serde_protobuf::descriptor::{Descriptors, MessageDescriptor};
+    ///
+    /// let mut descriptors = Descriptors::new();
+    /// descriptors.add_message(MessageDescriptor::new(".pkg.Message"));
+    /// assert!(descriptors.contains_message(".pkg.Message"));
+    /// assert!(descriptors.contains_message("pkg.Message"));
+    /// assert!(!descriptors.contains_message(".pkg.Other"));
+    /// ```
+    #[inline]
+    pub fn contains_message(&self, name: &str) -> bool {
+        self.message_by_name(name).is_some()
+    }
 
-        for message_proto in &file_proto.message_type {
-            self.add_message_proto(&path, message_proto);
-        }
+    /// Whether this registry contains an enum with the specified fully qualified name.
+    ///
+    /// Like for `enum_by_name`, the leading dot may be omitted.
+    ///
+    /// ```
+    /// use serde_protobuf::descriptor::{Descriptors, EnumDescriptor};
+    ///
+    /// let mut descriptors = Descriptors::new();
+    /// descriptors.add_enum(EnumDescriptor::new(".pkg.Enum"));
+    /// assert!(descriptors.contains_enum(".pkg.Enum"));
+    /// assert!(descriptors.contains_enum("pkg.Enum"));
+    /// assert!(!descriptors.contains_enum(".pkg.Message"));
+    /// ```
+    #[inline]
+    pub fn contains_enum(&self, name: &str) -> bool {
+        self.enum_by_name(name).is_some()
+    }
 
-        for enum_proto in &file_proto.enum_type {
-            self.add_enum(EnumDescriptor::from_proto(&path, enum_proto));
-        }
+    /// Looks up a message by its fully qualified name (i.e. `.foo.package.Message`).
+    ///
+    /// The leading dot may be omitted (i.e. `foo.package.Message`).
+    #[inline]
+    pub fn message_by_name(&self, name: &str) -> Option<&MessageDescriptor> {
+        self.messages_by_name
+            .get(name)
+            .or_else(|| {
+                if name.starts_with('.') {
+                    None
+                } else {
+                    self.messages_by_name.get(format!(".{}", name).as_str())
+                }
+            })
+            .map(|m| &self.messages[m.0])
     }
 
-    /// Adds a message and all nested types within that message from the specified protocol buffer
-    /// descriptor.
-    pub fn add_message_proto(&mut self, path: &str, message_proto: &descriptor::DescriptorProto) {
-        let message_descriptor = MessageDescriptor::from_proto(path, message_proto);
+    /// Looks up a message by its short name, i.e. the last dotted component of its fully
+    /// qualified name (`Message` for `.foo.package.Message`).
+    ///
+    /// Returns `None` if no message or more than one message has the specified short name; use
+    /// `message_by_name` to disambiguate in the latter case.
+    pub fn message_by_short_name(&self, name: &str) -> Option<&MessageDescriptor> {
+        unique(
+            self.messages
+                .iter()
+                .filter(|m| short_name(m.name()) == name),
+        )
+    }
 
-        for nested_message_proto in &message_proto.nested_type {
-            self.add_message_proto(message_descriptor.name(), nested_message_proto);
-        }
+    /// Looks up a message by its fully qualified name, ignoring ASCII case differences.
+    ///
+    /// The leading dot may be omitted.  Returns `None` if no message or more than one message
+    /// matches the specified name.
+    pub fn message_by_name_ignore_case(&self, name: &str) -> Option<&MessageDescriptor> {
+        let name = name.strip_prefix('.').unwrap_or(name);
+        unique(self.messages.iter().filter(|m| {
+            let other = m.name();
+            other
+                .strip_prefix('.')
+                .unwrap_or(other)
+                .eq_ignore_ascii_case(name)
+        }))
+    }
 
-        for nested_enum_proto in &message_proto.enum_type {
-            self.add_enum(EnumDescriptor::from_proto(
-                message_descriptor.name(),
-                nested_enum_proto,
-            ));
+    /// Looks up a message by its fully qualified name like `message_by_name`, or returns the names
+    /// of up to five similar messages if there is no such message, to help diagnose typos.
+    ///
+    /// Messages are similar if their names only differ in ASCII case, if their fully qualified
+    /// names end with the specified name (for example a short name, or a name without the leading
+    /// package components), or if either their names or their short names are within a small edit
+    /// distance of the specified name.  The suggestions are ordered by similarity, and then by
+    /// name.
+    pub fn message_by_name_suggest(&self, name: &str) -> Result<&MessageDescriptor, Vec<String>> {
+        if let Some(message) = self.message_by_name(name) {
+            return Ok(message);
         }
 
-        self.add_message(message_descriptor);
+        let name = name.strip_prefix('.').unwrap_or(name).to_ascii_lowercase();
+        let short = short_name(&name);
+        let max_distance = (short.len() / 4).clamp(1, 3);
+        let mut suggestions = self
+            .messages
+            .iter()
+            .filter_map(|m| {
+                let full = m.name().strip_prefix('.').unwrap_or(m.name());
+                let full = full.to_ascii_lowercase();
+                // Exact matches apart from case or leading components rank before everything else
+                let distance = if full == name || full.ends_with(&format!(".{}", name)) {
+                    0
+                } else {
+                    1 + edit_distance(&full, &name).min(edit_distance(short_name(&full), short))
+                };
+                if distance <= max_distance + 1 {
+                    Some((distance, m.name()))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        suggestions.sort_unstable();
+        Err(suggestions
+            .into_iter()
+            .take(MAX_SUGGESTIONS)
+            .map(|(_, name)| name.to_owned())
+            .collect())
     }
 
-    /// Adds a single custom built message descriptor.
-    pub fn add_message(&mut self, descriptor: MessageDescriptor) {
-        let name = descriptor.name.clone();
-        let message_id = MessageId(store(&mut self.messages, descriptor));
-        self.messages_by_name.insert(name, message_id);
+    /// Looks up an enum by its fully qualified name (i.e. `.foo.package.Enum`).
+    ///
+    /// The leading dot may be omitted (i.e. `foo.package.Enum`).
+    #[inline]
+    pub fn enum_by_name(&self, name: &str) -> Option<&EnumDescriptor> {
+        self.enums_by_name
+            .get(name)
+            .or_else(|| {
+                if name.starts_with('.') {
+                    None
+                } else {
+                    self.enums_by_name.get(format!(".{}", name).as_str())
+                }
+            })
+            .map(|e| &self.enums[e.0])
     }
 
-    /// Adds a single custom built enum descriptor.
-    pub fn add_enum(&mut self, descriptor: EnumDescriptor) {
-        let name = descriptor.name.clone();
-        let enum_id = EnumId(store(&mut self.enums, descriptor));
-        self.enums_by_name.insert(name, enum_id);
+    /// Looks up an enum by its short name, i.e. the last dotted component of its fully qualified
+    /// name (`Enum` for `.foo.package.Enum`).
+    ///
+    /// Returns `None` if no enum or more than one enum has the specified short name; use
+    /// `enum_by_name` to disambiguate in the latter case.
+    pub fn enum_by_short_name(&self, name: &str) -> Option<&EnumDescriptor> {
+        unique(self.enums.iter().filter(|e| short_name(e.name()) == name))
     }
 
-    /// Resolves all internal descriptor type references, making them cheaper to follow.
-    pub fn resolve_refs(&mut self) {
-        for m in &mut self.messages {
-            for f in &mut m.fields {
-                let field_type = &mut f.field_type;
-                let new = match *field_type {
-                    InternalFieldType::UnresolvedMessage(ref name) => {
-                        if let Some(res) = self.messages_by_name.get(name) {
-                            Some(InternalFieldType::Message(*res))
-                        } else {
-                            warn!("Inconsistent schema; unknown message type {}", name);
-                            None
-                        }
-                    }
-                    InternalFieldType::UnresolvedEnum(ref name) => {
-                        if let Some(res) = self.enums_by_name.get(name) {
-                            Some(InternalFieldType::Enum(*res))
-                        } else {
-                            warn!("Inconsistent schema; unknown enum type {}", name);
-                            None
-                        }
-                    }
-                    _ => None,
-                };
-
-                if let Some(t) = new {
-                    *field_type = t;
-                }
-            }
-        }
+    /// Looks up an enum value by name, together with the enum that defines it.
+    ///
+    /// Following the protobuf scoping rules, enum values are siblings of their enum rather than
+    /// children of it, so `.foo.package.VALUE` names the value `VALUE` of any enum defined directly
+    /// in `.foo.package`, such as `.foo.package.Enum`.  The leading dot of a qualified name may be
+    /// omitted, and a name without any dots matches values of enums in any scope.
+    ///
+    /// Returns `None` if no value or more than one value matches the specified name.
+    pub fn enum_value_by_name(
+        &self,
+        name: &str,
+    ) -> Option<(&EnumDescriptor, &EnumValueDescriptor)> {
+        let name = name.strip_prefix('.').unwrap_or(name);
+        let (scope, value_name) = match name.rfind('.') {
+            Some(i) => (Some(&name[..i]), &name[i + 1..]),
+            None => (None, name),
+        };
+        unique(
+            self.enums
+                .iter()
+                .filter(|e| {
+                    scope.is_none_or(|scope| {
+                        let enum_name = e.name().strip_prefix('.').unwrap_or(e.name());
+                        enum_name.rfind('.').map_or("", |i| &enum_name[..i]) == scope
+                    })
+                })
+                .filter_map(|e| e.value_by_name(value_name).map(|v| (e, v))),
+        )
     }
-}
 
-impl MessageDescriptor {
-    /// Creates a new message descriptor with the specified message name.
-    pub fn new<S>(name: S) -> MessageDescriptor
+    /// Registers an extension field of the specified extendee message.
+    ///
+    /// The extendee name must be fully qualified, and any existing extension with the same field
+    /// number is replaced.  Extensions are used to decode the items of messages using the
+    /// `MessageSet` wire format, where the field number of the extension is the type ID of the
+    /// item, and to decode custom options.
+    pub fn add_extension<S>(&mut self, extendee: S, mut field: FieldDescriptor)
     where
         S: Into<String>,
     {
-        MessageDescriptor {
-            name: name.into(),
-            fields: Vec::new(),
-            fields_by_name: linked_hash_map::LinkedHashMap::new(),
-            fields_by_number: linked_hash_map::LinkedHashMap::new(),
+        if let Some(ref mut names) = self.names {
+            field.intern_names(names);
         }
+        let key = (extendee.into(), field.number);
+        self.extension_names.remove(&key);
+        self.extensions.insert(key, field);
     }
 
-    /// Reads a message descriptor from a parsed Protobuf descriptor.
-    pub fn from_proto(path: &str, proto: &descriptor::DescriptorProto) -> MessageDescriptor {
-        let name = format!("{}.{}", path, proto.name());
-        let mut message_descriptor = MessageDescriptor::new(name);
+    /// Looks up the extension field with the specified field number of the specified extendee
+    /// message.
+    pub fn extension(&self, extendee: &str, number: i32) -> Option<&FieldDescriptor> {
+        self.extensions.get(&(extendee.to_owned(), number))
+    }
 
-        for field_proto in &proto.field {
-            message_descriptor.add_field(FieldDescriptor::from_proto(field_proto));
+    /// Looks up the message type of the extension with the specified field number of the
+    /// specified extendee message, if it is a message typed extension.
+    pub fn message_extension(&self, extendee: &str, number: i32) -> Option<&MessageDescriptor> {
+        match self.extension(extendee, number)?.field_type(self) {
+            FieldType::Message(m) => Some(m),
+            _ => None,
         }
-
-        message_descriptor
     }
 
-    /// All of the fields in the descriptor.
-    pub fn fields(&self) -> &[FieldDescriptor] {
-        &self.fields
+    /// Looks up the extension field with the specified name of the specified extendee message.
+    ///
+    /// The name may be the fully qualified name of the extension, which is scoped by the package
+    /// or message that declares it (e.g. `.pkg.my_extension` or `.pkg.Message.my_extension`), with
+    /// or without the leading dot, or just the name of the extension field.  Only extensions of
+    /// the specified extendee are considered, so extensions of other messages with the same name
+    /// or number are never returned.  Fully qualified names are only known for extensions loaded
+    /// from protocol buffer descriptors, so custom built extensions are found by their field name.
+    pub fn extension_by_name(&self, extendee: &str, name: &str) -> Option<&FieldDescriptor> {
+        let qualified = if name.starts_with('.') {
+            name.to_owned()
+        } else {
+            format!(".{}", name)
+        };
+        let mut candidates = self.extensions.iter().filter(|&((e, _), _)| e == extendee);
+        let by_qualified_name = candidates.clone().find(|&(key, _)| {
+            self.extension_names
+                .get(key)
+                .is_some_and(|n| *n == qualified)
+        });
+        by_qualified_name
+            .or_else(|| candidates.find(|&(_, f)| f.name() == name))
+            .map(|(_, f)| f)
     }
 
-    /// The name of the message.
-    #[inline]
-    pub fn name(&self) -> &str {
-        &self.name
+    fn add_extension_protos(
+        &mut self,
+        scope: &str,
+        extension_protos: &[descriptor::FieldDescriptorProto],
+    ) {
+        for extension_proto in extension_protos {
+            let extendee = extension_proto.extendee();
+            self.add_extension(extendee, FieldDescriptor::from_proto(extension_proto));
+            self.extension_names.insert(
+                (extendee.to_owned(), extension_proto.number()),
+                format!("{}.{}", scope, extension_proto.name()),
+            );
+        }
     }
 
-    /// Finds a field by field name.
+    /// Looks up a message by an ID obtained from a resolved field of this registry.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the ID was obtained from a different registry that has more messages.
     #[inline]
-    pub fn field_by_name(&self, name: &str) -> Option<&FieldDescriptor> {
-        self.fields_by_name.get(name).map(|f| &self.fields[f.0])
+    pub fn message_by_id(&self, id: MessageId) -> &MessageDescriptor {
+        &self.messages[id.0]
     }
 
-    /// Finds a field by field number.
+    /// Looks up an enum by an ID obtained from a resolved field of this registry.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the ID was obtained from a different registry that has more enums.
     #[inline]
-    pub fn field_by_number(&self, number: i32) -> Option<&FieldDescriptor> {
-        self.fields_by_number
-            .get(&number)
-            .map(|f| &self.fields[f.0])
+    pub fn enum_by_id(&self, id: EnumId) -> &EnumDescriptor {
+        &self.enums[id.0]
     }
 
-    /// Adds a new field to the descriptor.
-    pub fn add_field(&mut self, descriptor: FieldDescriptor) {
-        let name = descriptor.name.clone();
-        let number = descriptor.number;
-
-        let field_id = FieldId(store(&mut self.fields, descriptor));
+    /// Finds all fields whose resolved type is the message or enum with the specified fully
+    /// qualified name, as the pairs of the containing message and the field.
+    ///
+    /// This reverse index is built by `resolve_refs`, and is invalidated by adding any further
+    /// descriptors to this registry until `resolve_refs` is called again.
+    pub fn referencing_fields(&self, name: &str) -> Vec<(&MessageDescriptor, &FieldDescriptor)> {
+        self.references_by_name
+            .get(name)
+            .map(|refs| {
+                refs.iter()
+                    .map(|&(m, f)| {
+                        let message = &self.messages[m.0];
+                        (message, &message.fields[f.0])
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
 
-        self.fields_by_name.insert(name, field_id);
-        self.fields_by_number.insert(number, field_id);
+    /// Groups the messages and enums in this registry by their package.
+    ///
+    /// The package of a type is the prefix of its fully qualified name that remains after removing
+    /// the names of the type itself and of any messages that it is nested within, so nested types
+    /// are grouped under the package of their outermost message.  Package names keep their leading
+    /// dot (i.e. `.foo.package`), and types without a package are grouped under the empty string.
+    /// Packages are returned in name order, and types within a package in the order that they
+    /// were added.
+    pub fn packages(
+        &self,
+    ) -> impl Iterator<Item = (&str, Vec<&MessageDescriptor>, Vec<&EnumDescriptor>)> {
+        let mut packages = collections::BTreeMap::<&str, (Vec<_>, Vec<_>)>::new();
+        for &id in self.messages_by_name.values() {
+            let message = &self.messages[id.0];
+            packages
+                .entry(self.package_name(message.name()))
+                .or_default()
+                .0
+                .push(message);
+        }
+        for &id in self.enums_by_name.values() {
+            let enum_descriptor = &self.enums[id.0];
+            packages
+                .entry(self.package_name(enum_descriptor.name()))
+                .or_default()
+                .1
+                .push(enum_descriptor);
+        }
+        packages
+            .into_iter()
+            .map(|(package, (messages, enums))| (package, messages, enums))
     }
-}
 
-impl EnumDescriptor {
-    /// Creates a new enum descriptor with the specified enum name.
-    pub fn new<S>(name: S) -> EnumDescriptor
-    where
-        S: Into<String>,
-    {
-        EnumDescriptor {
-            name: name.into(),
-            values: Vec::new(),
-            values_by_name: linked_hash_map::LinkedHashMap::new(),
-            values_by_number: linked_hash_map::LinkedHashMap::new(),
+    fn package_name<'a>(&self, type_name: &'a str) -> &'a str {
+        let mut name = type_name;
+        loop {
+            name = name.rfind('.').map_or("", |i| &name[..i]);
+            if name.is_empty() || !self.messages_by_name.contains_key(name) {
+                return name;
+            }
         }
     }
 
-    /// Reads an enum descriptor from a parsed Protobuf descriptor.
-    pub fn from_proto(path: &str, proto: &descriptor::EnumDescriptorProto) -> EnumDescriptor {
-        let enum_name = format!("{}.{}", path, proto.name());
+    /// Computes the fully qualified names of all message and enum types that are transitively
+    /// referenced by the fields of the message with the specified name.
+    ///
+    /// The names are returned in the order that they are discovered, and the root message itself
+    /// is not included.  Recursive references are only followed once.  Type references are looked
+    /// up by name if `resolve_refs` hasn't been called, so calling it first makes this cheaper.
+    ///
+    /// Returns an error if the root message or any referenced type is unknown.
+    pub fn transitive_deps(&self, root: &str) -> error::Result<Vec<String>> {
+        let root = self
+            .message_by_name(root)
+            .ok_or_else(|| error::Error::UnknownMessage {
+                name: root.to_owned(),
+            })?;
 
-        let mut enum_descriptor = EnumDescriptor::new(enum_name);
+        let mut seen = collections::HashSet::new();
+        seen.insert(root.name());
+        let mut deps = Vec::new();
+        let mut queue = collections::VecDeque::new();
+        queue.push_back(root);
 
-        for value_proto in &proto.value {
-            enum_descriptor.add_value(EnumValueDescriptor::from_proto(value_proto));
-        }
+        while let Some(message) = queue.pop_front() {
+            for field in message.fields() {
+                match field.field_type(self) {
+                    FieldType::Message(m) | FieldType::Group(m) if seen.insert(m.name()) => {
+                        deps.push(m.name().to_owned());
+                        queue.push_back(m);
+                    }
+                    FieldType::Enum(e) if seen.insert(e.name()) => {
+                        deps.push(e.name().to_owned());
+                    }
+                    FieldType::UnresolvedMessage(name) | FieldType::UnresolvedGroup(name) => {
+                        return Err(error::Error::UnknownMessage {
+                            name: name.to_owned(),
+                        });
+                    }
+                    FieldType::UnresolvedEnum(name) => {
+                        return Err(error::Error::UnknownEnum {
+                            name: name.to_owned(),
+                        });
+                    }
+                    _ => (),
+                }
+            }
+        }
 
-        enum_descriptor
+        Ok(deps)
     }
 
-    /// The name of the enum.
-    #[inline]
-    pub fn name(&self) -> &str {
-        &self.name
-    }
+    /// Resolves a dotted field path such as `"person.address.city"` against the message with the
+    /// specified name.
+    ///
+    /// Every segment of the path except the last one must name a message field, whose type is
+    /// used to look up the next segment.  The returned fields are in path order, so the last one
+    /// is the field that the path refers to.
+    ///
+    /// Returns an error if the root message or any referenced message type is unknown, or if a
+    /// segment of the path doesn't name a suitable field.
+    pub fn resolve_field_path(
+        &self,
+        root: &str,
+        path: &str,
+    ) -> error::Result<Vec<&FieldDescriptor>> {
+        let bad = |reason: String| error::Error::BadFieldPath {
+            path: path.to_owned(),
+            reason,
+        };
 
-    /// Adds an enum value to the enum.
-    pub fn add_value(&mut self, descriptor: EnumValueDescriptor) {
-        let name = descriptor.name.clone();
-        let number = descriptor.number;
+        let mut message =
+            self.message_by_name(root)
+                .ok_or_else(|| error::Error::UnknownMessage {
+                    name: root.to_owned(),
+                })?;
+        let mut fields: Vec<&FieldDescriptor> = Vec::new();
 
-        let value_id = EnumValueId(store(&mut self.values, descriptor));
+        for segment in path.split('.') {
+            if let Some(field) = fields.last().copied() {
+                message = match field.field_type(self) {
+                    FieldType::Message(m) | FieldType::Group(m) => m,
+                    FieldType::UnresolvedMessage(name) | FieldType::UnresolvedGroup(name) => self
+                        .message_by_name(name)
+                        .ok_or_else(|| error::Error::UnknownMessage {
+                            name: name.to_owned(),
+                        })?,
+                    _ => {
+                        return Err(bad(format!(
+                            "field {} is not a message field",
+                            field.name()
+                        )))
+                    }
+                };
+            }
 
-        self.values_by_name.insert(name, value_id);
-        self.values_by_number.insert(number, value_id);
-    }
+            if segment.is_empty() {
+                return Err(bad("empty path segment".to_owned()));
+            }
+            let field = message.field_by_name(segment).ok_or_else(|| {
+                bad(format!(
+                    "message {} has no field named {}",
+                    message.name(),
+                    segment
+                ))
+            })?;
+            fields.push(field);
+        }
 
-    /// Finds a value by name.
-    #[inline]
-    pub fn value_by_name(&self, name: &str) -> Option<&EnumValueDescriptor> {
-        self.values_by_name.get(name).map(|v| &self.values[v.0])
+        Ok(fields)
     }
 
-    /// Finds a value by number.
-    #[inline]
-    pub fn value_by_number(&self, number: i32) -> Option<&EnumValueDescriptor> {
-        self.values_by_number
-            .get(&number)
-            .map(|v| &self.values[v.0])
+    /// Adds all types defined in the specified protocol buffer file descriptor set to this
+    /// registry.
+    pub fn add_file_set_proto(&mut self, file_set_proto: &descriptor::FileDescriptorSet) {
+        for file_proto in &file_set_proto.file {
+            self.add_file_proto(file_proto);
+        }
     }
-}
 
-impl EnumValueDescriptor {
-    /// Creates a new enum value descriptor with the given number.
-    pub fn new<S>(name: S, number: i32) -> EnumValueDescriptor
-    where
-        S: Into<String>,
-    {
-        let name = name.into();
-        EnumValueDescriptor { name, number }
+    /// Adds all types defined in the specified protocol buffer file descriptor to this registry.
+    pub fn add_file_proto(&mut self, file_proto: &descriptor::FileDescriptorProto) {
+        self.add_file_proto_with_options(file_proto, false)
     }
 
-    /// Reads an enum value descriptor from a parsed Protobuf descriptor.
-    pub fn from_proto(proto: &descriptor::EnumValueDescriptorProto) -> EnumValueDescriptor {
-        EnumValueDescriptor::new(proto.name().to_owned(), proto.number())
-    }
+    /// Adds all types defined in the specified protocol buffer file descriptor to this registry.
+    ///
+    /// If `with_source_info` is set, any comments found in the source code info of the file
+    /// descriptor are attached to the added message, field, enum and enum value descriptors.
+    pub fn add_file_proto_with_options(
+        &mut self,
+        file_proto: &descriptor::FileDescriptorProto,
+        with_source_info: bool,
+    ) {
+        let path = if file_proto.has_package() {
+            format!(".{}", file_proto.package())
+        } else {
+            "".to_owned()
+        };
 
-    /// The name of the enum value.
-    #[inline]
-    pub fn name(&self) -> &str {
-        &self.name
-    }
+        let syntax = Syntax::from_proto(file_proto.syntax());
+        let file = Some(self.add_file_info(FileInfo::from_proto(file_proto)));
+        for message_proto in &file_proto.message_type {
+            self.add_message_proto_with_syntax(&path, message_proto, syntax, file);
+        }
 
-    /// The number of the enum value.
-    #[inline]
-    pub fn number(&self) -> i32 {
-        self.number
-    }
-}
+        for enum_proto in &file_proto.enum_type {
+            let mut enum_descriptor = EnumDescriptor::from_proto(&path, enum_proto);
+            enum_descriptor.file = file;
+            self.add_enum(enum_descriptor);
+        }
 
-impl FieldLabel {
-    /// Converts a proto field label into a native field label.
-    pub fn from_proto(proto: descriptor::field_descriptor_proto::Label) -> FieldLabel {
-        use protobuf::descriptor::field_descriptor_proto::Label::*;
+        self.add_extension_protos(&path, &file_proto.extension);
 
-        match proto {
-            LABEL_OPTIONAL => FieldLabel::Optional,
-            LABEL_REQUIRED => FieldLabel::Required,
-            LABEL_REPEATED => FieldLabel::Repeated,
+        if with_source_info {
+            self.add_source_info(&path, file_proto);
         }
     }
 
-    /// Whether the label is repeated.
-    #[inline]
-    pub fn is_repeated(self) -> bool {
-        self == FieldLabel::Repeated
-    }
-}
+    fn add_source_info(&mut self, path: &str, file_proto: &descriptor::FileDescriptorProto) {
+        let locations: Locations = file_proto
+            .source_code_info
+            .location
+            .iter()
+            .map(|l| (l.path.as_slice(), l))
+            .collect();
 
-impl InternalFieldType {
-    /// Converts a proto field type into a native field type.
-    pub fn from_proto(
-        proto: descriptor::field_descriptor_proto::Type,
-        type_name: &str,
-    ) -> InternalFieldType {
-        use protobuf::descriptor::field_descriptor_proto::Type::*;
-        match proto {
-            TYPE_DOUBLE => InternalFieldType::Double,
-            TYPE_FLOAT => InternalFieldType::Float,
-            TYPE_INT64 => InternalFieldType::Int64,
-            TYPE_UINT64 => InternalFieldType::UInt64,
-            TYPE_INT32 => InternalFieldType::Int32,
-            TYPE_FIXED64 => InternalFieldType::Fixed64,
-            TYPE_FIXED32 => InternalFieldType::Fixed32,
-            TYPE_BOOL => InternalFieldType::Bool,
-            TYPE_STRING => InternalFieldType::String,
-            TYPE_GROUP => InternalFieldType::Group,
-            TYPE_MESSAGE => InternalFieldType::UnresolvedMessage(type_name.to_owned()),
-            TYPE_BYTES => InternalFieldType::Bytes,
-            TYPE_UINT32 => InternalFieldType::UInt32,
-            TYPE_ENUM => InternalFieldType::UnresolvedEnum(type_name.to_owned()),
-            TYPE_SFIXED32 => InternalFieldType::SFixed32,
-            TYPE_SFIXED64 => InternalFieldType::SFixed64,
-            TYPE_SINT32 => InternalFieldType::SInt32,
-            TYPE_SINT64 => InternalFieldType::SInt64,
+        for (i, message_proto) in file_proto.message_type.iter().enumerate() {
+            let location_path = [FILE_MESSAGE_TYPE_TAG, i as i32];
+            self.add_message_source_info(&locations, &location_path, path, message_proto);
+        }
+
+        for (i, enum_proto) in file_proto.enum_type.iter().enumerate() {
+            let location_path = [FILE_ENUM_TYPE_TAG, i as i32];
+            self.add_enum_source_info(&locations, &location_path, path, enum_proto);
         }
     }
 
-    #[inline]
-    fn resolve<'a>(&'a self, descriptors: &'a Descriptors) -> FieldType<'a> {
-        match *self {
-            InternalFieldType::UnresolvedMessage(ref n) => {
-                if let Some(m) = descriptors.message_by_name(n) {
-                    FieldType::Message(m)
-                } else {
-                    FieldType::UnresolvedMessage(n)
-                }
-            }
-            InternalFieldType::UnresolvedEnum(ref n) => {
-                if let Some(e) = descriptors.enum_by_name(n) {
-                    FieldType::Enum(e)
-                } else {
-                    FieldType::UnresolvedEnum(n)
+    fn add_message_source_info(
+        &mut self,
+        locations: &Locations,
+        location_path: &[i32],
+        path: &str,
+        message_proto: &descriptor::DescriptorProto,
+    ) {
+        let name = format!("{}.{}", path, message_proto.name());
+
+        if let Some(&id) = self.messages_by_name.get(name.as_str()) {
+            let message = &mut self.messages[id.0];
+            let (leading, trailing) = comments(locations, location_path);
+            message.leading_comments = leading;
+            message.trailing_comments = trailing;
+
+            for (i, field_proto) in message_proto.field.iter().enumerate() {
+                if let Some(&field_id) = message.fields_by_name.get(field_proto.name()) {
+                    let field = &mut message.fields[field_id.0];
+                    let field_path = child_path(location_path, MESSAGE_FIELD_TAG, i);
+                    let (leading, trailing) = comments(locations, &field_path);
+                    field.leading_comments = leading;
+                    field.trailing_comments = trailing;
                 }
             }
-            InternalFieldType::Double => FieldType::Double,
-            InternalFieldType::Float => FieldType::Float,
-            InternalFieldType::Int64 => FieldType::Int64,
-            InternalFieldType::UInt64 => FieldType::UInt64,
-            InternalFieldType::Int32 => FieldType::Int32,
-            InternalFieldType::Fixed64 => FieldType::Fixed64,
-            InternalFieldType::Fixed32 => FieldType::Fixed32,
-            InternalFieldType::Bool => FieldType::Bool,
-            InternalFieldType::String => FieldType::String,
-            InternalFieldType::Group => FieldType::Group,
-            InternalFieldType::Message(m) => FieldType::Message(&descriptors.messages[m.0]),
-            InternalFieldType::Bytes => FieldType::Bytes,
-            InternalFieldType::UInt32 => FieldType::UInt32,
-            InternalFieldType::Enum(e) => FieldType::Enum(&descriptors.enums[e.0]),
-            InternalFieldType::SFixed32 => FieldType::SFixed32,
-            InternalFieldType::SFixed64 => FieldType::SFixed64,
-            InternalFieldType::SInt32 => FieldType::SInt32,
-            InternalFieldType::SInt64 => FieldType::SInt64,
         }
-    }
-}
 
-impl FieldDescriptor {
-    /// Creates a new field descriptor.
-    pub fn new<S>(
-        name: S,
-        number: i32,
-        field_label: FieldLabel,
-        field_type: InternalFieldType,
-        default_value: Option<value::Value>,
-        optional: bool
-    ) -> FieldDescriptor
-    where
-        S: Into<String>,
-    {
-        let name = name.into();
-        FieldDescriptor {
-            name,
-            number,
-            field_label,
-            field_type,
-            default_value,
-            optional
+        for (i, nested_message_proto) in message_proto.nested_type.iter().enumerate() {
+            let nested_path = child_path(location_path, MESSAGE_NESTED_TYPE_TAG, i);
+            self.add_message_source_info(locations, &nested_path, &name, nested_message_proto);
+        }
+
+        for (i, nested_enum_proto) in message_proto.enum_type.iter().enumerate() {
+            let nested_path = child_path(location_path, MESSAGE_ENUM_TYPE_TAG, i);
+            self.add_enum_source_info(locations, &nested_path, &name, nested_enum_proto);
         }
     }
 
-    /// Reads a field descriptor from a parsed Protobuf descriptor.
-    pub fn from_proto(proto: &descriptor::FieldDescriptorProto) -> FieldDescriptor {
-        let name = proto.name().to_owned();
-        let number = proto.number();
-        let field_label = FieldLabel::from_proto(proto.label());
-        let field_type =
-            InternalFieldType::from_proto(proto.type_(), proto.type_name());
-        let default_value = if proto.has_default_value() {
-            // TODO: report error?
-            parse_default_value(proto.default_value(), &field_type).ok()
-        } else {
-            None
-        };
-        let optional = proto.proto3_optional() || field_label == FieldLabel::Optional;
+    fn add_enum_source_info(
+        &mut self,
+        locations: &Locations,
+        location_path: &[i32],
+        path: &str,
+        enum_proto: &descriptor::EnumDescriptorProto,
+    ) {
+        let name = format!("{}.{}", path, enum_proto.name());
 
-        FieldDescriptor::new(name, number, field_label, field_type, default_value, optional)
-    }
+        if let Some(&id) = self.enums_by_name.get(name.as_str()) {
+            let enum_descriptor = &mut self.enums[id.0];
+            let (leading, trailing) = comments(locations, location_path);
+            enum_descriptor.leading_comments = leading;
+            enum_descriptor.trailing_comments = trailing;
 
-    /// The name of the field.
-    #[inline]
-    pub fn name(&self) -> &str {
-        &self.name
+            for (i, value_proto) in enum_proto.value.iter().enumerate() {
+                if let Some(&value_id) = enum_descriptor.values_by_name.get(value_proto.name()) {
+                    let value = &mut enum_descriptor.values[value_id.0];
+                    let value_path = child_path(location_path, ENUM_VALUE_TAG, i);
+                    let (leading, trailing) = comments(locations, &value_path);
+                    value.leading_comments = leading;
+                    value.trailing_comments = trailing;
+                }
+            }
+        }
     }
 
-    /// The number of the field.
-    #[inline]
-    pub fn number(&self) -> i32 {
-        self.number
+    /// Adds a message and all nested types within that message from the specified protocol buffer
+    /// descriptor.
+    ///
+    /// The messages are assumed to use the `proto2` syntax.
+    pub fn add_message_proto(&mut self, path: &str, message_proto: &descriptor::DescriptorProto) {
+        self.add_message_proto_with_syntax(path, message_proto, Syntax::Proto2, None)
     }
 
-    /// The label of the field.
-    #[inline]
-    pub fn field_label(&self) -> FieldLabel {
-        self.field_label
-    }
+    fn add_message_proto_with_syntax(
+        &mut self,
+        path: &str,
+        message_proto: &descriptor::DescriptorProto,
+        syntax: Syntax,
+        file: Option<FileId>,
+    ) {
+        let mut message_descriptor = MessageDescriptor::from_proto(path, message_proto);
+        message_descriptor.set_syntax(syntax);
+        message_descriptor.file = file;
 
-    /// Whether the field is repeated.
-    #[inline]
-    pub fn is_repeated(&self) -> bool {
-        self.field_label == FieldLabel::Repeated
-    }
+        for nested_message_proto in &message_proto.nested_type {
+            self.add_message_proto_with_syntax(
+                message_descriptor.name(),
+                nested_message_proto,
+                syntax,
+                file,
+            );
+        }
 
-    /// The type of the field.
-    #[inline]
-    pub fn field_type<'a>(&'a self, descriptors: &'a Descriptors) -> FieldType<'a> {
-        self.field_type.resolve(descriptors)
-    }
+        for nested_enum_proto in &message_proto.enum_type {
+            let mut enum_descriptor =
+                EnumDescriptor::from_proto(message_descriptor.name(), nested_enum_proto);
+            enum_descriptor.file = file;
+            self.add_enum(enum_descriptor);
+        }
 
-    /// The default value of the field.
-    #[inline]
-    pub fn default_value(&self) -> Option<&value::Value> {
-        self.default_value.as_ref()
-    }
+        self.add_extension_protos(message_descriptor.name(), &message_proto.extension);
 
-    /// Whether the field is optional.
-    #[inline]
-    pub fn is_optional(&self) -> bool {
-        self.optional
+        self.add_message(message_descriptor);
     }
-}
 
-fn store<A>(vec: &mut Vec<A>, elem: A) -> usize {
-    let idx = vec.len();
-    vec.push(elem);
-    idx
-}
+    /// Creates a scope for adding custom built types within the specified package.
+    ///
+    /// The package name may be given with or without a leading `.`; an empty package name denotes
+    /// the root package.
+    pub fn with_package(&mut self, package: &str) -> PackageBuilder<'_> {
+        let package = package.trim_start_matches('.');
+        PackageBuilder {
+            descriptors: self,
+            package: if package.is_empty() {
+                String::new()
+            } else {
+                format!(".{}", package)
+            },
+        }
+    }
 
-fn parse_default_value(value: &str, field_type: &InternalFieldType) -> error::Result<value::Value> {
-    use std::str::FromStr;
+    /// Adds a single custom built message descriptor.
+    pub fn add_message(&mut self, mut descriptor: MessageDescriptor) {
+        if let Some(ref mut names) = self.names {
+            descriptor.intern_names(names);
+        }
+        let name = descriptor.name.clone();
+        let message_id = MessageId(store(&mut self.messages, descriptor));
+        self.messages_by_name.insert(name, message_id);
+    }
 
-    fn bad(v: &str) -> error::Error {
-        error::Error::BadDefaultValue {
-            default_value: v.to_owned(),
+    /// Adds a single custom built message descriptor, replacing any existing message descriptor
+    /// with the same name.
+    ///
+    /// The replaced descriptor is returned.  The replacement reuses the storage slot of the
+    /// replaced descriptor, so any already resolved references to a message with that name will
+    /// point to the new descriptor.  The fields of the replaced descriptor are removed from the
+    /// index used by `referencing_fields`.
+    pub fn add_or_replace_message(
+        &mut self,
+        mut descriptor: MessageDescriptor,
+    ) -> Option<MessageDescriptor> {
+        if let Some(&message_id) = self.messages_by_name.get(&descriptor.name) {
+            if let Some(ref mut names) = self.names {
+                descriptor.intern_names(names);
+            }
+            let old = mem::replace(&mut self.messages[message_id.0], descriptor);
+            // The field IDs of the old descriptor don't apply to the new one
+            for (_, refs) in self.references_by_name.iter_mut() {
+                refs.retain(|&(m, _)| m != message_id);
+            }
+            self.index_message_references(message_id);
+            Some(old)
+        } else {
+            self.add_message(descriptor);
+            None
         }
     }
 
-    match *field_type {
-        InternalFieldType::UnresolvedMessage(_)
-        | InternalFieldType::UnresolvedEnum(_)
-        | InternalFieldType::Message(_)
-        | InternalFieldType::Enum(_) => Err(bad(value)),
-        InternalFieldType::Bool => bool::from_str(value)
-            .map(value::Value::Bool)
-            .map_err(|_| bad(value)),
-        InternalFieldType::Double => match value {
-            "inf" => Ok(value::Value::F64(f64::INFINITY)),
-            "-inf" => Ok(value::Value::F64(f64::NEG_INFINITY)),
-            "nan" => Ok(value::Value::F64(f64::NAN)),
-            _ => f64::from_str(value)
-                .map(value::Value::F64)
-                .map_err(|_| bad(value)),
-        },
-        InternalFieldType::Float => match value {
-            "inf" => Ok(value::Value::F32(f32::INFINITY)),
-            "-inf" => Ok(value::Value::F32(f32::NEG_INFINITY)),
-            "nan" => Ok(value::Value::F32(f32::NAN)),
-            _ => f32::from_str(value)
-                .map(value::Value::F32)
-                .map_err(|_| bad(value)),
-        },
-        InternalFieldType::Int32 | InternalFieldType::SFixed32 | InternalFieldType::SInt32 => {
-            i32::from_str(value)
-                .map(value::Value::I32)
-                .map_err(|_| bad(value))
+    /// Renames the message with the specified fully qualified name, updating all references to it.
+    ///
+    /// Unresolved references to the old name, both from fields and extensions, are changed to
+    /// refer to the new name, and resolved references keep pointing to the same descriptor, so
+    /// this can be called both before and after `resolve_refs`.  Only the message itself is
+    /// renamed; the names of any nested types are left unchanged.
+    ///
+    /// Returns an error if there is no message with the old name, or if there already is a message
+    /// or enum with the new name.
+    pub fn rename_message(&mut self, old: &str, new: &str) -> error::Result<()> {
+        let message_id = match self.messages_by_name.get(old) {
+            Some(&message_id) => message_id,
+            None => {
+                return Err(error::Error::UnknownMessage {
+                    name: old.to_owned(),
+                })
+            }
+        };
+        if self.messages_by_name.contains_key(new) || self.enums_by_name.contains_key(new) {
+            return Err(error::Error::DuplicateType {
+                name: new.to_owned(),
+            });
         }
-        InternalFieldType::Int64 | InternalFieldType::SFixed64 | InternalFieldType::SInt64 => {
-            i64::from_str(value)
-                .map(value::Value::I64)
-                .map_err(|_| bad(value))
+
+        let new_name = match self.names {
+            Some(ref mut names) => intern(names, new),
+            None => sync::Arc::from(new),
+        };
+
+        // Rebuild the name index to keep the original order of the messages
+        self.messages_by_name = mem::take(&mut self.messages_by_name)
+            .into_iter()
+            .map(|(name, id)| {
+                if id == message_id {
+                    (new_name.clone(), id)
+                } else {
+                    (name, id)
+                }
+            })
+            .collect();
+        self.messages[message_id.0].name = new_name.clone();
+
+        let fields = self
+            .messages
+            .iter_mut()
+            .flat_map(|m| m.fields.iter_mut())
+            .chain(self.extensions.iter_mut().map(|(_, f)| f));
+        for field in fields {
+            match field.field_type {
+                InternalFieldType::UnresolvedMessage(ref mut name)
+                | InternalFieldType::UnresolvedGroup(ref mut name)
+                    if &**name == old =>
+                {
+                    *name = new_name.clone()
+                }
+                _ => (),
+            }
         }
-        InternalFieldType::UInt32 | InternalFieldType::Fixed32 => u32::from_str(value)
-            .map(value::Value::U32)
-            .map_err(|_| bad(value)),
-        InternalFieldType::UInt64 | InternalFieldType::Fixed64 => u64::from_str(value)
-            .map(value::Value::U64)
-            .map_err(|_| bad(value)),
-        InternalFieldType::String => Ok(value::Value::String(value.to_owned())),
-        InternalFieldType::Group => unimplemented!(),
-        InternalFieldType::Bytes => Ok(value::Value::Bytes(
-            value.chars().map(|c| c as u8).collect(),
-        )),
+
+        self.extensions = mem::take(&mut self.extensions)
+            .into_iter()
+            .map(|((extendee, number), field)| {
+                if extendee == old {
+                    ((new.to_owned(), number), field)
+                } else {
+                    ((extendee, number), field)
+                }
+            })
+            .collect();
+        self.extension_names = mem::take(&mut self.extension_names)
+            .into_iter()
+            .map(|((extendee, number), name)| {
+                if extendee == old {
+                    ((new.to_owned(), number), name)
+                } else {
+                    ((extendee, number), name)
+                }
+            })
+            .collect();
+
+        if let Some(references) = self.references_by_name.remove(old) {
+            self.references_by_name.insert(new_name, references);
+        }
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod test {
-    use std::fs;
+    /// Adds a single custom built enum descriptor.
+    pub fn add_enum(&mut self, mut descriptor: EnumDescriptor) {
+        if let Some(ref mut names) = self.names {
+            descriptor.name = intern(names, &descriptor.name);
+        }
+        let name = descriptor.name.clone();
+        let enum_id = EnumId(store(&mut self.enums, descriptor));
+        self.enums_by_name.insert(name, enum_id);
+    }
 
-    use protobuf::{self, descriptor::FileDescriptorSet, Message};
+    /// Resolves all internal descriptor type references, making them cheaper to follow.
+    ///
+    /// This resolves the types of the fields of all messages and of all extensions, and also
+    /// rebuilds the reverse index used by `referencing_fields`.
+    pub fn resolve_refs(&mut self) {
+        let fields = self
+            .messages
+            .iter_mut()
+            .flat_map(|m| m.fields.iter_mut())
+            .chain(self.extensions.iter_mut().map(|(_, f)| f));
+        for f in fields {
+            let field_type = &mut f.field_type;
+            let new = match *field_type {
+                InternalFieldType::UnresolvedMessage(ref name) => {
+                    if let Some(res) = self.messages_by_name.get(name) {
+                        Some(InternalFieldType::Message(*res))
+                    } else {
+                        warn!("Inconsistent schema; unknown message type {}", name);
+                        None
+                    }
+                }
+                InternalFieldType::UnresolvedEnum(ref name) => {
+                    if let Some(res) = self.enums_by_name.get(name) {
+                        Some(InternalFieldType::Enum(*res))
+                    } else {
+                        warn!("Inconsistent schema; unknown enum type {}", name);
+                        None
+                    }
+                }
+                InternalFieldType::UnresolvedGroup(ref name) => {
+                    if let Some(res) = self.messages_by_name.get(name) {
+                        Some(InternalFieldType::Group(*res))
+                    } else {
+                        warn!("Inconsistent schema; unknown group type {}", name);
+                        None
+                    }
+                }
+                _ => None,
+            };
 
-    use super::FieldLabel::*;
-    use super::FieldType::*;
-    use super::*;
+            if let Some(t) = new {
+                *field_type = t;
+            }
 
-    fn load_descriptors() -> Descriptors {
-        let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
-        let proto = FileDescriptorSet::parse_from_reader(&mut file).unwrap();
+            // The default value of an enum field names one of its values, which can only be
+            // looked up once the enum type is resolved
+            if let (InternalFieldType::Enum(id), None, Some(raw)) =
+                (&f.field_type, &f.default_value, &f.default_value_raw)
+            {
+                f.default_value = self.enums[id.0]
+                    .value_by_name(raw)
+                    .map(|v| value::Value::Enum(v.number()));
+            }
+        }
 
-        Descriptors::from_proto(&proto)
+        self.index_references();
     }
 
-    macro_rules! check_field {
-        ($id:ident, $msg:expr, $field:expr, $t:pat, $label:expr, $num:expr) => {
-            #[test]
-            fn $id() {
-                let mut d = load_descriptors();
-                d.resolve_refs();
-                let msg = d.message_by_name($msg).unwrap();
-                let field_by_name = msg.field_by_name($field).unwrap();
-                match field_by_name.field_type(&d) {
-                    $t => (),
-                    t => panic!("Expected type {}, got {:?}", stringify!($t), t),
+    /// Converts all resolved type references back into references by fully qualified name.
+    ///
+    /// Resolved references point to storage slots of this registry rather than to names.  To
+    /// safely make modifications that change which descriptor a name refers to, call this method
+    /// first, then modify the registry, and finally call `resolve_refs` again.  Each reference
+    /// keeps the name of the type that it pointed to at the time of this call.
+    ///
+    /// This also clears the reverse index used by `referencing_fields`.
+    pub fn unresolve_refs(&mut self) {
+        let message_names = self
+            .messages
+            .iter()
+            .map(|m| m.name.clone())
+            .collect::<Vec<_>>();
+        let enum_names = self
+            .enums
+            .iter()
+            .map(|e| e.name.clone())
+            .collect::<Vec<_>>();
+
+        let fields = self
+            .messages
+            .iter_mut()
+            .flat_map(|m| m.fields.iter_mut())
+            .chain(self.extensions.iter_mut().map(|(_, f)| f));
+        for field in fields {
+            let new = match field.field_type {
+                InternalFieldType::Message(id) => {
+                    InternalFieldType::UnresolvedMessage(message_names[id.0].clone())
                 }
-                assert_eq!(field_by_name.name(), $field);
-                assert_eq!(field_by_name.number(), $num);
-                assert_eq!(field_by_name.field_label(), $label);
+                InternalFieldType::Group(id) => {
+                    InternalFieldType::UnresolvedGroup(message_names[id.0].clone())
+                }
+                InternalFieldType::Enum(id) => {
+                    InternalFieldType::UnresolvedEnum(enum_names[id.0].clone())
+                }
+                _ => continue,
+            };
+            field.field_type = new;
+        }
 
-                let field_by_number = msg.field_by_number($num).unwrap();
-                match field_by_number.field_type(&d) {
-                    $t => (),
-                    t => panic!("Expected type {}, got {:?}", stringify!($t), t),
+        self.references_by_name.clear();
+    }
+
+    fn index_references(&mut self) {
+        self.references_by_name.clear();
+        for i in 0..self.messages.len() {
+            self.index_message_references(MessageId(i));
+        }
+    }
+
+    fn index_message_references(&mut self, message_id: MessageId) {
+        for (j, f) in self.messages[message_id.0].fields.iter().enumerate() {
+            let name = match f.field_type {
+                InternalFieldType::Message(id) | InternalFieldType::Group(id) => {
+                    &self.messages[id.0].name
                 }
-                assert_eq!(field_by_number.name(), $field);
-                assert_eq!(field_by_number.number(), $num);
-                assert_eq!(field_by_number.field_label(), $label);
-            }
-        };
+                InternalFieldType::Enum(id) => &self.enums[id.0].name,
+                _ => continue,
+            };
+            self.references_by_name
+                .entry(name.clone())
+                .or_default()
+                .push((message_id, FieldId(j)));
+        }
     }
+}
 
-    macro_rules! check_enum_value {
-        ($id:ident, $enu:expr, $value:expr, $num:expr) => {
-            #[test]
-            fn $id() {
-                let mut d = load_descriptors();
-                d.resolve_refs();
-                let enu = d.enum_by_name($enu).unwrap();
-                let value_by_name = enu.value_by_name($value).unwrap();
-                assert_eq!(value_by_name.name(), $value);
-                assert_eq!(value_by_name.number(), $num);
+impl MessageDescriptor {
+    /// Creates a new message descriptor with the specified message name.
+    pub fn new<S>(name: S) -> MessageDescriptor
+    where
+        S: Into<String>,
+    {
+        MessageDescriptor {
+            name: sync::Arc::from(name.into()),
+            file: None,
+            syntax: Syntax::Proto2,
+            map_entry: false,
+            message_set: false,
+            custom_options: protobuf::UnknownFields::new(),
+            leading_comments: None,
+            trailing_comments: None,
+            reserved_ranges: Vec::new(),
+            extension_ranges: Vec::new(),
+            fields: Vec::new(),
+            fields_by_name: ordered::OrderedMap::new(),
+            fields_by_number: ordered::OrderedMap::new(),
+        }
+    }
 
-                let value_by_number = enu.value_by_number($num).unwrap();
-                assert_eq!(value_by_number.name(), $value);
-                assert_eq!(value_by_number.number(), $num);
+    /// Reads a message descriptor from a parsed Protobuf descriptor.
+    pub fn from_proto(path: &str, proto: &descriptor::DescriptorProto) -> MessageDescriptor {
+        let name = format!("{}.{}", path, proto.name());
+        let mut message_descriptor = MessageDescriptor::new(name);
+        message_descriptor.map_entry = proto.options.map_entry();
+        message_descriptor.message_set = proto.options.message_set_wire_format();
+        message_descriptor.custom_options = proto.options.special_fields.unknown_fields().clone();
+        message_descriptor.reserved_ranges = proto
+            .reserved_range
+            .iter()
+            .map(|r| r.start()..r.end())
+            .collect();
+        message_descriptor.extension_ranges = proto
+            .extension_range
+            .iter()
+            .map(|r| r.start()..r.end())
+            .collect();
+
+        for field_proto in &proto.field {
+            let mut field = FieldDescriptor::from_proto(field_proto);
+            // Synthetic oneofs of proto3 optional fields are not real oneofs
+            if field_proto.has_oneof_index() && !field_proto.proto3_optional() {
+                field.oneof = proto
+                    .oneof_decl
+                    .get(field_proto.oneof_index() as usize)
+                    .map(|o| o.name().to_owned());
             }
-        };
+            message_descriptor.add_field(field);
+        }
+
+        message_descriptor
     }
 
-    check_field!(
-        optional_int32_field,
-        ".protobuf_unittest.TestAllTypes",
-        "optional_int32",
-        Int32,
-        Optional,
-        1
-    );
+    /// The ID of the file that the message was defined in, if it was loaded from a file; see
+    /// `Descriptors::file_by_id`.
+    #[inline]
+    pub fn file_id(&self) -> Option<FileId> {
+        self.file
+    }
 
-    check_field!(
-        optional_int64_field,
-        ".protobuf_unittest.TestAllTypes",
-        "optional_int64",
-        Int64,
-        Optional,
-        2
-    );
+    /// The syntax of the file that the message was defined in.
+    ///
+    /// Custom built message descriptors use the `proto2` syntax unless changed with `set_syntax`.
+    #[inline]
+    pub fn syntax(&self) -> Syntax {
+        self.syntax
+    }
 
-    check_field!(
-        optional_uint32_field,
-        ".protobuf_unittest.TestAllTypes",
-        "optional_uint32",
-        UInt32,
-        Optional,
-        3
-    );
+    /// Changes the syntax of the message.
+    ///
+    /// This also changes whether repeated scalar fields without an explicit `packed` option use the
+    /// packed encoding.
+    pub fn set_syntax(&mut self, syntax: Syntax) {
+        self.syntax = syntax;
+        for field in &mut self.fields {
+            field.syntax = syntax;
+        }
+    }
 
-    check_field!(
-        optional_uint64_field,
-        ".protobuf_unittest.TestAllTypes",
-        "optional_uint64",
-        UInt64,
-        Optional,
-        4
-    );
+    /// Whether the message is the implicitly defined entry type of a map field.
+    #[inline]
+    pub fn is_map_entry(&self) -> bool {
+        self.map_entry
+    }
 
-    check_field!(
-        optional_sint32_field,
-        ".protobuf_unittest.TestAllTypes",
-        "optional_sint32",
-        SInt32,
-        Optional,
-        5
-    );
+    /// Changes whether the message is the entry type of a map field.
+    pub fn set_map_entry(&mut self, map_entry: bool) {
+        self.map_entry = map_entry;
+    }
 
-    check_field!(
-        optional_sint64_field,
-        ".protobuf_unittest.TestAllTypes",
-        "optional_sint64",
-        SInt64,
-        Optional,
-        6
-    );
+    /// Whether the message uses the legacy `MessageSet` wire format for its extensions.
+    ///
+    /// The items of such messages are decoded into the extensions of the message value, using the
+    /// message typed extensions registered with `Descriptors::add_message_extension`.
+    #[inline]
+    pub fn is_message_set(&self) -> bool {
+        self.message_set
+    }
 
-    check_field!(
-        optional_fixed32_field,
-        ".protobuf_unittest.TestAllTypes",
-        "optional_fixed32",
-        Fixed32,
-        Optional,
-        7
-    );
+    /// Changes whether the message uses the legacy `MessageSet` wire format.
+    pub fn set_message_set(&mut self, message_set: bool) {
+        self.message_set = message_set;
+    }
 
-    check_field!(
-        optional_fixed64_field,
-        ".protobuf_unittest.TestAllTypes",
-        "optional_fixed64",
-        Fixed64,
-        Optional,
-        8
-    );
+    /// The ranges of field numbers that are reserved in the message, with exclusive ends.
+    #[inline]
+    pub fn reserved_ranges(&self) -> &[ops::Range<i32>] {
+        &self.reserved_ranges
+    }
 
-    check_field!(
-        optional_sfixed32_field,
-        ".protobuf_unittest.TestAllTypes",
-        "optional_sfixed32",
-        SFixed32,
-        Optional,
-        9
-    );
+    /// Reserves a range of field numbers in the message, with an exclusive end.
+    pub fn add_reserved_range(&mut self, range: ops::Range<i32>) {
+        self.reserved_ranges.push(range);
+    }
 
-    check_field!(
-        optional_sfixed64_field,
-        ".protobuf_unittest.TestAllTypes",
-        "optional_sfixed64",
-        SFixed64,
-        Optional,
-        10
-    );
+    /// The ranges of field numbers that are declared for extensions of the message, with exclusive
+    /// ends.
+    #[inline]
+    pub fn extension_ranges(&self) -> &[ops::Range<i32>] {
+        &self.extension_ranges
+    }
 
-    check_field!(
-        optional_float_field,
-        ".protobuf_unittest.TestAllTypes",
-        "optional_float",
-        Float,
-        Optional,
-        11
-    );
+    /// Declares a range of field numbers for extensions of the message, with an exclusive end.
+    pub fn add_extension_range(&mut self, range: ops::Range<i32>) {
+        self.extension_ranges.push(range);
+    }
+
+    /// Looks up the raw value of a custom option with the specified field number.
+    ///
+    /// Custom options are extensions of `google.protobuf.MessageOptions`, so their values are
+    /// kept as unknown fields of the options.  If the option is set multiple times, the last value
+    /// is returned.
+    pub fn custom_option(&self, number: u32) -> Option<protobuf::UnknownValueRef<'_>> {
+        self.custom_options.get(number)
+    }
+
+    /// Decodes the value of a custom option with the specified field number, using the extension
+    /// registered for `google.protobuf.MessageOptions` in the specified registry.
+    ///
+    /// Returns `None` if the option isn't set, or if the registry doesn't know the extension.
+    pub fn custom_option_value(
+        &self,
+        descriptors: &Descriptors,
+        number: u32,
+    ) -> Option<error::Result<value::Field>> {
+        decode_custom_option(
+            descriptors,
+            MESSAGE_OPTIONS_NAME,
+            &self.custom_options,
+            number,
+        )
+    }
+
+    /// Changes the raw custom options of the message.
+    pub fn set_custom_options(&mut self, custom_options: protobuf::UnknownFields) {
+        self.custom_options = custom_options;
+    }
+
+    /// All of the fields in the descriptor, in the order that they were declared.
+    ///
+    /// This is the order in which the fields were added, which for loaded types is the order of
+    /// the fields in the `.proto` file.  It is not necessarily the order of the field numbers; use
+    /// `fields_in_number_order` for that.
+    pub fn fields(&self) -> &[FieldDescriptor] {
+        &self.fields
+    }
+
+    /// All of the fields in the descriptor, in the order that they were declared; the same as
+    /// `fields`, for code that wants to make the chosen order explicit.
+    #[inline]
+    pub fn fields_by_declaration_order(&self) -> &[FieldDescriptor] {
+        &self.fields
+    }
+
+    /// All of the fields in the descriptor, ordered by their field numbers.
+    ///
+    /// This is the order in which the fields are encoded on the wire, and is useful for canonical
+    /// output that shouldn't depend on how the fields happened to be declared.
+    pub fn fields_in_number_order(&self) -> Vec<&FieldDescriptor> {
+        let mut fields = self.fields.iter().collect::<Vec<_>>();
+        fields.sort_by_key(|f| f.number());
+        fields
+    }
+
+    /// The fields in the descriptor whose resolved type is a message or a group, in the order
+    /// that they were declared.
+    ///
+    /// Fields whose type can't be resolved are skipped.  This is useful for walking nested
+    /// messages recursively; note that message types may be recursive, so such a walk needs to
+    /// keep track of the types it has already visited:
+    ///
+    /// ```
+    /// use std::collections::BTreeSet;
+    ///
+    /// use serde_protobuf::descriptor::{
+    ///     Descriptors, FieldDescriptor, FieldLabel, FieldType, InternalFieldType,
+    ///     MessageDescriptor,
+    /// };
+    ///
+    /// fn reachable<'a>(
+    ///     descriptors: &'a Descriptors,
+    ///     message: &'a MessageDescriptor,
+    ///     seen: &mut BTreeSet<&'a str>,
+    /// ) {
+    ///     if seen.insert(message.name()) {
+    ///         for field in message.message_fields(descriptors) {
+    ///             if let FieldType::Message(m) | FieldType::Group(m) =
+    ///                 field.field_type(descriptors)
+    ///             {
+    ///                 reachable(descriptors, m, seen);
+    ///             }
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let message_field = |name: &str, number, type_name: &str| {
+    ///     FieldDescriptor::builder(name)
+    ///         .number(number)
+    ///         .label(FieldLabel::Repeated)
+    ///         .field_type(InternalFieldType::UnresolvedMessage(type_name.into()))
+    ///         .build()
+    /// };
+    /// let mut node = MessageDescriptor::new(".pkg.Node");
+    /// node.add_field(FieldDescriptor::builder("id").number(1).build());
+    /// node.add_field(message_field("children", 2, ".pkg.Node"));
+    /// node.add_field(message_field("attributes", 3, ".pkg.Attribute"));
+    /// let mut descriptors = Descriptors::new();
+    /// descriptors.add_message(node);
+    /// descriptors.add_message(MessageDescriptor::new(".pkg.Attribute"));
+    /// descriptors.add_message(MessageDescriptor::new(".pkg.Unused"));
+    /// descriptors.resolve_refs();
+    ///
+    /// let node = descriptors.message_by_name(".pkg.Node").unwrap();
+    /// let names = node
+    ///     .message_fields(&descriptors)
+    ///     .map(FieldDescriptor::name)
+    ///     .collect::<Vec<_>>();
+    /// assert_eq!(names, vec!["children", "attributes"]);
+    ///
+    /// let mut seen = BTreeSet::new();
+    /// reachable(&descriptors, node, &mut seen);
+    /// assert_eq!(
+    ///     seen.into_iter().collect::<Vec<_>>(),
+    ///     vec![".pkg.Attribute", ".pkg.Node"]
+    /// );
+    /// ```
+    pub fn message_fields<'a>(
+        &'a self,
+        descriptors: &'a Descriptors,
+    ) -> impl Iterator<Item = &'a FieldDescriptor> {
+        self.fields.iter().filter(move |f| {
+            matches!(
+                f.field_type(descriptors),
+                FieldType::Message(_) | FieldType::Group(_)
+            )
+        })
+    }
+
+    /// The name of the message.
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The comments preceding the message declaration, if source info was loaded.
+    #[inline]
+    pub fn leading_comments(&self) -> Option<&str> {
+        self.leading_comments.as_deref()
+    }
+
+    /// The comments following the message declaration, if source info was loaded.
+    #[inline]
+    pub fn trailing_comments(&self) -> Option<&str> {
+        self.trailing_comments.as_deref()
+    }
+
+    /// The fully qualified name of a field of this message, for logging and cross-referencing.
+    ///
+    /// ```
+    /// use serde_protobuf::descriptor::{FieldDescriptor, MessageDescriptor};
+    ///
+    /// let mut message = MessageDescriptor::new(".pkg.Message");
+    /// message.add_field(FieldDescriptor::builder("id").number(1).build());
+    /// let field = message.field_by_name("id").unwrap();
+    /// assert_eq!(message.qualified_field_name(field), ".pkg.Message.id");
+    /// ```
+    pub fn qualified_field_name(&self, field: &FieldDescriptor) -> String {
+        format!("{}.{}", self.name, field.name)
+    }
+
+    /// Finds a field by field name.
+    #[inline]
+    pub fn field_by_name(&self, name: &str) -> Option<&FieldDescriptor> {
+        self.fields_by_name.get(name).map(|f| &self.fields[f.0])
+    }
+
+    /// Finds a field by field number.
+    #[inline]
+    pub fn field_by_number(&self, number: i32) -> Option<&FieldDescriptor> {
+        self.fields_by_number
+            .get(&number)
+            .map(|f| &self.fields[f.0])
+    }
+
+    /// The numbers of all of the fields in the descriptor, in the order that the fields were added.
+    ///
+    /// ```
+    /// use serde_protobuf::descriptor::{FieldDescriptor, MessageDescriptor};
+    ///
+    /// let mut message = MessageDescriptor::new(".pkg.Message");
+    /// message.add_field(FieldDescriptor::builder("id").number(2).build());
+    /// message.add_field(FieldDescriptor::builder("name").number(1).build());
+    /// assert_eq!(message.field_numbers().collect::<Vec<_>>(), vec![2, 1]);
+    /// assert_eq!(message.field_names().collect::<Vec<_>>(), vec!["id", "name"]);
+    /// ```
+    #[inline]
+    pub fn field_numbers(&self) -> impl Iterator<Item = i32> + '_ {
+        self.fields_by_number.keys().cloned()
+    }
+
+    /// The names of all of the fields in the descriptor, in the order that the fields were added.
+    #[inline]
+    pub fn field_names(&self) -> impl Iterator<Item = &str> {
+        self.fields_by_name.keys().map(|name| &**name)
+    }
+
+    /// Looks up the other fields in the same oneof as the field with the specified name.
+    ///
+    /// Returns `None` if there is no such field or if the field isn't part of a oneof.
+    pub fn oneof_siblings(&self, field_name: &str) -> Option<Vec<&FieldDescriptor>> {
+        let field = self.field_by_name(field_name)?;
+        let oneof = field.oneof()?;
+        Some(
+            self.fields
+                .iter()
+                .filter(|f| f.oneof() == Some(oneof) && f.number() != field.number())
+                .collect(),
+        )
+    }
+
+    /// Adds a new field to the descriptor.
+    ///
+    /// If the message already has a field with the same name or number, the new field is rejected
+    /// and a warning is logged; use `try_add_field` to handle this case explicitly.
+    pub fn add_field(&mut self, descriptor: FieldDescriptor) {
+        if let Err(e) = self.try_add_field(descriptor) {
+            warn!(
+                "Inconsistent schema; ignoring field in {}: {}",
+                self.name, e
+            );
+        }
+    }
+
+    /// Adds a new field to the descriptor, failing if the message already has a field with the
+    /// same name or number.
+    pub fn try_add_field(&mut self, mut descriptor: FieldDescriptor) -> error::Result<()> {
+        let name = descriptor.name.clone();
+        let number = descriptor.number;
+
+        if self.fields_by_name.contains_key(&name) || self.fields_by_number.contains_key(&number) {
+            return Err(error::Error::DuplicateField {
+                name: name.to_string(),
+                number,
+            });
+        }
+
+        descriptor.syntax = self.syntax;
+        let field_id = FieldId(store(&mut self.fields, descriptor));
+
+        self.fields_by_name.insert(name, field_id);
+        self.fields_by_number.insert(number, field_id);
+        Ok(())
+    }
+
+    /// Replaces all names of this message and its fields with shared copies from the pool.
+    fn intern_names(&mut self, names: &mut collections::HashSet<sync::Arc<str>>) {
+        self.name = intern(names, &self.name);
+        for field in &mut self.fields {
+            field.intern_names(names);
+        }
+        self.fields_by_name = self
+            .fields_by_name
+            .iter()
+            .map(|(_, &id)| (self.fields[id.0].name.clone(), id))
+            .collect();
+    }
+}
+
+impl<'a> IntoIterator for &'a MessageDescriptor {
+    type Item = &'a FieldDescriptor;
+    type IntoIter = slice::Iter<'a, FieldDescriptor>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.fields.iter()
+    }
+}
+
+impl EnumDescriptor {
+    /// Creates a new enum descriptor with the specified enum name.
+    pub fn new<S>(name: S) -> EnumDescriptor
+    where
+        S: Into<String>,
+    {
+        EnumDescriptor {
+            name: sync::Arc::from(name.into()),
+            file: None,
+            leading_comments: None,
+            trailing_comments: None,
+            values: Vec::new(),
+            values_by_name: ordered::OrderedMap::new(),
+            values_by_number: ordered::OrderedMap::new(),
+        }
+    }
+
+    /// Reads an enum descriptor from a parsed Protobuf descriptor.
+    pub fn from_proto(path: &str, proto: &descriptor::EnumDescriptorProto) -> EnumDescriptor {
+        let enum_name = format!("{}.{}", path, proto.name());
+
+        let mut enum_descriptor = EnumDescriptor::new(enum_name);
+
+        for value_proto in &proto.value {
+            enum_descriptor.add_value(EnumValueDescriptor::from_proto(value_proto));
+        }
+
+        enum_descriptor
+    }
+
+    /// The name of the enum.
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The ID of the file that the enum was defined in, if it was loaded from a file; see
+    /// `Descriptors::file_by_id`.
+    #[inline]
+    pub fn file_id(&self) -> Option<FileId> {
+        self.file
+    }
+
+    /// The comments preceding the enum declaration, if source info was loaded.
+    #[inline]
+    pub fn leading_comments(&self) -> Option<&str> {
+        self.leading_comments.as_deref()
+    }
+
+    /// The comments following the enum declaration, if source info was loaded.
+    #[inline]
+    pub fn trailing_comments(&self) -> Option<&str> {
+        self.trailing_comments.as_deref()
+    }
+
+    /// All of the values in the enum, in declaration order.
+    pub fn values(&self) -> &[EnumValueDescriptor] {
+        &self.values
+    }
+
+    /// The default value of the enum, i.e. the first declared value, or `None` if the enum has
+    /// no values.
+    #[inline]
+    pub fn default_value(&self) -> Option<&EnumValueDescriptor> {
+        self.values.first()
+    }
+
+    /// Adds an enum value to the enum.
+    pub fn add_value(&mut self, descriptor: EnumValueDescriptor) {
+        let name = descriptor.name.clone();
+        let number = descriptor.number;
+
+        let value_id = EnumValueId(store(&mut self.values, descriptor));
+
+        self.values_by_name.insert(name, value_id);
+        self.values_by_number.insert(number, value_id);
+    }
+
+    /// Finds a value by name.
+    #[inline]
+    pub fn value_by_name(&self, name: &str) -> Option<&EnumValueDescriptor> {
+        self.values_by_name.get(name).map(|v| &self.values[v.0])
+    }
+
+    /// Finds a value by number.
+    #[inline]
+    pub fn value_by_number(&self, number: i32) -> Option<&EnumValueDescriptor> {
+        self.values_by_number
+            .get(&number)
+            .map(|v| &self.values[v.0])
+    }
+
+    /// Finds the name of the value with the specified number.
+    ///
+    /// Enum fields may contain numbers that are not known to the enum, for example when data was
+    /// produced using a newer version of the schema, in which case `None` is returned.
+    #[inline]
+    pub fn name_for(&self, number: i32) -> Option<&str> {
+        self.value_by_number(number).map(|v| v.name())
+    }
+
+    /// The name of the value with the specified number, or the number in decimal if the enum
+    /// doesn't have such a value, which is how unknown values are usually displayed.
+    pub fn value_display(&self, number: i32) -> String {
+        match self.name_for(number) {
+            Some(name) => name.to_owned(),
+            None => number.to_string(),
+        }
+    }
+
+    /// Whether the enum has a value with the specified number.
+    #[inline]
+    pub fn contains_number(&self, number: i32) -> bool {
+        self.values_by_number.contains_key(&number)
+    }
+
+    /// Whether the enum has a value with the specified name.
+    #[inline]
+    pub fn contains_name(&self, name: &str) -> bool {
+        self.values_by_name.contains_key(name)
+    }
+
+    /// The number of values in the enum, counting every alias of a number separately.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Whether the enum has no values.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+impl<'a> IntoIterator for &'a EnumDescriptor {
+    type Item = &'a EnumValueDescriptor;
+    type IntoIter = slice::Iter<'a, EnumValueDescriptor>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.values.iter()
+    }
+}
+
+impl EnumValueDescriptor {
+    /// Creates a new enum value descriptor with the given number.
+    pub fn new<S>(name: S, number: i32) -> EnumValueDescriptor
+    where
+        S: Into<String>,
+    {
+        let name = name.into();
+        EnumValueDescriptor {
+            name,
+            number,
+            options: descriptor::EnumValueOptions::new(),
+            leading_comments: None,
+            trailing_comments: None,
+        }
+    }
+
+    /// Reads an enum value descriptor from a parsed Protobuf descriptor.
+    pub fn from_proto(proto: &descriptor::EnumValueDescriptorProto) -> EnumValueDescriptor {
+        let mut value = EnumValueDescriptor::new(proto.name().to_owned(), proto.number());
+        value.options = (*proto.options).clone();
+        value
+    }
+
+    /// The name of the enum value.
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The number of the enum value.
+    #[inline]
+    pub fn number(&self) -> i32 {
+        self.number
+    }
+
+    /// The options of the enum value.
+    #[inline]
+    pub fn options(&self) -> &descriptor::EnumValueOptions {
+        &self.options
+    }
+
+    /// Changes the options of the enum value.
+    pub fn set_options(&mut self, options: descriptor::EnumValueOptions) {
+        self.options = options;
+    }
+
+    /// Whether the enum value is marked as deprecated.
+    #[inline]
+    pub fn is_deprecated(&self) -> bool {
+        self.options.deprecated()
+    }
+
+    /// Looks up the raw value of a custom option with the specified field number.
+    ///
+    /// Custom options are extensions of `google.protobuf.EnumValueOptions`, so their values are
+    /// kept as unknown fields of the options.  If the option is set multiple times, the last value
+    /// is returned.
+    pub fn custom_option(&self, number: u32) -> Option<protobuf::UnknownValueRef<'_>> {
+        self.options.special_fields.unknown_fields().get(number)
+    }
+
+    /// The comments preceding the enum value declaration, if source info was loaded.
+    #[inline]
+    pub fn leading_comments(&self) -> Option<&str> {
+        self.leading_comments.as_deref()
+    }
+
+    /// The comments following the enum value declaration, if source info was loaded.
+    #[inline]
+    pub fn trailing_comments(&self) -> Option<&str> {
+        self.trailing_comments.as_deref()
+    }
+}
+
+impl FieldLabel {
+    /// Converts a proto field label into a native field label.
+    pub fn from_proto(proto: descriptor::field_descriptor_proto::Label) -> FieldLabel {
+        use protobuf::descriptor::field_descriptor_proto::Label::*;
+
+        match proto {
+            LABEL_OPTIONAL => FieldLabel::Optional,
+            LABEL_REQUIRED => FieldLabel::Required,
+            LABEL_REPEATED => FieldLabel::Repeated,
+        }
+    }
+
+    /// Converts a native field label into a proto field label.
+    pub fn to_proto(self) -> descriptor::field_descriptor_proto::Label {
+        use protobuf::descriptor::field_descriptor_proto::Label::*;
+
+        match self {
+            FieldLabel::Optional => LABEL_OPTIONAL,
+            FieldLabel::Required => LABEL_REQUIRED,
+            FieldLabel::Repeated => LABEL_REPEATED,
+        }
+    }
+
+    /// Whether the label is repeated.
+    #[inline]
+    pub fn is_repeated(self) -> bool {
+        self == FieldLabel::Repeated
+    }
+}
+
+impl Syntax {
+    /// Converts the `syntax` field of a proto file descriptor into a native syntax.
+    ///
+    /// Files without a syntax use `proto2`.
+    pub fn from_proto(proto: &str) -> Syntax {
+        match proto {
+            "proto3" => Syntax::Proto3,
+            _ => Syntax::Proto2,
+        }
+    }
+}
+
+impl CType {
+    /// Converts a proto C type into a native C type.
+    pub fn from_proto(proto: descriptor::field_options::CType) -> CType {
+        use protobuf::descriptor::field_options::CType::*;
+
+        match proto {
+            STRING => CType::String,
+            CORD => CType::Cord,
+            STRING_PIECE => CType::StringPiece,
+        }
+    }
+
+    /// Converts a native C type into a proto C type.
+    pub fn to_proto(self) -> descriptor::field_options::CType {
+        use protobuf::descriptor::field_options::CType::*;
+
+        match self {
+            CType::String => STRING,
+            CType::Cord => CORD,
+            CType::StringPiece => STRING_PIECE,
+        }
+    }
+}
+
+impl JsType {
+    /// Converts a proto JS type into a native JS type.
+    pub fn from_proto(proto: descriptor::field_options::JSType) -> JsType {
+        use protobuf::descriptor::field_options::JSType::*;
+
+        match proto {
+            JS_NORMAL => JsType::Normal,
+            JS_STRING => JsType::String,
+            JS_NUMBER => JsType::Number,
+        }
+    }
+
+    /// Converts a native JS type into a proto JS type.
+    pub fn to_proto(self) -> descriptor::field_options::JSType {
+        use protobuf::descriptor::field_options::JSType::*;
+
+        match self {
+            JsType::Normal => JS_NORMAL,
+            JsType::String => JS_STRING,
+            JsType::Number => JS_NUMBER,
+        }
+    }
+}
+
+impl<'a> FieldType<'a> {
+    /// The kind of this field type, without any referenced descriptors.
+    pub fn tag(&self) -> FieldTypeTag {
+        match *self {
+            FieldType::UnresolvedMessage(_) => FieldTypeTag::UnresolvedMessage,
+            FieldType::UnresolvedEnum(_) => FieldTypeTag::UnresolvedEnum,
+            FieldType::UnresolvedGroup(_) => FieldTypeTag::UnresolvedGroup,
+            FieldType::Double => FieldTypeTag::Double,
+            FieldType::Float => FieldTypeTag::Float,
+            FieldType::Int64 => FieldTypeTag::Int64,
+            FieldType::UInt64 => FieldTypeTag::UInt64,
+            FieldType::Int32 => FieldTypeTag::Int32,
+            FieldType::Fixed64 => FieldTypeTag::Fixed64,
+            FieldType::Fixed32 => FieldTypeTag::Fixed32,
+            FieldType::Bool => FieldTypeTag::Bool,
+            FieldType::String => FieldTypeTag::String,
+            FieldType::Group(_) => FieldTypeTag::Group,
+            FieldType::Message(_) => FieldTypeTag::Message,
+            FieldType::Bytes => FieldTypeTag::Bytes,
+            FieldType::UInt32 => FieldTypeTag::UInt32,
+            FieldType::Enum(_) => FieldTypeTag::Enum,
+            FieldType::SFixed32 => FieldTypeTag::SFixed32,
+            FieldType::SFixed64 => FieldTypeTag::SFixed64,
+            FieldType::SInt32 => FieldTypeTag::SInt32,
+            FieldType::SInt64 => FieldTypeTag::SInt64,
+        }
+    }
+
+    /// Converts this field type into an internal field type, for example to build a field
+    /// descriptor with the same type.
+    ///
+    /// Resolved message, enum and group types are converted into unresolved references to the
+    /// fully qualified names of their types, since their internal IDs can't be constructed outside
+    /// of the registry.  The references are resolved again when the field is added to a registry
+    /// that contains the types.
+    pub fn to_internal(&self) -> InternalFieldType {
+        match *self {
+            FieldType::UnresolvedMessage(n) => InternalFieldType::UnresolvedMessage(n.into()),
+            FieldType::UnresolvedEnum(n) => InternalFieldType::UnresolvedEnum(n.into()),
+            FieldType::UnresolvedGroup(n) => InternalFieldType::UnresolvedGroup(n.into()),
+            FieldType::Double => InternalFieldType::Double,
+            FieldType::Float => InternalFieldType::Float,
+            FieldType::Int64 => InternalFieldType::Int64,
+            FieldType::UInt64 => InternalFieldType::UInt64,
+            FieldType::Int32 => InternalFieldType::Int32,
+            FieldType::Fixed64 => InternalFieldType::Fixed64,
+            FieldType::Fixed32 => InternalFieldType::Fixed32,
+            FieldType::Bool => InternalFieldType::Bool,
+            FieldType::String => InternalFieldType::String,
+            FieldType::Group(m) => InternalFieldType::UnresolvedGroup(m.name().into()),
+            FieldType::Message(m) => InternalFieldType::UnresolvedMessage(m.name().into()),
+            FieldType::Bytes => InternalFieldType::Bytes,
+            FieldType::UInt32 => InternalFieldType::UInt32,
+            FieldType::Enum(e) => InternalFieldType::UnresolvedEnum(e.name().into()),
+            FieldType::SFixed32 => InternalFieldType::SFixed32,
+            FieldType::SFixed64 => InternalFieldType::SFixed64,
+            FieldType::SInt32 => InternalFieldType::SInt32,
+            FieldType::SInt64 => InternalFieldType::SInt64,
+        }
+    }
+}
+
+impl InternalFieldType {
+    /// Converts a proto field type into a native field type.
+    pub fn from_proto(
+        proto: descriptor::field_descriptor_proto::Type,
+        type_name: &str,
+    ) -> InternalFieldType {
+        use protobuf::descriptor::field_descriptor_proto::Type::*;
+        match proto {
+            TYPE_DOUBLE => InternalFieldType::Double,
+            TYPE_FLOAT => InternalFieldType::Float,
+            TYPE_INT64 => InternalFieldType::Int64,
+            TYPE_UINT64 => InternalFieldType::UInt64,
+            TYPE_INT32 => InternalFieldType::Int32,
+            TYPE_FIXED64 => InternalFieldType::Fixed64,
+            TYPE_FIXED32 => InternalFieldType::Fixed32,
+            TYPE_BOOL => InternalFieldType::Bool,
+            TYPE_STRING => InternalFieldType::String,
+            TYPE_GROUP => InternalFieldType::UnresolvedGroup(type_name.into()),
+            TYPE_MESSAGE => InternalFieldType::UnresolvedMessage(type_name.into()),
+            TYPE_BYTES => InternalFieldType::Bytes,
+            TYPE_UINT32 => InternalFieldType::UInt32,
+            TYPE_ENUM => InternalFieldType::UnresolvedEnum(type_name.into()),
+            TYPE_SFIXED32 => InternalFieldType::SFixed32,
+            TYPE_SFIXED64 => InternalFieldType::SFixed64,
+            TYPE_SINT32 => InternalFieldType::SInt32,
+            TYPE_SINT64 => InternalFieldType::SInt64,
+        }
+    }
+
+    #[inline]
+    fn resolve<'a>(&'a self, descriptors: &'a Descriptors) -> FieldType<'a> {
+        match *self {
+            InternalFieldType::UnresolvedMessage(ref n) => {
+                if let Some(m) = descriptors.message_by_name(n) {
+                    FieldType::Message(m)
+                } else {
+                    FieldType::UnresolvedMessage(n)
+                }
+            }
+            InternalFieldType::UnresolvedEnum(ref n) => {
+                if let Some(e) = descriptors.enum_by_name(n) {
+                    FieldType::Enum(e)
+                } else {
+                    FieldType::UnresolvedEnum(n)
+                }
+            }
+            InternalFieldType::UnresolvedGroup(ref n) => {
+                if let Some(m) = descriptors.message_by_name(n) {
+                    FieldType::Group(m)
+                } else {
+                    FieldType::UnresolvedGroup(n)
+                }
+            }
+            InternalFieldType::Double => FieldType::Double,
+            InternalFieldType::Float => FieldType::Float,
+            InternalFieldType::Int64 => FieldType::Int64,
+            InternalFieldType::UInt64 => FieldType::UInt64,
+            InternalFieldType::Int32 => FieldType::Int32,
+            InternalFieldType::Fixed64 => FieldType::Fixed64,
+            InternalFieldType::Fixed32 => FieldType::Fixed32,
+            InternalFieldType::Bool => FieldType::Bool,
+            InternalFieldType::String => FieldType::String,
+            InternalFieldType::Group(m) => FieldType::Group(&descriptors.messages[m.0]),
+            InternalFieldType::Message(m) => FieldType::Message(&descriptors.messages[m.0]),
+            InternalFieldType::Bytes => FieldType::Bytes,
+            InternalFieldType::UInt32 => FieldType::UInt32,
+            InternalFieldType::Enum(e) => FieldType::Enum(&descriptors.enums[e.0]),
+            InternalFieldType::SFixed32 => FieldType::SFixed32,
+            InternalFieldType::SFixed64 => FieldType::SFixed64,
+            InternalFieldType::SInt32 => FieldType::SInt32,
+            InternalFieldType::SInt64 => FieldType::SInt64,
+        }
+    }
+
+    /// Whether repeated values of this type can use the packed encoding.
+    #[inline]
+    fn is_packable(&self) -> bool {
+        !matches!(
+            *self,
+            InternalFieldType::UnresolvedMessage(_)
+                | InternalFieldType::UnresolvedGroup(_)
+                | InternalFieldType::Message(_)
+                | InternalFieldType::Group(_)
+                | InternalFieldType::String
+                | InternalFieldType::Bytes
+        )
+    }
+}
+
+impl FieldDescriptor {
+    /// Creates a new field descriptor.
+    pub fn new<S>(
+        name: S,
+        number: i32,
+        field_label: FieldLabel,
+        field_type: InternalFieldType,
+        default_value: Option<value::Value>,
+        optional: bool
+    ) -> FieldDescriptor
+    where
+        S: Into<String>,
+    {
+        let name = name.into();
+        let json_name = to_json_name(&name);
+        FieldDescriptor {
+            name: sync::Arc::from(name),
+            number,
+            field_label,
+            field_type,
+            default_value,
+            default_value_raw: None,
+            optional,
+            proto3_optional: false,
+            json_name,
+            packed: None,
+            syntax: Syntax::Proto2,
+            ctype: CType::String,
+            jstype: JsType::Normal,
+            oneof: None,
+            oneof_index: None,
+            custom_options: protobuf::UnknownFields::new(),
+            leading_comments: None,
+            trailing_comments: None,
+        }
+    }
+
+    /// Creates a builder for a field descriptor with the specified field name.
+    pub fn builder<S>(name: S) -> FieldDescriptorBuilder
+    where
+        S: Into<String>,
+    {
+        FieldDescriptorBuilder::new(name)
+    }
+
+    /// Reads a field descriptor from a parsed Protobuf descriptor.
+    pub fn from_proto(proto: &descriptor::FieldDescriptorProto) -> FieldDescriptor {
+        let name = proto.name().to_owned();
+        let number = proto.number();
+        let field_label = FieldLabel::from_proto(proto.label());
+        let field_type =
+            InternalFieldType::from_proto(proto.type_(), proto.type_name());
+        let default_value = if proto.has_default_value() && !field_label.is_repeated() {
+            // TODO: report error?
+            parse_default_value(proto.default_value(), &field_type).ok()
+        } else {
+            None
+        };
+        let optional = proto.proto3_optional() || field_label == FieldLabel::Optional;
+
+        let mut builder = FieldDescriptor::builder(name)
+            .number(number)
+            .label(field_label)
+            .field_type(field_type)
+            .optional(optional)
+            .proto3_optional(proto.proto3_optional())
+            .ctype(CType::from_proto(proto.options.ctype()))
+            .jstype(JsType::from_proto(proto.options.jstype()));
+        if let Some(default_value) = default_value {
+            builder = builder.default_value(default_value);
+        }
+        if proto.has_json_name() {
+            builder = builder.json_name(proto.json_name());
+        }
+        if proto.options.has_packed() {
+            builder = builder.packed(proto.options.packed());
+        }
+        let mut field = builder
+            .custom_options(proto.options.special_fields.unknown_fields().clone())
+            .build();
+        if proto.has_oneof_index() {
+            field.oneof_index = Some(proto.oneof_index());
+        }
+        if proto.has_default_value() {
+            field.default_value_raw = Some(proto.default_value().to_owned());
+        }
+        field
+    }
+
+    /// Checks that the field is consistent with the rules that `protoc` enforces.
+    ///
+    /// Returns an error if a repeated field or a message typed field has a default value.
+    pub fn validate(&self) -> error::Result<()> {
+        if self.default_value.is_none() {
+            return Ok(());
+        }
+
+        let reason = match self.field_type {
+            _ if self.field_label.is_repeated() => "repeated field must not have a default value",
+            InternalFieldType::UnresolvedMessage(_)
+            | InternalFieldType::Message(_)
+            | InternalFieldType::UnresolvedGroup(_)
+            | InternalFieldType::Group(_) => "message type must not have a default value",
+            _ => return Ok(()),
+        };
+
+        Err(error::Error::BadField {
+            field: self.name.to_string(),
+            reason: reason.to_owned(),
+        })
+    }
+
+    /// The name of the field.
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The number of the field.
+    #[inline]
+    pub fn number(&self) -> i32 {
+        self.number
+    }
+
+    /// The label of the field.
+    #[inline]
+    pub fn field_label(&self) -> FieldLabel {
+        self.field_label
+    }
+
+    /// Whether the field is repeated.
+    #[inline]
+    pub fn is_repeated(&self) -> bool {
+        self.field_label == FieldLabel::Repeated
+    }
+
+    /// The type of the field.
+    #[inline]
+    pub fn field_type<'a>(&'a self, descriptors: &'a Descriptors) -> FieldType<'a> {
+        self.field_type.resolve(descriptors)
+    }
+
+    /// The ID of the message type of the field, if the field has a resolved message or group type.
+    ///
+    /// Use `Descriptors::message_by_id` to look up the message descriptor.
+    #[inline]
+    pub fn message_id(&self) -> Option<MessageId> {
+        match self.field_type {
+            InternalFieldType::Message(id) | InternalFieldType::Group(id) => Some(id),
+            _ => None,
+        }
+    }
+
+    /// The ID of the enum type of the field, if the field has a resolved enum type.
+    ///
+    /// Use `Descriptors::enum_by_id` to look up the enum descriptor.
+    #[inline]
+    pub fn enum_id(&self) -> Option<EnumId> {
+        match self.field_type {
+            InternalFieldType::Enum(id) => Some(id),
+            _ => None,
+        }
+    }
+
+    /// The default value of the field.
+    #[inline]
+    pub fn default_value(&self) -> Option<&value::Value> {
+        self.default_value.as_ref()
+    }
+
+    /// The name of the enum value that is the default value of the field.
+    ///
+    /// Returns `None` if the field doesn't have a resolvable enum type, if it has no default
+    /// value, or if the enum has no value with the default number.
+    pub fn default_value_name<'a>(&'a self, descriptors: &'a Descriptors) -> Option<&'a str> {
+        match (self.field_type(descriptors), self.default_value.as_ref()?) {
+            (FieldType::Enum(e), &value::Value::Enum(number)) => {
+                e.value_by_number(number).map(|v| v.name())
+            }
+            _ => None,
+        }
+    }
+
+    /// The default value of the field exactly as written in its descriptor, before parsing.
+    ///
+    /// Parsing the default value is lossy for some types (e.g. escaped bytes or special float
+    /// values), so this is useful to re-emit the original `.proto` literal.  It is only set for
+    /// fields that were read from a parsed descriptor.
+    #[inline]
+    pub fn default_value_raw(&self) -> Option<&str> {
+        self.default_value_raw.as_deref()
+    }
+
+    /// Whether the field is optional.
+    #[inline]
+    pub fn is_optional(&self) -> bool {
+        self.optional
+    }
+
+    /// Whether the field is a `proto3` field with an explicit `optional` label.
+    ///
+    /// Such fields track presence like `proto2` optional fields, and are implemented as the only
+    /// member of a synthetic oneof, which is not exposed through `oneof`.  Unlike `is_optional`,
+    /// this is false for `proto2` optional fields.
+    #[inline]
+    pub fn is_proto3_optional(&self) -> bool {
+        self.proto3_optional
+    }
+
+    /// Whether the field tracks presence, so that a field that is set to its default value can be
+    /// distinguished from an unset field.
+    ///
+    /// All singular fields of `proto2` messages track presence.  In `proto3` messages, only
+    /// singular message fields, fields with an explicit `optional` label and fields that are part
+    /// of a oneof do.  Repeated fields never do.
+    pub fn has_presence(&self) -> bool {
+        !self.is_repeated()
+            && (self.syntax == Syntax::Proto2
+                || self.proto3_optional
+                || self.oneof.is_some()
+                || matches!(
+                    self.field_type,
+                    InternalFieldType::Message(_)
+                        | InternalFieldType::UnresolvedMessage(_)
+                        | InternalFieldType::Group(_)
+                        | InternalFieldType::UnresolvedGroup(_)
+                ))
+    }
+
+    /// The `key` and `value` fields of the entry message type, if this is a map field.
+    ///
+    /// Returns `None` if the field isn't a repeated field of a map entry message type, or if the
+    /// entry type doesn't have both fields.
+    pub fn map_entry_fields<'a>(
+        &'a self,
+        descriptors: &'a Descriptors,
+    ) -> Option<(&'a FieldDescriptor, &'a FieldDescriptor)> {
+        if !self.is_repeated() {
+            return None;
+        }
+        match self.field_type(descriptors) {
+            FieldType::Message(m) if m.is_map_entry() => {
+                Some((m.field_by_number(1)?, m.field_by_number(2)?))
+            }
+            _ => None,
+        }
+    }
+
+    /// The JSON name of the field.
+    #[inline]
+    pub fn json_name(&self) -> &str {
+        &self.json_name
+    }
+
+    /// Whether the field uses the packed encoding for repeated values.
+    ///
+    /// An explicit `packed` option always takes precedence.  Otherwise, repeated scalar fields are
+    /// packed in `proto3` messages and unpacked in `proto2` messages, following the syntax of the
+    /// message that the field was added to.
+    pub fn is_packed(&self) -> bool {
+        self.packed.unwrap_or_else(|| {
+            self.syntax == Syntax::Proto3
+                && self.field_label.is_repeated()
+                && self.field_type.is_packable()
+        })
+    }
+
+    /// The explicitly set `packed` option of the field, if any.
+    #[inline]
+    pub fn packed_option(&self) -> Option<bool> {
+        self.packed
+    }
+
+    /// The C++ string representation hint of the field.
+    #[inline]
+    pub fn ctype(&self) -> CType {
+        self.ctype
+    }
+
+    /// The JavaScript representation hint of the field.
+    #[inline]
+    pub fn jstype(&self) -> JsType {
+        self.jstype
+    }
+
+    /// The name of the oneof that the field is part of, if any.
+    #[inline]
+    pub fn oneof(&self) -> Option<&str> {
+        self.oneof.as_deref()
+    }
+
+    /// The raw index of the oneof that the field is part of into the `oneof_decl` list of its
+    /// message, as read from the field's descriptor, if any.
+    ///
+    /// Unlike `oneof`, this is also set for the synthetic oneofs of proto3 optional fields, and it
+    /// is only set for fields that were read from a parsed descriptor.
+    #[inline]
+    pub fn oneof_index(&self) -> Option<i32> {
+        self.oneof_index
+    }
+
+    /// Looks up the raw value of a custom option with the specified field number.
+    ///
+    /// Custom options are extensions of `google.protobuf.FieldOptions`, so their values are kept
+    /// as unknown fields of the options.  If the option is set multiple times, the last value is
+    /// returned.
+    pub fn custom_option(&self, number: u32) -> Option<protobuf::UnknownValueRef<'_>> {
+        self.custom_options.get(number)
+    }
+
+    /// Decodes the value of a custom option with the specified field number, using the extension
+    /// registered for `google.protobuf.FieldOptions` in the specified registry.
+    ///
+    /// Returns `None` if the option isn't set, or if the registry doesn't know the extension.
+    pub fn custom_option_value(
+        &self,
+        descriptors: &Descriptors,
+        number: u32,
+    ) -> Option<error::Result<value::Field>> {
+        decode_custom_option(
+            descriptors,
+            FIELD_OPTIONS_NAME,
+            &self.custom_options,
+            number,
+        )
+    }
+
+    /// The comments preceding the field declaration, if source info was loaded.
+    #[inline]
+    pub fn leading_comments(&self) -> Option<&str> {
+        self.leading_comments.as_deref()
+    }
+
+    /// The comments following the field declaration, if source info was loaded.
+    #[inline]
+    pub fn trailing_comments(&self) -> Option<&str> {
+        self.trailing_comments.as_deref()
+    }
+
+    /// Replaces the name and any unresolved type name of this field with shared copies from the
+    /// pool.
+    fn intern_names(&mut self, names: &mut collections::HashSet<sync::Arc<str>>) {
+        self.name = intern(names, &self.name);
+        match self.field_type {
+            InternalFieldType::UnresolvedMessage(ref mut name)
+            | InternalFieldType::UnresolvedEnum(ref mut name)
+            | InternalFieldType::UnresolvedGroup(ref mut name) => *name = intern(names, name),
+            _ => (),
+        }
+    }
+}
+
+impl FieldDescriptorBuilder {
+    /// Creates a new field descriptor builder with the specified field name.
+    pub fn new<S>(name: S) -> FieldDescriptorBuilder
+    where
+        S: Into<String>,
+    {
+        FieldDescriptorBuilder {
+            name: name.into(),
+            number: 0,
+            field_label: FieldLabel::Optional,
+            field_type: InternalFieldType::Int32,
+            default_value: None,
+            optional: None,
+            proto3_optional: false,
+            json_name: None,
+            packed: None,
+            ctype: CType::String,
+            jstype: JsType::Normal,
+            oneof: None,
+            custom_options: protobuf::UnknownFields::new(),
+        }
+    }
+
+    /// Sets the number of the field.
+    pub fn number(mut self, number: i32) -> FieldDescriptorBuilder {
+        self.number = number;
+        self
+    }
+
+    /// Sets the label of the field.
+    pub fn label(mut self, field_label: FieldLabel) -> FieldDescriptorBuilder {
+        self.field_label = field_label;
+        self
+    }
+
+    /// Sets the type of the field.
+    pub fn field_type(mut self, field_type: InternalFieldType) -> FieldDescriptorBuilder {
+        self.field_type = field_type;
+        self
+    }
+
+    /// Sets the default value of the field.
+    pub fn default_value(mut self, default_value: value::Value) -> FieldDescriptorBuilder {
+        self.default_value = Some(default_value);
+        self
+    }
+
+    /// Sets whether the field is optional.
+    pub fn optional(mut self, optional: bool) -> FieldDescriptorBuilder {
+        self.optional = Some(optional);
+        self
+    }
+
+    /// Sets whether the field is a `proto3` field with an explicit `optional` label.
+    ///
+    /// This doesn't change whether the field is considered optional, which must be set separately.
+    pub fn proto3_optional(mut self, proto3_optional: bool) -> FieldDescriptorBuilder {
+        self.proto3_optional = proto3_optional;
+        self
+    }
+
+    /// Sets the JSON name of the field.
+    pub fn json_name<S>(mut self, json_name: S) -> FieldDescriptorBuilder
+    where
+        S: Into<String>,
+    {
+        self.json_name = Some(json_name.into());
+        self
+    }
+
+    /// Sets whether the field uses the packed encoding for repeated values.
+    ///
+    /// If not set, the packed encoding is used for repeated scalar fields in `proto3` messages.
+    pub fn packed(mut self, packed: bool) -> FieldDescriptorBuilder {
+        self.packed = Some(packed);
+        self
+    }
+
+    /// Sets the C++ string representation hint of the field.
+    pub fn ctype(mut self, ctype: CType) -> FieldDescriptorBuilder {
+        self.ctype = ctype;
+        self
+    }
+
+    /// Sets the JavaScript representation hint of the field.
+    pub fn jstype(mut self, jstype: JsType) -> FieldDescriptorBuilder {
+        self.jstype = jstype;
+        self
+    }
+
+    /// Sets the name of the oneof that the field is part of.
+    pub fn oneof<S>(mut self, oneof: S) -> FieldDescriptorBuilder
+    where
+        S: Into<String>,
+    {
+        self.oneof = Some(oneof.into());
+        self
+    }
+
+    /// Sets the raw custom options of the field, as the unknown fields of its options.
+    pub fn custom_options(
+        mut self,
+        custom_options: protobuf::UnknownFields,
+    ) -> FieldDescriptorBuilder {
+        self.custom_options = custom_options;
+        self
+    }
+
+    /// Builds the field descriptor.
+    pub fn build(self) -> FieldDescriptor {
+        let optional = self
+            .optional
+            .unwrap_or(self.field_label == FieldLabel::Optional);
+        let name = self.name;
+        let json_name = self.json_name.unwrap_or_else(|| to_json_name(&name));
+        FieldDescriptor {
+            name: sync::Arc::from(name),
+            number: self.number,
+            field_label: self.field_label,
+            field_type: self.field_type,
+            default_value: self.default_value,
+            default_value_raw: None,
+            optional,
+            proto3_optional: self.proto3_optional,
+            json_name,
+            packed: self.packed,
+            syntax: Syntax::Proto2,
+            ctype: self.ctype,
+            jstype: self.jstype,
+            oneof: self.oneof,
+            oneof_index: None,
+            custom_options: self.custom_options,
+            leading_comments: None,
+            trailing_comments: None,
+        }
+    }
+}
+
+impl<'a> PackageBuilder<'a> {
+    /// The fully qualified name of the package, with a leading `.`, or an empty string for the
+    /// root package.
+    pub fn package(&self) -> &str {
+        &self.package
+    }
+
+    /// Adds a custom built message descriptor within the package.
+    pub fn add_message(&mut self, mut descriptor: MessageDescriptor) {
+        descriptor.name = self.qualify(&descriptor.name).into();
+        for field in &mut descriptor.fields {
+            match field.field_type {
+                InternalFieldType::UnresolvedMessage(ref mut name)
+                | InternalFieldType::UnresolvedEnum(ref mut name)
+                | InternalFieldType::UnresolvedGroup(ref mut name) => {
+                    *name = self.qualify(name).into()
+                }
+                _ => (),
+            }
+        }
+        self.descriptors.add_message(descriptor);
+    }
+
+    /// Adds a custom built enum descriptor within the package.
+    pub fn add_enum(&mut self, mut descriptor: EnumDescriptor) {
+        descriptor.name = self.qualify(&descriptor.name).into();
+        self.descriptors.add_enum(descriptor);
+    }
+
+    fn qualify(&self, name: &str) -> String {
+        if name.starts_with('.') {
+            name.to_owned()
+        } else {
+            format!("{}.{}", self.package, name)
+        }
+    }
+}
+
+fn intern(names: &mut collections::HashSet<sync::Arc<str>>, name: &str) -> sync::Arc<str> {
+    match names.get(name) {
+        Some(name) => name.clone(),
+        None => {
+            let name = sync::Arc::<str>::from(name);
+            names.insert(name.clone());
+            name
+        }
+    }
+}
+
+fn store<A>(vec: &mut Vec<A>, elem: A) -> usize {
+    let idx = vec.len();
+    vec.push(elem);
+    idx
+}
+
+fn decode_custom_option(
+    descriptors: &Descriptors,
+    extendee: &str,
+    custom_options: &protobuf::UnknownFields,
+    number: u32,
+) -> Option<error::Result<value::Field>> {
+    let extension = descriptors.extension(extendee, number as i32)?;
+    custom_options.get(number)?;
+
+    let mut bytes = Vec::new();
+    let result = (|| {
+        let mut output = protobuf::CodedOutputStream::vec(&mut bytes);
+        for (n, v) in custom_options.iter() {
+            if n == number {
+                output.write_unknown(n, v)?;
+            }
+        }
+        output.flush()?;
+        drop(output);
+
+        let mut field = value::Field::new(extension);
+        let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+        while !input.eof()? {
+            let (_, wire_type) = value::read_tag(&mut input)?;
+            field.merge_from(descriptors, extension, &mut input, wire_type)?;
+        }
+        Ok(field)
+    })();
+    Some(result)
+}
+
+fn validate_file_proto(file_proto: &descriptor::FileDescriptorProto) -> error::Result<()> {
+    let path = if file_proto.has_package() {
+        format!(".{}", file_proto.package())
+    } else {
+        "".to_owned()
+    };
+
+    for message_proto in &file_proto.message_type {
+        validate_message_proto(&path, message_proto)?;
+    }
+
+    for enum_proto in &file_proto.enum_type {
+        validate_enum_proto(&path, enum_proto)?;
+    }
+
+    Ok(())
+}
+
+fn validate_message_proto(path: &str, proto: &descriptor::DescriptorProto) -> error::Result<()> {
+    let name = validate_name(path, proto.name())?;
+
+    for field_proto in &proto.field {
+        validate_field_proto(&name, field_proto)?;
+    }
+
+    for nested_message_proto in &proto.nested_type {
+        validate_message_proto(&name, nested_message_proto)?;
+    }
+
+    for nested_enum_proto in &proto.enum_type {
+        validate_enum_proto(&name, nested_enum_proto)?;
+    }
+
+    Ok(())
+}
+
+fn validate_enum_proto(path: &str, proto: &descriptor::EnumDescriptorProto) -> error::Result<()> {
+    let name = validate_name(path, proto.name())?;
+
+    for value_proto in &proto.value {
+        validate_name(&name, value_proto.name())?;
+    }
+
+    Ok(())
+}
+
+fn validate_field_proto(path: &str, proto: &descriptor::FieldDescriptorProto) -> error::Result<()> {
+    use protobuf::descriptor::field_descriptor_proto::Label::*;
+    use protobuf::descriptor::field_descriptor_proto::Type::*;
+
+    let field = validate_name(path, proto.name())?;
+
+    let number = proto.number();
+    if !(MIN_FIELD_NUMBER..=MAX_FIELD_NUMBER).contains(&number)
+        || (MIN_RESERVED_FIELD_NUMBER..=MAX_RESERVED_FIELD_NUMBER).contains(&number)
+    {
+        return Err(error::Error::BadFieldNumber { field, number });
+    }
+
+    let bad = |reason: &str| {
+        Err(error::Error::BadField {
+            field: field.clone(),
+            reason: reason.to_owned(),
+        })
+    };
+
+    let has_type_name = !proto.type_name().is_empty();
+    match proto.type_() {
+        TYPE_MESSAGE | TYPE_ENUM | TYPE_GROUP if !has_type_name => {
+            return bad("type requires a type name");
+        }
+        TYPE_MESSAGE | TYPE_ENUM | TYPE_GROUP => (),
+        _ if has_type_name => return bad("scalar type must not have a type name"),
+        _ => (),
+    }
+
+    if proto.proto3_optional() && proto.label() != LABEL_OPTIONAL {
+        return bad("proto3 optional field must have the optional label");
+    }
+
+    if proto.has_default_value() {
+        if proto.label() == LABEL_REPEATED {
+            return bad("repeated field must not have a default value");
+        }
+
+        match proto.type_() {
+            // Enum defaults are value names that can only be checked once the enum is known
+            TYPE_ENUM => (),
+            TYPE_MESSAGE | TYPE_GROUP => return bad("message type must not have a default value"),
+            _ => {
+                let field_type = InternalFieldType::from_proto(proto.type_(), proto.type_name());
+                parse_default_value(proto.default_value(), &field_type)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_name(path: &str, name: &str) -> error::Result<String> {
+    if name.is_empty() {
+        Err(error::Error::EmptyName {
+            scope: path.to_owned(),
+        })
+    } else {
+        Ok(format!("{}.{}", path, name))
+    }
+}
+
+/// The number of edits (insertions, deletions or substitutions of characters) needed to turn one
+/// string into the other.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+fn short_name(name: &str) -> &str {
+    name.rsplit('.').next().unwrap_or(name)
+}
+
+fn unique<A, I>(mut iter: I) -> Option<A>
+where
+    I: Iterator<Item = A>,
+{
+    let first = iter.next()?;
+    if iter.next().is_some() {
+        None
+    } else {
+        Some(first)
+    }
+}
+
+fn comments(locations: &Locations, path: &[i32]) -> (Option<String>, Option<String>) {
+    match locations.get(path) {
+        Some(location) => (
+            location.leading_comments.clone(),
+            location.trailing_comments.clone(),
+        ),
+        None => (None, None),
+    }
+}
+
+fn child_path(path: &[i32], tag: i32, index: usize) -> Vec<i32> {
+    let mut result = path.to_vec();
+    result.push(tag);
+    result.push(index as i32);
+    result
+}
+
+/// Derives the JSON name of a field the same way that `protoc` does, i.e. by removing underscores
+/// and capitalizing the letter following each underscore.
+fn to_json_name(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+    let mut capitalize_next = false;
+
+    for c in name.chars() {
+        if c == '_' {
+            capitalize_next = true;
+        } else if capitalize_next {
+            result.extend(c.to_uppercase());
+            capitalize_next = false;
+        } else {
+            result.push(c);
+        }
+    }
+
+    result
+}
+
+fn parse_default_value(value: &str, field_type: &InternalFieldType) -> error::Result<value::Value> {
+    use std::str::FromStr;
+
+    fn bad(v: &str) -> error::Error {
+        error::Error::BadDefaultValue {
+            default_value: v.to_owned(),
+        }
+    }
+
+    match *field_type {
+        InternalFieldType::UnresolvedMessage(_)
+        | InternalFieldType::UnresolvedEnum(_)
+        | InternalFieldType::Message(_)
+        | InternalFieldType::Enum(_)
+        | InternalFieldType::UnresolvedGroup(_)
+        | InternalFieldType::Group(_) => Err(bad(value)),
+        InternalFieldType::Bool => bool::from_str(value)
+            .map(value::Value::Bool)
+            .map_err(|_| bad(value)),
+        InternalFieldType::Double => match value {
+            "inf" => Ok(value::Value::F64(f64::INFINITY)),
+            "-inf" => Ok(value::Value::F64(f64::NEG_INFINITY)),
+            "nan" => Ok(value::Value::F64(f64::NAN)),
+            _ => f64::from_str(value)
+                .map(value::Value::F64)
+                .map_err(|_| bad(value)),
+        },
+        InternalFieldType::Float => match value {
+            "inf" => Ok(value::Value::F32(f32::INFINITY)),
+            "-inf" => Ok(value::Value::F32(f32::NEG_INFINITY)),
+            "nan" => Ok(value::Value::F32(f32::NAN)),
+            _ => f32::from_str(value)
+                .map(value::Value::F32)
+                .map_err(|_| bad(value)),
+        },
+        InternalFieldType::Int32 | InternalFieldType::SFixed32 | InternalFieldType::SInt32 => {
+            i32::from_str(value)
+                .map(value::Value::I32)
+                .map_err(|_| bad(value))
+        }
+        InternalFieldType::Int64 | InternalFieldType::SFixed64 | InternalFieldType::SInt64 => {
+            i64::from_str(value)
+                .map(value::Value::I64)
+                .map_err(|_| bad(value))
+        }
+        InternalFieldType::UInt32 | InternalFieldType::Fixed32 => u32::from_str(value)
+            .map(value::Value::U32)
+            .map_err(|_| bad(value)),
+        InternalFieldType::UInt64 | InternalFieldType::Fixed64 => u64::from_str(value)
+            .map(value::Value::U64)
+            .map_err(|_| bad(value)),
+        InternalFieldType::String => Ok(value::Value::String(value.to_owned())),
+        InternalFieldType::Bytes => Ok(value::Value::Bytes(
+            value.chars().map(|c| c as u8).collect(),
+        )),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::fs;
+
+    use protobuf::{self, descriptor::FileDescriptorSet, Message};
+
+    use super::FieldLabel::*;
+    use super::FieldType::*;
+    use super::*;
+
+    /// Loads the descriptors of the `unittest.proto` schema in `testdata`.
+    pub(crate) fn load_descriptors() -> Descriptors {
+        let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+        let proto = FileDescriptorSet::parse_from_reader(&mut file).unwrap();
+
+        Descriptors::from_proto(&proto)
+    }
+
+    macro_rules! check_field {
+        ($id:ident, $msg:expr, $field:expr, $t:pat, $label:expr, $num:expr) => {
+            #[test]
+            fn $id() {
+                let mut d = load_descriptors();
+                d.resolve_refs();
+                let msg = d.message_by_name($msg).unwrap();
+                let field_by_name = msg.field_by_name($field).unwrap();
+                match field_by_name.field_type(&d) {
+                    $t => (),
+                    t => panic!("Expected type {}, got {:?}", stringify!($t), t),
+                }
+                assert_eq!(field_by_name.name(), $field);
+                assert_eq!(field_by_name.number(), $num);
+                assert_eq!(field_by_name.field_label(), $label);
+
+                let field_by_number = msg.field_by_number($num).unwrap();
+                match field_by_number.field_type(&d) {
+                    $t => (),
+                    t => panic!("Expected type {}, got {:?}", stringify!($t), t),
+                }
+                assert_eq!(field_by_number.name(), $field);
+                assert_eq!(field_by_number.number(), $num);
+                assert_eq!(field_by_number.field_label(), $label);
+            }
+        };
+    }
+
+    macro_rules! check_enum_value {
+        ($id:ident, $enu:expr, $value:expr, $num:expr) => {
+            #[test]
+            fn $id() {
+                let mut d = load_descriptors();
+                d.resolve_refs();
+                let enu = d.enum_by_name($enu).unwrap();
+                let value_by_name = enu.value_by_name($value).unwrap();
+                assert_eq!(value_by_name.name(), $value);
+                assert_eq!(value_by_name.number(), $num);
+
+                let value_by_number = enu.value_by_number($num).unwrap();
+                assert_eq!(value_by_number.name(), $value);
+                assert_eq!(value_by_number.number(), $num);
+            }
+        };
+    }
+
+    check_field!(
+        optional_int32_field,
+        ".protobuf_unittest.TestAllTypes",
+        "optional_int32",
+        Int32,
+        Optional,
+        1
+    );
+
+    check_field!(
+        optional_int64_field,
+        ".protobuf_unittest.TestAllTypes",
+        "optional_int64",
+        Int64,
+        Optional,
+        2
+    );
+
+    check_field!(
+        optional_uint32_field,
+        ".protobuf_unittest.TestAllTypes",
+        "optional_uint32",
+        UInt32,
+        Optional,
+        3
+    );
+
+    check_field!(
+        optional_uint64_field,
+        ".protobuf_unittest.TestAllTypes",
+        "optional_uint64",
+        UInt64,
+        Optional,
+        4
+    );
+
+    check_field!(
+        optional_sint32_field,
+        ".protobuf_unittest.TestAllTypes",
+        "optional_sint32",
+        SInt32,
+        Optional,
+        5
+    );
+
+    check_field!(
+        optional_sint64_field,
+        ".protobuf_unittest.TestAllTypes",
+        "optional_sint64",
+        SInt64,
+        Optional,
+        6
+    );
+
+    check_field!(
+        optional_fixed32_field,
+        ".protobuf_unittest.TestAllTypes",
+        "optional_fixed32",
+        Fixed32,
+        Optional,
+        7
+    );
+
+    check_field!(
+        optional_fixed64_field,
+        ".protobuf_unittest.TestAllTypes",
+        "optional_fixed64",
+        Fixed64,
+        Optional,
+        8
+    );
+
+    check_field!(
+        optional_sfixed32_field,
+        ".protobuf_unittest.TestAllTypes",
+        "optional_sfixed32",
+        SFixed32,
+        Optional,
+        9
+    );
+
+    check_field!(
+        optional_sfixed64_field,
+        ".protobuf_unittest.TestAllTypes",
+        "optional_sfixed64",
+        SFixed64,
+        Optional,
+        10
+    );
+
+    check_field!(
+        optional_float_field,
+        ".protobuf_unittest.TestAllTypes",
+        "optional_float",
+        Float,
+        Optional,
+        11
+    );
+
+    check_field!(
+        optional_double_field,
+        ".protobuf_unittest.TestAllTypes",
+        "optional_double",
+        Double,
+        Optional,
+        12
+    );
+
+    check_field!(
+        optional_bool_field,
+        ".protobuf_unittest.TestAllTypes",
+        "optional_bool",
+        Bool,
+        Optional,
+        13
+    );
+
+    check_field!(
+        optional_string_field,
+        ".protobuf_unittest.TestAllTypes",
+        "optional_string",
+        String,
+        Optional,
+        14
+    );
+
+    check_field!(
+        optional_bytes_field,
+        ".protobuf_unittest.TestAllTypes",
+        "optional_bytes",
+        Bytes,
+        Optional,
+        15
+    );
+
+    check_field!(
+        repeated_int32_field,
+        ".protobuf_unittest.TestAllTypes",
+        "repeated_int32",
+        Int32,
+        Repeated,
+        31
+    );
+
+    check_field!(
+        repeated_int64_field,
+        ".protobuf_unittest.TestAllTypes",
+        "repeated_int64",
+        Int64,
+        Repeated,
+        32
+    );
+
+    check_field!(
+        repeated_uint32_field,
+        ".protobuf_unittest.TestAllTypes",
+        "repeated_uint32",
+        UInt32,
+        Repeated,
+        33
+    );
+
+    check_field!(
+        repeated_uint64_field,
+        ".protobuf_unittest.TestAllTypes",
+        "repeated_uint64",
+        UInt64,
+        Repeated,
+        34
+    );
+
+    check_field!(
+        repeated_sint32_field,
+        ".protobuf_unittest.TestAllTypes",
+        "repeated_sint32",
+        SInt32,
+        Repeated,
+        35
+    );
+
+    check_field!(
+        repeated_sint64_field,
+        ".protobuf_unittest.TestAllTypes",
+        "repeated_sint64",
+        SInt64,
+        Repeated,
+        36
+    );
+
+    check_field!(
+        repeated_fixed32_field,
+        ".protobuf_unittest.TestAllTypes",
+        "repeated_fixed32",
+        Fixed32,
+        Repeated,
+        37
+    );
+
+    check_field!(
+        repeated_fixed64_field,
+        ".protobuf_unittest.TestAllTypes",
+        "repeated_fixed64",
+        Fixed64,
+        Repeated,
+        38
+    );
+
+    check_field!(
+        repeated_sfixed32_field,
+        ".protobuf_unittest.TestAllTypes",
+        "repeated_sfixed32",
+        SFixed32,
+        Repeated,
+        39
+    );
+
+    check_field!(
+        repeated_sfixed64_field,
+        ".protobuf_unittest.TestAllTypes",
+        "repeated_sfixed64",
+        SFixed64,
+        Repeated,
+        40
+    );
+
+    check_field!(
+        repeated_float_field,
+        ".protobuf_unittest.TestAllTypes",
+        "repeated_float",
+        Float,
+        Repeated,
+        41
+    );
+
+    check_field!(
+        repeated_double_field,
+        ".protobuf_unittest.TestAllTypes",
+        "repeated_double",
+        Double,
+        Repeated,
+        42
+    );
+
+    check_field!(
+        repeated_bool_field,
+        ".protobuf_unittest.TestAllTypes",
+        "repeated_bool",
+        Bool,
+        Repeated,
+        43
+    );
+
+    check_field!(
+        repeated_string_field,
+        ".protobuf_unittest.TestAllTypes",
+        "repeated_string",
+        String,
+        Repeated,
+        44
+    );
+
+    check_field!(
+        repeated_bytes_field,
+        ".protobuf_unittest.TestAllTypes",
+        "repeated_bytes",
+        Bytes,
+        Repeated,
+        45
+    );
+
+    check_field!(
+        repppeated_message_field,
+        ".protobuf_unittest.TestAllTypes",
+        "repeated_foreign_message",
+        Message(..),
+        Repeated,
+        49
+    );
+
+    check_field!(
+        repeated_enum_field,
+        ".protobuf_unittest.TestAllTypes",
+        "repeated_foreign_enum",
+        Enum(..),
+        Repeated,
+        52
+    );
+
+    check_field!(
+        required_field_a,
+        ".protobuf_unittest.TestRequired",
+        "a",
+        Int32,
+        Required,
+        1
+    );
+
+    check_field!(
+        required_field_b,
+        ".protobuf_unittest.TestRequired",
+        "b",
+        Int32,
+        Required,
+        3
+    );
+
+    #[test]
+    fn field_builder_defaults() {
+        let field = FieldDescriptor::builder("some_field_name").build();
+        assert_eq!(field.name(), "some_field_name");
+        assert_eq!(field.number(), 0);
+        assert_eq!(field.field_label(), Optional);
+        assert!(field.is_optional());
+        assert!(!field.is_packed());
+        assert!(field.default_value().is_none());
+        assert_eq!(field.json_name(), "someFieldName");
+    }
+
+    #[test]
+    fn field_builder_overrides() {
+        let field = FieldDescriptor::builder("values")
+            .number(4)
+            .label(Repeated)
+            .field_type(InternalFieldType::SInt64)
+            .json_name("vals")
+            .packed(true)
+            .build();
+        assert_eq!(field.number(), 4);
+        assert_eq!(field.field_label(), Repeated);
+        assert!(!field.is_optional());
+        assert!(field.is_packed());
+        assert_eq!(field.json_name(), "vals");
+
+        let d = Descriptors::new();
+        match field.field_type(&d) {
+            SInt64 => (),
+            t => panic!("Expected type SInt64, got {:?}", t),
+        }
+    }
+
+    #[test]
+    fn field_from_proto_json_name_and_packed() {
+        let d = load_descriptors();
+        let msg = d
+            .message_by_name(".protobuf_unittest.TestPackedTypes")
+            .unwrap();
+        let field = msg.field_by_name("packed_int32").unwrap();
+        assert_eq!(field.json_name(), "packedInt32");
+        assert!(field.is_packed());
+
+        let msg = d
+            .message_by_name(".protobuf_unittest.TestAllTypes")
+            .unwrap();
+        let field = msg.field_by_name("repeated_int32").unwrap();
+        assert!(!field.is_packed());
+    }
+
+    #[test]
+    fn source_info_comments() {
+        use protobuf::descriptor::source_code_info::Location;
+
+        fn location(path: &[i32], leading: &str, trailing: &str) -> Location {
+            let mut location = Location::new();
+            location.path = path.to_vec();
+            location.leading_comments = Some(leading.to_owned());
+            location.trailing_comments = Some(trailing.to_owned());
+            location
+        }
+
+        let mut field_proto = descriptor::FieldDescriptorProto::new();
+        field_proto.set_name("name".to_owned());
+        field_proto.set_number(1);
+        field_proto.set_type(descriptor::field_descriptor_proto::Type::TYPE_STRING);
+
+        let mut value_proto = descriptor::EnumValueDescriptorProto::new();
+        value_proto.set_name("RED".to_owned());
+        value_proto.set_number(0);
+
+        let mut enum_proto = descriptor::EnumDescriptorProto::new();
+        enum_proto.set_name("Color".to_owned());
+        enum_proto.value.push(value_proto);
+
+        let mut message_proto = descriptor::DescriptorProto::new();
+        message_proto.set_name("Person".to_owned());
+        message_proto.field.push(field_proto);
+        message_proto.enum_type.push(enum_proto);
+
+        let mut file_proto = descriptor::FileDescriptorProto::new();
+        file_proto.set_package("pkg".to_owned());
+        file_proto.message_type.push(message_proto);
+        let locations = &mut file_proto.source_code_info.mut_or_insert_default().location;
+        locations.push(location(&[4, 0], " A person.\n", " Trailing person.\n"));
+        locations.push(location(&[4, 0, 2, 0], " The name.\n", " Trailing name.\n"));
+        locations.push(location(&[4, 0, 4, 0], " A color.\n", " Trailing color.\n"));
+        locations.push(location(&[4, 0, 4, 0, 2, 0], " Red.\n", " Trailing red.\n"));
+
+        let mut without = Descriptors::new();
+        without.add_file_proto(&file_proto);
+        let msg = without.message_by_name(".pkg.Person").unwrap();
+        assert_eq!(msg.leading_comments(), None);
+        assert_eq!(msg.field_by_name("name").unwrap().leading_comments(), None);
+
+        let mut with = Descriptors::new();
+        with.add_file_proto_with_options(&file_proto, true);
+        let msg = with.message_by_name(".pkg.Person").unwrap();
+        assert_eq!(msg.leading_comments(), Some(" A person.\n"));
+        assert_eq!(msg.trailing_comments(), Some(" Trailing person.\n"));
+        let field = msg.field_by_name("name").unwrap();
+        assert_eq!(field.leading_comments(), Some(" The name.\n"));
+        assert_eq!(field.trailing_comments(), Some(" Trailing name.\n"));
+        let enu = with.enum_by_name(".pkg.Person.Color").unwrap();
+        assert_eq!(enu.leading_comments(), Some(" A color.\n"));
+        let value = enu.value_by_name("RED").unwrap();
+        assert_eq!(value.leading_comments(), Some(" Red.\n"));
+        assert_eq!(value.trailing_comments(), Some(" Trailing red.\n"));
+    }
+
+    fn field_set_proto(field_proto: descriptor::FieldDescriptorProto) -> FileDescriptorSet {
+        let mut message_proto = descriptor::DescriptorProto::new();
+        message_proto.set_name("Message".to_owned());
+        message_proto.field.push(field_proto);
+
+        let mut file_proto = descriptor::FileDescriptorProto::new();
+        file_proto.set_package("pkg".to_owned());
+        file_proto.message_type.push(message_proto);
+
+        let mut file_set_proto = FileDescriptorSet::new();
+        file_set_proto.file.push(file_proto);
+        file_set_proto
+    }
+
+    fn int32_field_proto(name: &str, number: i32) -> descriptor::FieldDescriptorProto {
+        let mut field_proto = descriptor::FieldDescriptorProto::new();
+        field_proto.set_name(name.to_owned());
+        field_proto.set_number(number);
+        field_proto.set_label(descriptor::field_descriptor_proto::Label::LABEL_OPTIONAL);
+        field_proto.set_type(descriptor::field_descriptor_proto::Type::TYPE_INT32);
+        field_proto
+    }
+
+    #[test]
+    fn with_package() {
+        let mut m = MessageDescriptor::new("Outer.Inner");
+        m.add_field(
+            FieldDescriptor::builder("relative")
+                .number(1)
+                .field_type(InternalFieldType::UnresolvedMessage("Other".into()))
+                .build(),
+        );
+        m.add_field(
+            FieldDescriptor::builder("absolute")
+                .number(2)
+                .field_type(InternalFieldType::UnresolvedEnum(".other.Enum".into()))
+                .build(),
+        );
+
+        let mut d = Descriptors::new();
+        let mut package = d.with_package(".pkg");
+        assert_eq!(package.package(), ".pkg");
+        package.add_message(m);
+        package.add_message(MessageDescriptor::new(".pkg.Other"));
+        package.add_enum(EnumDescriptor::new("Enum"));
+        d.with_package("")
+            .add_message(MessageDescriptor::new("Root"));
+
+        let m = d.message_by_name(".pkg.Outer.Inner").unwrap();
+        match m.field_by_name("relative").unwrap().field_type(&d) {
+            Message(m) => assert_eq!(m.name(), ".pkg.Other"),
+            t => panic!("Expected a message type, got {:?}", t),
+        }
+        match m.field_by_name("absolute").unwrap().field_type(&d) {
+            UnresolvedEnum(name) => assert_eq!(name, ".other.Enum"),
+            t => panic!("Expected an unresolved enum type, got {:?}", t),
+        }
+        assert!(d.enum_by_name(".pkg.Enum").is_some());
+        assert!(d.message_by_name(".Root").is_some());
+    }
+
+    #[test]
+    fn enum_value_options() {
+        let mut proto = descriptor::EnumValueDescriptorProto::new();
+        proto.set_name("OLD".to_owned());
+        proto.set_number(1);
+        proto.options.mut_or_insert_default().set_deprecated(true);
+        proto
+            .options
+            .mut_or_insert_default()
+            .mut_unknown_fields()
+            .add_varint(50_000, 7);
+
+        let value = EnumValueDescriptor::from_proto(&proto);
+        assert!(value.is_deprecated());
+        assert_eq!(
+            value.custom_option(50_000),
+            Some(protobuf::UnknownValueRef::Varint(7))
+        );
+        assert_eq!(value.custom_option(50_001), None);
+
+        let value = EnumValueDescriptor::new("NEW", 2);
+        assert!(!value.is_deprecated());
+        assert_eq!(value.custom_option(50_000), None);
+    }
+
+    #[test]
+    fn field_and_message_custom_options() {
+        let mut field_proto = int32_field_proto("id", 1);
+        let field_options = field_proto.options.mut_or_insert_default();
+        field_options.mut_unknown_fields().add_varint(50_000, 7);
+        field_options
+            .mut_unknown_fields()
+            .add_length_delimited(50_001, b"primary".to_vec());
+        let mut message_proto = descriptor::DescriptorProto::new();
+        message_proto.set_name("Message".to_owned());
+        message_proto.field.push(field_proto);
+        message_proto
+            .options
+            .mut_or_insert_default()
+            .mut_unknown_fields()
+            .add_varint(50_000, 1);
+
+        let mut d = Descriptors::new();
+        d.add_message(MessageDescriptor::from_proto(".pkg", &message_proto));
+        d.add_extension(
+            FIELD_OPTIONS_NAME,
+            FieldDescriptor::builder("weight").number(50_000).build(),
+        );
+        d.add_extension(
+            MESSAGE_OPTIONS_NAME,
+            FieldDescriptor::builder("table")
+                .number(50_000)
+                .field_type(InternalFieldType::Bool)
+                .build(),
+        );
+
+        let m = d.message_by_name(".pkg.Message").unwrap();
+        let f = m.field_by_number(1).unwrap();
+        assert_eq!(
+            f.custom_option(50_000),
+            Some(protobuf::UnknownValueRef::Varint(7))
+        );
+        assert_eq!(
+            f.custom_option(50_001),
+            Some(protobuf::UnknownValueRef::LengthDelimited(b"primary"))
+        );
+        match f.custom_option_value(&d, 50_000) {
+            Some(Ok(value::Field::Singular(Some(value::Value::I32(7))))) => (),
+            v => panic!("Expected a decoded option, got {:?}", v),
+        }
+        // The option is set, but the registry doesn't know the extension
+        assert!(f.custom_option_value(&d, 50_001).is_none());
+        assert!(f.custom_option_value(&d, 50_002).is_none());
+
+        match m.custom_option_value(&d, 50_000) {
+            Some(Ok(value::Field::Singular(Some(value::Value::Bool(true))))) => (),
+            v => panic!("Expected a decoded option, got {:?}", v),
+        }
+
+        let d = Descriptors::from_cache_bytes(&d.to_cache_bytes()).unwrap();
+        let m = d.message_by_name(".pkg.Message").unwrap();
+        assert_eq!(
+            m.field_by_number(1).unwrap().custom_option(50_000),
+            Some(protobuf::UnknownValueRef::Varint(7))
+        );
+        assert_eq!(
+            m.custom_option(50_000),
+            Some(protobuf::UnknownValueRef::Varint(1))
+        );
+    }
+
+    /// The total size of all names, and the size of their distinct allocations.
+    fn name_storage(d: &Descriptors) -> (usize, usize) {
+        let mut names = Vec::new();
+        names.extend(d.messages_by_name.keys());
+        names.extend(d.enums_by_name.keys());
+        for m in &d.messages {
+            names.push(&m.name);
+            names.extend(m.fields_by_name.keys());
+            for f in &m.fields {
+                names.push(&f.name);
+                match f.field_type {
+                    InternalFieldType::UnresolvedMessage(ref name)
+                    | InternalFieldType::UnresolvedEnum(ref name)
+                    | InternalFieldType::UnresolvedGroup(ref name) => names.push(name),
+                    _ => (),
+                }
+            }
+        }
+        names.extend(d.enums.iter().map(|e| &e.name));
+
+        let total = names.iter().map(|n| n.len()).sum();
+        let mut seen = collections::HashSet::new();
+        let distinct = names
+            .iter()
+            .filter(|n| seen.insert(sync::Arc::as_ptr(n) as *const u8))
+            .map(|n| n.len())
+            .sum();
+        (total, distinct)
+    }
+
+    #[test]
+    fn interning() {
+        let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+        let proto = FileDescriptorSet::parse_from_reader(&mut file).unwrap();
+        let plain = Descriptors::from_proto(&proto);
+        let mut interned = Descriptors::with_interning();
+        interned.add_file_set_proto(&proto);
+        assert!(!plain.is_interning());
+        assert!(interned.is_interning());
+
+        let (total, shared) = name_storage(&plain);
+        let (interned_total, interned_shared) = name_storage(&interned);
+        assert_eq!(total, interned_total);
+        assert!(shared < total);
+        assert!(interned_shared < shared);
+
+        // Interning doesn't change lookups or resolution
+        interned.resolve_refs();
+        let m = interned
+            .message_by_name(".protobuf_unittest.TestAllTypes")
+            .unwrap();
+        let f = m.field_by_name("optional_nested_message").unwrap();
+        assert_eq!(f.name(), "optional_nested_message");
+        match f.field_type(&interned) {
+            Message(m) => assert_eq!(m.name(), ".protobuf_unittest.TestAllTypes.NestedMessage"),
+            t => panic!("Expected a message type, got {:?}", t),
+        }
+    }
+
+    #[test]
+    fn rename_message() {
+        let registry = || {
+            let mut user = MessageDescriptor::new(".pkg.User");
+            user.add_field(
+                FieldDescriptor::builder("address")
+                    .number(1)
+                    .field_type(InternalFieldType::UnresolvedMessage(".pkg.Old".into()))
+                    .build(),
+            );
+            let mut d = Descriptors::new();
+            d.add_message(MessageDescriptor::new(".pkg.Old"));
+            d.add_message(user);
+            d
+        };
+        let check = |d: &Descriptors| {
+            assert!(d.message_by_name(".pkg.Old").is_none());
+            let user = d.message_by_name(".pkg.User").unwrap();
+            match user.field_by_name("address").unwrap().field_type(d) {
+                Message(m) => assert_eq!(m.name(), ".pkg.New"),
+                t => panic!("Expected a message type, got {:?}", t),
+            }
+        };
+
+        // Before resolving references
+        let mut d = registry();
+        d.rename_message(".pkg.Old", ".pkg.New").unwrap();
+        d.resolve_refs();
+        check(&d);
+
+        // After resolving references
+        let mut d = registry();
+        d.resolve_refs();
+        d.rename_message(".pkg.Old", ".pkg.New").unwrap();
+        check(&d);
+        assert_eq!(d.referencing_fields(".pkg.New").len(), 1);
+        assert_eq!(
+            d.messages_by_name.keys().map(|n| &**n).collect::<Vec<_>>(),
+            vec![".pkg.New", ".pkg.User"]
+        );
+
+        match d.rename_message(".pkg.New", ".pkg.User") {
+            Err(error::Error::DuplicateType { name }) => assert_eq!(name, ".pkg.User"),
+            r => panic!("Expected a duplicate type error, got {:?}", r),
+        }
+        match d.rename_message(".pkg.Old", ".pkg.Other") {
+            Err(error::Error::UnknownMessage { name }) => assert_eq!(name, ".pkg.Old"),
+            r => panic!("Expected an unknown message error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn proto3_optional() {
+        let mut explicit = int32_field_proto("explicit", 1);
+        explicit.set_proto3_optional(true);
+        explicit.set_oneof_index(0);
+        let mut choice = int32_field_proto("choice", 2);
+        choice.set_oneof_index(1);
+        let implicit = int32_field_proto("implicit", 3);
+
+        let mut message_proto = descriptor::DescriptorProto::new();
+        message_proto.set_name("Message".to_owned());
+        message_proto.field.extend(vec![explicit, choice, implicit]);
+        for name in &["_explicit", "kind"] {
+            let mut oneof_proto = descriptor::OneofDescriptorProto::new();
+            oneof_proto.set_name((*name).to_owned());
+            message_proto.oneof_decl.push(oneof_proto);
+        }
+        let mut file_proto = descriptor::FileDescriptorProto::new();
+        file_proto.set_package("pkg".to_owned());
+        file_proto.set_syntax("proto3".to_owned());
+        file_proto.message_type.push(message_proto);
+        let mut file_set_proto = FileDescriptorSet::new();
+        file_set_proto.file.push(file_proto);
+
+        let d = Descriptors::from_proto(&file_set_proto);
+        let m = d.message_by_name(".pkg.Message").unwrap();
+        let explicit = m.field_by_name("explicit").unwrap();
+        assert!(explicit.is_proto3_optional());
+        assert!(explicit.is_optional());
+        assert_eq!(explicit.oneof(), None);
+        let choice = m.field_by_name("choice").unwrap();
+        assert!(!choice.is_proto3_optional());
+        assert_eq!(choice.oneof(), Some("kind"));
+        assert_eq!(explicit.oneof_index(), Some(0));
+        assert_eq!(choice.oneof_index(), Some(1));
+        assert_eq!(m.field_by_name("implicit").unwrap().oneof_index(), None);
+
+        let source = d.to_proto_source();
+        assert!(source.contains("  optional int32 explicit = 1;\n"));
+        assert!(source.contains("  int32 implicit = 3;\n"));
+
+        let d = Descriptors::from_cache_bytes(&d.to_cache_bytes()).unwrap();
+        let m = d.message_by_name(".pkg.Message").unwrap();
+        assert!(m.field_by_name("explicit").unwrap().is_proto3_optional());
+        assert!(!m.field_by_name("implicit").unwrap().is_proto3_optional());
+    }
+
+    #[test]
+    fn unresolve_refs() {
+        let mut user = MessageDescriptor::new(".pkg.User");
+        user.add_field(
+            FieldDescriptor::builder("address")
+                .number(1)
+                .field_type(InternalFieldType::UnresolvedMessage(".pkg.Address".into()))
+                .build(),
+        );
+        let mut d = Descriptors::new();
+        d.add_message(MessageDescriptor::new(".pkg.Address"));
+        d.add_message(user);
+        d.resolve_refs();
+        let address = |d: &Descriptors| match d
+            .message_by_name(".pkg.User")
+            .unwrap()
+            .field_by_name("address")
+            .unwrap()
+            .field_type
+        {
+            InternalFieldType::Message(_) => "resolved".to_owned(),
+            InternalFieldType::UnresolvedMessage(ref name) => name.to_string(),
+            ref t => panic!("Expected a message type, got {:?}", t),
+        };
+        assert_eq!(address(&d), "resolved");
+        assert_eq!(d.referencing_fields(".pkg.Address").len(), 1);
+
+        d.unresolve_refs();
+        assert_eq!(address(&d), ".pkg.Address");
+        assert!(d.referencing_fields(".pkg.Address").is_empty());
+
+        d.resolve_refs();
+        assert_eq!(address(&d), "resolved");
+        assert_eq!(d.referencing_fields(".pkg.Address").len(), 1);
+    }
+
+    #[test]
+    fn default_value_raw() {
+        let d = load_descriptors();
+        let m = d
+            .message_by_name(".protobuf_unittest.TestExtremeDefaultValues")
+            .unwrap();
+        let raw = |name| m.field_by_name(name).unwrap().default_value_raw();
+        assert_eq!(raw("bytes_with_zero"), Some("wor\\000ld"));
+        assert_eq!(raw("large_float"), Some("2e+08"));
+
+        let m = d
+            .message_by_name(".protobuf_unittest.TestAllTypes")
+            .unwrap();
+        assert_eq!(
+            m.field_by_name("optional_int32")
+                .unwrap()
+                .default_value_raw(),
+            None
+        );
+        assert_eq!(
+            m.field_by_name("default_int32")
+                .unwrap()
+                .default_value_raw(),
+            Some("41")
+        );
+
+        let d = Descriptors::from_cache_bytes(&d.to_cache_bytes()).unwrap();
+        let m = d
+            .message_by_name(".protobuf_unittest.TestExtremeDefaultValues")
+            .unwrap();
+        assert_eq!(
+            m.field_by_name("bytes_with_zero")
+                .unwrap()
+                .default_value_raw(),
+            Some("wor\\000ld")
+        );
+
+        let built = FieldDescriptor::builder("built")
+            .default_value(value::Value::I32(1))
+            .build();
+        assert_eq!(built.default_value_raw(), None);
+    }
+
+    #[test]
+    fn default_value_name() {
+        let mut color = EnumDescriptor::new(".pkg.Color");
+        color.add_value(EnumValueDescriptor::new("RED", 0));
+        color.add_value(EnumValueDescriptor::new("BLUE", 1));
+        let mut d = Descriptors::new();
+        d.add_enum(color);
+
+        let enum_field = |default_value| {
+            let builder = FieldDescriptor::builder("color")
+                .number(1)
+                .field_type(InternalFieldType::UnresolvedEnum(".pkg.Color".into()));
+            match default_value {
+                Some(v) => builder.default_value(v).build(),
+                None => builder.build(),
+            }
+        };
+
+        let field = enum_field(Some(value::Value::Enum(1)));
+        assert_eq!(field.default_value_name(&d), Some("BLUE"));
+        assert_eq!(
+            enum_field(Some(value::Value::Enum(7))).default_value_name(&d),
+            None
+        );
+        assert_eq!(enum_field(None).default_value_name(&d), None);
+        let field = FieldDescriptor::builder("id")
+            .number(2)
+            .default_value(value::Value::I32(1))
+            .build();
+        assert_eq!(field.default_value_name(&d), None);
+
+        // Enum default values of loaded fields are resolved together with their types
+        let mut d = load_descriptors();
+        d.resolve_refs();
+        let m = d
+            .message_by_name(".protobuf_unittest.TestAllTypes")
+            .unwrap();
+        let field = m.field_by_name("default_nested_enum").unwrap();
+        assert_eq!(field.default_value_name(&d), Some("BAR"));
+        assert!(matches!(field.default_value(), Some(value::Value::Enum(2))));
+        let field = m.field_by_name("default_import_enum").unwrap();
+        assert_eq!(field.default_value_name(&d), Some("IMPORT_BAR"));
+    }
+
+    #[test]
+    fn into_iter() {
+        let mut m = MessageDescriptor::new(".pkg.Message");
+        m.add_field(FieldDescriptor::builder("a").number(1).build());
+        m.add_field(FieldDescriptor::builder("b").number(2).build());
+        let mut names = Vec::new();
+        for field in &m {
+            names.push(field.name());
+        }
+        assert_eq!(names, vec!["a", "b"]);
+
+        let mut e = EnumDescriptor::new(".pkg.Enum");
+        e.add_value(EnumValueDescriptor::new("A", 0));
+        e.add_value(EnumValueDescriptor::new("B", 1));
+        assert_eq!((&e).into_iter().map(|v| v.number()).sum::<i32>(), 1);
+    }
+
+    #[test]
+    fn enum_contains() {
+        let mut e = EnumDescriptor::new(".pkg.Enum");
+        assert!(e.is_empty());
+        assert_eq!(e.len(), 0);
+        assert!(!e.contains_number(0));
+
+        e.add_value(EnumValueDescriptor::new("A", 0));
+        e.add_value(EnumValueDescriptor::new("B", -1));
+        e.add_value(EnumValueDescriptor::new("ALIAS_OF_B", -1));
+        assert!(!e.is_empty());
+        assert_eq!(e.len(), 3);
+        assert!(e.contains_number(0));
+        assert!(e.contains_number(-1));
+        assert!(!e.contains_number(1));
+        assert!(e.contains_name("A"));
+        assert!(e.contains_name("ALIAS_OF_B"));
+        assert!(!e.contains_name("a"));
+        assert!(!e.contains_name("C"));
+    }
+
+    #[test]
+    fn enum_value_display() {
+        let mut e = EnumDescriptor::new(".pkg.Enum");
+        e.add_value(EnumValueDescriptor::new("A", 0));
+        e.add_value(EnumValueDescriptor::new("B", -1));
+        assert_eq!(e.value_display(0), "A");
+        assert_eq!(e.value_display(-1), "B");
+        assert_eq!(e.value_display(1), "1");
+        assert_eq!(e.value_display(-2), "-2");
+    }
+
+    #[test]
+    fn field_orders() {
+        let mut m = MessageDescriptor::new(".pkg.Message");
+        for &(name, number) in &[("c", 3), ("a", 1), ("b", 2)] {
+            m.add_field(FieldDescriptor::builder(name).number(number).build());
+        }
+        fn names<'a>(fields: &[&'a FieldDescriptor]) -> Vec<&'a str> {
+            fields.iter().map(|f| f.name()).collect()
+        }
+        let declared = m.fields_by_declaration_order().iter().collect::<Vec<_>>();
+        assert_eq!(names(&declared), vec!["c", "a", "b"]);
+        assert_eq!(m.fields().len(), declared.len());
+        assert_eq!(names(&m.fields_in_number_order()), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn oneof_siblings() {
+        let mut m = MessageDescriptor::new(".pkg.Message");
+        m.add_field(
+            FieldDescriptor::builder("a")
+                .number(1)
+                .oneof("choice")
+                .build(),
+        );
+        m.add_field(
+            FieldDescriptor::builder("b")
+                .number(2)
+                .oneof("choice")
+                .build(),
+        );
+        m.add_field(FieldDescriptor::builder("c").number(3).build());
+
+        let names = |fields: Vec<&FieldDescriptor>| {
+            fields
+                .iter()
+                .map(|f| f.name().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(m.oneof_siblings("a").unwrap()), vec!["b"]);
+        assert_eq!(names(m.oneof_siblings("b").unwrap()), vec!["a"]);
+        assert!(m.oneof_siblings("c").is_none());
+        assert!(m.oneof_siblings("d").is_none());
+
+        let d = load_descriptors();
+        let m = d.message_by_name(".protobuf_unittest.TestOneof").unwrap();
+        assert_eq!(m.field_by_name("foo_int").unwrap().oneof(), Some("foo"));
+        assert_eq!(
+            names(m.oneof_siblings("foo_int").unwrap()),
+            vec!["foo_string", "foo_message"]
+        );
+    }
+
+    #[test]
+    fn message_fields() {
+        let d = load_descriptors();
+        let m = d
+            .message_by_name(".protobuf_unittest.TestAllTypes")
+            .unwrap();
+        let fields = m.message_fields(&d).collect::<Vec<_>>();
+        assert!(fields.iter().any(|f| f.name() == "optional_nested_message"));
+        assert!(fields
+            .iter()
+            .any(|f| f.name() == "repeated_foreign_message"));
+        assert!(!fields.iter().any(|f| f.name() == "optional_nested_enum"));
+        assert_eq!(
+            fields.len(),
+            m.fields()
+                .iter()
+                .filter(|f| matches!(f.field_type(&d), Message(_)))
+                .count()
+        );
+
+        let mut message = MessageDescriptor::new(".pkg.Message");
+        message.add_field(
+            FieldDescriptor::builder("group")
+                .number(1)
+                .field_type(InternalFieldType::UnresolvedGroup(".pkg.Message".into()))
+                .build(),
+        );
+        message.add_field(
+            FieldDescriptor::builder("missing")
+                .number(2)
+                .field_type(InternalFieldType::UnresolvedMessage(".pkg.Missing".into()))
+                .build(),
+        );
+        let mut d = Descriptors::new();
+        d.add_message(message);
+        d.resolve_refs();
+        let m = d.message_by_name(".pkg.Message").unwrap();
+        assert_eq!(
+            m.message_fields(&d).map(|f| f.name()).collect::<Vec<_>>(),
+            vec!["group"]
+        );
+    }
+
+    #[test]
+    fn oneof_index() {
+        let d = load_descriptors();
+        let m = d.message_by_name(".protobuf_unittest.TestOneof").unwrap();
+        assert_eq!(m.syntax(), Syntax::Proto2);
+        for name in &["foo_int", "foo_string", "foo_message"] {
+            assert_eq!(m.field_by_name(name).unwrap().oneof_index(), Some(0));
+        }
+
+        let m = d
+            .message_by_name(".protobuf_unittest.TestAllTypes")
+            .unwrap();
+        assert_eq!(
+            m.field_by_name("optional_int32").unwrap().oneof_index(),
+            None
+        );
+        assert_eq!(
+            m.field_by_name("oneof_uint32").unwrap().oneof_index(),
+            Some(0)
+        );
+
+        let d = Descriptors::from_cache_bytes(&d.to_cache_bytes()).unwrap();
+        let m = d.message_by_name(".protobuf_unittest.TestOneof").unwrap();
+        assert_eq!(m.field_by_name("foo_int").unwrap().oneof_index(), Some(0));
+
+        let built = FieldDescriptor::builder("built").oneof("choice").build();
+        assert_eq!(built.oneof_index(), None);
+    }
+
+    #[test]
+    fn extension_by_name() {
+        let mut file = descriptor::FileDescriptorProto::new();
+        file.set_package("pkg".to_owned());
+        let extension = |extendee: &str, name: &str| {
+            let mut proto = descriptor::FieldDescriptorProto::new();
+            proto.set_name(name.to_owned());
+            proto.set_number(100);
+            proto.set_extendee(extendee.to_owned());
+            proto
+        };
+        file.extension.push(extension(".pkg.A", "ext"));
+        let mut message = descriptor::DescriptorProto::new();
+        message.set_name("Scope".to_owned());
+        message.extension.push(extension(".pkg.B", "ext"));
+        file.message_type.push(message);
+        let mut extendee = descriptor::DescriptorProto::new();
+        extendee.set_name("B".to_owned());
+        file.message_type.push(extendee);
+
+        let mut d = Descriptors::new();
+        d.add_file_proto(&file);
+        d.add_extension(
+            ".pkg.C",
+            FieldDescriptor::builder("custom").number(100).build(),
+        );
+
+        let found = |extendee, name| d.extension_by_name(extendee, name).map(|f| f.number());
+        assert_eq!(found(".pkg.A", ".pkg.ext"), Some(100));
+        assert_eq!(found(".pkg.A", "pkg.ext"), Some(100));
+        assert_eq!(found(".pkg.B", ".pkg.Scope.ext"), Some(100));
+        // Extensions with the same number of other extendees are not found
+        assert_eq!(found(".pkg.A", ".pkg.Scope.ext"), None);
+        assert_eq!(found(".pkg.B", ".pkg.ext"), None);
+        assert_eq!(found(".pkg.C", ".pkg.ext"), None);
+        // Short names are scoped by the extendee too
+        assert!(std::ptr::eq(
+            d.extension_by_name(".pkg.A", "ext").unwrap(),
+            d.extension(".pkg.A", 100).unwrap()
+        ));
+        assert!(std::ptr::eq(
+            d.extension_by_name(".pkg.B", "ext").unwrap(),
+            d.extension(".pkg.B", 100).unwrap()
+        ));
+        assert_eq!(found(".pkg.C", "custom"), Some(100));
+        assert_eq!(found(".pkg.A", "custom"), None);
+
+        // The names survive renaming the extendee and caching
+        d.rename_message(".pkg.B", ".pkg.Renamed").unwrap();
+        let d = Descriptors::from_cache_bytes(&d.to_cache_bytes()).unwrap();
+        assert!(d
+            .extension_by_name(".pkg.Renamed", ".pkg.Scope.ext")
+            .is_some());
+        assert!(d.extension_by_name(".pkg.B", ".pkg.Scope.ext").is_none());
+    }
+
+    #[test]
+    fn message_extensions_from_proto() {
+        let d = load_descriptors();
+        let extension = d
+            .message_extension(".protobuf_unittest.TestAllExtensions", 18)
+            .unwrap();
+        assert_eq!(
+            extension.name(),
+            ".protobuf_unittest.TestAllTypes.NestedMessage"
+        );
+        assert!(d
+            .message_extension(".protobuf_unittest.TestAllExtensions", 1)
+            .is_none());
+        assert!(!d
+            .message_by_name(".protobuf_unittest.TestAllExtensions")
+            .unwrap()
+            .is_message_set());
+    }
+
+    #[test]
+    fn message_ranges_from_proto() {
+        let d = load_descriptors();
+        let reserved = d
+            .message_by_name(".protobuf_unittest.TestReservedFields")
+            .unwrap();
+        assert_eq!(reserved.reserved_ranges(), &[2..3, 15..16, 9..12]);
+        assert!(reserved.extension_ranges().is_empty());
+        let extendable = d
+            .message_by_name(".protobuf_unittest.TestAllExtensions")
+            .unwrap();
+        assert_eq!(extendable.extension_ranges().len(), 1);
+        assert_eq!(extendable.extension_ranges()[0], 1..MAX_FIELD_NUMBER + 1);
+        assert!(extendable.reserved_ranges().is_empty());
+    }
+
+    #[test]
+    fn packages() {
+        let mut d = load_descriptors();
+        d.add_message(MessageDescriptor::new("Unpackaged"));
+        let packages = d.packages().collect::<Vec<_>>();
+        assert_eq!(
+            packages.iter().map(|p| p.0).collect::<Vec<_>>(),
+            vec!["", ".protobuf_unittest", ".protobuf_unittest_import"]
+        );
+
+        let (_, messages, enums) = &packages[1];
+        let names = messages.iter().map(|m| m.name()).collect::<Vec<_>>();
+        assert!(names.contains(&".protobuf_unittest.TestAllTypes"));
+        assert!(names.contains(&".protobuf_unittest.TestAllTypes.NestedMessage"));
+        let names = enums.iter().map(|e| e.name()).collect::<Vec<_>>();
+        assert!(names.contains(&".protobuf_unittest.ForeignEnum"));
+        assert!(names.contains(&".protobuf_unittest.TestAllTypes.NestedEnum"));
+        let count = packages
+            .iter()
+            .map(|(_, messages, enums)| messages.len() + enums.len())
+            .sum::<usize>();
+        assert_eq!(count, d.messages_by_name.len() + d.enums_by_name.len());
+    }
+
+    #[test]
+    fn hash_field_types() {
+        let mut d = load_descriptors();
+        d.resolve_refs();
+        let msg = d
+            .message_by_name(".protobuf_unittest.TestAllTypes")
+            .unwrap();
+
+        let mut by_tag = collections::HashMap::new();
+        let mut by_type = collections::HashMap::new();
+        for field in msg.fields() {
+            *by_tag
+                .entry((field.field_label(), field.field_type(&d).tag()))
+                .or_insert(0) += 1;
+            *by_type.entry(&field.field_type).or_insert(0) += 1;
+        }
+        assert_eq!(by_tag[&(Optional, FieldTypeTag::Int32)], 2);
+        assert_eq!(by_tag[&(Repeated, FieldTypeTag::Message)], 4);
+        assert_eq!(by_type[&InternalFieldType::Int32], 3);
+    }
+
+    #[test]
+    fn lookup_by_id() {
+        let mut d = load_descriptors();
+        let msg = d
+            .message_by_name(".protobuf_unittest.TestAllTypes")
+            .unwrap();
+        assert_eq!(
+            msg.field_by_name("optional_foreign_message")
+                .unwrap()
+                .message_id(),
+            None
+        );
+
+        d.resolve_refs();
+        let msg = d
+            .message_by_name(".protobuf_unittest.TestAllTypes")
+            .unwrap();
+        let field = msg.field_by_name("optional_foreign_message").unwrap();
+        let id = field.message_id().unwrap();
+        assert_eq!(
+            d.message_by_id(id).name(),
+            ".protobuf_unittest.ForeignMessage"
+        );
+        assert_eq!(field.enum_id(), None);
+
+        let field = msg.field_by_name("optional_foreign_enum").unwrap();
+        let id = field.enum_id().unwrap();
+        assert_eq!(d.enum_by_id(id).name(), ".protobuf_unittest.ForeignEnum");
+        assert_eq!(field.message_id(), None);
+    }
+
+    #[test]
+    fn packed_by_syntax() {
+        let packed = |syntax: &str, packed: Option<bool>| {
+            let mut field_proto = int32_field_proto("values", 1);
+            field_proto.set_label(descriptor::field_descriptor_proto::Label::LABEL_REPEATED);
+            if let Some(packed) = packed {
+                field_proto
+                    .options
+                    .mut_or_insert_default()
+                    .set_packed(packed);
+            }
+            let mut file_set_proto = field_set_proto(field_proto);
+            file_set_proto.file[0].set_syntax(syntax.to_owned());
+            let d = Descriptors::from_proto(&file_set_proto);
+            let field = d
+                .message_by_name(".pkg.Message")
+                .unwrap()
+                .field_by_number(1);
+            field.unwrap().is_packed()
+        };
+
+        assert!(!packed("proto2", None));
+        assert!(packed("proto2", Some(true)));
+        assert!(packed("proto3", None));
+        assert!(!packed("proto3", Some(false)));
+
+        let mut m = MessageDescriptor::new(".pkg.Message");
+        m.add_field(
+            FieldDescriptor::builder("values")
+                .number(1)
+                .label(Repeated)
+                .build(),
+        );
+        m.add_field(
+            FieldDescriptor::builder("names")
+                .number(2)
+                .label(Repeated)
+                .field_type(InternalFieldType::String)
+                .build(),
+        );
+        m.set_syntax(Syntax::Proto3);
+        assert!(m.field_by_number(1).unwrap().is_packed());
+        assert!(!m.field_by_number(2).unwrap().is_packed());
+    }
+
+    #[test]
+    fn message_syntax() {
+        let d = load_descriptors();
+        let m = d
+            .message_by_name(".protobuf_unittest.TestAllTypes")
+            .unwrap();
+        assert_eq!(m.syntax(), Syntax::Proto2);
+
+        let mut file_set_proto = field_set_proto(int32_field_proto("a", 1));
+        file_set_proto.file[0].set_syntax("proto3".to_owned());
+        let mut nested_proto = descriptor::DescriptorProto::new();
+        nested_proto.set_name("Nested".to_owned());
+        file_set_proto.file[0].message_type[0]
+            .nested_type
+            .push(nested_proto);
+        let d = Descriptors::from_proto(&file_set_proto);
+        for name in &[".pkg.Message", ".pkg.Message.Nested"] {
+            assert_eq!(d.message_by_name(name).unwrap().syntax(), Syntax::Proto3);
+        }
+    }
+
+    #[test]
+    fn try_from_proto_valid() {
+        let mut file = fs::File::open("testdata/descriptors.pb").unwrap();
+        let proto = FileDescriptorSet::parse_from_reader(&mut file).unwrap();
+        assert!(Descriptors::try_from_proto(&proto).is_ok());
+
+        let d = Descriptors::try_from_proto(&field_set_proto(int32_field_proto("a", 1))).unwrap();
+        assert!(d.message_by_name(".pkg.Message").is_some());
+    }
+
+    #[test]
+    fn try_from_proto_empty_name() {
+        let proto = field_set_proto(int32_field_proto("", 1));
+        match Descriptors::try_from_proto(&proto) {
+            Err(error::Error::EmptyName { scope }) => assert_eq!(scope, ".pkg.Message"),
+            r => panic!("Expected empty name error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn try_from_proto_bad_field_number() {
+        for &number in &[0, -1, 19_000, 19_999, 536_870_912] {
+            let proto = field_set_proto(int32_field_proto("a", number));
+            match Descriptors::try_from_proto(&proto) {
+                Err(error::Error::BadFieldNumber { field, number: n }) => {
+                    assert_eq!(field, ".pkg.Message.a");
+                    assert_eq!(n, number);
+                }
+                r => panic!("Expected bad field number error, got {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn try_from_proto_inconsistent_field() {
+        let mut field_proto = int32_field_proto("a", 1);
+        field_proto.set_type(descriptor::field_descriptor_proto::Type::TYPE_MESSAGE);
+        match Descriptors::try_from_proto(&field_set_proto(field_proto)) {
+            Err(error::Error::BadField { field, .. }) => assert_eq!(field, ".pkg.Message.a"),
+            r => panic!("Expected bad field error, got {:?}", r),
+        }
+
+        let mut field_proto = int32_field_proto("a", 1);
+        field_proto.set_default_value("not a number".to_owned());
+        match Descriptors::try_from_proto(&field_set_proto(field_proto)) {
+            Err(error::Error::BadDefaultValue { default_value }) => {
+                assert_eq!(default_value, "not a number")
+            }
+            r => panic!("Expected bad default value error, got {:?}", r),
+        }
+
+        let mut field_proto = int32_field_proto("a", 1);
+        field_proto.set_label(descriptor::field_descriptor_proto::Label::LABEL_REPEATED);
+        field_proto.set_default_value("1".to_owned());
+        match Descriptors::try_from_proto(&field_set_proto(field_proto)) {
+            Err(error::Error::BadField { field, .. }) => assert_eq!(field, ".pkg.Message.a"),
+            r => panic!("Expected bad field error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn referencing_fields() {
+        let mut d = load_descriptors();
+        assert!(d
+            .referencing_fields(".protobuf_unittest.ForeignEnum")
+            .is_empty());
+        d.resolve_refs();
+
+        let refs = d.referencing_fields(".protobuf_unittest.ForeignEnum");
+        assert!(refs.iter().any(|(m, f)| {
+            m.name() == ".protobuf_unittest.TestAllTypes" && f.name() == "repeated_foreign_enum"
+        }));
+        assert!(refs.iter().all(|(_, f)| match f.field_type(&d) {
+            Enum(e) => e.name() == ".protobuf_unittest.ForeignEnum",
+            _ => false,
+        }));
+
+        let refs = d.referencing_fields(".protobuf_unittest.TestAllTypes.NestedMessage");
+        assert!(refs.iter().any(|(m, f)| {
+            m.name() == ".protobuf_unittest.TestAllTypes" && f.name() == "optional_nested_message"
+        }));
+
+        assert!(d
+            .referencing_fields(".protobuf_unittest.DoesNotExist")
+            .is_empty());
+    }
+
+    #[test]
+    fn add_or_replace_message() {
+        let mut d = Descriptors::new();
+        let mut m = MessageDescriptor::new(".pkg.Message");
+        m.add_field(FieldDescriptor::builder("a").number(1).build());
+        assert!(d.add_or_replace_message(m).is_none());
+
+        let mut holder = MessageDescriptor::new(".pkg.Holder");
+        holder.add_field(
+            FieldDescriptor::builder("m")
+                .number(1)
+                .field_type(InternalFieldType::UnresolvedMessage(".pkg.Message".into()))
+                .build(),
+        );
+        d.add_message(holder);
+        d.resolve_refs();
+
+        let mut m = MessageDescriptor::new(".pkg.Message");
+        m.add_field(FieldDescriptor::builder("b").number(2).build());
+        let old = d.add_or_replace_message(m).unwrap();
+        assert!(old.field_by_name("a").is_some());
+
+        let holder = d.message_by_name(".pkg.Holder").unwrap();
+        match holder.field_by_name("m").unwrap().field_type(&d) {
+            Message(m) => {
+                assert!(m.field_by_name("a").is_none());
+                assert!(m.field_by_name("b").is_some());
+            }
+            t => panic!("Expected a message type, got {:?}", t),
+        }
+    }
+
+    #[test]
+    fn add_or_replace_message_updates_references() {
+        let mut d = Descriptors::new();
+        let mut a = MessageDescriptor::new(".t.A");
+        a.add_field(FieldDescriptor::builder("x").number(1).build());
+        a.add_field(
+            FieldDescriptor::builder("b")
+                .number(2)
+                .field_type(InternalFieldType::UnresolvedMessage(".t.B".into()))
+                .build(),
+        );
+        d.add_message(a);
+        d.add_message(MessageDescriptor::new(".t.B"));
+        d.resolve_refs();
+        assert_eq!(d.referencing_fields(".t.B").len(), 1);
+
+        d.add_or_replace_message(MessageDescriptor::new(".t.A"));
+        assert!(d.referencing_fields(".t.B").is_empty());
+    }
+
+    #[test]
+    fn transitive_deps() {
+        let mut d = load_descriptors();
+        let unresolved = d
+            .transitive_deps(".protobuf_unittest.TestAllTypes")
+            .unwrap();
+        d.resolve_refs();
+        let deps = d
+            .transitive_deps(".protobuf_unittest.TestAllTypes")
+            .unwrap();
+        assert_eq!(deps, unresolved);
 
-    check_field!(
-        optional_double_field,
-        ".protobuf_unittest.TestAllTypes",
-        "optional_double",
-        Double,
-        Optional,
-        12
-    );
+        for name in &[
+            ".protobuf_unittest.TestAllTypes.NestedMessage",
+            ".protobuf_unittest.TestAllTypes.NestedEnum",
+            ".protobuf_unittest.ForeignMessage",
+            ".protobuf_unittest.ForeignEnum",
+            ".protobuf_unittest_import.ImportMessage",
+            ".protobuf_unittest_import.PublicImportMessage",
+        ] {
+            assert!(deps.iter().any(|d| d == name), "missing {}", name);
+        }
+        assert!(!deps.iter().any(|d| d == ".protobuf_unittest.TestAllTypes"));
+    }
 
-    check_field!(
-        optional_bool_field,
-        ".protobuf_unittest.TestAllTypes",
-        "optional_bool",
-        Bool,
-        Optional,
-        13
-    );
+    #[test]
+    fn transitive_deps_recursive() {
+        let d = load_descriptors();
+        let deps = d
+            .transitive_deps(".protobuf_unittest.TestRecursiveMessage")
+            .unwrap();
+        assert!(deps.is_empty());
 
-    check_field!(
-        optional_string_field,
-        ".protobuf_unittest.TestAllTypes",
-        "optional_string",
-        String,
-        Optional,
-        14
-    );
+        let deps = d
+            .transitive_deps(".protobuf_unittest.TestMutualRecursionA")
+            .unwrap();
+        assert!(deps
+            .iter()
+            .any(|d| d == ".protobuf_unittest.TestMutualRecursionB"));
+    }
 
-    check_field!(
-        optional_bytes_field,
-        ".protobuf_unittest.TestAllTypes",
-        "optional_bytes",
-        Bytes,
-        Optional,
-        15
-    );
+    #[test]
+    fn transitive_deps_unknown() {
+        let mut d = Descriptors::new();
+        let mut m = MessageDescriptor::new(".pkg.Message");
+        m.add_field(
+            FieldDescriptor::builder("missing")
+                .number(1)
+                .field_type(InternalFieldType::UnresolvedEnum(".pkg.Missing".into()))
+                .build(),
+        );
+        d.add_message(m);
 
-    check_field!(
-        repeated_int32_field,
-        ".protobuf_unittest.TestAllTypes",
-        "repeated_int32",
-        Int32,
-        Repeated,
-        31
-    );
+        match d.transitive_deps(".pkg.Message") {
+            Err(error::Error::UnknownEnum { name }) => assert_eq!(name, ".pkg.Missing"),
+            r => panic!("Expected unknown enum error, got {:?}", r),
+        }
+        match d.transitive_deps(".pkg.Other") {
+            Err(error::Error::UnknownMessage { name }) => assert_eq!(name, ".pkg.Other"),
+            r => panic!("Expected unknown message error, got {:?}", r),
+        }
+    }
 
-    check_field!(
-        repeated_int64_field,
-        ".protobuf_unittest.TestAllTypes",
-        "repeated_int64",
-        Int64,
-        Repeated,
-        32
-    );
+    #[test]
+    fn recursive_messages() {
+        let message_field = |name: &str, number, label, type_name: &str| {
+            FieldDescriptor::builder(name)
+                .number(number)
+                .label(label)
+                .field_type(InternalFieldType::UnresolvedMessage(type_name.into()))
+                .build()
+        };
+        let mut d = Descriptors::new();
+        let mut node = MessageDescriptor::new(".pkg.Node");
+        node.add_field(message_field("child", 1, Optional, ".pkg.Node"));
+        node.add_field(message_field("children", 2, Repeated, ".pkg.Node"));
+        node.add_field(
+            FieldDescriptor::builder("value")
+                .number(3)
+                .default_value(value::Value::I32(7))
+                .build(),
+        );
+        d.add_message(node);
+        let mut a = MessageDescriptor::new(".pkg.A");
+        a.add_field(message_field("b", 1, Optional, ".pkg.B"));
+        d.add_message(a);
+        let mut b = MessageDescriptor::new(".pkg.B");
+        b.add_field(message_field("a", 1, Optional, ".pkg.A"));
+        d.add_message(b);
+        d.resolve_refs();
 
-    check_field!(
-        repeated_uint32_field,
-        ".protobuf_unittest.TestAllTypes",
-        "repeated_uint32",
-        UInt32,
-        Repeated,
-        33
-    );
+        let node = d.message_by_name(".pkg.Node").unwrap();
+        match node.field_by_name("child").unwrap().field_type(&d) {
+            Message(m) => assert_eq!(m.name(), ".pkg.Node"),
+            t => panic!("Expected a message type, got {:?}", t),
+        }
+        let b = d.message_by_name(".pkg.B").unwrap();
+        match b.field_by_name("a").unwrap().field_type(&d) {
+            Message(m) => match m.field_by_name("b").unwrap().field_type(&d) {
+                Message(m) => assert_eq!(m.name(), ".pkg.B"),
+                t => panic!("Expected a message type, got {:?}", t),
+            },
+            t => panic!("Expected a message type, got {:?}", t),
+        }
 
-    check_field!(
-        repeated_uint64_field,
-        ".protobuf_unittest.TestAllTypes",
-        "repeated_uint64",
-        UInt64,
-        Repeated,
-        34
-    );
+        // Every traversal of the type graph terminates
+        assert!(d.transitive_deps(".pkg.Node").unwrap().is_empty());
+        assert_eq!(d.transitive_deps(".pkg.A").unwrap(), vec![".pkg.B"]);
+        assert_eq!(d.transitive_deps(".pkg.B").unwrap(), vec![".pkg.A"]);
+        assert!(d.validate_schema().is_ok());
+        let subset = d.subset(&[".pkg.B"]).unwrap();
+        assert!(subset.contains_message(".pkg.A") && !subset.contains_message(".pkg.Node"));
+        let round_tripped = Descriptors::try_from_proto(&d.to_proto()).unwrap();
+        assert_eq!(round_tripped.schema_hash(), d.schema_hash());
+        assert!(d.diff(&round_tripped).is_empty());
+        assert!(d.to_proto_source().contains("Node child = 1;"));
 
-    check_field!(
-        repeated_sint32_field,
-        ".protobuf_unittest.TestAllTypes",
-        "repeated_sint32",
-        SInt32,
-        Repeated,
-        35
-    );
+        // Node { child { child { value: 1 } } }
+        let data = [10, 4, 10, 2, 24, 1];
+        let mut input = protobuf::CodedInputStream::from_bytes(&data);
+        let mut message = value::Message::new(node);
+        message.merge_from(&d, node, &mut input).unwrap();
+        message.fill_defaults(&d, node);
+        let mut value = value::Value::Message(message);
+        for expected in &[7, 7, 1] {
+            let view = value::MessageView::new(node, &value).unwrap();
+            match view.get("value") {
+                Some(&value::Value::I32(v)) => assert_eq!(v, *expected),
+                v => panic!("Expected an int32 value, got {:?}", v),
+            }
+            value = match view.get("child") {
+                Some(child) => child.clone(),
+                None => {
+                    assert_eq!(*expected, 1);
+                    break;
+                }
+            };
+        }
+    }
 
-    check_field!(
-        repeated_sint64_field,
-        ".protobuf_unittest.TestAllTypes",
-        "repeated_sint64",
-        SInt64,
-        Repeated,
-        36
-    );
+    #[test]
+    fn resolve_field_path() {
+        let d = load_descriptors();
+        let root = ".protobuf_unittest.TestAllTypes";
 
-    check_field!(
-        repeated_fixed32_field,
-        ".protobuf_unittest.TestAllTypes",
-        "repeated_fixed32",
-        Fixed32,
-        Repeated,
-        37
-    );
+        let fields = d
+            .resolve_field_path(root, "optional_nested_message.bb")
+            .unwrap();
+        let names = fields.iter().map(|f| f.name()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["optional_nested_message", "bb"]);
 
-    check_field!(
-        repeated_fixed64_field,
-        ".protobuf_unittest.TestAllTypes",
-        "repeated_fixed64",
-        Fixed64,
-        Repeated,
-        38
-    );
+        let fields = d.resolve_field_path(root, "optional_int32").unwrap();
+        assert_eq!(fields.len(), 1);
+        assert_eq!(fields[0].number(), 1);
 
-    check_field!(
-        repeated_sfixed32_field,
-        ".protobuf_unittest.TestAllTypes",
-        "repeated_sfixed32",
-        SFixed32,
-        Repeated,
-        39
-    );
+        for path in &[
+            "optional_int32.bb",
+            "optional_nested_message.missing",
+            "missing",
+            "",
+            "optional_nested_message.",
+        ] {
+            match d.resolve_field_path(root, path) {
+                Err(error::Error::BadFieldPath { path: p, .. }) => assert_eq!(&p, path),
+                r => panic!("Expected bad field path error for {:?}, got {:?}", path, r),
+            }
+        }
+        match d.resolve_field_path(".pkg.Missing", "a") {
+            Err(error::Error::UnknownMessage { name }) => assert_eq!(name, ".pkg.Missing"),
+            r => panic!("Expected unknown message error, got {:?}", r),
+        }
+    }
 
-    check_field!(
-        repeated_sfixed64_field,
-        ".protobuf_unittest.TestAllTypes",
-        "repeated_sfixed64",
-        SFixed64,
-        Repeated,
-        40
-    );
+    #[test]
+    fn from_proto_bytes() {
+        let bytes = fs::read("testdata/descriptors.pb").unwrap();
+        let d = Descriptors::from_proto_bytes(&bytes).unwrap();
+        assert!(d
+            .message_by_name(".protobuf_unittest.TestAllTypes")
+            .is_some());
 
-    check_field!(
-        repeated_float_field,
-        ".protobuf_unittest.TestAllTypes",
-        "repeated_float",
-        Float,
-        Repeated,
-        41
-    );
+        match Descriptors::from_proto_bytes(&bytes[..bytes.len() / 2]) {
+            Err(error::Error::Protobuf(_)) => (),
+            r => panic!("Expected protobuf error, got {:?}", r),
+        }
+    }
 
-    check_field!(
-        repeated_double_field,
-        ".protobuf_unittest.TestAllTypes",
-        "repeated_double",
-        Double,
-        Repeated,
-        42
-    );
+    #[cfg(feature = "std")]
+    #[test]
+    fn from_proto_reader() {
+        let file = fs::File::open("testdata/descriptors.pb").unwrap();
+        let d = Descriptors::from_proto_reader(file).unwrap();
+        assert!(d
+            .message_by_name(".protobuf_unittest.TestAllTypes")
+            .is_some());
+    }
 
-    check_field!(
-        repeated_bool_field,
-        ".protobuf_unittest.TestAllTypes",
-        "repeated_bool",
-        Bool,
-        Repeated,
-        43
-    );
+    #[test]
+    fn enum_values_in_declaration_order() {
+        let d = load_descriptors();
+        let enu = d
+            .enum_by_name(".protobuf_unittest.TestAllTypes.NestedEnum")
+            .unwrap();
+        let values = enu
+            .values()
+            .iter()
+            .map(|v| (v.name(), v.number()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            values,
+            vec![("FOO", 1), ("BAR", 2), ("BAZ", 3), ("NEG", -1)]
+        );
+        assert_eq!(enu.default_value().unwrap().name(), "FOO");
+        assert!(EnumDescriptor::new(".pkg.Empty").default_value().is_none());
+    }
 
-    check_field!(
-        repeated_string_field,
-        ".protobuf_unittest.TestAllTypes",
-        "repeated_string",
-        String,
-        Repeated,
-        44
-    );
+    #[test]
+    fn field_validate_default_value() {
+        let field = FieldDescriptor::builder("a")
+            .number(1)
+            .default_value(value::Value::I32(1))
+            .build();
+        assert!(field.validate().is_ok());
 
-    check_field!(
-        repeated_bytes_field,
-        ".protobuf_unittest.TestAllTypes",
-        "repeated_bytes",
-        Bytes,
-        Repeated,
-        45
-    );
+        let field = FieldDescriptor::builder("a")
+            .number(1)
+            .label(Repeated)
+            .default_value(value::Value::I32(1))
+            .build();
+        match field.validate() {
+            Err(error::Error::BadField { field, .. }) => assert_eq!(field, "a"),
+            r => panic!("Expected bad field error, got {:?}", r),
+        }
 
-    check_field!(
-        repppeated_message_field,
-        ".protobuf_unittest.TestAllTypes",
-        "repeated_foreign_message",
-        Message(..),
-        Repeated,
-        49
-    );
+        let field = FieldDescriptor::builder("m")
+            .number(1)
+            .field_type(InternalFieldType::UnresolvedMessage(".pkg.M".into()))
+            .default_value(value::Value::I32(1))
+            .build();
+        assert!(field.validate().is_err());
+    }
 
-    check_field!(
-        repeated_enum_field,
-        ".protobuf_unittest.TestAllTypes",
-        "repeated_foreign_enum",
-        Enum(..),
-        Repeated,
-        52
-    );
+    #[test]
+    fn group_fields_resolve() {
+        let mut field_proto = int32_field_proto("optionalgroup", 1);
+        field_proto.set_type(descriptor::field_descriptor_proto::Type::TYPE_GROUP);
+        field_proto.set_type_name(".pkg.Message.OptionalGroup".to_owned());
+        let mut file_set_proto = field_set_proto(field_proto);
+        let mut group_proto = descriptor::DescriptorProto::new();
+        group_proto.set_name("OptionalGroup".to_owned());
+        group_proto.field.push(int32_field_proto("a", 17));
+        file_set_proto.file[0].message_type[0]
+            .nested_type
+            .push(group_proto);
 
-    check_field!(
-        required_field_a,
-        ".protobuf_unittest.TestRequired",
-        "a",
-        Int32,
-        Required,
-        1
-    );
+        let mut d = Descriptors::from_proto(&file_set_proto);
+        let check = |d: &Descriptors| {
+            let m = d.message_by_name(".pkg.Message").unwrap();
+            match m.field_by_name("optionalgroup").unwrap().field_type(d) {
+                Group(g) => assert_eq!(g.field_by_name("a").unwrap().number(), 17),
+                t => panic!("Expected a group type, got {:?}", t),
+            }
+        };
+        check(&d);
+        d.resolve_refs();
+        check(&d);
+        assert_eq!(d.referencing_fields(".pkg.Message.OptionalGroup").len(), 1);
+        assert_eq!(
+            d.resolve_field_path(".pkg.Message", "optionalgroup.a")
+                .unwrap()
+                .len(),
+            2
+        );
+    }
 
-    check_field!(
-        required_field_b,
-        ".protobuf_unittest.TestRequired",
-        "b",
-        Int32,
-        Required,
-        3
-    );
+    #[test]
+    fn group_default_value() {
+        let mut field_proto = int32_field_proto("group", 1);
+        field_proto.set_type(descriptor::field_descriptor_proto::Type::TYPE_GROUP);
+        field_proto.set_default_value("1".to_owned());
+
+        let group_type = InternalFieldType::UnresolvedGroup(".pkg.Group".into());
+        match parse_default_value("1", &group_type) {
+            Err(error::Error::BadDefaultValue { default_value }) => assert_eq!(default_value, "1"),
+            r => panic!("Expected bad default value error, got {:?}", r),
+        }
+        assert!(FieldDescriptor::from_proto(&field_proto)
+            .default_value()
+            .is_none());
+        assert!(Descriptors::try_from_proto(&field_set_proto(field_proto)).is_err());
+    }
+
+    #[test]
+    fn field_from_proto_ignores_repeated_default() {
+        let mut proto = int32_field_proto("a", 1);
+        proto.set_label(descriptor::field_descriptor_proto::Label::LABEL_REPEATED);
+        proto.set_default_value("1".to_owned());
+        let field = FieldDescriptor::from_proto(&proto);
+        assert!(field.default_value().is_none());
+        assert!(field.validate().is_ok());
+    }
+
+    #[test]
+    fn try_add_field_rejects_duplicates() {
+        let mut m = MessageDescriptor::new(".pkg.Message");
+        m.try_add_field(FieldDescriptor::builder("a").number(5).build())
+            .unwrap();
+
+        match m.try_add_field(FieldDescriptor::builder("b").number(5).build()) {
+            Err(error::Error::DuplicateField { name, number }) => {
+                assert_eq!(name, "b");
+                assert_eq!(number, 5);
+            }
+            r => panic!("Expected duplicate field error, got {:?}", r),
+        }
+        assert!(m
+            .try_add_field(FieldDescriptor::builder("a").number(6).build())
+            .is_err());
+
+        m.add_field(FieldDescriptor::builder("c").number(5).build());
+        assert_eq!(m.fields().len(), 1);
+        assert_eq!(m.field_by_number(5).unwrap().name(), "a");
+        assert!(m.field_by_name("c").is_none());
+    }
+
+    #[test]
+    fn field_representation_hints() {
+        let d = load_descriptors();
+        let msg = d
+            .message_by_name(".protobuf_unittest.TestAllTypes")
+            .unwrap();
+        let field = msg.field_by_name("optional_cord").unwrap();
+        assert_eq!(field.ctype(), CType::Cord);
+        assert_eq!(field.jstype(), JsType::Normal);
+        let field = msg.field_by_name("optional_string_piece").unwrap();
+        assert_eq!(field.ctype(), CType::StringPiece);
+        let field = msg.field_by_name("optional_int64").unwrap();
+        assert_eq!(field.ctype(), CType::String);
+
+        let field = FieldDescriptor::builder("id")
+            .field_type(InternalFieldType::Int64)
+            .jstype(JsType::String)
+            .build();
+        assert_eq!(field.jstype(), JsType::String);
+    }
+
+    #[test]
+    fn message_by_name_without_leading_dot() {
+        let d = load_descriptors();
+        let msg = d.message_by_name("protobuf_unittest.TestAllTypes").unwrap();
+        assert_eq!(msg.name(), ".protobuf_unittest.TestAllTypes");
+        assert!(d.message_by_name("TestAllTypes").is_none());
+    }
+
+    #[test]
+    fn enum_by_name_without_leading_dot() {
+        let d = load_descriptors();
+        let e = d.enum_by_name("protobuf_unittest.ForeignEnum").unwrap();
+        assert_eq!(e.name(), ".protobuf_unittest.ForeignEnum");
+        assert!(d.contains_enum("protobuf_unittest.ForeignEnum"));
+        assert!(d.enum_by_name("ForeignEnum").is_none());
+    }
+
+    #[test]
+    fn message_by_short_name_unique() {
+        let d = load_descriptors();
+        let msg = d.message_by_short_name("TestRequired").unwrap();
+        assert_eq!(msg.name(), ".protobuf_unittest.TestRequired");
+        assert!(d.message_by_short_name("DoesNotExist").is_none());
+    }
+
+    #[test]
+    fn message_by_short_name_ambiguous() {
+        let d = load_descriptors();
+        assert!(d.message_by_short_name("NestedMessage").is_none());
+        assert!(d
+            .message_by_name(".protobuf_unittest.TestAllTypes.NestedMessage")
+            .is_some());
+    }
+
+    #[test]
+    fn enum_by_short_name() {
+        let d = load_descriptors();
+        let e = d.enum_by_short_name("ForeignEnum").unwrap();
+        assert_eq!(e.name(), ".protobuf_unittest.ForeignEnum");
+        assert!(d.enum_by_short_name("DoesNotExist").is_none());
+        // Both TestAllTypes and another message define a NestedEnum
+        assert!(d.enum_by_short_name("NestedEnum").is_none());
+    }
+
+    #[test]
+    fn enum_value_by_name() {
+        let d = load_descriptors();
+        let names = |name: &str| {
+            d.enum_value_by_name(name)
+                .map(|(e, v)| (e.name().to_owned(), v.name().to_owned(), v.number()))
+        };
+
+        assert_eq!(
+            names("FOREIGN_BAR"),
+            Some((
+                ".protobuf_unittest.ForeignEnum".to_owned(),
+                "FOREIGN_BAR".to_owned(),
+                5
+            ))
+        );
+        assert_eq!(names("DOES_NOT_EXIST"), None);
+        // BAZ is defined by a top-level enum and by several nested enums
+        assert_eq!(names("BAZ"), None);
+
+        // Values are scoped like their enums, not within them
+        assert_eq!(
+            names(".protobuf_unittest.BAZ"),
+            Some((
+                ".protobuf_unittest.TestEnumWithDupValue".to_owned(),
+                "BAZ".to_owned(),
+                3
+            ))
+        );
+        assert_eq!(
+            names("protobuf_unittest.TestAllTypes.BAZ"),
+            Some((
+                ".protobuf_unittest.TestAllTypes.NestedEnum".to_owned(),
+                "BAZ".to_owned(),
+                3
+            ))
+        );
+        assert_eq!(
+            names(".protobuf_unittest.TestAllTypes.NestedEnum.BAZ"),
+            None
+        );
+        assert_eq!(names(".protobuf_unittest.FOREIGN_BAR").unwrap().2, 5);
+        assert_eq!(names(".other_package.FOREIGN_BAR"), None);
+    }
+
+    #[test]
+    fn message_by_name_suggest() {
+        let d = load_descriptors();
+        let name = ".protobuf_unittest.TestAllTypes";
+        assert_eq!(d.message_by_name_suggest(name).unwrap().name(), name);
+
+        for near_miss in &[
+            "TestAllTypes",
+            "protobuf_unittest.testalltypes",
+            ".protobuf_unittest.TestAllTypse",
+            ".protobuf_unitest.TestAllTypes",
+        ] {
+            let suggestions = d.message_by_name_suggest(near_miss).unwrap_err();
+            assert_eq!(
+                suggestions.first().map(|s| s.as_str()),
+                Some(name),
+                "{}",
+                near_miss
+            );
+            assert!(suggestions.len() <= MAX_SUGGESTIONS);
+        }
+        // Suffix matches are suggested before messages that only have the same short name
+        let suggestions = d
+            .message_by_name_suggest("TestAllTypes.NestedMessage")
+            .unwrap_err();
+        assert_eq!(
+            suggestions[0],
+            ".protobuf_unittest.TestAllTypes.NestedMessage"
+        );
+        assert!(suggestions[1..]
+            .iter()
+            .all(|s| s.ends_with(".NestedMessage")));
+        assert!(d
+            .message_by_name_suggest(".pkg.SomethingElseEntirely")
+            .unwrap_err()
+            .is_empty());
+    }
+
+    #[test]
+    fn message_by_name_ignore_case() {
+        let d = load_descriptors();
+        let msg = d
+            .message_by_name_ignore_case("PROTOBUF_UNITTEST.testalltypes")
+            .unwrap();
+        assert_eq!(msg.name(), ".protobuf_unittest.TestAllTypes");
+        let msg = d
+            .message_by_name_ignore_case(".protobuf_unittest.testrequired")
+            .unwrap();
+        assert_eq!(msg.name(), ".protobuf_unittest.TestRequired");
+    }
+
+    #[test]
+    fn map_entry_fields() {
+        let mut d = Descriptors::new();
+        d.add_message(MessageDescriptor::new(".pkg.Value"));
+        let mut entry = MessageDescriptor::new(".pkg.Message.ValuesEntry");
+        entry.set_map_entry(true);
+        entry.add_field(
+            FieldDescriptor::builder("key")
+                .number(1)
+                .field_type(InternalFieldType::Int32)
+                .build(),
+        );
+        entry.add_field(
+            FieldDescriptor::builder("value")
+                .number(2)
+                .field_type(InternalFieldType::UnresolvedMessage(".pkg.Value".into()))
+                .build(),
+        );
+        d.add_message(entry);
+        let mut message = MessageDescriptor::new(".pkg.Message");
+        message.add_field(
+            FieldDescriptor::builder("values")
+                .number(1)
+                .label(Repeated)
+                .field_type(InternalFieldType::UnresolvedMessage(
+                    ".pkg.Message.ValuesEntry".into(),
+                ))
+                .build(),
+        );
+        message.add_field(
+            FieldDescriptor::builder("value")
+                .number(2)
+                .field_type(InternalFieldType::UnresolvedMessage(".pkg.Value".into()))
+                .build(),
+        );
+        d.add_message(message);
+        d.resolve_refs();
+
+        let message = d.message_by_name(".pkg.Message").unwrap();
+        let (key, value) = message
+            .field_by_name("values")
+            .unwrap()
+            .map_entry_fields(&d)
+            .unwrap();
+        assert_eq!(key.name(), "key");
+        assert!(matches!(key.field_type(&d), Int32));
+        match value.field_type(&d) {
+            Message(m) => assert_eq!(m.name(), ".pkg.Value"),
+            t => panic!("Expected a message type, got {:?}", t),
+        }
+        assert!(message
+            .field_by_name("value")
+            .unwrap()
+            .map_entry_fields(&d)
+            .is_none());
+    }
+
+    #[test]
+    fn field_type_to_internal() {
+        let mut d = Descriptors::new();
+        for internal in &[
+            InternalFieldType::Double,
+            InternalFieldType::Float,
+            InternalFieldType::Int64,
+            InternalFieldType::UInt64,
+            InternalFieldType::Int32,
+            InternalFieldType::Fixed64,
+            InternalFieldType::Fixed32,
+            InternalFieldType::Bool,
+            InternalFieldType::String,
+            InternalFieldType::Bytes,
+            InternalFieldType::UInt32,
+            InternalFieldType::SFixed32,
+            InternalFieldType::SFixed64,
+            InternalFieldType::SInt32,
+            InternalFieldType::SInt64,
+            InternalFieldType::UnresolvedMessage(".pkg.Missing".into()),
+        ] {
+            assert_eq!(&internal.resolve(&d).to_internal(), internal);
+        }
+
+        d.add_message(MessageDescriptor::new(".pkg.Message"));
+        d.add_enum(EnumDescriptor::new(".pkg.Enum"));
+        let message = d.message_by_name(".pkg.Message").unwrap();
+        let internal = Message(message).to_internal();
+        assert_eq!(
+            internal,
+            InternalFieldType::UnresolvedMessage(".pkg.Message".into())
+        );
+        assert_eq!(
+            Group(message).to_internal(),
+            InternalFieldType::UnresolvedGroup(".pkg.Message".into())
+        );
+        assert_eq!(
+            Enum(d.enum_by_name(".pkg.Enum").unwrap()).to_internal(),
+            InternalFieldType::UnresolvedEnum(".pkg.Enum".into())
+        );
+
+        // The converted type resolves to the same type again
+        let mut other = MessageDescriptor::new(".pkg.Other");
+        other.add_field(
+            FieldDescriptor::builder("message")
+                .number(1)
+                .field_type(internal)
+                .build(),
+        );
+        d.add_message(other);
+        d.resolve_refs();
+        let field = d
+            .message_by_name(".pkg.Other")
+            .unwrap()
+            .field_by_number(1)
+            .unwrap();
+        match field.field_type(&d) {
+            Message(m) => assert_eq!(m.name(), ".pkg.Message"),
+            t => panic!("Expected a message type, got {:?}", t),
+        }
+    }
 
     check_enum_value!(
         enum_value_foo,
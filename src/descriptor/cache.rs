@@ -0,0 +1,680 @@
+//! A compact binary cache format for descriptor registries.
+//!
+//! The format stores all descriptors in storage order, so that resolved type references can be
+//! stored as raw IDs and don't need to be resolved again when the cache is loaded.
+use std::str;
+
+use protobuf::Message as _;
+
+use super::*;
+
+const MAGIC: &[u8; 4] = b"SPDC";
+const VERSION: u32 = 10;
+
+impl Descriptors {
+    /// Serializes this registry into a compact binary cache blob that can be loaded using
+    /// `from_cache_bytes`.
+    ///
+    /// Resolved type references are stored as-is, so a registry that has been resolved using
+    /// `resolve_refs` doesn't need to be resolved again after loading.  Message-valued default
+    /// values, which can't be expressed in a protocol buffer schema, are not preserved.
+    pub fn to_cache_bytes(&self) -> Vec<u8> {
+        let mut w = Writer { buf: Vec::new() };
+        w.buf.extend_from_slice(MAGIC);
+        w.u32(VERSION);
+
+        w.len(self.files.len());
+        for f in &self.files {
+            w.str(&f.name);
+            w.str(&f.package);
+            w.syntax(f.syntax);
+            w.bytes(&f.options.write_to_bytes().unwrap_or_default());
+        }
+
+        w.len(self.messages.len());
+        for m in &self.messages {
+            w.str(&m.name);
+            w.file(m.file);
+            w.syntax(m.syntax);
+            w.u8(m.map_entry as u8);
+            w.u8(m.message_set as u8);
+            w.bytes(&m.custom_options.write_to_bytes());
+            w.opt_str(&m.leading_comments);
+            w.opt_str(&m.trailing_comments);
+            w.ranges(&m.reserved_ranges);
+            w.ranges(&m.extension_ranges);
+            w.len(m.fields.len());
+            for f in &m.fields {
+                w.field(f);
+            }
+        }
+
+        w.len(self.enums.len());
+        for e in &self.enums {
+            w.str(&e.name);
+            w.file(e.file);
+            w.opt_str(&e.leading_comments);
+            w.opt_str(&e.trailing_comments);
+            w.len(e.values.len());
+            for v in &e.values {
+                w.str(&v.name);
+                w.i32(v.number);
+                w.bytes(&v.options.write_to_bytes().unwrap_or_default());
+                w.opt_str(&v.leading_comments);
+                w.opt_str(&v.trailing_comments);
+            }
+        }
+
+        w.len(self.extensions.len());
+        for (key, f) in &self.extensions {
+            w.str(&key.0);
+            w.opt_str(&self.extension_names.get(key).cloned());
+            w.field(f);
+        }
+
+        w.buf
+    }
+
+    /// Loads a registry from a binary cache blob created by `to_cache_bytes`.
+    ///
+    /// Returns an error if the blob was created by an incompatible version of this library, or if
+    /// it is malformed.
+    pub fn from_cache_bytes(bytes: &[u8]) -> error::Result<Descriptors> {
+        let mut r = Reader { bytes, pos: 0 };
+        if r.take(MAGIC.len())? != MAGIC {
+            return Err(r.invalid("bad magic number"));
+        }
+        let version = r.u32()?;
+        if version != VERSION {
+            return Err(error::Error::UnsupportedCacheVersion { version });
+        }
+
+        let mut descriptors = Descriptors::new();
+
+        for _ in 0..r.len()? {
+            let name = r.string()?;
+            let package = r.string()?;
+            let syntax = r.syntax()?;
+            let options = r.byte_vec()?;
+            let options = descriptor::FileOptions::parse_from_bytes(&options)
+                .map_err(|_| r.invalid("bad file options"))?;
+            descriptors.add_file_info(FileInfo {
+                name,
+                package,
+                syntax,
+                options,
+            });
+        }
+        let file_count = descriptors.files.len();
+
+        for _ in 0..r.len()? {
+            let mut m = MessageDescriptor::new(r.string()?);
+            m.file = r.file(file_count)?;
+            m.syntax = r.syntax()?;
+            m.map_entry = r.bool()?;
+            m.message_set = r.bool()?;
+            m.custom_options = r.custom_options()?;
+            m.leading_comments = r.opt_string()?;
+            m.trailing_comments = r.opt_string()?;
+            m.reserved_ranges = r.ranges()?;
+            m.extension_ranges = r.ranges()?;
+            for _ in 0..r.len()? {
+                m.try_add_field(r.field()?)?;
+            }
+            descriptors.add_message(m);
+        }
+
+        for _ in 0..r.len()? {
+            let mut e = EnumDescriptor::new(r.string()?);
+            e.file = r.file(file_count)?;
+            e.leading_comments = r.opt_string()?;
+            e.trailing_comments = r.opt_string()?;
+            for _ in 0..r.len()? {
+                let mut v = EnumValueDescriptor::new(r.string()?, r.i32()?);
+                let options = r.byte_vec()?;
+                v.options = descriptor::EnumValueOptions::parse_from_bytes(&options)
+                    .map_err(|_| r.invalid("bad enum value options"))?;
+                v.leading_comments = r.opt_string()?;
+                v.trailing_comments = r.opt_string()?;
+                e.add_value(v);
+            }
+            descriptors.add_enum(e);
+        }
+
+        for _ in 0..r.len()? {
+            let extendee = r.string()?;
+            let name = r.opt_string()?;
+            let field = r.field()?;
+            let key = (extendee.clone(), field.number);
+            descriptors.add_extension(extendee, field);
+            if let Some(name) = name {
+                descriptors.extension_names.insert(key, name);
+            }
+        }
+
+        if r.pos != bytes.len() {
+            return Err(r.invalid("trailing data"));
+        }
+
+        let message_count = descriptors.messages.len();
+        let enum_count = descriptors.enums.len();
+        let fields = descriptors.messages.iter().flat_map(|m| &m.fields);
+        for f in fields.chain(descriptors.extensions.values()) {
+            match f.field_type {
+                InternalFieldType::Message(id) | InternalFieldType::Group(id)
+                    if id.0 >= message_count =>
+                {
+                    return Err(r.invalid("dangling message reference"));
+                }
+                InternalFieldType::Enum(id) if id.0 >= enum_count => {
+                    return Err(r.invalid("dangling enum reference"));
+                }
+                _ => (),
+            }
+        }
+
+        descriptors.index_references();
+        Ok(descriptors)
+    }
+}
+
+struct Writer {
+    buf: Vec<u8>,
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn i32(&mut self, v: i32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn len(&mut self, v: usize) {
+        self.u32(v as u32);
+    }
+
+    fn ranges(&mut self, v: &[ops::Range<i32>]) {
+        self.len(v.len());
+        for range in v {
+            self.i32(range.start);
+            self.i32(range.end);
+        }
+    }
+
+    fn bytes(&mut self, v: &[u8]) {
+        self.len(v.len());
+        self.buf.extend_from_slice(v);
+    }
+
+    fn str(&mut self, v: &str) {
+        self.bytes(v.as_bytes());
+    }
+
+    fn opt_str(&mut self, v: &Option<String>) {
+        match *v {
+            Some(ref v) => {
+                self.u8(1);
+                self.str(v);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn opt_i32(&mut self, v: Option<i32>) {
+        match v {
+            Some(v) => {
+                self.u8(1);
+                self.i32(v);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn syntax(&mut self, v: Syntax) {
+        self.u8(match v {
+            Syntax::Proto2 => 0,
+            Syntax::Proto3 => 1,
+        });
+    }
+
+    /// Writes an optional file ID, offset by one so that zero means no file.
+    fn file(&mut self, v: Option<FileId>) {
+        self.len(v.map_or(0, |id| id.0 + 1));
+    }
+
+    fn field(&mut self, f: &FieldDescriptor) {
+        self.str(&f.name);
+        self.i32(f.number);
+        self.u8(match f.field_label {
+            FieldLabel::Optional => 0,
+            FieldLabel::Required => 1,
+            FieldLabel::Repeated => 2,
+        });
+        self.field_type(&f.field_type);
+        self.default_value(f.default_value.as_ref());
+        self.u8(f.optional as u8);
+        self.u8(f.proto3_optional as u8);
+        self.str(&f.json_name);
+        self.u8(match f.packed {
+            None => 0,
+            Some(false) => 1,
+            Some(true) => 2,
+        });
+        self.u8(match f.ctype {
+            CType::String => 0,
+            CType::Cord => 1,
+            CType::StringPiece => 2,
+        });
+        self.u8(match f.jstype {
+            JsType::Normal => 0,
+            JsType::String => 1,
+            JsType::Number => 2,
+        });
+        self.opt_str(&f.oneof);
+        self.opt_i32(f.oneof_index);
+        self.opt_str(&f.default_value_raw);
+        self.bytes(&f.custom_options.write_to_bytes());
+        self.opt_str(&f.leading_comments);
+        self.opt_str(&f.trailing_comments);
+    }
+
+    fn field_type(&mut self, t: &InternalFieldType) {
+        match *t {
+            InternalFieldType::UnresolvedMessage(ref name) => {
+                self.u8(0);
+                self.str(name);
+            }
+            InternalFieldType::UnresolvedEnum(ref name) => {
+                self.u8(1);
+                self.str(name);
+            }
+            InternalFieldType::Message(id) => {
+                self.u8(2);
+                self.len(id.0);
+            }
+            InternalFieldType::Enum(id) => {
+                self.u8(3);
+                self.len(id.0);
+            }
+            InternalFieldType::Double => self.u8(4),
+            InternalFieldType::Float => self.u8(5),
+            InternalFieldType::Int64 => self.u8(6),
+            InternalFieldType::UInt64 => self.u8(7),
+            InternalFieldType::Int32 => self.u8(8),
+            InternalFieldType::Fixed64 => self.u8(9),
+            InternalFieldType::Fixed32 => self.u8(10),
+            InternalFieldType::Bool => self.u8(11),
+            InternalFieldType::String => self.u8(12),
+            InternalFieldType::UnresolvedGroup(ref name) => {
+                self.u8(13);
+                self.str(name);
+            }
+            InternalFieldType::Group(id) => {
+                self.u8(20);
+                self.len(id.0);
+            }
+            InternalFieldType::Bytes => self.u8(14),
+            InternalFieldType::UInt32 => self.u8(15),
+            InternalFieldType::SFixed32 => self.u8(16),
+            InternalFieldType::SFixed64 => self.u8(17),
+            InternalFieldType::SInt32 => self.u8(18),
+            InternalFieldType::SInt64 => self.u8(19),
+        }
+    }
+
+    fn default_value(&mut self, v: Option<&value::Value>) {
+        match v {
+            Some(value::Value::Bool(v)) => {
+                self.u8(1);
+                self.u8(*v as u8);
+            }
+            Some(value::Value::I32(v)) => {
+                self.u8(2);
+                self.i32(*v);
+            }
+            Some(value::Value::I64(v)) => {
+                self.u8(3);
+                self.u64(*v as u64);
+            }
+            Some(value::Value::U32(v)) => {
+                self.u8(4);
+                self.u32(*v);
+            }
+            Some(value::Value::U64(v)) => {
+                self.u8(5);
+                self.u64(*v);
+            }
+            Some(value::Value::F32(v)) => {
+                self.u8(6);
+                self.u32(v.to_bits());
+            }
+            Some(value::Value::F64(v)) => {
+                self.u8(7);
+                self.u64(v.to_bits());
+            }
+            Some(value::Value::Bytes(v)) => {
+                self.u8(8);
+                self.bytes(v);
+            }
+            Some(value::Value::String(v)) => {
+                self.u8(9);
+                self.str(v);
+            }
+            Some(value::Value::Enum(v)) => {
+                self.u8(10);
+                self.i32(*v);
+            }
+            Some(value::Value::Message(_)) | None => self.u8(0),
+        }
+    }
+}
+
+impl<'a> Reader<'a> {
+    fn invalid(&self, reason: &str) -> error::Error {
+        error::Error::InvalidCache {
+            reason: reason.to_owned(),
+            offset: self.pos,
+        }
+    }
+
+    fn take(&mut self, n: usize) -> error::Result<&'a [u8]> {
+        if self.bytes.len() - self.pos < n {
+            return Err(self.invalid("unexpected end of data"));
+        }
+        let result = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(result)
+    }
+
+    fn array<const N: usize>(&mut self) -> error::Result<[u8; N]> {
+        let mut result = [0; N];
+        result.copy_from_slice(self.take(N)?);
+        Ok(result)
+    }
+
+    fn u8(&mut self) -> error::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> error::Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(self.invalid("bad boolean")),
+        }
+    }
+
+    fn u32(&mut self) -> error::Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn i32(&mut self) -> error::Result<i32> {
+        Ok(i32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> error::Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn len(&mut self) -> error::Result<usize> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn ranges(&mut self) -> error::Result<Vec<ops::Range<i32>>> {
+        let mut ranges = Vec::new();
+        for _ in 0..self.len()? {
+            ranges.push(self.i32()?..self.i32()?);
+        }
+        Ok(ranges)
+    }
+
+    fn byte_vec(&mut self) -> error::Result<Vec<u8>> {
+        let len = self.len()?;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn string(&mut self) -> error::Result<String> {
+        let len = self.len()?;
+        let bytes = self.take(len)?;
+        match str::from_utf8(bytes) {
+            Ok(s) => Ok(s.to_owned()),
+            Err(_) => Err(self.invalid("bad string")),
+        }
+    }
+
+    fn opt_string(&mut self) -> error::Result<Option<String>> {
+        if self.bool()? {
+            Ok(Some(self.string()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn opt_i32(&mut self) -> error::Result<Option<i32>> {
+        if self.bool()? {
+            Ok(Some(self.i32()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn syntax(&mut self) -> error::Result<Syntax> {
+        match self.u8()? {
+            0 => Ok(Syntax::Proto2),
+            1 => Ok(Syntax::Proto3),
+            _ => Err(self.invalid("bad syntax")),
+        }
+    }
+
+    fn file(&mut self, file_count: usize) -> error::Result<Option<FileId>> {
+        match self.len()? {
+            0 => Ok(None),
+            n if n <= file_count => Ok(Some(FileId(n - 1))),
+            _ => Err(self.invalid("dangling file reference")),
+        }
+    }
+
+    fn custom_options(&mut self) -> error::Result<protobuf::UnknownFields> {
+        let bytes = self.byte_vec()?;
+        // Custom options are stored as a message that consists of nothing but unknown fields
+        let options = protobuf::well_known_types::empty::Empty::parse_from_bytes(&bytes)
+            .map_err(|_| self.invalid("bad custom options"))?;
+        Ok(options.special_fields.unknown_fields().clone())
+    }
+
+    fn field(&mut self) -> error::Result<FieldDescriptor> {
+        let name = self.string()?;
+        let number = self.i32()?;
+        let field_label = match self.u8()? {
+            0 => FieldLabel::Optional,
+            1 => FieldLabel::Required,
+            2 => FieldLabel::Repeated,
+            _ => return Err(self.invalid("bad field label")),
+        };
+        let field_type = self.field_type()?;
+        let default_value = self.default_value()?;
+        let optional = self.bool()?;
+        let proto3_optional = self.bool()?;
+        let json_name = self.string()?;
+        let packed = match self.u8()? {
+            0 => None,
+            1 => Some(false),
+            2 => Some(true),
+            _ => return Err(self.invalid("bad packed option")),
+        };
+        let ctype = match self.u8()? {
+            0 => CType::String,
+            1 => CType::Cord,
+            2 => CType::StringPiece,
+            _ => return Err(self.invalid("bad C type")),
+        };
+        let jstype = match self.u8()? {
+            0 => JsType::Normal,
+            1 => JsType::String,
+            2 => JsType::Number,
+            _ => return Err(self.invalid("bad JS type")),
+        };
+
+        let mut builder = FieldDescriptor::builder(name)
+            .number(number)
+            .label(field_label)
+            .field_type(field_type)
+            .optional(optional)
+            .proto3_optional(proto3_optional)
+            .json_name(json_name)
+            .ctype(ctype)
+            .jstype(jstype);
+        if let Some(packed) = packed {
+            builder = builder.packed(packed);
+        }
+        if let Some(default_value) = default_value {
+            builder = builder.default_value(default_value);
+        }
+
+        let mut field = builder.build();
+        field.oneof = self.opt_string()?;
+        field.oneof_index = self.opt_i32()?;
+        field.default_value_raw = self.opt_string()?;
+        field.custom_options = self.custom_options()?;
+        field.leading_comments = self.opt_string()?;
+        field.trailing_comments = self.opt_string()?;
+        Ok(field)
+    }
+
+    fn field_type(&mut self) -> error::Result<InternalFieldType> {
+        Ok(match self.u8()? {
+            0 => InternalFieldType::UnresolvedMessage(self.string()?.into()),
+            1 => InternalFieldType::UnresolvedEnum(self.string()?.into()),
+            2 => InternalFieldType::Message(MessageId(self.len()?)),
+            3 => InternalFieldType::Enum(EnumId(self.len()?)),
+            4 => InternalFieldType::Double,
+            5 => InternalFieldType::Float,
+            6 => InternalFieldType::Int64,
+            7 => InternalFieldType::UInt64,
+            8 => InternalFieldType::Int32,
+            9 => InternalFieldType::Fixed64,
+            10 => InternalFieldType::Fixed32,
+            11 => InternalFieldType::Bool,
+            12 => InternalFieldType::String,
+            13 => InternalFieldType::UnresolvedGroup(self.string()?.into()),
+            14 => InternalFieldType::Bytes,
+            15 => InternalFieldType::UInt32,
+            16 => InternalFieldType::SFixed32,
+            17 => InternalFieldType::SFixed64,
+            18 => InternalFieldType::SInt32,
+            19 => InternalFieldType::SInt64,
+            20 => InternalFieldType::Group(MessageId(self.len()?)),
+            _ => return Err(self.invalid("bad field type")),
+        })
+    }
+
+    fn default_value(&mut self) -> error::Result<Option<value::Value>> {
+        Ok(Some(match self.u8()? {
+            0 => return Ok(None),
+            1 => value::Value::Bool(self.bool()?),
+            2 => value::Value::I32(self.i32()?),
+            3 => value::Value::I64(self.u64()? as i64),
+            4 => value::Value::U32(self.u32()?),
+            5 => value::Value::U64(self.u64()?),
+            6 => value::Value::F32(f32::from_bits(self.u32()?)),
+            7 => value::Value::F64(f64::from_bits(self.u64()?)),
+            8 => value::Value::Bytes(self.byte_vec()?),
+            9 => value::Value::String(self.string()?),
+            10 => value::Value::Enum(self.i32()?),
+            _ => return Err(self.invalid("bad default value")),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::descriptor::test::load_descriptors;
+
+    #[test]
+    fn roundtrip_resolved() {
+        let mut d = load_descriptors();
+        d.resolve_refs();
+        let bytes = d.to_cache_bytes();
+        let loaded = Descriptors::from_cache_bytes(&bytes).unwrap();
+        assert_eq!(loaded.to_cache_bytes(), bytes);
+
+        let msg = loaded
+            .message_by_name(".protobuf_unittest.TestAllTypes")
+            .unwrap();
+        let field = msg.field_by_name("optional_nested_message").unwrap();
+        assert!(matches!(field.field_type, InternalFieldType::Message(_)));
+        match field.field_type(&loaded) {
+            FieldType::Message(m) => assert_eq!(m.field_by_name("bb").unwrap().number(), 1),
+            t => panic!("Expected a message type, got {:?}", t),
+        }
+        match msg.field_by_name("default_string").unwrap().default_value() {
+            Some(value::Value::String(s)) => assert_eq!(s, "hello"),
+            v => panic!("Expected a string default value, got {:?}", v),
+        }
+        assert_eq!(
+            loaded
+                .message_by_name(".protobuf_unittest.TestReservedFields")
+                .unwrap()
+                .reserved_ranges(),
+            &[2..3, 15..16, 9..12]
+        );
+        assert!(!loaded
+            .referencing_fields(".protobuf_unittest.ForeignEnum")
+            .is_empty());
+        assert_eq!(loaded.files().len(), d.files().len());
+        assert_eq!(
+            loaded
+                .file_of_enum(".protobuf_unittest.ForeignEnum")
+                .map(FileInfo::name),
+            d.file_of_enum(".protobuf_unittest.ForeignEnum")
+                .map(FileInfo::name)
+        );
+    }
+
+    #[test]
+    fn roundtrip_unresolved() {
+        let d = load_descriptors();
+        let bytes = d.to_cache_bytes();
+        let loaded = Descriptors::from_cache_bytes(&bytes).unwrap();
+        assert_eq!(loaded.to_cache_bytes(), bytes);
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut bytes = Descriptors::new().to_cache_bytes();
+        bytes[4] = 99;
+        match Descriptors::from_cache_bytes(&bytes) {
+            Err(error::Error::UnsupportedCacheVersion { version }) => assert_eq!(version, 99),
+            r => panic!("Expected unsupported version error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        let mut d = load_descriptors();
+        d.resolve_refs();
+        let bytes = d.to_cache_bytes();
+        for &len in &[0, 3, 8, bytes.len() / 2, bytes.len() - 1] {
+            match Descriptors::from_cache_bytes(&bytes[..len]) {
+                Err(error::Error::InvalidCache { .. }) => (),
+                r => panic!("Expected invalid cache error, got {:?}", r.map(|_| ())),
+            }
+        }
+    }
+}
@@ -0,0 +1,532 @@
+//! Structured comparison of two descriptor registries.
+use super::*;
+
+/// The differences between an old and a new schema, as computed by `Descriptors::diff`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SchemaDiff {
+    /// All changes, grouped by message in the order of the old schema, followed by the added
+    /// messages.
+    pub changes: Vec<SchemaChange>,
+}
+
+/// A single change between an old and a new schema.
+///
+/// Messages are identified by their fully qualified names.  Fields are matched by number first,
+/// and then by name, so a field that keeps its number but changes its name is reported as
+/// renamed, and a field that keeps its name but changes its number is reported as renumbered.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SchemaChange {
+    /// A message was added.
+    MessageAdded {
+        /// The name of the message.
+        message: String,
+    },
+    /// A message was removed.
+    MessageRemoved {
+        /// The name of the message.
+        message: String,
+    },
+    /// A field was added to a message.
+    FieldAdded {
+        /// The name of the message.
+        message: String,
+        /// The name of the field.
+        field: String,
+        /// The number of the field.
+        number: i32,
+        /// The label of the field.
+        label: FieldLabel,
+    },
+    /// A field was removed from a message.
+    FieldRemoved {
+        /// The name of the message.
+        message: String,
+        /// The name of the field.
+        field: String,
+        /// The number of the field.
+        number: i32,
+    },
+    /// A field was given a different name but kept its number.
+    FieldRenamed {
+        /// The name of the message.
+        message: String,
+        /// The number of the field.
+        number: i32,
+        /// The old name of the field.
+        old_name: String,
+        /// The new name of the field.
+        new_name: String,
+    },
+    /// A field was given a different number but kept its name.
+    FieldNumberChanged {
+        /// The name of the message.
+        message: String,
+        /// The name of the field.
+        field: String,
+        /// The old number of the field.
+        old_number: i32,
+        /// The new number of the field.
+        new_number: i32,
+    },
+    /// The type of a field changed.
+    ///
+    /// Types are compared by name, so resolved and unresolved references to the same type are
+    /// considered equal.
+    FieldTypeChanged {
+        /// The name of the message.
+        message: String,
+        /// The new name of the field.
+        field: String,
+        /// The name of the old type of the field.
+        old_type: String,
+        /// The name of the new type of the field.
+        new_type: String,
+        /// Whether the old and the new type use compatible encodings; see
+        /// `FieldDescriptor::is_wire_compatible_with`.
+        wire_compatible: bool,
+    },
+    /// The label of a field changed.
+    FieldLabelChanged {
+        /// The name of the message.
+        message: String,
+        /// The new name of the field.
+        field: String,
+        /// The old label of the field.
+        old_label: FieldLabel,
+        /// The new label of the field.
+        new_label: FieldLabel,
+    },
+}
+
+impl Descriptors {
+    /// Compares this registry, as the old schema, with another registry, as the new schema.
+    ///
+    /// Only messages and their fields are compared; comments, options and enums are ignored.
+    pub fn diff(&self, other: &Descriptors) -> SchemaDiff {
+        let mut changes = Vec::new();
+
+        for (name, &id) in self.messages_by_name.iter() {
+            match other.messages_by_name.get(name) {
+                Some(&other_id) => diff_message(
+                    (self, &self.messages[id.0]),
+                    (other, &other.messages[other_id.0]),
+                    &mut changes,
+                ),
+                None => changes.push(SchemaChange::MessageRemoved {
+                    message: name.to_string(),
+                }),
+            }
+        }
+        for name in other.messages_by_name.keys() {
+            if !self.messages_by_name.contains_key(name) {
+                changes.push(SchemaChange::MessageAdded {
+                    message: name.to_string(),
+                });
+            }
+        }
+
+        SchemaDiff { changes }
+    }
+}
+
+impl SchemaDiff {
+    /// Whether the schemas are equivalent.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// The changes that break compatibility; see `SchemaChange::is_breaking`.
+    pub fn breaking_changes(&self) -> impl Iterator<Item = &SchemaChange> {
+        self.changes.iter().filter(|c| c.is_breaking())
+    }
+}
+
+impl SchemaChange {
+    /// Whether this change breaks compatibility of the binary wire format, so that data written
+    /// using one schema can't necessarily be read using the other.
+    ///
+    /// Removing messages or fields, changing field numbers or labels, changing field types to
+    /// types with incompatible encodings, and adding `required` fields are breaking.  Renaming a
+    /// field isn't, although it breaks the JSON mapping.
+    pub fn is_breaking(&self) -> bool {
+        match *self {
+            SchemaChange::MessageAdded { .. } | SchemaChange::FieldRenamed { .. } => false,
+            SchemaChange::FieldAdded { label, .. } => label == FieldLabel::Required,
+            SchemaChange::FieldTypeChanged {
+                wire_compatible, ..
+            } => !wire_compatible,
+            SchemaChange::MessageRemoved { .. }
+            | SchemaChange::FieldRemoved { .. }
+            | SchemaChange::FieldNumberChanged { .. }
+            | SchemaChange::FieldLabelChanged { .. } => true,
+        }
+    }
+}
+
+/// The groups of field types that share a wire encoding.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Encoding {
+    Varint,
+    Bool,
+    Enum,
+    ZigZag,
+    Fixed32,
+    Fixed64,
+    Float,
+    Double,
+    String,
+    Bytes,
+    Message,
+    Group,
+}
+
+impl Encoding {
+    fn of(field_type: &InternalFieldType) -> Encoding {
+        match *field_type {
+            InternalFieldType::Int32
+            | InternalFieldType::Int64
+            | InternalFieldType::UInt32
+            | InternalFieldType::UInt64 => Encoding::Varint,
+            InternalFieldType::Bool => Encoding::Bool,
+            InternalFieldType::Enum(_) | InternalFieldType::UnresolvedEnum(_) => Encoding::Enum,
+            InternalFieldType::SInt32 | InternalFieldType::SInt64 => Encoding::ZigZag,
+            InternalFieldType::Fixed32 | InternalFieldType::SFixed32 => Encoding::Fixed32,
+            InternalFieldType::Fixed64 | InternalFieldType::SFixed64 => Encoding::Fixed64,
+            InternalFieldType::Float => Encoding::Float,
+            InternalFieldType::Double => Encoding::Double,
+            InternalFieldType::String => Encoding::String,
+            InternalFieldType::Bytes => Encoding::Bytes,
+            InternalFieldType::Message(_) | InternalFieldType::UnresolvedMessage(_) => {
+                Encoding::Message
+            }
+            InternalFieldType::Group(_) | InternalFieldType::UnresolvedGroup(_) => Encoding::Group,
+        }
+    }
+
+    /// Whether values written using this encoding can be read using the other encoding.
+    fn is_compatible_with(self, other: Encoding) -> bool {
+        use self::Encoding::*;
+
+        matches!(
+            (self, other),
+            (Varint, Bool)
+                | (Bool, Varint)
+                | (Varint, Enum)
+                | (Enum, Varint)
+                | (String, Bytes)
+                | (Bytes, String)
+                | (Bytes, Message)
+                | (Message, Bytes)
+        ) || self == other
+    }
+}
+
+impl FieldDescriptor {
+    /// Whether this field and another field with the same number can read each other's values
+    /// from the wire, according to the compatibility rules of the protocol buffer language guide.
+    ///
+    /// `int32`, `uint32`, `int64`, `uint64` and `bool` are mutually compatible, and enums are
+    /// compatible with each other and with all of those types except `bool`.  `sint32` and
+    /// `sint64` are only compatible with each other, as are `fixed32` and `sfixed32`, and
+    /// `fixed64` and `sfixed64`.  `string` is compatible with `bytes`, and `bytes` with message
+    /// types, which are all compatible with each other; whether the values are actually valid
+    /// UTF-8 or encoded messages depends on the data.  Switching between a singular and a
+    /// repeated field is only compatible for strings, bytes, messages and groups, because repeated
+    /// scalars may use the packed encoding.  Values of integer types with different widths may be
+    /// truncated when they are read.
+    pub fn is_wire_compatible_with(&self, other: &FieldDescriptor) -> bool {
+        let encoding = Encoding::of(&self.field_type);
+        encoding.is_compatible_with(Encoding::of(&other.field_type))
+            && (self.is_repeated() == other.is_repeated()
+                || matches!(
+                    encoding,
+                    Encoding::String | Encoding::Bytes | Encoding::Message | Encoding::Group
+                ))
+    }
+}
+
+fn diff_message(
+    (old_descriptors, old): (&Descriptors, &MessageDescriptor),
+    (new_descriptors, new): (&Descriptors, &MessageDescriptor),
+    changes: &mut Vec<SchemaChange>,
+) {
+    let message = old.name();
+    let mut matched = collections::HashSet::new();
+
+    for old_field in old.fields() {
+        let new_field = if let Some(f) = new.field_by_number(old_field.number()) {
+            if f.name() != old_field.name() {
+                changes.push(SchemaChange::FieldRenamed {
+                    message: message.to_owned(),
+                    number: f.number(),
+                    old_name: old_field.name().to_owned(),
+                    new_name: f.name().to_owned(),
+                });
+            }
+            f
+        } else if let Some(f) = new
+            .field_by_name(old_field.name())
+            .filter(|f| old.field_by_number(f.number()).is_none())
+        {
+            changes.push(SchemaChange::FieldNumberChanged {
+                message: message.to_owned(),
+                field: f.name().to_owned(),
+                old_number: old_field.number(),
+                new_number: f.number(),
+            });
+            f
+        } else {
+            changes.push(SchemaChange::FieldRemoved {
+                message: message.to_owned(),
+                field: old_field.name().to_owned(),
+                number: old_field.number(),
+            });
+            continue;
+        };
+        matched.insert(new_field.number());
+
+        let old_type = source::type_name(&old_field.field_type(old_descriptors));
+        let new_type = source::type_name(&new_field.field_type(new_descriptors));
+        if old_type != new_type {
+            changes.push(SchemaChange::FieldTypeChanged {
+                message: message.to_owned(),
+                field: new_field.name().to_owned(),
+                old_type: old_type.to_owned(),
+                new_type: new_type.to_owned(),
+                wire_compatible: Encoding::of(&old_field.field_type)
+                    .is_compatible_with(Encoding::of(&new_field.field_type)),
+            });
+        }
+        if old_field.field_label() != new_field.field_label() {
+            changes.push(SchemaChange::FieldLabelChanged {
+                message: message.to_owned(),
+                field: new_field.name().to_owned(),
+                old_label: old_field.field_label(),
+                new_label: new_field.field_label(),
+            });
+        }
+    }
+
+    for new_field in new.fields() {
+        if !matched.contains(&new_field.number()) {
+            changes.push(SchemaChange::FieldAdded {
+                message: message.to_owned(),
+                field: new_field.name().to_owned(),
+                number: new_field.number(),
+                label: new_field.field_label(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::descriptor::test::load_descriptors;
+
+    fn field(name: &str, number: i32, field_type: InternalFieldType) -> FieldDescriptor {
+        FieldDescriptor::builder(name)
+            .number(number)
+            .field_type(field_type)
+            .build()
+    }
+
+    fn registry(messages: Vec<MessageDescriptor>) -> Descriptors {
+        let mut d = Descriptors::new();
+        for m in messages {
+            d.add_message(m);
+        }
+        d
+    }
+
+    #[test]
+    fn diff_identical() {
+        let old = load_descriptors();
+        let mut new = load_descriptors();
+        new.resolve_refs();
+        assert!(old.diff(&new).is_empty());
+    }
+
+    #[test]
+    fn diff_messages() {
+        let old = registry(vec![MessageDescriptor::new(".pkg.Old")]);
+        let new = registry(vec![MessageDescriptor::new(".pkg.New")]);
+        let diff = old.diff(&new);
+        assert_eq!(
+            diff.changes,
+            vec![
+                SchemaChange::MessageRemoved {
+                    message: ".pkg.Old".to_owned(),
+                },
+                SchemaChange::MessageAdded {
+                    message: ".pkg.New".to_owned(),
+                },
+            ]
+        );
+        assert_eq!(diff.breaking_changes().count(), 1);
+    }
+
+    #[test]
+    fn diff_fields() {
+        let mut old = MessageDescriptor::new(".pkg.Message");
+        old.add_field(field("kept", 1, InternalFieldType::Int32));
+        old.add_field(field("removed", 2, InternalFieldType::Int32));
+        old.add_field(field("old_name", 3, InternalFieldType::Int32));
+        old.add_field(field("renumbered", 4, InternalFieldType::Int32));
+        old.add_field(field("retyped", 5, InternalFieldType::Int32));
+        old.add_field(field("relabeled", 6, InternalFieldType::Int32));
+
+        let mut new = MessageDescriptor::new(".pkg.Message");
+        new.add_field(field("kept", 1, InternalFieldType::Int32));
+        new.add_field(field("new_name", 3, InternalFieldType::Int32));
+        new.add_field(field("renumbered", 14, InternalFieldType::Int32));
+        new.add_field(field(
+            "retyped",
+            5,
+            InternalFieldType::UnresolvedMessage(".pkg.Message".into()),
+        ));
+        new.add_field(
+            FieldDescriptor::builder("relabeled")
+                .number(6)
+                .label(FieldLabel::Repeated)
+                .build(),
+        );
+        new.add_field(field("added", 7, InternalFieldType::String));
+        new.add_field(
+            FieldDescriptor::builder("added_required")
+                .number(8)
+                .label(FieldLabel::Required)
+                .build(),
+        );
+
+        let diff = registry(vec![old]).diff(&registry(vec![new]));
+        let message = ".pkg.Message".to_owned();
+        assert_eq!(
+            diff.changes,
+            vec![
+                SchemaChange::FieldRemoved {
+                    message: message.clone(),
+                    field: "removed".to_owned(),
+                    number: 2,
+                },
+                SchemaChange::FieldRenamed {
+                    message: message.clone(),
+                    number: 3,
+                    old_name: "old_name".to_owned(),
+                    new_name: "new_name".to_owned(),
+                },
+                SchemaChange::FieldNumberChanged {
+                    message: message.clone(),
+                    field: "renumbered".to_owned(),
+                    old_number: 4,
+                    new_number: 14,
+                },
+                SchemaChange::FieldTypeChanged {
+                    message: message.clone(),
+                    field: "retyped".to_owned(),
+                    old_type: "int32".to_owned(),
+                    new_type: ".pkg.Message".to_owned(),
+                    wire_compatible: false,
+                },
+                SchemaChange::FieldLabelChanged {
+                    message: message.clone(),
+                    field: "relabeled".to_owned(),
+                    old_label: FieldLabel::Optional,
+                    new_label: FieldLabel::Repeated,
+                },
+                SchemaChange::FieldAdded {
+                    message: message.clone(),
+                    field: "added".to_owned(),
+                    number: 7,
+                    label: FieldLabel::Optional,
+                },
+                SchemaChange::FieldAdded {
+                    message,
+                    field: "added_required".to_owned(),
+                    number: 8,
+                    label: FieldLabel::Required,
+                },
+            ]
+        );
+        assert_eq!(
+            diff.changes
+                .iter()
+                .map(|c| c.is_breaking())
+                .collect::<Vec<_>>(),
+            vec![true, false, true, true, true, false, true]
+        );
+    }
+
+    #[test]
+    fn wire_compatibility() {
+        use super::InternalFieldType::*;
+
+        let message = || UnresolvedMessage(".pkg.Message".into());
+        let compatible = vec![
+            (Int32, Int64),
+            (Int32, UInt32),
+            (UInt64, Bool),
+            (Int64, UnresolvedEnum(".pkg.Enum".into())),
+            (
+                UnresolvedEnum(".pkg.A".into()),
+                UnresolvedEnum(".pkg.B".into()),
+            ),
+            (SInt32, SInt64),
+            (Fixed32, SFixed32),
+            (Fixed64, SFixed64),
+            (String, Bytes),
+            (Bytes, message()),
+            (message(), UnresolvedMessage(".pkg.Other".into())),
+            (Float, Float),
+        ];
+        let incompatible = vec![
+            (Int32, SInt32),
+            (SInt64, UInt64),
+            (Bool, UnresolvedEnum(".pkg.Enum".into())),
+            (Fixed32, Fixed64),
+            (Fixed32, Float),
+            (Float, Double),
+            (Int32, Fixed32),
+            (String, message()),
+            (message(), UnresolvedGroup(".pkg.Message".into())),
+            (Int32, String),
+        ];
+        for (a, b) in &compatible {
+            let (a, b) = (field("a", 1, a.clone()), field("b", 1, b.clone()));
+            assert!(a.is_wire_compatible_with(&b), "{:?} {:?}", a, b);
+            assert!(b.is_wire_compatible_with(&a), "{:?} {:?}", b, a);
+        }
+        for (a, b) in &incompatible {
+            let (a, b) = (field("a", 1, a.clone()), field("b", 1, b.clone()));
+            assert!(!a.is_wire_compatible_with(&b), "{:?} {:?}", a, b);
+            assert!(!b.is_wire_compatible_with(&a), "{:?} {:?}", b, a);
+        }
+
+        // Only length-delimited values can switch between singular and repeated
+        let repeated = |field_type| {
+            FieldDescriptor::builder("r")
+                .number(1)
+                .label(FieldLabel::Repeated)
+                .field_type(field_type)
+                .build()
+        };
+        assert!(field("a", 1, String).is_wire_compatible_with(&repeated(Bytes)));
+        assert!(repeated(message()).is_wire_compatible_with(&field("a", 1, message())));
+        assert!(!field("a", 1, Int32).is_wire_compatible_with(&repeated(Int32)));
+
+        // Compatible type changes aren't breaking
+        let mut old = MessageDescriptor::new(".pkg.Message");
+        old.add_field(field("a", 1, Int32));
+        let mut new = MessageDescriptor::new(".pkg.Message");
+        new.add_field(field("a", 1, Int64));
+        let diff = registry(vec![old]).diff(&registry(vec![new]));
+        match diff.changes.as_slice() {
+            [change @ SchemaChange::FieldTypeChanged {
+                wire_compatible: true,
+                ..
+            }] => assert!(!change.is_breaking()),
+            c => panic!("Expected a compatible type change, got {:?}", c),
+        }
+    }
+}
@@ -0,0 +1,515 @@
+//! Conversion of descriptor registries back into protocol buffer file descriptor sets.
+use std::collections::BTreeSet;
+
+use super::*;
+
+type Children<'a> =
+    collections::HashMap<&'a str, (Vec<&'a MessageDescriptor>, Vec<&'a EnumDescriptor>)>;
+
+impl Descriptors {
+    /// Reconstructs a protocol buffer file descriptor set describing the types in this registry.
+    ///
+    /// This is the inverse of `from_proto`.  Types are grouped into one file per package and
+    /// syntax, named after the package (or `root.proto` for types without a package), and types
+    /// nested within messages are nested within those messages again.  Fields keep their labels,
+    /// types, numbers, default values, JSON names, oneofs and options, messages keep their
+    /// reserved and extension ranges, enums keep their values, and extensions are placed in a
+    /// file of the package of their extendee.  Each file depends on the files that define the
+    /// types it references.  Comments are not included.
+    ///
+    /// Default values read by `from_proto` are written exactly as they were read (see
+    /// `FieldDescriptor::default_value_raw`), and other default values of `bytes` fields are C
+    /// escaped like `protoc` writes them.
+    pub fn to_proto(&self) -> descriptor::FileDescriptorSet {
+        let mut children = Children::new();
+        for &id in self.messages_by_name.values() {
+            let message = &self.messages[id.0];
+            children
+                .entry(source::parent_name(message.name()))
+                .or_default()
+                .0
+                .push(message);
+        }
+        for &id in self.enums_by_name.values() {
+            let enum_descriptor = &self.enums[id.0];
+            children
+                .entry(source::parent_name(enum_descriptor.name()))
+                .or_default()
+                .1
+                .push(enum_descriptor);
+        }
+
+        let mut exporter = Exporter {
+            descriptors: self,
+            children: &children,
+            files: Vec::new(),
+            file_of: collections::HashMap::new(),
+        };
+        for (package, messages, enums) in self.packages() {
+            for message in messages {
+                if source::parent_name(message.name()) == package {
+                    let file = exporter.file(package, message.syntax());
+                    let proto = exporter.message(file, message);
+                    exporter.files[file].2.message_type.push(proto);
+                }
+            }
+            for enum_descriptor in enums {
+                if source::parent_name(enum_descriptor.name()) == package {
+                    let file = exporter.package_file(package);
+                    exporter.file_of.insert(enum_descriptor.name(), file);
+                    let proto = enum_proto(enum_descriptor);
+                    exporter.files[file].2.enum_type.push(proto);
+                }
+            }
+        }
+        for ((extendee, _), field) in &self.extensions {
+            let file = exporter.package_file(self.package_name(extendee));
+            let mut proto = exporter.field(field);
+            proto.set_extendee(extendee.clone());
+            exporter.files[file].2.extension.push(proto);
+        }
+
+        let dependencies = exporter.dependencies();
+        let mut names = exporter
+            .files
+            .iter()
+            .map(|&(package, syntax, _)| {
+                let mut name = package.trim_start_matches('.').to_owned();
+                if name.is_empty() {
+                    name.push_str("root");
+                }
+                // Only disambiguate packages that have files of both syntaxes
+                let both = exporter
+                    .files
+                    .iter()
+                    .filter(|&&(p, _, _)| p == package)
+                    .count()
+                    > 1;
+                if both && syntax == Syntax::Proto3 {
+                    name.push_str("_proto3");
+                }
+                name + ".proto"
+            })
+            .collect::<Vec<_>>();
+
+        let mut file_set = descriptor::FileDescriptorSet::new();
+        for (i, (_, _, mut file)) in exporter.files.into_iter().enumerate() {
+            file.set_name(mem::take(&mut names[i]));
+            file_set.file.push(file);
+        }
+        for (i, dependencies) in dependencies.into_iter().enumerate() {
+            file_set.file[i].dependency = dependencies
+                .into_iter()
+                .map(|d| file_set.file[d].name().to_owned())
+                .collect();
+        }
+        file_set
+    }
+}
+
+struct Exporter<'a> {
+    descriptors: &'a Descriptors,
+    children: &'a Children<'a>,
+    // The package, syntax and contents of every file
+    files: Vec<(&'a str, Syntax, descriptor::FileDescriptorProto)>,
+    // The index of the file that defines each type
+    file_of: collections::HashMap<&'a str, usize>,
+}
+
+impl<'a> Exporter<'a> {
+    /// Finds or creates the file for the specified package and syntax.
+    fn file(&mut self, package: &'a str, syntax: Syntax) -> usize {
+        if let Some(i) = self
+            .files
+            .iter()
+            .position(|&(p, s, _)| p == package && s == syntax)
+        {
+            return i;
+        }
+
+        let mut file = descriptor::FileDescriptorProto::new();
+        if !package.is_empty() {
+            file.set_package(package.trim_start_matches('.').to_owned());
+        }
+        if syntax == Syntax::Proto3 {
+            file.set_syntax("proto3".to_owned());
+        }
+        self.files.push((package, syntax, file));
+        self.files.len() - 1
+    }
+
+    /// Finds any file for the specified package, preferring `proto2` files, or creates one.
+    fn package_file(&mut self, package: &'a str) -> usize {
+        let syntax = if self
+            .files
+            .iter()
+            .any(|&(p, s, _)| p == package && s == Syntax::Proto2)
+        {
+            Syntax::Proto2
+        } else {
+            self.files
+                .iter()
+                .find(|&&(p, _, _)| p == package)
+                .map_or(Syntax::Proto2, |&(_, s, _)| s)
+        };
+        self.file(package, syntax)
+    }
+
+    fn message(
+        &mut self,
+        file: usize,
+        message: &'a MessageDescriptor,
+    ) -> descriptor::DescriptorProto {
+        self.file_of.insert(message.name(), file);
+
+        let mut proto = descriptor::DescriptorProto::new();
+        proto.set_name(short_name(message.name()).to_owned());
+
+        let mut oneofs = Vec::new();
+        for field in message.fields() {
+            if let Some(oneof) = field.oneof() {
+                if !oneofs.contains(&oneof.to_owned()) {
+                    oneofs.push(oneof.to_owned());
+                }
+            }
+        }
+        for field in message.fields() {
+            let mut field_proto = self.field(field);
+            if let Some(oneof) = field.oneof() {
+                let index = oneofs.iter().position(|o| o == oneof).unwrap_or(0);
+                field_proto.set_oneof_index(index as i32);
+            } else if field.is_proto3_optional() {
+                // Synthetic oneofs of proto3 optional fields follow the real oneofs
+                oneofs.push(format!("_{}", field.name()));
+                field_proto.set_oneof_index(oneofs.len() as i32 - 1);
+            }
+            proto.field.push(field_proto);
+        }
+        for oneof in oneofs {
+            let mut oneof_proto = descriptor::OneofDescriptorProto::new();
+            oneof_proto.set_name(oneof);
+            proto.oneof_decl.push(oneof_proto);
+        }
+
+        if let Some((messages, enums)) = self.children.get(message.name()) {
+            for nested in messages {
+                let nested_proto = self.message(file, nested);
+                proto.nested_type.push(nested_proto);
+            }
+            for nested in enums {
+                self.file_of.insert(nested.name(), file);
+                proto.enum_type.push(enum_proto(nested));
+            }
+        }
+
+        for range in message.reserved_ranges() {
+            let mut range_proto = descriptor::descriptor_proto::ReservedRange::new();
+            range_proto.set_start(range.start);
+            range_proto.set_end(range.end);
+            proto.reserved_range.push(range_proto);
+        }
+        for range in message.extension_ranges() {
+            let mut range_proto = descriptor::descriptor_proto::ExtensionRange::new();
+            range_proto.set_start(range.start);
+            range_proto.set_end(range.end);
+            proto.extension_range.push(range_proto);
+        }
+
+        if message.is_map_entry()
+            || message.is_message_set()
+            || message.custom_options.iter().next().is_some()
+        {
+            let mut options = descriptor::MessageOptions::new();
+            if message.is_map_entry() {
+                options.set_map_entry(true);
+            }
+            if message.is_message_set() {
+                options.set_message_set_wire_format(true);
+            }
+            *options.special_fields.mut_unknown_fields() = message.custom_options.clone();
+            proto.options = protobuf::MessageField::some(options);
+        }
+        proto
+    }
+
+    fn field(&self, field: &FieldDescriptor) -> descriptor::FieldDescriptorProto {
+        use protobuf::descriptor::field_descriptor_proto::Type;
+
+        let field_type = field.field_type(self.descriptors);
+        let mut proto = descriptor::FieldDescriptorProto::new();
+        proto.set_name(field.name().to_owned());
+        proto.set_number(field.number());
+        proto.set_label(field.field_label().to_proto());
+        proto.set_type(match field_type {
+            FieldType::UnresolvedMessage(_) | FieldType::Message(_) => Type::TYPE_MESSAGE,
+            FieldType::UnresolvedEnum(_) | FieldType::Enum(_) => Type::TYPE_ENUM,
+            FieldType::UnresolvedGroup(_) | FieldType::Group(_) => Type::TYPE_GROUP,
+            FieldType::Double => Type::TYPE_DOUBLE,
+            FieldType::Float => Type::TYPE_FLOAT,
+            FieldType::Int64 => Type::TYPE_INT64,
+            FieldType::UInt64 => Type::TYPE_UINT64,
+            FieldType::Int32 => Type::TYPE_INT32,
+            FieldType::Fixed64 => Type::TYPE_FIXED64,
+            FieldType::Fixed32 => Type::TYPE_FIXED32,
+            FieldType::Bool => Type::TYPE_BOOL,
+            FieldType::String => Type::TYPE_STRING,
+            FieldType::Bytes => Type::TYPE_BYTES,
+            FieldType::UInt32 => Type::TYPE_UINT32,
+            FieldType::SFixed32 => Type::TYPE_SFIXED32,
+            FieldType::SFixed64 => Type::TYPE_SFIXED64,
+            FieldType::SInt32 => Type::TYPE_SINT32,
+            FieldType::SInt64 => Type::TYPE_SINT64,
+        });
+        if let Some(type_name) = referenced_type_name(&field_type) {
+            proto.set_type_name(type_name.to_owned());
+        }
+        let default_value = match field.default_value_raw() {
+            Some(raw) => Some(raw.to_owned()),
+            None => field
+                .default_value()
+                .and_then(|v| default_value_string(&field_type, v)),
+        };
+        if let Some(default_value) = default_value {
+            proto.set_default_value(default_value);
+        }
+        proto.set_json_name(field.json_name().to_owned());
+        if field.is_proto3_optional() {
+            proto.set_proto3_optional(true);
+        }
+
+        if field.packed_option().is_some()
+            || field.ctype() != CType::String
+            || field.jstype() != JsType::Normal
+            || field.custom_options.iter().next().is_some()
+        {
+            let mut options = descriptor::FieldOptions::new();
+            if let Some(packed) = field.packed_option() {
+                options.set_packed(packed);
+            }
+            if field.ctype() != CType::String {
+                options.set_ctype(field.ctype().to_proto());
+            }
+            if field.jstype() != JsType::Normal {
+                options.set_jstype(field.jstype().to_proto());
+            }
+            *options.special_fields.mut_unknown_fields() = field.custom_options.clone();
+            proto.options = protobuf::MessageField::some(options);
+        }
+        proto
+    }
+
+    /// Computes the indices of the files that each file depends on.
+    fn dependencies(&self) -> Vec<BTreeSet<usize>> {
+        let mut dependencies = vec![BTreeSet::new(); self.files.len()];
+        let mut add = |file: usize, type_name: &str| {
+            if let Some(&dependency) = self.file_of.get(type_name) {
+                if dependency != file {
+                    dependencies[file].insert(dependency);
+                }
+            }
+        };
+
+        for message in &self.descriptors.messages {
+            let file = match self.file_of.get(message.name()) {
+                Some(&file) => file,
+                None => continue,
+            };
+            for field in message.fields() {
+                if let Some(type_name) = referenced_type_name(&field.field_type(self.descriptors)) {
+                    add(file, type_name);
+                }
+            }
+        }
+        for ((extendee, _), field) in &self.descriptors.extensions {
+            let package = self.descriptors.package_name(extendee);
+            let file = match self.files.iter().position(|&(p, _, _)| p == package) {
+                Some(file) => file,
+                None => continue,
+            };
+            add(file, extendee);
+            if let Some(type_name) = referenced_type_name(&field.field_type(self.descriptors)) {
+                add(file, type_name);
+            }
+        }
+        dependencies
+    }
+}
+
+fn enum_proto(enum_descriptor: &EnumDescriptor) -> descriptor::EnumDescriptorProto {
+    let mut proto = descriptor::EnumDescriptorProto::new();
+    proto.set_name(short_name(enum_descriptor.name()).to_owned());
+    for value in enum_descriptor.values() {
+        let mut value_proto = descriptor::EnumValueDescriptorProto::new();
+        value_proto.set_name(value.name().to_owned());
+        value_proto.set_number(value.number());
+        if *value.options() != descriptor::EnumValueOptions::new() {
+            value_proto.options = protobuf::MessageField::some(value.options().clone());
+        }
+        proto.value.push(value_proto);
+    }
+    proto
+}
+
+fn referenced_type_name<'a>(field_type: &FieldType<'a>) -> Option<&'a str> {
+    match *field_type {
+        FieldType::UnresolvedMessage(_)
+        | FieldType::UnresolvedEnum(_)
+        | FieldType::UnresolvedGroup(_)
+        | FieldType::Message(_)
+        | FieldType::Enum(_)
+        | FieldType::Group(_) => Some(source::type_name(field_type)),
+        _ => None,
+    }
+}
+
+fn default_value_string(field_type: &FieldType, value: &value::Value) -> Option<String> {
+    Some(match *value {
+        value::Value::Bool(v) => v.to_string(),
+        value::Value::I32(v) => v.to_string(),
+        value::Value::I64(v) => v.to_string(),
+        value::Value::U32(v) => v.to_string(),
+        value::Value::U64(v) => v.to_string(),
+        value::Value::F32(v) => source::render_float(v),
+        value::Value::F64(v) => source::render_float(v),
+        value::Value::Bytes(ref v) => source::escape_bytes(v),
+        value::Value::String(ref v) => v.clone(),
+        value::Value::Enum(v) => match *field_type {
+            FieldType::Enum(e) => e.name_for(v)?.to_owned(),
+            _ => return None,
+        },
+        value::Value::Message(_) => return None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::descriptor::test::load_descriptors;
+
+    #[test]
+    fn to_proto_round_trip() {
+        let d = load_descriptors();
+        let file_set = d.to_proto();
+
+        assert_eq!(
+            file_set
+                .file
+                .iter()
+                .map(|f| (f.name(), f.dependency.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    "protobuf_unittest.proto",
+                    vec!["protobuf_unittest_import.proto".to_owned()]
+                ),
+                ("protobuf_unittest_import.proto", vec![]),
+            ]
+        );
+
+        let round_tripped = Descriptors::try_from_proto(&file_set).unwrap();
+        assert_eq!(round_tripped.schema_hash(), d.schema_hash());
+        assert_eq!(round_tripped.to_proto_source(), d.to_proto_source());
+        assert!(d.diff(&round_tripped).is_empty());
+        assert_eq!(round_tripped.to_proto(), file_set);
+        let extendable = round_tripped
+            .message_by_name(".protobuf_unittest.TestAllExtensions")
+            .unwrap();
+        assert_eq!(extendable.extension_ranges().len(), 1);
+        assert_eq!(extendable.extension_ranges()[0], 1..MAX_FIELD_NUMBER + 1);
+    }
+
+    #[test]
+    fn to_proto_manual() {
+        let mut d = Descriptors::new();
+        let mut color = EnumDescriptor::new(".pkg.Color");
+        color.add_value(EnumValueDescriptor::new("RED", 0));
+        d.add_enum(color);
+
+        let mut message = MessageDescriptor::new(".pkg.Message");
+        message.set_syntax(Syntax::Proto3);
+        message.add_field(
+            FieldDescriptor::builder("color")
+                .number(1)
+                .field_type(InternalFieldType::UnresolvedEnum(".pkg.Color".into()))
+                .proto3_optional(true)
+                .build(),
+        );
+        message.add_field(
+            FieldDescriptor::builder("name")
+                .number(2)
+                .field_type(InternalFieldType::String)
+                .oneof("id")
+                .build(),
+        );
+        message.add_field(
+            FieldDescriptor::builder("values")
+                .number(3)
+                .label(FieldLabel::Repeated)
+                .packed(false)
+                .build(),
+        );
+        d.add_message(message);
+        d.add_message(MessageDescriptor::new(".pkg.Legacy"));
+        d.add_message(MessageDescriptor::new(".Root"));
+        d.resolve_refs();
+
+        let file_set = d.to_proto();
+        let names = file_set.file.iter().map(|f| f.name()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["root.proto", "pkg_proto3.proto", "pkg.proto"]);
+        assert_eq!(file_set.file[1].syntax(), "proto3");
+        assert_eq!(file_set.file[1].dependency, vec!["pkg.proto".to_owned()]);
+
+        let message_proto = &file_set.file[1].message_type[0];
+        let oneofs = message_proto
+            .oneof_decl
+            .iter()
+            .map(|o| o.name())
+            .collect::<Vec<_>>();
+        assert_eq!(oneofs, vec!["id", "_color"]);
+        assert_eq!(message_proto.field[0].oneof_index(), 1);
+        assert!(message_proto.field[0].proto3_optional());
+        assert_eq!(message_proto.field[1].oneof_index(), 0);
+
+        let round_tripped = Descriptors::from_proto(&file_set);
+        assert_eq!(round_tripped.schema_hash(), d.schema_hash());
+        let m = round_tripped.message_by_name(".pkg.Message").unwrap();
+        assert_eq!(m.syntax(), Syntax::Proto3);
+        assert!(m.field_by_number(1).unwrap().is_proto3_optional());
+        assert_eq!(m.field_by_number(1).unwrap().oneof(), None);
+        assert_eq!(m.field_by_number(2).unwrap().oneof(), Some("id"));
+        assert_eq!(m.field_by_number(3).unwrap().packed_option(), Some(false));
+    }
+
+    #[test]
+    fn to_proto_bytes_default_values() {
+        let d = load_descriptors();
+        let file_set = d.to_proto();
+        let extreme = file_set.file[0]
+            .message_type
+            .iter()
+            .find(|m| m.name() == "TestExtremeDefaultValues")
+            .unwrap();
+        let bytes_with_zero = extreme
+            .field
+            .iter()
+            .find(|f| f.name() == "bytes_with_zero")
+            .unwrap();
+        assert_eq!(bytes_with_zero.default_value(), "wor\\000ld");
+
+        let mut d = Descriptors::new();
+        let mut message = MessageDescriptor::new(".pkg.Message");
+        message.add_field(
+            FieldDescriptor::builder("data")
+                .number(1)
+                .field_type(InternalFieldType::Bytes)
+                .default_value(value::Value::Bytes(b"a\0\xff\"".to_vec()))
+                .build(),
+        );
+        d.add_message(message);
+        let file_set = d.to_proto();
+        assert_eq!(
+            file_set.file[0].message_type[0].field[0].default_value(),
+            "a\\000\\377\\\""
+        );
+    }
+}
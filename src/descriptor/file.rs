@@ -0,0 +1,233 @@
+//! Information about the files that types were defined in.
+use super::*;
+
+/// An ID used for internal tracking of the files that types were loaded from.
+///
+/// It is not possible to construct a value of this type from outside this module.  IDs are only
+/// meaningful for the registry that they were obtained from.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct FileId(pub(super) usize);
+
+/// Information about a protocol buffer file that types were loaded from.
+///
+/// The registry otherwise flattens away the file boundaries, so this keeps the file-level
+/// properties that don't belong to any single type: the name, package and syntax of the file, and
+/// its `FileOptions`.  The standard options that name a language package or prefix, and the
+/// `deprecated` option, have their own accessors; all other options, such as `optimize_for`, can
+/// be read from `options`.
+#[derive(Clone, Debug)]
+pub struct FileInfo {
+    pub(super) name: String,
+    pub(super) package: String,
+    pub(super) syntax: Syntax,
+    pub(super) options: descriptor::FileOptions,
+}
+
+impl FileInfo {
+    /// Reads the file information from a parsed Protobuf file descriptor.
+    pub fn from_proto(proto: &descriptor::FileDescriptorProto) -> FileInfo {
+        FileInfo {
+            name: proto.name().to_owned(),
+            package: proto.package().to_owned(),
+            syntax: Syntax::from_proto(proto.syntax()),
+            options: (*proto.options).clone(),
+        }
+    }
+
+    /// The name of the file, relative to its source tree root (e.g. `foo/bar.proto`).
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The package of the file, without a leading dot, or an empty string for the root package.
+    #[inline]
+    pub fn package(&self) -> &str {
+        &self.package
+    }
+
+    /// The syntax of the file.
+    #[inline]
+    pub fn syntax(&self) -> Syntax {
+        self.syntax
+    }
+
+    /// All of the options of the file.
+    #[inline]
+    pub fn options(&self) -> &descriptor::FileOptions {
+        &self.options
+    }
+
+    /// Whether the whole file is marked as deprecated.
+    #[inline]
+    pub fn deprecated(&self) -> bool {
+        self.options.deprecated()
+    }
+
+    /// The `java_package` option, if set.
+    #[inline]
+    pub fn java_package(&self) -> Option<&str> {
+        self.options.java_package.as_deref()
+    }
+
+    /// The `java_outer_classname` option, if set.
+    #[inline]
+    pub fn java_outer_classname(&self) -> Option<&str> {
+        self.options.java_outer_classname.as_deref()
+    }
+
+    /// The `go_package` option, if set.
+    #[inline]
+    pub fn go_package(&self) -> Option<&str> {
+        self.options.go_package.as_deref()
+    }
+
+    /// The `csharp_namespace` option, if set.
+    #[inline]
+    pub fn csharp_namespace(&self) -> Option<&str> {
+        self.options.csharp_namespace.as_deref()
+    }
+
+    /// The `objc_class_prefix` option, if set.
+    #[inline]
+    pub fn objc_class_prefix(&self) -> Option<&str> {
+        self.options.objc_class_prefix.as_deref()
+    }
+
+    /// The `php_namespace` option, if set.
+    #[inline]
+    pub fn php_namespace(&self) -> Option<&str> {
+        self.options.php_namespace.as_deref()
+    }
+
+    /// The `ruby_package` option, if set.
+    #[inline]
+    pub fn ruby_package(&self) -> Option<&str> {
+        self.options.ruby_package.as_deref()
+    }
+
+    /// The `swift_prefix` option, if set.
+    #[inline]
+    pub fn swift_prefix(&self) -> Option<&str> {
+        self.options.swift_prefix.as_deref()
+    }
+}
+
+impl Descriptors {
+    /// All files that types were loaded from, in the order that they were added.
+    ///
+    /// Only files added using `add_file_proto` and related methods are recorded; custom built
+    /// types don't belong to any file.
+    #[inline]
+    pub fn files(&self) -> &[FileInfo] {
+        &self.files
+    }
+
+    /// Looks up a file by an ID obtained from a type of this registry.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the ID was obtained from a different registry that has more files.
+    #[inline]
+    pub fn file_by_id(&self, id: FileId) -> &FileInfo {
+        &self.files[id.0]
+    }
+
+    /// Looks up a file by its name.
+    ///
+    /// If the same file was added more than once, the most recently added one is returned.
+    pub fn file_by_name(&self, name: &str) -> Option<&FileInfo> {
+        self.files.iter().rev().find(|f| f.name == name)
+    }
+
+    /// The file that the message with the specified fully qualified name was defined in, if it
+    /// was loaded from a file.
+    pub fn file_of_message(&self, name: &str) -> Option<&FileInfo> {
+        self.message_by_name(name)
+            .and_then(MessageDescriptor::file_id)
+            .map(|id| self.file_by_id(id))
+    }
+
+    /// The file that the enum with the specified fully qualified name was defined in, if it was
+    /// loaded from a file.
+    pub fn file_of_enum(&self, name: &str) -> Option<&FileInfo> {
+        self.enum_by_name(name)
+            .and_then(EnumDescriptor::file_id)
+            .map(|id| self.file_by_id(id))
+    }
+
+    /// Records a file, returning its ID.
+    pub(super) fn add_file_info(&mut self, file: FileInfo) -> FileId {
+        FileId(store(&mut self.files, file))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::descriptor::test::load_descriptors;
+
+    #[test]
+    fn file_options() {
+        let mut file = descriptor::FileDescriptorProto::new();
+        file.set_name("pkg/a.proto".to_owned());
+        file.set_package("pkg".to_owned());
+        file.set_syntax("proto3".to_owned());
+        let options = file.options.mut_or_insert_default();
+        options.set_deprecated(true);
+        options.set_java_package("com.example.pkg".to_owned());
+        options.set_go_package("example.com/pkg".to_owned());
+        options.set_optimize_for(descriptor::file_options::OptimizeMode::LITE_RUNTIME);
+        let mut message = descriptor::DescriptorProto::new();
+        message.set_name("Message".to_owned());
+        let mut nested = descriptor::DescriptorProto::new();
+        nested.set_name("Nested".to_owned());
+        message.nested_type.push(nested);
+        file.message_type.push(message);
+        let mut enum_proto = descriptor::EnumDescriptorProto::new();
+        enum_proto.set_name("Enum".to_owned());
+        file.enum_type.push(enum_proto);
+
+        let mut descriptors = Descriptors::new();
+        descriptors.add_file_proto(&file);
+        descriptors.add_message(MessageDescriptor::new(".pkg.Custom"));
+
+        let info = descriptors.file_of_message(".pkg.Message.Nested").unwrap();
+        assert_eq!(info.name(), "pkg/a.proto");
+        assert_eq!(info.package(), "pkg");
+        assert_eq!(info.syntax(), Syntax::Proto3);
+        assert!(info.deprecated());
+        assert_eq!(info.java_package(), Some("com.example.pkg"));
+        assert_eq!(info.go_package(), Some("example.com/pkg"));
+        assert_eq!(info.csharp_namespace(), None);
+        assert_eq!(
+            info.options().optimize_for(),
+            descriptor::file_options::OptimizeMode::LITE_RUNTIME
+        );
+        assert_eq!(
+            descriptors.file_of_enum(".pkg.Enum").unwrap().name(),
+            "pkg/a.proto"
+        );
+        assert!(descriptors.file_of_message(".pkg.Custom").is_none());
+        assert!(descriptors.file_of_message(".pkg.Missing").is_none());
+        assert_eq!(descriptors.files().len(), 1);
+        assert!(descriptors.file_by_name("pkg/a.proto").is_some());
+        assert!(descriptors.file_by_name("pkg/b.proto").is_none());
+    }
+
+    #[test]
+    fn file_of_loaded_types() {
+        let descriptors = load_descriptors();
+        let info = descriptors
+            .file_of_message(".protobuf_unittest.TestAllTypes")
+            .unwrap();
+        assert_eq!(info.package(), "protobuf_unittest");
+        assert!(!info.deprecated());
+        let id = descriptors
+            .message_by_name(".protobuf_unittest.TestAllTypes")
+            .unwrap()
+            .file_id()
+            .unwrap();
+        assert_eq!(descriptors.file_by_id(id).name(), info.name());
+    }
+}
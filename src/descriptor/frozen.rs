@@ -0,0 +1,136 @@
+//! An immutable descriptor registry for sharing between threads.
+use std::ops;
+
+use super::*;
+
+// Registries are shared between decoding threads, so make sure that they stay `Send` and `Sync`
+const _: fn() = || {
+    fn assert_send_sync<A: Send + Sync>() {}
+    assert_send_sync::<Descriptors>();
+    assert_send_sync::<FrozenDescriptors>();
+};
+
+/// A descriptor registry whose type references have been resolved, and which can no longer be
+/// modified.
+///
+/// `Descriptors` is `Send` and `Sync`, so a registry that is no longer modified can already be
+/// shared between threads, for example in an `Arc`.  Freezing it makes that contract explicit:
+/// all lookups are available through `Deref`, but none of the methods that take `&mut self`
+/// are, so the registry can't accidentally be modified after it has been shared.  Since the
+/// references are resolved while freezing, all lookups take the fast path.
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// use serde_protobuf::descriptor::{Descriptors, FrozenDescriptors, MessageDescriptor};
+///
+/// let mut descriptors = Descriptors::new();
+/// descriptors.add_message(MessageDescriptor::new(".pkg.Message"));
+/// let descriptors = Arc::new(FrozenDescriptors::new(descriptors));
+///
+/// let shared = Arc::clone(&descriptors);
+/// thread::spawn(move || assert!(shared.contains_message(".pkg.Message")))
+///     .join()
+///     .unwrap();
+/// ```
+#[derive(Debug)]
+pub struct FrozenDescriptors(Descriptors);
+
+impl FrozenDescriptors {
+    /// Freezes a registry, resolving all of its type references first.
+    pub fn new(mut descriptors: Descriptors) -> FrozenDescriptors {
+        descriptors.resolve_refs();
+        FrozenDescriptors(descriptors)
+    }
+
+    /// Unfreezes the registry, so that it can be modified again.
+    ///
+    /// Since this takes ownership, the registry can't be shared with other threads at this point.
+    #[inline]
+    pub fn into_inner(self) -> Descriptors {
+        self.0
+    }
+}
+
+impl Descriptors {
+    /// Freezes this registry; see `FrozenDescriptors`.
+    #[inline]
+    pub fn freeze(self) -> FrozenDescriptors {
+        FrozenDescriptors::new(self)
+    }
+}
+
+impl From<Descriptors> for FrozenDescriptors {
+    #[inline]
+    fn from(descriptors: Descriptors) -> FrozenDescriptors {
+        FrozenDescriptors::new(descriptors)
+    }
+}
+
+impl ops::Deref for FrozenDescriptors {
+    type Target = Descriptors;
+
+    #[inline]
+    fn deref(&self) -> &Descriptors {
+        &self.0
+    }
+}
+
+impl AsRef<Descriptors> for FrozenDescriptors {
+    #[inline]
+    fn as_ref(&self) -> &Descriptors {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+    use crate::descriptor::test::load_descriptors;
+
+    #[test]
+    fn shared_between_threads() {
+        let descriptors = Arc::new(load_descriptors().freeze());
+
+        let threads = (0..4)
+            .map(|_| {
+                let descriptors = Arc::clone(&descriptors);
+                thread::spawn(move || {
+                    let message = descriptors
+                        .message_by_name(".protobuf_unittest.TestAllTypes")
+                        .unwrap();
+                    let field = message.field_by_name("optional_nested_message").unwrap();
+                    // References were resolved while freezing
+                    assert!(matches!(field.field_type, InternalFieldType::Message(_)));
+                    match field.field_type(&descriptors) {
+                        FieldType::Message(m) => m.name().to_owned(),
+                        t => panic!("Expected a message type, got {:?}", t),
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            assert_eq!(
+                thread.join().unwrap(),
+                ".protobuf_unittest.TestAllTypes.NestedMessage"
+            );
+        }
+
+        // Extension fields are resolved too
+        let extension = descriptors
+            .extension(".protobuf_unittest.TestAllExtensions", 18)
+            .unwrap();
+        assert!(matches!(
+            extension.field_type,
+            InternalFieldType::Message(_)
+        ));
+
+        let mut descriptors = Arc::try_unwrap(descriptors).unwrap().into_inner();
+        descriptors.add_message(MessageDescriptor::new(".pkg.Added"));
+        assert!(FrozenDescriptors::from(descriptors).contains_message(".pkg.Added"));
+    }
+}
@@ -0,0 +1,223 @@
+//! Stable content hashing of descriptor registries.
+use super::*;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+impl Descriptors {
+    /// Computes a hash of the schema described by this registry.
+    ///
+    /// The hash covers the names, syntaxes and fields of all messages, the names and values of all
+    /// enums and all extensions, but not comments or options.  Types are hashed in name
+    /// order and fields in number order, so two registries describing the same schema hash equal
+    /// regardless of the order that types were added in, and regardless of whether `resolve_refs`
+    /// has been called.  The hash is stable across platforms and versions of this library, but it
+    /// is not cryptographically secure.
+    pub fn schema_hash(&self) -> u64 {
+        let mut h = Fnv(FNV_OFFSET_BASIS);
+
+        let mut messages = self
+            .messages_by_name
+            .values()
+            .map(|id| &self.messages[id.0])
+            .collect::<Vec<_>>();
+        messages.sort_by_key(|m| m.name());
+        h.len(messages.len());
+        for m in messages {
+            h.str(m.name());
+            h.u8(match m.syntax() {
+                Syntax::Proto2 => 0,
+                Syntax::Proto3 => 1,
+            });
+            h.u8(m.is_map_entry() as u8);
+            h.u8(m.is_message_set() as u8);
+
+            let mut fields = m.fields().iter().collect::<Vec<_>>();
+            fields.sort_by_key(|f| f.number());
+            h.len(fields.len());
+            for f in fields {
+                h.field(self, f);
+            }
+        }
+
+        let mut enums = self
+            .enums_by_name
+            .values()
+            .map(|id| &self.enums[id.0])
+            .collect::<Vec<_>>();
+        enums.sort_by_key(|e| e.name());
+        h.len(enums.len());
+        for e in enums {
+            h.str(e.name());
+            // The order of enum values is significant, since the first value is the default
+            h.len(e.values().len());
+            for v in e.values() {
+                h.str(v.name());
+                h.bytes(&v.number().to_le_bytes());
+            }
+        }
+
+        let mut extensions = self.extensions.iter().collect::<Vec<_>>();
+        extensions.sort_by_key(|&(key, _)| key);
+        h.len(extensions.len());
+        for ((extendee, _), f) in extensions {
+            h.str(extendee);
+            h.field(self, f);
+        }
+
+        h.0
+    }
+}
+
+/// A 64-bit FNV-1a hasher, which unlike the standard library hashers is guaranteed to be stable.
+struct Fnv(u64);
+
+impl Fnv {
+    fn bytes(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= u64::from(b);
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    fn u8(&mut self, v: u8) {
+        self.bytes(&[v]);
+    }
+
+    fn len(&mut self, v: usize) {
+        self.bytes(&(v as u64).to_le_bytes());
+    }
+
+    fn str(&mut self, v: &str) {
+        self.len(v.len());
+        self.bytes(v.as_bytes());
+    }
+
+    fn opt_str(&mut self, v: Option<&str>) {
+        match v {
+            Some(v) => {
+                self.u8(1);
+                self.str(v);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn field(&mut self, descriptors: &Descriptors, f: &FieldDescriptor) {
+        self.str(f.name());
+        self.bytes(&f.number().to_le_bytes());
+        self.u8(match f.field_label() {
+            FieldLabel::Optional => 0,
+            FieldLabel::Required => 1,
+            FieldLabel::Repeated => 2,
+        });
+        // Hash type references by name, so that resolving references doesn't change the hash
+        let field_type = f.field_type(descriptors);
+        self.str(source::type_name(&field_type));
+        // Enum default values are only looked up when the enum type is resolved, so hash them by
+        // name where possible
+        let is_enum = matches!(
+            field_type,
+            FieldType::Enum(_) | FieldType::UnresolvedEnum(_)
+        );
+        match (f.default_value_raw(), f.default_value()) {
+            (Some(raw), _) if is_enum => {
+                self.u8(2);
+                self.str(raw);
+            }
+            (_, Some(v)) => {
+                self.u8(1);
+                self.value(v);
+            }
+            (_, None) => self.u8(0),
+        }
+        self.u8(f.is_optional() as u8);
+        self.u8(f.is_proto3_optional() as u8);
+        self.str(f.json_name());
+        self.u8(f.is_packed() as u8);
+        self.opt_str(f.oneof());
+    }
+
+    fn value(&mut self, v: &value::Value) {
+        match *v {
+            value::Value::Bool(v) => self.bytes(&[0, v as u8]),
+            value::Value::I32(v) => {
+                self.u8(1);
+                self.bytes(&v.to_le_bytes());
+            }
+            value::Value::I64(v) => {
+                self.u8(2);
+                self.bytes(&v.to_le_bytes());
+            }
+            value::Value::U32(v) => {
+                self.u8(3);
+                self.bytes(&v.to_le_bytes());
+            }
+            value::Value::U64(v) => {
+                self.u8(4);
+                self.bytes(&v.to_le_bytes());
+            }
+            value::Value::F32(v) => {
+                self.u8(5);
+                self.bytes(&v.to_bits().to_le_bytes());
+            }
+            value::Value::F64(v) => {
+                self.u8(6);
+                self.bytes(&v.to_bits().to_le_bytes());
+            }
+            value::Value::Bytes(ref v) => {
+                self.u8(7);
+                self.len(v.len());
+                self.bytes(v);
+            }
+            value::Value::String(ref v) => {
+                self.u8(8);
+                self.str(v);
+            }
+            value::Value::Enum(v) => {
+                self.u8(9);
+                self.bytes(&v.to_le_bytes());
+            }
+            value::Value::Message(_) => self.u8(10),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::descriptor::test::load_descriptors;
+
+    fn message(name: &str, field_number: i32) -> MessageDescriptor {
+        let mut m = MessageDescriptor::new(name);
+        m.add_field(FieldDescriptor::builder("a").number(field_number).build());
+        m
+    }
+
+    #[test]
+    fn schema_hash_is_stable() {
+        let mut d = load_descriptors();
+        let hash = d.schema_hash();
+        assert_eq!(load_descriptors().schema_hash(), hash);
+        d.resolve_refs();
+        assert_eq!(d.schema_hash(), hash);
+        assert_ne!(Descriptors::new().schema_hash(), hash);
+    }
+
+    #[test]
+    fn schema_hash_ignores_insertion_order() {
+        let mut a = Descriptors::new();
+        a.add_message(message(".pkg.A", 1));
+        a.add_message(message(".pkg.B", 2));
+
+        let mut b = Descriptors::new();
+        b.add_message(message(".pkg.B", 2));
+        b.add_message(message(".pkg.A", 1));
+        assert_eq!(a.schema_hash(), b.schema_hash());
+
+        let mut c = Descriptors::new();
+        c.add_message(message(".pkg.A", 1));
+        c.add_message(message(".pkg.B", 3));
+        assert_ne!(a.schema_hash(), c.schema_hash());
+    }
+}
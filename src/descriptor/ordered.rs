@@ -0,0 +1,254 @@
+//! The insertion-ordered map that descriptor registries are indexed with.
+//!
+//! With the `std` feature, this is `linked_hash_map::LinkedHashMap`.  Without it, it is a vector
+//! of entries in insertion order together with a `BTreeMap` index, which only needs `alloc`.
+#[cfg(not(feature = "std"))]
+use std::borrow;
+#[cfg(not(feature = "std"))]
+use std::collections;
+#[cfg(not(feature = "std"))]
+use std::iter;
+#[cfg(not(feature = "std"))]
+use std::slice;
+#[cfg(not(feature = "std"))]
+use std::vec;
+
+/// A map that iterates over its entries in insertion order.
+#[cfg(feature = "std")]
+pub(crate) type OrderedMap<K, V> = linked_hash_map::LinkedHashMap<K, V>;
+
+/// A map that iterates over its entries in insertion order.
+#[cfg(not(feature = "std"))]
+#[derive(Clone, Debug)]
+pub(crate) struct OrderedMap<K, V> {
+    entries: Vec<(K, V)>,
+    // The position of each key in `entries`
+    index: collections::BTreeMap<K, usize>,
+}
+
+#[cfg(not(feature = "std"))]
+impl<K, V> Default for OrderedMap<K, V> {
+    fn default() -> Self {
+        OrderedMap {
+            entries: Vec::new(),
+            index: collections::BTreeMap::new(),
+        }
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<K, V> OrderedMap<K, V>
+where
+    K: Clone + Ord,
+{
+    pub(crate) fn new() -> Self {
+        OrderedMap::default()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub(crate) fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.index.contains_key(key)
+    }
+
+    pub(crate) fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.index.get(key).map(|&i| &self.entries[i].1)
+    }
+
+    /// Inserts an entry, returning the previous value of the key.
+    ///
+    /// Like with `LinkedHashMap`, an existing entry with the same key is moved to the end.
+    pub(crate) fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let previous = self.remove(&key);
+        self.index.insert(key.clone(), self.entries.len());
+        self.entries.push((key, value));
+        previous
+    }
+
+    pub(crate) fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: borrow::Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let removed = self.index.remove(key)?;
+        for i in self.index.values_mut() {
+            if *i > removed {
+                *i -= 1;
+            }
+        }
+        Some(self.entries.remove(removed).1)
+    }
+
+    /// The entry of a key, for in-place insertion like with `LinkedHashMap::entry`.
+    pub(crate) fn entry(&mut self, key: K) -> Entry<'_, K, V> {
+        Entry { map: self, key }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.index.clear();
+    }
+
+    pub(crate) fn iter(&self) -> Iter<'_, K, V> {
+        Iter(self.entries.iter())
+    }
+
+    pub(crate) fn iter_mut(
+        &mut self,
+    ) -> impl DoubleEndedIterator<Item = (&K, &mut V)> + ExactSizeIterator {
+        self.entries.iter_mut().map(|(k, v)| (&*k, v))
+    }
+
+    pub(crate) fn keys(&self) -> impl DoubleEndedIterator<Item = &K> + ExactSizeIterator {
+        self.entries.iter().map(|(k, _)| k)
+    }
+
+    pub(crate) fn values(&self) -> impl DoubleEndedIterator<Item = &V> + ExactSizeIterator {
+        self.entries.iter().map(|(_, v)| v)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<K, V> iter::FromIterator<(K, V)> for OrderedMap<K, V>
+where
+    K: Clone + Ord,
+{
+    fn from_iter<I>(entries: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut map = OrderedMap::new();
+        for (key, value) in entries {
+            map.insert(key, value);
+        }
+        map
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<K, V> IntoIterator for OrderedMap<K, V> {
+    type Item = (K, V);
+    type IntoIter = vec::IntoIter<(K, V)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<'a, K, V> IntoIterator for &'a OrderedMap<K, V>
+where
+    K: Clone + Ord,
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// An iterator over the entries of an `OrderedMap`, in insertion order.
+#[cfg(not(feature = "std"))]
+#[derive(Clone, Debug)]
+pub(crate) struct Iter<'a, K, V>(slice::Iter<'a, (K, V)>);
+
+#[cfg(not(feature = "std"))]
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(k, v)| (k, v))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<'a, K, V> DoubleEndedIterator for Iter<'a, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.0.next_back().map(|(k, v)| (k, v))
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<'a, K, V> ExactSizeIterator for Iter<'a, K, V> {}
+
+/// A key of an `OrderedMap` that may or may not have a value yet.
+#[cfg(not(feature = "std"))]
+pub(crate) struct Entry<'a, K, V> {
+    map: &'a mut OrderedMap<K, V>,
+    key: K,
+}
+
+#[cfg(not(feature = "std"))]
+impl<'a, K, V> Entry<'a, K, V>
+where
+    K: Clone + Ord,
+{
+    /// The value of the key, inserting the default value at the end if there is none.
+    pub(crate) fn or_default(self) -> &'a mut V
+    where
+        V: Default,
+    {
+        let i = match self.map.index.get(&self.key) {
+            Some(&i) => i,
+            None => {
+                let i = self.map.entries.len();
+                self.map.index.insert(self.key.clone(), i);
+                self.map.entries.push((self.key, V::default()));
+                i
+            }
+        };
+        &mut self.map.entries[i].1
+    }
+}
+
+#[cfg(all(test, not(feature = "std")))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn insertion_order() {
+        let mut map = OrderedMap::new();
+        assert!(map.is_empty());
+        map.insert("b", 1);
+        map.insert("a", 2);
+        map.insert("c", 3);
+        assert_eq!(map.keys().cloned().collect::<Vec<_>>(), vec!["b", "a", "c"]);
+
+        // Re-inserting a key moves it to the end, and removing a key keeps the others in order
+        assert_eq!(map.insert("b", 4), Some(1));
+        assert_eq!(map.remove("a"), Some(2));
+        assert_eq!(
+            map.iter().map(|(&k, &v)| (k, v)).collect::<Vec<_>>(),
+            vec![("c", 3), ("b", 4)]
+        );
+        assert_eq!(map.get("b"), Some(&4));
+        assert!(!map.contains_key("a"));
+        assert_eq!(map.len(), 2);
+
+        *map.entry("a").or_default() += 5;
+        *map.entry("c").or_default() += 1;
+        assert_eq!(
+            map.into_iter().collect::<Vec<_>>(),
+            vec![("c", 4), ("b", 4), ("a", 5)]
+        );
+    }
+}
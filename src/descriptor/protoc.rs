@@ -0,0 +1,126 @@
+//! Compilation of `.proto` files into descriptors by invoking `protoc`.
+use std::fs;
+use std::path;
+use std::process;
+use std::sync::atomic;
+
+use super::*;
+
+static NEXT_OUTPUT_ID: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+impl Descriptors {
+    /// Compiles the specified `.proto` files into a descriptor registry, using the `protoc`
+    /// compiler found on the `PATH`.
+    ///
+    /// Imported files are looked up in the specified include directories, and all imported types
+    /// are included in the registry.  Type references are not resolved; call `resolve_refs` to do
+    /// that.
+    ///
+    /// Returns `Error::ProtocNotFound` if `protoc` can't be run, and `Error::ProtocFailed` if the
+    /// compilation fails.
+    pub fn compile(
+        proto_paths: &[&path::Path],
+        include_dirs: &[&path::Path],
+    ) -> error::Result<Descriptors> {
+        Descriptors::compile_with_protoc(path::Path::new("protoc"), proto_paths, include_dirs)
+    }
+
+    /// Compiles the specified `.proto` files into a descriptor registry, using the specified
+    /// `protoc` compiler.
+    ///
+    /// See `compile` for more information.
+    pub fn compile_with_protoc(
+        protoc: &path::Path,
+        proto_paths: &[&path::Path],
+        include_dirs: &[&path::Path],
+    ) -> error::Result<Descriptors> {
+        let output = create_output_file()?;
+
+        let mut command = process::Command::new(protoc);
+        command.arg("--include_imports").arg("-o").arg(&output);
+        for include_dir in include_dirs {
+            command.arg("-I").arg(include_dir);
+        }
+        command.args(proto_paths);
+
+        let result = match command.output() {
+            Ok(result) => result,
+            Err(source) => {
+                let _ = fs::remove_file(&output);
+                return Err(error::Error::ProtocNotFound {
+                    path: protoc.to_owned(),
+                    source,
+                });
+            }
+        };
+
+        let descriptors = if result.status.success() {
+            fs::File::open(&output)
+                .map_err(|e| error::Error::from(protobuf::Error::from(e)))
+                .and_then(Descriptors::from_proto_reader)
+        } else {
+            Err(error::Error::ProtocFailed {
+                status: result.status,
+                stderr: String::from_utf8_lossy(&result.stderr).into_owned(),
+            })
+        };
+
+        let _ = fs::remove_file(&output);
+        descriptors
+    }
+}
+
+/// Creates a new empty file in the temporary directory for `protoc` to write its output to.
+///
+/// The file is created exclusively, and only readable by the current user, so that nobody else can
+/// have created it or a symlink in its place beforehand to read or redirect the output.
+fn create_output_file() -> error::Result<path::PathBuf> {
+    loop {
+        let output = std::env::temp_dir().join(format!(
+            "serde-protobuf-{}-{}.pb",
+            process::id(),
+            NEXT_OUTPUT_ID.fetch_add(1, atomic::Ordering::Relaxed)
+        ));
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        match options.open(&output) {
+            Ok(_) => return Ok(output),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(protobuf::Error::from(e).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn compile_missing_protoc() {
+        let protoc = path::Path::new("/nonexistent/protoc");
+        let proto = path::Path::new("testdata/google/protobuf/unittest.proto");
+        match Descriptors::compile_with_protoc(protoc, &[proto], &[path::Path::new("testdata")]) {
+            Err(error::Error::ProtocNotFound { path, .. }) => assert_eq!(path, protoc),
+            r => panic!("Expected protoc not found error, got {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn create_output_file_skips_existing_files() {
+        // Pre-create the file that would be used next
+        let next = std::env::temp_dir().join(format!(
+            "serde-protobuf-{}-{}.pb",
+            process::id(),
+            NEXT_OUTPUT_ID.load(atomic::Ordering::Relaxed)
+        ));
+        fs::write(&next, b"planted").unwrap();
+
+        let output = create_output_file().unwrap();
+        assert_ne!(output, next);
+        assert!(fs::read(&output).unwrap().is_empty());
+        fs::remove_file(&output).unwrap();
+        fs::remove_file(&next).unwrap();
+    }
+}
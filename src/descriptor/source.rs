@@ -0,0 +1,351 @@
+//! Rendering of descriptor registries as approximate `.proto` source code.
+use std::collections::BTreeMap;
+
+use super::*;
+
+type Children<'a> = BTreeMap<&'a str, (Vec<&'a MessageDescriptor>, Vec<&'a EnumDescriptor>)>;
+
+impl Descriptors {
+    /// Renders the types in this registry as approximate `.proto` source code.
+    ///
+    /// The output has one section per package, each starting with a `package` declaration, and
+    /// types nested within messages are rendered inside of those messages.  Types are sorted by
+    /// name and fields are kept in declaration order, so the output is stable and can be diffed.
+    /// Type references are rendered as fully qualified names, and field options other than default
+    /// values and packing are not rendered.
+    pub fn to_proto_source(&self) -> String {
+        let mut children = Children::new();
+        for &id in self.messages_by_name.values() {
+            let message = &self.messages[id.0];
+            children
+                .entry(parent_name(message.name()))
+                .or_default()
+                .0
+                .push(message);
+        }
+        for &id in self.enums_by_name.values() {
+            let enum_descriptor = &self.enums[id.0];
+            children
+                .entry(parent_name(enum_descriptor.name()))
+                .or_default()
+                .1
+                .push(enum_descriptor);
+        }
+        for (messages, enums) in children.values_mut() {
+            messages.sort_by_key(|m| m.name());
+            enums.sort_by_key(|e| e.name());
+        }
+
+        let printer = Printer {
+            descriptors: self,
+            children: &children,
+        };
+        let mut out = String::new();
+        for (&package, (messages, _)) in &children {
+            if self.messages_by_name.contains_key(package) {
+                continue;
+            }
+
+            if !out.is_empty() {
+                out.push('\n');
+            }
+            if messages.iter().any(|m| m.syntax() == Syntax::Proto3) {
+                out.push_str("syntax = \"proto3\";\n\n");
+            }
+            if !package.is_empty() {
+                out.push_str(&format!("package {};\n\n", package.trim_start_matches('.')));
+            }
+            printer.types(&mut out, package, 0);
+        }
+        out
+    }
+}
+
+struct Printer<'a> {
+    descriptors: &'a Descriptors,
+    children: &'a Children<'a>,
+}
+
+impl<'a> Printer<'a> {
+    fn types(&self, out: &mut String, parent: &str, depth: usize) {
+        let (messages, enums) = match self.children.get(parent) {
+            Some(children) => children,
+            None => return,
+        };
+
+        let mut first = true;
+        for e in enums {
+            if !first {
+                out.push('\n');
+            }
+            first = false;
+            self.enum_type(out, e, depth);
+        }
+        for m in messages {
+            if !first {
+                out.push('\n');
+            }
+            first = false;
+            self.message(out, m, depth);
+        }
+    }
+
+    fn message(&self, out: &mut String, message: &MessageDescriptor, depth: usize) {
+        let indent = "  ".repeat(depth);
+        out.push_str(&format!(
+            "{}message {} {{\n",
+            indent,
+            short_name(message.name())
+        ));
+        for field in message.fields() {
+            self.field(out, message, field, depth + 1);
+        }
+        if self.children.contains_key(message.name()) {
+            if !message.fields().is_empty() {
+                out.push('\n');
+            }
+            self.types(out, message.name(), depth + 1);
+        }
+        out.push_str(&format!("{}}}\n", indent));
+    }
+
+    fn field(
+        &self,
+        out: &mut String,
+        message: &MessageDescriptor,
+        field: &FieldDescriptor,
+        depth: usize,
+    ) {
+        let label = match field.field_label() {
+            FieldLabel::Optional
+                if message.syntax() == Syntax::Proto3 && !field.is_proto3_optional() =>
+            {
+                ""
+            }
+            FieldLabel::Optional => "optional ",
+            FieldLabel::Required => "required ",
+            FieldLabel::Repeated => "repeated ",
+        };
+        let field_type = field.field_type(self.descriptors);
+
+        let mut options = Vec::new();
+        let default_value = match (field.default_value(), field.default_value_raw()) {
+            (Some(v), _) => render_value(&field_type, v),
+            // Enum default values are only parsed once the enum type is resolved
+            (None, Some(raw)) => Some(match field_type {
+                FieldType::String => render_bytes(raw.as_bytes()),
+                FieldType::Bytes => format!("\"{}\"", raw),
+                _ => raw.to_owned(),
+            }),
+            (None, None) => None,
+        };
+        if let Some(default_value) = default_value {
+            options.push(format!("default = {}", default_value));
+        }
+        if let Some(packed) = field.packed_option() {
+            options.push(format!("packed = {}", packed));
+        }
+        let options = if options.is_empty() {
+            String::new()
+        } else {
+            format!(" [{}]", options.join(", "))
+        };
+
+        out.push_str(&format!(
+            "{}{}{} {} = {}{};\n",
+            "  ".repeat(depth),
+            label,
+            type_name(&field_type),
+            field.name(),
+            field.number(),
+            options
+        ));
+    }
+
+    fn enum_type(&self, out: &mut String, enum_descriptor: &EnumDescriptor, depth: usize) {
+        let indent = "  ".repeat(depth);
+        out.push_str(&format!(
+            "{}enum {} {{\n",
+            indent,
+            short_name(enum_descriptor.name())
+        ));
+        for value in enum_descriptor.values() {
+            out.push_str(&format!(
+                "{}  {} = {};\n",
+                indent,
+                value.name(),
+                value.number()
+            ));
+        }
+        out.push_str(&format!("{}}}\n", indent));
+    }
+}
+
+pub(super) fn parent_name(name: &str) -> &str {
+    name.rfind('.').map_or("", |i| &name[..i])
+}
+
+pub(super) fn type_name<'a>(field_type: &FieldType<'a>) -> &'a str {
+    match *field_type {
+        FieldType::UnresolvedMessage(name)
+        | FieldType::UnresolvedEnum(name)
+        | FieldType::UnresolvedGroup(name) => name,
+        FieldType::Message(m) | FieldType::Group(m) => m.name(),
+        FieldType::Enum(e) => e.name(),
+        FieldType::Double => "double",
+        FieldType::Float => "float",
+        FieldType::Int64 => "int64",
+        FieldType::UInt64 => "uint64",
+        FieldType::Int32 => "int32",
+        FieldType::Fixed64 => "fixed64",
+        FieldType::Fixed32 => "fixed32",
+        FieldType::Bool => "bool",
+        FieldType::String => "string",
+        FieldType::Bytes => "bytes",
+        FieldType::UInt32 => "uint32",
+        FieldType::SFixed32 => "sfixed32",
+        FieldType::SFixed64 => "sfixed64",
+        FieldType::SInt32 => "sint32",
+        FieldType::SInt64 => "sint64",
+    }
+}
+
+fn render_value(field_type: &FieldType, value: &value::Value) -> Option<String> {
+    Some(match *value {
+        value::Value::Bool(v) => v.to_string(),
+        value::Value::I32(v) => v.to_string(),
+        value::Value::I64(v) => v.to_string(),
+        value::Value::U32(v) => v.to_string(),
+        value::Value::U64(v) => v.to_string(),
+        value::Value::F32(v) => render_float(v),
+        value::Value::F64(v) => render_float(v),
+        value::Value::Bytes(ref v) => render_bytes(v),
+        value::Value::String(ref v) => render_bytes(v.as_bytes()),
+        value::Value::Enum(v) => match *field_type {
+            FieldType::Enum(e) => e
+                .value_by_number(v)
+                .map_or_else(|| v.to_string(), |v| v.name().to_owned()),
+            _ => v.to_string(),
+        },
+        value::Value::Message(_) => return None,
+    })
+}
+
+pub(super) fn render_float<F>(v: F) -> String
+where
+    F: Copy + Into<f64> + ToString,
+{
+    let f = v.into();
+    if f.is_nan() {
+        "nan".to_owned()
+    } else if f.is_infinite() {
+        if f > 0.0 { "inf" } else { "-inf" }.to_owned()
+    } else {
+        v.to_string()
+    }
+}
+
+fn render_bytes(bytes: &[u8]) -> String {
+    format!("\"{}\"", escape_bytes(bytes))
+}
+
+/// C escapes bytes the way `protoc` writes them in string literals and default values.
+pub(super) fn escape_bytes(bytes: &[u8]) -> String {
+    let mut result = String::new();
+    for &b in bytes {
+        match b {
+            b'"' => result.push_str("\\\""),
+            b'\\' => result.push_str("\\\\"),
+            b'\n' => result.push_str("\\n"),
+            b'\r' => result.push_str("\\r"),
+            b'\t' => result.push_str("\\t"),
+            0x20..=0x7e => result.push(b as char),
+            _ => result.push_str(&format!("\\{:03o}", b)),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+    use crate::descriptor::test::load_descriptors;
+
+    #[test]
+    fn to_proto_source_golden() {
+        let mut d = load_descriptors();
+        d.resolve_refs();
+        let expected = fs::read_to_string("testdata/descriptors.proto.golden").unwrap();
+        assert_eq!(d.to_proto_source(), expected);
+    }
+
+    #[test]
+    fn to_proto_source_unresolved_enum_defaults() {
+        let d = load_descriptors();
+        let source = d.to_proto_source();
+        assert!(source.contains(
+            "optional .protobuf_unittest.TestAllTypes.NestedEnum default_nested_enum = 81 \
+             [default = BAR];"
+        ));
+    }
+
+    #[test]
+    fn to_proto_source_nested() {
+        let mut d = Descriptors::new();
+        let mut outer = MessageDescriptor::new(".pkg.Outer");
+        outer.set_syntax(Syntax::Proto3);
+        outer.add_field(
+            FieldDescriptor::builder("inner")
+                .number(1)
+                .field_type(InternalFieldType::UnresolvedMessage(
+                    ".pkg.Outer.Inner".into(),
+                ))
+                .build(),
+        );
+        let mut inner = MessageDescriptor::new(".pkg.Outer.Inner");
+        inner.set_syntax(Syntax::Proto3);
+        inner.add_field(
+            FieldDescriptor::builder("values")
+                .number(2)
+                .label(FieldLabel::Repeated)
+                .packed(true)
+                .build(),
+        );
+        let mut kind = EnumDescriptor::new(".pkg.Outer.Kind");
+        kind.add_value(EnumValueDescriptor::new("UNKNOWN", 0));
+        d.add_message(inner);
+        d.add_message(outer);
+        d.add_enum(kind);
+        d.resolve_refs();
+
+        assert_eq!(
+            d.to_proto_source(),
+            "syntax = \"proto3\";\n\
+             \n\
+             package pkg;\n\
+             \n\
+             message Outer {\n\
+             \x20 .pkg.Outer.Inner inner = 1;\n\
+             \n\
+             \x20 enum Kind {\n\
+             \x20   UNKNOWN = 0;\n\
+             \x20 }\n\
+             \n\
+             \x20 message Inner {\n\
+             \x20   repeated int32 values = 2 [packed = true];\n\
+             \x20 }\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn render_default_values() {
+        assert_eq!(render_bytes(b"a\"\\\n\x01"), "\"a\\\"\\\\\\n\\001\"");
+        assert_eq!(render_float(f32::NEG_INFINITY), "-inf");
+        assert_eq!(render_float(f64::NAN), "nan");
+        assert_eq!(render_float(1.5f64), "1.5");
+        assert_eq!(render_float(-8e-28f32), "-0.0000000000000000000000000008");
+    }
+}
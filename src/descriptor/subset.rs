@@ -0,0 +1,175 @@
+//! Extraction of self-contained subsets of descriptor registries.
+use super::*;
+
+impl Descriptors {
+    /// Extracts a new registry that contains exactly the messages with the specified fully
+    /// qualified names and all types that they transitively reference; see `transitive_deps`.
+    ///
+    /// The subset doesn't refer to any type outside of itself, and its type references are
+    /// resolved.  Types keep the order that they have in this registry, along with the files that
+    /// they were loaded from.  Extensions of the included messages are included too, unless their
+    /// type is a message or enum outside of the subset.  The subset interns names if this registry
+    /// does.
+    ///
+    /// Returns an error if any root message or any referenced type is unknown.
+    pub fn subset(&self, roots: &[&str]) -> error::Result<Descriptors> {
+        let mut names = collections::HashSet::new();
+        for &root in roots {
+            let message =
+                self.message_by_name(root)
+                    .ok_or_else(|| error::Error::UnknownMessage {
+                        name: root.to_owned(),
+                    })?;
+            names.insert(message.name().to_owned());
+            names.extend(self.transitive_deps(root)?);
+        }
+
+        let mut subset = Descriptors {
+            names: self.names.as_ref().map(|_| collections::HashSet::new()),
+            ..Descriptors::new()
+        };
+        let mut files = collections::HashMap::new();
+        let mut copy_file = |subset: &mut Descriptors, file: Option<FileId>| {
+            file.map(|id| {
+                *files
+                    .entry(id)
+                    .or_insert_with(|| subset.add_file_info(self.files[id.0].clone()))
+            })
+        };
+
+        for (name, &id) in self.messages_by_name.iter() {
+            if names.contains(&**name) {
+                let mut message = self.messages[id.0].clone();
+                for field in &mut message.fields {
+                    field.field_type = self.unresolved(&field.field_type);
+                }
+                message.file = copy_file(&mut subset, message.file);
+                subset.add_message(message);
+            }
+        }
+        for (name, &id) in self.enums_by_name.iter() {
+            if names.contains(&**name) {
+                let mut enum_descriptor = self.enums[id.0].clone();
+                enum_descriptor.file = copy_file(&mut subset, enum_descriptor.file);
+                subset.add_enum(enum_descriptor);
+            }
+        }
+        for (key, field) in &self.extensions {
+            let field_type = self.unresolved(&field.field_type);
+            let included = match field_type {
+                InternalFieldType::UnresolvedMessage(ref name)
+                | InternalFieldType::UnresolvedGroup(ref name)
+                | InternalFieldType::UnresolvedEnum(ref name) => names.contains(&**name),
+                _ => true,
+            };
+            if names.contains(&key.0) && included {
+                let mut field = field.clone();
+                field.field_type = field_type;
+                subset.add_extension(key.0.clone(), field);
+                if let Some(name) = self.extension_names.get(key) {
+                    subset.extension_names.insert(key.clone(), name.clone());
+                }
+            }
+        }
+
+        subset.resolve_refs();
+        Ok(subset)
+    }
+
+    /// The equivalent of a field type of this registry that refers to other types by name.
+    fn unresolved(&self, field_type: &InternalFieldType) -> InternalFieldType {
+        match *field_type {
+            InternalFieldType::Message(id) => {
+                InternalFieldType::UnresolvedMessage(self.messages[id.0].name.clone())
+            }
+            InternalFieldType::Group(id) => {
+                InternalFieldType::UnresolvedGroup(self.messages[id.0].name.clone())
+            }
+            InternalFieldType::Enum(id) => {
+                InternalFieldType::UnresolvedEnum(self.enums[id.0].name.clone())
+            }
+            ref t => t.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::descriptor::test::load_descriptors;
+
+    fn is_resolved(descriptors: &Descriptors) -> bool {
+        descriptors
+            .messages
+            .iter()
+            .flat_map(|m| &m.fields)
+            .chain(descriptors.extensions.values())
+            .all(|f| {
+                !matches!(
+                    f.field_type,
+                    InternalFieldType::UnresolvedMessage(_)
+                        | InternalFieldType::UnresolvedGroup(_)
+                        | InternalFieldType::UnresolvedEnum(_)
+                )
+            })
+    }
+
+    #[test]
+    fn subset_resolves_on_its_own() {
+        let root = ".protobuf_unittest.TestAllTypes";
+        for resolved in &[false, true] {
+            let mut d = load_descriptors();
+            if *resolved {
+                d.resolve_refs();
+            }
+            let subset = d.subset(&[root]).unwrap();
+            assert!(is_resolved(&subset));
+
+            let mut expected = d.transitive_deps(root).unwrap();
+            expected.push(root.to_owned());
+            expected.sort();
+            let mut names = subset
+                .messages_by_name
+                .keys()
+                .chain(subset.enums_by_name.keys())
+                .map(|n| n.to_string())
+                .collect::<Vec<_>>();
+            names.sort();
+            assert_eq!(names, expected);
+            assert!(!subset.contains_message(".protobuf_unittest.TestAllExtensions"));
+
+            // The subset can be unresolved and resolved again without any other types
+            let mut subset = Descriptors::from_cache_bytes(&subset.to_cache_bytes()).unwrap();
+            subset.unresolve_refs();
+            subset.resolve_refs();
+            assert!(is_resolved(&subset));
+            assert_eq!(
+                subset.transitive_deps(root).unwrap().len(),
+                expected.len() - 1
+            );
+            assert_eq!(
+                subset.file_of_message(root).map(FileInfo::name),
+                d.file_of_message(root).map(FileInfo::name)
+            );
+        }
+    }
+
+    #[test]
+    fn subset_extensions() {
+        let d = load_descriptors();
+        let root = ".protobuf_unittest.TestAllExtensions";
+        let subset = d.subset(&[root]).unwrap();
+        assert!(is_resolved(&subset));
+        // Extensions of scalar types are kept, and those of types outside the subset dropped
+        assert!(subset.extension(root, 1).is_some());
+        assert!(d.extension(root, 18).is_some());
+        assert!(subset.extension(root, 18).is_none());
+
+        match d.subset(&[root, ".protobuf_unittest.Missing"]) {
+            Err(error::Error::UnknownMessage { name }) => {
+                assert_eq!(name, ".protobuf_unittest.Missing")
+            }
+            r => panic!("Expected an unknown message error, got {:?}", r.map(|_| ())),
+        }
+    }
+}
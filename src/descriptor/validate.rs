@@ -0,0 +1,294 @@
+//! Global consistency checks of descriptor registries.
+use super::*;
+
+impl Descriptors {
+    /// Checks the whole schema described by this registry for consistency, returning every problem
+    /// that was found.
+    ///
+    /// In addition to the per-field checks done by `FieldDescriptor::validate`, this checks that:
+    ///
+    ///   * field names and numbers are unique within each message;
+    ///   * JSON names of fields are unique within each `proto3` message;
+    ///   * field numbers are within the valid range, and outside of the range reserved for the
+    ///     protobuf implementation;
+    ///   * field numbers are outside of the reserved ranges and extension ranges of their message;
+    ///   * map entry messages have exactly a `key` field with number 1 and a `value` field with
+    ///     number 2, and that the key type is an integral, `bool` or `string` type;
+    ///   * enums used by `proto3` messages have a first value with number 0;
+    ///   * all type references can be resolved.
+    pub fn validate_schema(&self) -> Result<(), Vec<error::Error>> {
+        let mut errors = Vec::new();
+        let mut checked_enums = collections::HashSet::new();
+
+        for &id in self.messages_by_name.values() {
+            let message = &self.messages[id.0];
+            let mut names = collections::HashSet::new();
+            let mut numbers = collections::HashSet::new();
+            let mut json_names = collections::HashSet::new();
+
+            for field in message.fields() {
+                let qualified_name = message.qualified_field_name(field);
+
+                let new_name = names.insert(field.name());
+                if !new_name || !numbers.insert(field.number()) {
+                    errors.push(error::Error::DuplicateField {
+                        name: qualified_name.clone(),
+                        number: field.number(),
+                    });
+                }
+
+                // A field with a duplicate name has already been reported above
+                if new_name
+                    && message.syntax() == Syntax::Proto3
+                    && !json_names.insert(field.json_name())
+                {
+                    errors.push(error::Error::DuplicateJsonName {
+                        name: qualified_name.clone(),
+                        json_name: field.json_name().to_owned(),
+                    });
+                }
+
+                let number = field.number();
+                if !(MIN_FIELD_NUMBER..=MAX_FIELD_NUMBER).contains(&number)
+                    || (MIN_RESERVED_FIELD_NUMBER..=MAX_RESERVED_FIELD_NUMBER).contains(&number)
+                {
+                    errors.push(error::Error::BadFieldNumber {
+                        field: qualified_name.clone(),
+                        number,
+                    });
+                }
+                if message
+                    .reserved_ranges()
+                    .iter()
+                    .any(|r| r.contains(&number))
+                {
+                    errors.push(error::Error::BadField {
+                        field: qualified_name.clone(),
+                        reason: format!("field number {} is in a reserved range", number),
+                    });
+                }
+                if message
+                    .extension_ranges()
+                    .iter()
+                    .any(|r| r.contains(&number))
+                {
+                    errors.push(error::Error::BadField {
+                        field: qualified_name.clone(),
+                        reason: format!("field number {} is in an extension range", number),
+                    });
+                }
+
+                if let Err(error::Error::BadField { reason, .. }) = field.validate() {
+                    errors.push(error::Error::BadField {
+                        field: qualified_name.clone(),
+                        reason,
+                    });
+                }
+
+                match field.field_type(self) {
+                    FieldType::UnresolvedMessage(name) | FieldType::UnresolvedGroup(name) => errors
+                        .push(error::Error::UnknownMessage {
+                            name: name.to_owned(),
+                        }),
+                    FieldType::UnresolvedEnum(name) => errors.push(error::Error::UnknownEnum {
+                        name: name.to_owned(),
+                    }),
+                    FieldType::Enum(e)
+                        if message.syntax() == Syntax::Proto3
+                            && checked_enums.insert(e.name())
+                            && e.values().first().map(|v| v.number()) != Some(0) =>
+                    {
+                        errors.push(error::Error::BadType {
+                            name: e.name().to_owned(),
+                            reason: "the first value of a proto3 enum must be 0".to_owned(),
+                        })
+                    }
+                    _ => (),
+                }
+            }
+
+            if message.is_map_entry() {
+                if let Err(reason) = validate_map_entry(self, message) {
+                    errors.push(error::Error::BadType {
+                        name: message.name().to_owned(),
+                        reason: reason.to_owned(),
+                    });
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+fn validate_map_entry(
+    descriptors: &Descriptors,
+    message: &MessageDescriptor,
+) -> Result<(), &'static str> {
+    let (key, value) = match message.fields() {
+        [key, value] if key.number() == 1 && value.number() == 2 => (key, value),
+        _ => return Err("map entry must have exactly a key field 1 and a value field 2"),
+    };
+
+    if key.name() != "key" || value.name() != "value" {
+        return Err("map entry fields must be named key and value");
+    }
+    if key.field_label() != FieldLabel::Optional || value.field_label() != FieldLabel::Optional {
+        return Err("map entry fields must be optional");
+    }
+
+    match key.field_type(descriptors) {
+        FieldType::Int32
+        | FieldType::Int64
+        | FieldType::UInt32
+        | FieldType::UInt64
+        | FieldType::SInt32
+        | FieldType::SInt64
+        | FieldType::Fixed32
+        | FieldType::Fixed64
+        | FieldType::SFixed32
+        | FieldType::SFixed64
+        | FieldType::Bool
+        | FieldType::String => Ok(()),
+        _ => Err("map key must have an integral, bool or string type"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::descriptor::test::load_descriptors;
+
+    fn field(name: &str, number: i32) -> FieldDescriptor {
+        FieldDescriptor::builder(name).number(number).build()
+    }
+
+    #[test]
+    fn validate_schema_valid() {
+        let d = load_descriptors();
+        assert!(d.validate_schema().is_ok());
+    }
+
+    #[test]
+    fn validate_schema_reports_all_problems() {
+        let mut m = MessageDescriptor::new(".pkg.Message");
+        m.set_syntax(Syntax::Proto3);
+        m.add_field(field("a", 1));
+        // Bypass the duplicate checks of add_field
+        m.fields.push(field("a", 2));
+        m.add_field(field("reserved", 19_500));
+        m.add_field(
+            FieldDescriptor::builder("default")
+                .number(3)
+                .field_type(InternalFieldType::UnresolvedMessage(".pkg.Entry".into()))
+                .default_value(value::Value::I32(1))
+                .build(),
+        );
+        m.add_field(
+            FieldDescriptor::builder("missing")
+                .number(4)
+                .field_type(InternalFieldType::UnresolvedMessage(".pkg.Missing".into()))
+                .build(),
+        );
+        m.add_field(
+            FieldDescriptor::builder("color")
+                .number(5)
+                .field_type(InternalFieldType::UnresolvedEnum(".pkg.Color".into()))
+                .build(),
+        );
+
+        let mut entry = MessageDescriptor::new(".pkg.Entry");
+        entry.set_map_entry(true);
+        entry.add_field(
+            FieldDescriptor::builder("key")
+                .number(1)
+                .field_type(InternalFieldType::Double)
+                .build(),
+        );
+        entry.add_field(field("value", 2));
+
+        let mut color = EnumDescriptor::new(".pkg.Color");
+        color.add_value(EnumValueDescriptor::new("RED", 1));
+
+        let mut d = Descriptors::new();
+        d.add_message(m);
+        d.add_message(entry);
+        d.add_enum(color);
+
+        let errors = d.validate_schema().unwrap_err();
+        let descriptions = errors.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            descriptions,
+            vec![
+                "duplicate field .pkg.Message.a with number 2",
+                "bad field number for field .pkg.Message.reserved: 19500",
+                "bad field .pkg.Message.default: message type must not have a default value",
+                "unknown message: .pkg.Missing",
+                "bad type .pkg.Color: the first value of a proto3 enum must be 0",
+                "bad type .pkg.Entry: map key must have an integral, bool or string type",
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_schema_duplicate_json_name() {
+        let mut m = MessageDescriptor::new(".pkg.Message");
+        m.add_field(field("foo_bar", 1));
+        m.add_field(field("fooBar", 2));
+
+        let mut d = Descriptors::new();
+        d.add_message(m.clone());
+        // proto2 tolerates colliding JSON names
+        assert!(d.validate_schema().is_ok());
+
+        m.set_syntax(Syntax::Proto3);
+        let mut d = Descriptors::new();
+        d.add_message(m);
+        match d.validate_schema().unwrap_err().as_slice() {
+            [error::Error::DuplicateJsonName { name, json_name }] => {
+                assert_eq!(name, ".pkg.Message.fooBar");
+                assert_eq!(json_name, "fooBar");
+            }
+            errors => panic!("Expected a duplicate JSON name, got {:?}", errors),
+        }
+    }
+
+    #[test]
+    fn validate_schema_reserved_and_extension_ranges() {
+        let mut m = MessageDescriptor::new(".pkg.Message");
+        m.add_reserved_range(2..3);
+        m.add_reserved_range(9..12);
+        m.add_extension_range(100..200);
+        m.add_field(field("ok", 1));
+        m.add_field(field("reserved", 11));
+        m.add_field(field("extension", 100));
+        m.add_field(field("after", 200));
+
+        let mut d = Descriptors::new();
+        d.add_message(m);
+        let errors = d.validate_schema().unwrap_err();
+        let descriptions = errors.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            descriptions,
+            vec![
+                "bad field .pkg.Message.reserved: field number 11 is in a reserved range",
+                "bad field .pkg.Message.extension: field number 100 is in an extension range",
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_map_entry_shape() {
+        let d = Descriptors::new();
+        let mut entry = MessageDescriptor::new(".pkg.Entry");
+        entry.set_map_entry(true);
+        entry.add_field(field("key", 1));
+        assert!(validate_map_entry(&d, &entry).is_err());
+        entry.add_field(field("value", 2));
+        assert!(validate_map_entry(&d, &entry).is_ok());
+    }
+}
@@ -1,6 +1,13 @@
 //! Common error types for this crate.
 use std::fmt;
+#[cfg(feature = "protoc")]
+use std::io;
+#[cfg(feature = "protoc")]
+use std::path;
+#[cfg(feature = "protoc")]
+use std::process;
 use std::result;
+use std::string;
 
 use protobuf;
 use serde;
@@ -42,12 +49,185 @@ pub enum Error {
         /// The encountered wire type.
         wire_type: protobuf::rt::WireType,
     },
+    /// A length-delimited field that exceeds the maximum field size was encountered.
+    #[error("field too large: {size} bytes exceeds the maximum of {max_size} bytes")]
+    FieldTooLarge {
+        /// The size of the field, as claimed by its length prefix.
+        size: u64,
+        /// The maximum allowed field size.
+        max_size: u64,
+    },
+    /// A message contained more field occurrences than the maximum total number of fields.
+    #[error("too many fields: decoding exceeded the maximum of {max_fields} fields")]
+    TooManyFields {
+        /// The maximum allowed total number of field occurrences.
+        max_fields: u64,
+    },
+    /// Groups nested more deeply than the maximum group depth were encountered while skipping a
+    /// field; see `wire::MAX_GROUP_DEPTH`.
+    #[error("groups nested more than {max_depth} levels deep")]
+    GroupTooDeep {
+        /// The maximum allowed depth of nested groups.
+        max_depth: u32,
+    },
+    /// A string field with invalid UTF-8 contents was encountered.
+    #[error("invalid UTF-8 in string field {field}")]
+    InvalidUtf8 {
+        /// The name of the field.
+        field: String,
+        /// The underlying UTF-8 error.
+        #[source]
+        source: string::FromUtf8Error,
+    },
+    /// A field tag with an invalid wire type was encountered.
+    #[error("bad tag: {tag}")]
+    BadTag {
+        /// The encountered tag.
+        tag: u32,
+    },
     /// A default value that can't be parsed was received.
     #[error("bad default value: {default_value:?}")]
     BadDefaultValue {
         /// The default value that couldn't be parsed.
         default_value: String,
     },
+    /// A descriptor without a name was encountered.
+    #[error("descriptor without a name in {scope:?}")]
+    EmptyName {
+        /// The fully qualified name of the scope containing the nameless descriptor.
+        scope: String,
+    },
+    /// A field with a number outside of the valid field number range was encountered, or an item
+    /// of a message using the `MessageSet` wire format with such a type ID was decoded.
+    #[error("bad field number for field {field}: {number}")]
+    BadFieldNumber {
+        /// The fully qualified name of the field, or of the `type_id` field of the item.
+        field: String,
+        /// The invalid field number.
+        number: i32,
+    },
+    /// A field with the same name or number as an existing field was added to a message, or a
+    /// singular field occurred more than once while decoding with
+    /// `DecodeOptions::reject_duplicate_scalars`.
+    #[error("duplicate field {name} with number {number}")]
+    DuplicateField {
+        /// The name of the rejected field.
+        name: String,
+        /// The number of the rejected field.
+        number: i32,
+    },
+    /// Two fields of a `proto3` message have the same JSON name.
+    #[error("duplicate JSON name {json_name} of field {name}")]
+    DuplicateJsonName {
+        /// The fully qualified name of the field whose JSON name collides with an earlier field.
+        name: String,
+        /// The colliding JSON name.
+        json_name: String,
+    },
+    /// A message or enum type with the same name as an existing type was encountered.
+    #[error("duplicate type {name}")]
+    DuplicateType {
+        /// The fully qualified name of the type.
+        name: String,
+    },
+    /// A field with an inconsistent definition was encountered.
+    #[error("bad field {field}: {reason}")]
+    BadField {
+        /// The fully qualified name of the field.
+        field: String,
+        /// A description of the inconsistency.
+        reason: String,
+    },
+    /// A message or enum type with an inconsistent definition was encountered.
+    #[error("bad type {name}: {reason}")]
+    BadType {
+        /// The fully qualified name of the type.
+        name: String,
+        /// A description of the inconsistency.
+        reason: String,
+    },
+    /// A value that doesn't match its field or message type was encountered while encoding.
+    #[error("bad value for {name}: {reason}")]
+    BadValue {
+        /// The name of the field or message type.
+        name: String,
+        /// Why the value doesn't match.
+        reason: String,
+    },
+    /// A field path couldn't be resolved.
+    #[error("bad field path {path}: {reason}")]
+    BadFieldPath {
+        /// The field path.
+        path: String,
+        /// Why the field path couldn't be resolved.
+        reason: String,
+    },
+    /// A message was missing a value for a `required` field.
+    #[error("missing required field {field}")]
+    MissingRequiredField {
+        /// The name of the field.
+        field: String,
+    },
+    /// An element of a repeated field could not be decoded.
+    #[error("bad element {index} of field {field}: {source}")]
+    InField {
+        /// The name of the field.
+        field: String,
+        /// The index of the element within the field.
+        index: usize,
+        /// The error that occurred while decoding the element.
+        #[source]
+        source: Box<Error>,
+    },
+    /// Decoding failed at a specific position of the input.
+    #[error("decoding failed at byte offset {offset}: {source}")]
+    DecodeAt {
+        /// The byte offset of the field whose decoding failed, relative to the start of the input
+        /// stream.
+        offset: usize,
+        /// The error that occurred while decoding the field.
+        #[source]
+        source: Box<Error>,
+    },
+    /// Bytes remained in the input after a complete frame was decoded.
+    #[error("{remaining} trailing bytes after the frame")]
+    TrailingData {
+        /// The number of bytes after the end of the frame.
+        remaining: usize,
+    },
+    /// A descriptor cache was created by an incompatible version of this library.
+    #[error("unsupported descriptor cache version {version}")]
+    UnsupportedCacheVersion {
+        /// The version of the cache.
+        version: u32,
+    },
+    /// A descriptor cache is malformed.
+    #[error("invalid descriptor cache at offset {offset}: {reason}")]
+    InvalidCache {
+        /// Why the cache is invalid.
+        reason: String,
+        /// The byte offset at which the problem was detected.
+        offset: usize,
+    },
+    /// The `protoc` compiler couldn't be run.
+    #[cfg(feature = "protoc")]
+    #[error("could not run protoc at {}", path.display())]
+    ProtocNotFound {
+        /// The path of the `protoc` compiler.
+        path: path::PathBuf,
+        /// The underlying I/O error.
+        #[source]
+        source: io::Error,
+    },
+    /// The `protoc` compiler failed to compile some `.proto` files.
+    #[cfg(feature = "protoc")]
+    #[error("protoc failed ({status}): {stderr}")]
+    ProtocFailed {
+        /// The exit status of `protoc`.
+        status: process::ExitStatus,
+        /// The error output of `protoc`.
+        stderr: String,
+    },
     /// Some user-defined error occurred.
     #[error("{message}")]
     Custom {
@@ -63,6 +243,19 @@ pub type CompatResult<A> = result::Result<A, CompatError>;
 #[derive(Debug, Error)]
 pub struct CompatError(#[from] Error);
 
+impl Error {
+    /// The byte offset at which decoding failed, if known; see `Error::DecodeAt`.
+    ///
+    /// This also finds the offset of errors within elements of repeated fields.
+    pub fn offset(&self) -> Option<usize> {
+        match *self {
+            Error::DecodeAt { offset, .. } => Some(offset),
+            Error::InField { ref source, .. } => source.offset(),
+            _ => None,
+        }
+    }
+}
+
 impl From<protobuf::Error> for Error {
     fn from(e: protobuf::Error) -> Self {
         Error::Protobuf(e)
@@ -0,0 +1,721 @@
+//! Conversion between runtime values and the canonical protocol buffer JSON mapping.
+//!
+//! Unlike the structural conversion of `serde_json::Value::from(&value::Value)`, this needs the
+//! descriptor of the message type, and follows the
+//! [JSON mapping](https://developers.google.com/protocol-buffers/docs/proto3#json): fields are
+//! keyed by their JSON names, enums become their value names, 64-bit integers become strings,
+//! bytes become base64 strings, and map fields become objects.
+//!
+//! The dynamic JSON well-known types `google.protobuf.Struct`, `google.protobuf.Value` and
+//! `google.protobuf.ListValue` become the JSON object, value and array that they hold, and the
+//! `google.protobuf.NullValue` enum becomes `null`.  Other well-known types, such as
+//! `google.protobuf.Timestamp`, are treated like any other message.
+//!
+//! This module requires the `json` feature.
+use std::collections;
+use std::convert::TryFrom;
+
+use protobuf;
+use serde_json;
+
+use crate::descriptor;
+use crate::error;
+use crate::value;
+
+const STRUCT_NAME: &str = ".google.protobuf.Struct";
+const VALUE_NAME: &str = ".google.protobuf.Value";
+const LIST_VALUE_NAME: &str = ".google.protobuf.ListValue";
+const NULL_VALUE_NAME: &str = ".google.protobuf.NullValue";
+
+// The JSON names of the fields of the dynamic JSON well-known types
+const STRUCT_FIELDS: &str = "fields";
+const LIST_VALUE_VALUES: &str = "values";
+const VALUE_KINDS: [&str; 6] = [
+    "nullValue",
+    "numberValue",
+    "stringValue",
+    "boolValue",
+    "structValue",
+    "listValue",
+];
+
+/// Converts a message value of the specified message type into its canonical JSON form.
+///
+/// Unset fields are left out, and unknown fields and extensions are dropped.
+pub fn to_value(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    value: &value::Value,
+) -> error::Result<serde_json::Value> {
+    match *value {
+        value::Value::Message(ref message) => message_to_json(descriptors, descriptor, message),
+        _ => Err(bad_value(descriptor.name(), "expected a message")),
+    }
+}
+
+/// Converts a JSON value in canonical form into a message value of the specified message type.
+///
+/// Fields may be keyed by either their JSON names or their original names, and fields whose value
+/// is `null` are left unset, like fields that are missing from the JSON object even if they have a
+/// declared default value.  Integers and floats are accepted both as JSON numbers and as
+/// strings, and enums both as value names and as numbers.
+pub fn from_value(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    json: &serde_json::Value,
+) -> error::Result<value::Value> {
+    message_from_json(descriptors, descriptor, json).map(value::Value::Message)
+}
+
+/// Decodes a binary encoded message of the specified type directly into its canonical JSON form;
+/// see `de::to_json`.
+///
+/// Fields are converted to JSON as soon as they have been read, so the message itself is never
+/// materialized as a `value::Message`.  Only singular message fields, whose occurrences have to be
+/// merged, and map fields, whose entries are needed as a whole, are decoded into values first.
+pub(crate) fn decode_to_json(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    input: &mut protobuf::CodedInputStream,
+) -> error::Result<serde_json::Value> {
+    let options = value::DecodeOptions::default();
+    if [STRUCT_NAME, VALUE_NAME, LIST_VALUE_NAME].contains(&descriptor.name()) {
+        let mut message = value::Message::new(descriptor);
+        message.merge_from_with_options(descriptors, descriptor, input, &options)?;
+        return message_to_json(descriptors, descriptor, &message);
+    }
+
+    // A single decode operation for all fields, so that the required fields of singular message
+    // fields only have to occur in one of their occurrences
+    let context = value::DecodeContext::new(&options);
+    let mut converted = collections::BTreeMap::new();
+    // Singular message fields, decoded straight from the input so that their occurrences are
+    // merged
+    let mut messages = collections::BTreeMap::<i32, value::Field>::new();
+    let mut map_entries = collections::BTreeMap::<i32, Vec<value::Value>>::new();
+    let mut seen_required = collections::BTreeSet::new();
+    while !input.eof()? {
+        let (number, wire_type) = value::read_tag(input)?;
+        let field = match descriptor.field_by_number(number as i32) {
+            Some(field) => field,
+            None => {
+                value::skip_field(input, wire_type, &options)?;
+                continue;
+            }
+        };
+        if field.field_label() == descriptor::FieldLabel::Required {
+            seen_required.insert(field.number());
+        }
+
+        let is_map = match field.field_type(descriptors) {
+            descriptor::FieldType::Message(_) if !field.is_repeated() => {
+                messages
+                    .entry(field.number())
+                    .or_insert_with(|| value::Field::new(field))
+                    .merge_value(descriptors, field, input, wire_type, &context)?;
+                continue;
+            }
+            descriptor::FieldType::Message(m) => m.is_map_entry(),
+            _ => false,
+        };
+
+        let mut decoded = value::Field::new(field);
+        decoded.merge_value(descriptors, field, input, wire_type, &context)?;
+        match decoded {
+            value::Field::Singular(Some(v)) => {
+                converted.insert(field.number(), field_value_to_json(descriptors, field, &v)?);
+            }
+            value::Field::Repeated(vs) if is_map => {
+                map_entries.entry(field.number()).or_default().extend(vs)
+            }
+            value::Field::Repeated(vs) if !vs.is_empty() => {
+                let json = converted
+                    .entry(field.number())
+                    .or_insert_with(|| serde_json::Value::Array(Vec::new()));
+                if let serde_json::Value::Array(ref mut array) = *json {
+                    for v in vs {
+                        array.push(field_value_to_json(descriptors, field, &v)?);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    options.check_required(descriptor, &seen_required)?;
+    context.check_nested_required()?;
+
+    let mut object = serde_json::Map::new();
+    for field in descriptor.fields() {
+        let json = if let Some(value::Field::Singular(Some(v))) = messages.remove(&field.number()) {
+            field_value_to_json(descriptors, field, &v)?
+        } else if let Some(entries) = map_entries.remove(&field.number()) {
+            repeated_to_json(descriptors, field, &entries)?
+        } else if let Some(json) = converted.remove(&field.number()) {
+            json
+        } else {
+            continue;
+        };
+        object.insert(field.json_name().to_owned(), json);
+    }
+    Ok(serde_json::Value::Object(object))
+}
+
+fn message_to_json(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    message: &value::Message,
+) -> error::Result<serde_json::Value> {
+    let mut object = serde_json::Map::new();
+    for field in descriptor.fields() {
+        let json = match message.fields.get(&field.number()) {
+            Some(&value::Field::Singular(Some(ref v))) => {
+                field_value_to_json(descriptors, field, v)?
+            }
+            Some(value::Field::Repeated(vs)) if !vs.is_empty() => {
+                repeated_to_json(descriptors, field, vs)?
+            }
+            _ => continue,
+        };
+        object.insert(field.json_name().to_owned(), json);
+    }
+
+    Ok(match descriptor.name() {
+        STRUCT_NAME => object
+            .remove(STRUCT_FIELDS)
+            .unwrap_or_else(|| serde_json::Value::Object(serde_json::Map::new())),
+        LIST_VALUE_NAME => object
+            .remove(LIST_VALUE_VALUES)
+            .unwrap_or_else(|| serde_json::Value::Array(Vec::new())),
+        VALUE_NAME => VALUE_KINDS
+            .iter()
+            .find_map(|kind| object.remove(*kind))
+            .unwrap_or(serde_json::Value::Null),
+        _ => serde_json::Value::Object(object),
+    })
+}
+
+fn repeated_to_json(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    values: &[value::Value],
+) -> error::Result<serde_json::Value> {
+    let entry = match field.field_type(descriptors) {
+        descriptor::FieldType::Message(m) if m.is_map_entry() => m,
+        _ => {
+            return values
+                .iter()
+                .map(|v| field_value_to_json(descriptors, field, v))
+                .collect()
+        }
+    };
+
+    let (key_field, value_field) = match (entry.field_by_number(1), entry.field_by_number(2)) {
+        (Some(k), Some(v)) => (k, v),
+        _ => return Err(bad_value(entry.name(), "map entry without key or value")),
+    };
+    let mut object = serde_json::Map::new();
+    for v in values {
+        let entry_value = match *v {
+            value::Value::Message(ref m) => m,
+            _ => return Err(bad_value(field.name(), "expected a map entry")),
+        };
+        let key = match entry_value.fields.get(&1) {
+            Some(&value::Field::Singular(Some(ref k))) => map_key_to_string(key_field, k)?,
+            _ => String::new(),
+        };
+        let json = match entry_value.fields.get(&2) {
+            Some(&value::Field::Singular(Some(ref v))) => {
+                field_value_to_json(descriptors, value_field, v)?
+            }
+            _ => match value_field.field_type(descriptors) {
+                descriptor::FieldType::Message(m) => {
+                    message_to_json(descriptors, m, &value::Message::new(m))?
+                }
+                _ => match value::scalar_default(descriptors, value_field) {
+                    Some(v) => field_value_to_json(descriptors, value_field, &v)?,
+                    None => serde_json::Value::Null,
+                },
+            },
+        };
+        object.insert(key, json);
+    }
+    Ok(serde_json::Value::Object(object))
+}
+
+fn field_value_to_json(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    value: &value::Value,
+) -> error::Result<serde_json::Value> {
+    use crate::descriptor::FieldType as T;
+    use crate::value::Value as V;
+
+    Ok(match (field.field_type(descriptors), value) {
+        (_, &V::Bool(v)) => v.into(),
+        (_, &V::I32(v)) => v.into(),
+        (_, &V::U32(v)) => v.into(),
+        (_, &V::I64(v)) => v.to_string().into(),
+        (_, &V::U64(v)) => v.to_string().into(),
+        (_, &V::F32(v)) => float_to_json(f64::from(v)),
+        (_, &V::F64(v)) => float_to_json(v),
+        (_, V::Bytes(v)) => value::base64_encode(v).into(),
+        (_, V::String(v)) => v.as_str().into(),
+        (T::Enum(e), _) if e.name() == NULL_VALUE_NAME => serde_json::Value::Null,
+        (T::Enum(e), &V::Enum(v)) => match e.name_for(v) {
+            Some(name) => name.into(),
+            None => v.into(),
+        },
+        (_, &V::Enum(v)) => v.into(),
+        (T::Message(m), V::Message(v)) | (T::Group(m), V::Message(v)) => {
+            message_to_json(descriptors, m, v)?
+        }
+        (T::UnresolvedMessage(name), _) | (T::UnresolvedGroup(name), _) => {
+            return Err(error::Error::UnknownMessage {
+                name: name.to_owned(),
+            })
+        }
+        (_, V::Message(_)) => return Err(bad_value(field.name(), "unexpected message")),
+    })
+}
+
+fn map_key_to_string(
+    field: &descriptor::FieldDescriptor,
+    key: &value::Value,
+) -> error::Result<String> {
+    match *key {
+        value::Value::Bool(v) => Ok(v.to_string()),
+        value::Value::I32(v) => Ok(v.to_string()),
+        value::Value::I64(v) => Ok(v.to_string()),
+        value::Value::U32(v) => Ok(v.to_string()),
+        value::Value::U64(v) => Ok(v.to_string()),
+        value::Value::String(ref v) => Ok(v.clone()),
+        _ => Err(bad_value(field.name(), "bad map key")),
+    }
+}
+
+fn float_to_json(v: f64) -> serde_json::Value {
+    if v.is_nan() {
+        "NaN".into()
+    } else if v == f64::INFINITY {
+        "Infinity".into()
+    } else if v == f64::NEG_INFINITY {
+        "-Infinity".into()
+    } else {
+        v.into()
+    }
+}
+
+fn message_from_json(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    json: &serde_json::Value,
+) -> error::Result<value::Message> {
+    // Wrap the dynamic JSON well-known types into the generic message form
+    let wrapped;
+    let json = match (descriptor.name(), json) {
+        (STRUCT_NAME, _) | (LIST_VALUE_NAME, _) | (VALUE_NAME, _) => {
+            let key = match (descriptor.name(), json) {
+                (STRUCT_NAME, _) => STRUCT_FIELDS,
+                (LIST_VALUE_NAME, _) => LIST_VALUE_VALUES,
+                (_, serde_json::Value::Null) => VALUE_KINDS[0],
+                (_, serde_json::Value::Number(_)) => VALUE_KINDS[1],
+                (_, serde_json::Value::String(_)) => VALUE_KINDS[2],
+                (_, serde_json::Value::Bool(_)) => VALUE_KINDS[3],
+                (_, serde_json::Value::Object(_)) => VALUE_KINDS[4],
+                (_, serde_json::Value::Array(_)) => VALUE_KINDS[5],
+            };
+            let mut object = serde_json::Map::new();
+            object.insert(key.to_owned(), json.clone());
+            wrapped = serde_json::Value::Object(object);
+            &wrapped
+        }
+        _ => json,
+    };
+
+    let object = match *json {
+        serde_json::Value::Object(ref object) => object,
+        _ => return Err(bad_value(descriptor.name(), "expected a JSON object")),
+    };
+
+    let mut message = value::Message::new(descriptor);
+    for (key, json) in object {
+        let field = match descriptor
+            .fields()
+            .iter()
+            .find(|f| f.json_name() == key || f.name() == key)
+        {
+            Some(f) => f,
+            None => {
+                return Err(bad_value(
+                    descriptor.name(),
+                    &format!("unknown field {}", key),
+                ))
+            }
+        };
+        // A null value leaves the field unset, except for the null value of the `Value` type
+        let is_null_value = match field.field_type(descriptors) {
+            descriptor::FieldType::Enum(e) => e.name() == NULL_VALUE_NAME,
+            _ => false,
+        };
+        if json.is_null() && !is_null_value {
+            continue;
+        }
+
+        let field_value = if field.is_repeated() {
+            value::Field::Repeated(repeated_from_json(descriptors, field, json)?)
+        } else {
+            value::Field::Singular(Some(field_value_from_json(descriptors, field, json)?))
+        };
+        message.fields.insert(field.number(), field_value);
+    }
+    Ok(message)
+}
+
+fn repeated_from_json(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    json: &serde_json::Value,
+) -> error::Result<Vec<value::Value>> {
+    match (field.field_type(descriptors), json) {
+        (descriptor::FieldType::Message(entry), serde_json::Value::Object(object))
+            if entry.is_map_entry() =>
+        {
+            let (key_field, value_field) =
+                match (entry.field_by_number(1), entry.field_by_number(2)) {
+                    (Some(k), Some(v)) => (k, v),
+                    _ => return Err(bad_value(entry.name(), "map entry without key or value")),
+                };
+            object
+                .iter()
+                .map(|(key, json)| {
+                    let key_json = match key_field.field_type(descriptors) {
+                        descriptor::FieldType::String => serde_json::Value::from(key.as_str()),
+                        descriptor::FieldType::Bool => match key.as_str() {
+                            "true" => true.into(),
+                            "false" => false.into(),
+                            _ => return Err(bad_value(field.name(), "bad map key")),
+                        },
+                        _ => serde_json::Value::from(key.as_str()),
+                    };
+                    let mut entry_value = value::Message::new(entry);
+                    entry_value.fields.insert(
+                        1,
+                        value::Field::Singular(Some(field_value_from_json(
+                            descriptors,
+                            key_field,
+                            &key_json,
+                        )?)),
+                    );
+                    entry_value.fields.insert(
+                        2,
+                        value::Field::Singular(Some(field_value_from_json(
+                            descriptors,
+                            value_field,
+                            json,
+                        )?)),
+                    );
+                    Ok(value::Value::Message(entry_value))
+                })
+                .collect()
+        }
+        (_, serde_json::Value::Array(values)) => values
+            .iter()
+            .map(|v| field_value_from_json(descriptors, field, v))
+            .collect(),
+        _ => Err(bad_value(field.name(), "expected a JSON array")),
+    }
+}
+
+fn field_value_from_json(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    json: &serde_json::Value,
+) -> error::Result<value::Value> {
+    use crate::descriptor::FieldType as T;
+    use crate::value::Value as V;
+    use serde_json::Value as J;
+
+    let bad = |reason: &str| bad_value(field.name(), reason);
+    let integer = || {
+        match *json {
+            J::Number(ref n) => n
+                .as_i64()
+                .map(i128::from)
+                .or_else(|| n.as_u64().map(i128::from))
+                .or_else(|| n.as_f64().filter(|f| f.fract() == 0.0).map(|f| f as i128)),
+            J::String(ref s) => s.parse::<i128>().ok(),
+            _ => None,
+        }
+        .ok_or_else(|| bad("expected an integer"))
+    };
+    let float = || {
+        match *json {
+            J::Number(ref n) => n.as_f64(),
+            J::String(ref s) => match s.as_str() {
+                "NaN" => Some(f64::NAN),
+                "Infinity" => Some(f64::INFINITY),
+                "-Infinity" => Some(f64::NEG_INFINITY),
+                s => s.parse().ok(),
+            },
+            _ => None,
+        }
+        .ok_or_else(|| bad("expected a number"))
+    };
+    macro_rules! int {
+        ($variant:path, $ty:ty) => {{
+            let v = integer()?;
+            <$ty>::try_from(v)
+                .map($variant)
+                .map_err(|_| bad("integer out of range"))
+        }};
+    }
+
+    match field.field_type(descriptors) {
+        T::Bool => json
+            .as_bool()
+            .map(V::Bool)
+            .ok_or_else(|| bad("expected a boolean")),
+        T::Int32 | T::SInt32 | T::SFixed32 => int!(V::I32, i32),
+        T::Int64 | T::SInt64 | T::SFixed64 => int!(V::I64, i64),
+        T::UInt32 | T::Fixed32 => int!(V::U32, u32),
+        T::UInt64 | T::Fixed64 => int!(V::U64, u64),
+        T::Float => float().map(|v| V::F32(v as f32)),
+        T::Double => float().map(V::F64),
+        T::String => json
+            .as_str()
+            .map(|s| V::String(s.to_owned()))
+            .ok_or_else(|| bad("expected a string")),
+        T::Bytes => json
+            .as_str()
+            .and_then(base64_decode)
+            .map(V::Bytes)
+            .ok_or_else(|| bad("expected a base64 string")),
+        T::Enum(e) if e.name() == NULL_VALUE_NAME => Ok(V::Enum(0)),
+        T::Enum(e) => match *json {
+            J::String(ref name) => e
+                .value_by_name(name)
+                .map(|v| V::Enum(v.number()))
+                .ok_or_else(|| bad("unknown enum value name")),
+            _ => int!(V::Enum, i32),
+        },
+        T::Message(m) | T::Group(m) => message_from_json(descriptors, m, json).map(V::Message),
+        T::UnresolvedEnum(name) => Err(error::Error::UnknownEnum {
+            name: name.to_owned(),
+        }),
+        T::UnresolvedMessage(name) | T::UnresolvedGroup(name) => {
+            Err(error::Error::UnknownMessage {
+                name: name.to_owned(),
+            })
+        }
+    }
+}
+
+fn bad_value(name: &str, reason: &str) -> error::Error {
+    error::Error::BadValue {
+        name: name.to_owned(),
+        reason: reason.to_owned(),
+    }
+}
+
+/// Decodes both the standard and the URL-safe base64 alphabets, with or without padding.
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() / 4 * 3);
+    let mut n = 0u32;
+    let mut bits = 0;
+    for c in s.trim_end_matches('=').bytes() {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return None,
+        };
+        n = n << 6 | u32::from(v);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((n >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::descriptor::{
+        Descriptors, EnumDescriptor, EnumValueDescriptor, FieldDescriptor, FieldLabel,
+        InternalFieldType, MessageDescriptor,
+    };
+
+    fn field(name: &str, number: i32, field_type: InternalFieldType) -> FieldDescriptor {
+        FieldDescriptor::builder(name)
+            .number(number)
+            .field_type(field_type)
+            .build()
+    }
+
+    fn message_type(name: &str) -> InternalFieldType {
+        InternalFieldType::UnresolvedMessage(name.into())
+    }
+
+    fn descriptors() -> Descriptors {
+        let mut d = Descriptors::new();
+
+        let mut entry = MessageDescriptor::new(".google.protobuf.Struct.FieldsEntry");
+        entry.set_map_entry(true);
+        entry.add_field(field("key", 1, InternalFieldType::String));
+        entry.add_field(field("value", 2, message_type(VALUE_NAME)));
+        d.add_message(entry);
+
+        let mut structure = MessageDescriptor::new(STRUCT_NAME);
+        structure.add_field(
+            FieldDescriptor::builder("fields")
+                .number(1)
+                .label(FieldLabel::Repeated)
+                .field_type(message_type(".google.protobuf.Struct.FieldsEntry"))
+                .build(),
+        );
+        d.add_message(structure);
+
+        let mut value = MessageDescriptor::new(VALUE_NAME);
+        value.add_field(field(
+            "null_value",
+            1,
+            InternalFieldType::UnresolvedEnum(NULL_VALUE_NAME.into()),
+        ));
+        value.add_field(field("number_value", 2, InternalFieldType::Double));
+        value.add_field(field("string_value", 3, InternalFieldType::String));
+        value.add_field(field("bool_value", 4, InternalFieldType::Bool));
+        value.add_field(field("struct_value", 5, message_type(STRUCT_NAME)));
+        value.add_field(field("list_value", 6, message_type(LIST_VALUE_NAME)));
+        d.add_message(value);
+
+        let mut list = MessageDescriptor::new(LIST_VALUE_NAME);
+        list.add_field(
+            FieldDescriptor::builder("values")
+                .number(1)
+                .label(FieldLabel::Repeated)
+                .field_type(message_type(VALUE_NAME))
+                .build(),
+        );
+        d.add_message(list);
+
+        let mut null_value = EnumDescriptor::new(NULL_VALUE_NAME);
+        null_value.add_value(EnumValueDescriptor::new("NULL_VALUE", 0));
+        d.add_enum(null_value);
+
+        let mut color = EnumDescriptor::new(".test.Color");
+        color.add_value(EnumValueDescriptor::new("RED", 0));
+        color.add_value(EnumValueDescriptor::new("BLUE", 1));
+        d.add_enum(color);
+
+        let mut event = MessageDescriptor::new(".test.Event");
+        event.add_field(field("event_id", 1, InternalFieldType::Int64));
+        event.add_field(field("payload", 2, InternalFieldType::Bytes));
+        event.add_field(field(
+            "color",
+            3,
+            InternalFieldType::UnresolvedEnum(".test.Color".into()),
+        ));
+        event.add_field(field("ratio", 4, InternalFieldType::Float));
+        event.add_field(field("details", 5, message_type(STRUCT_NAME)));
+        d.add_message(event);
+
+        d.resolve_refs();
+        d
+    }
+
+    #[test]
+    fn round_trip_well_known_types() {
+        let d = descriptors();
+        let event = d.message_by_name(".test.Event").unwrap();
+        let json = serde_json::json!({
+            "eventId": "9007199254740993",
+            "payload": "AAEC/w==",
+            "color": "BLUE",
+            "ratio": "NaN",
+            "details": {
+                "name": "bob",
+                "age": 42.0,
+                "admin": false,
+                "manager": null,
+                "tags": ["a", 1.5, null, {"nested": true}],
+            },
+        });
+
+        let value = from_value(&d, event, &json).unwrap();
+        let message = match value {
+            value::Value::Message(ref m) => m,
+            ref v => panic!("Expected a message, got {:?}", v),
+        };
+        match message.fields.get(&1) {
+            Some(value::Field::Singular(Some(value::Value::I64(9_007_199_254_740_993)))) => (),
+            f => panic!("Expected an int64 value, got {:?}", f),
+        }
+        match message.fields.get(&2) {
+            Some(value::Field::Singular(Some(value::Value::Bytes(b)))) => {
+                assert_eq!(b, &[0, 1, 2, 255])
+            }
+            f => panic!("Expected a bytes value, got {:?}", f),
+        }
+        assert_eq!(to_value(&d, event, &value).unwrap(), json);
+    }
+
+    #[test]
+    fn empty_well_known_types() {
+        let d = descriptors();
+        let structure = d.message_by_name(STRUCT_NAME).unwrap();
+        let value = d.message_by_name(VALUE_NAME).unwrap();
+        let list = d.message_by_name(LIST_VALUE_NAME).unwrap();
+        let empty = |m| value::Value::Message(value::Message::new(m));
+
+        assert_eq!(
+            to_value(&d, structure, &empty(structure)).unwrap(),
+            serde_json::json!({})
+        );
+        assert_eq!(
+            to_value(&d, value, &empty(value)).unwrap(),
+            serde_json::Value::Null
+        );
+        assert_eq!(
+            to_value(&d, list, &empty(list)).unwrap(),
+            serde_json::json!([])
+        );
+    }
+
+    #[test]
+    fn bad_json() {
+        let d = descriptors();
+        let event = d.message_by_name(".test.Event").unwrap();
+        for json in &[
+            serde_json::json!({"unknown": 1}),
+            serde_json::json!({"eventId": "x"}),
+            serde_json::json!({"color": "GREEN"}),
+            serde_json::json!({"payload": "not base64!"}),
+            serde_json::json!([]),
+        ] {
+            match from_value(&d, event, json) {
+                Err(error::Error::BadValue { .. }) => (),
+                r => panic!("Expected a bad value error for {}, got {:?}", json, r),
+            }
+        }
+    }
+
+    #[test]
+    fn base64() {
+        for (bytes, encoded) in &[
+            (&b""[..], ""),
+            (&b"f"[..], "Zg=="),
+            (&b"fo"[..], "Zm8="),
+            (&b"foo"[..], "Zm9v"),
+            (&[0xfb, 0xff][..], "+/8="),
+        ] {
+            assert_eq!(value::base64_encode(bytes), *encoded);
+            assert_eq!(base64_decode(encoded).unwrap(), *bytes);
+        }
+        assert_eq!(base64_decode("-_8").unwrap(), vec![0xfb, 0xff]);
+    }
+}
@@ -8,8 +8,14 @@
 //!     buffer decoded data (but the representation is heavily coupled with a schema).
 //!   * The [`de`](de/index.html) module can be used to deserialize binary encoded protocol buffer
 //!     messages given some schema descriptors.
+//!   * The [`ser`](ser/index.html) module can be used to encode the values of the `value` module
+//!     back into binary protocol buffer messages.
+//!   * The [`json`](json/index.html) module, which requires the `json` feature, converts values to
+//!     and from the canonical protocol buffer JSON mapping.
+//!   * The [`wire`](wire/index.html) module provides low-level primitives for reading the wire
+//!     format without any descriptors.
 //!
-//! Serialization is not yet implemented in this version.
+//! Serialization through `serde` is not yet implemented in this version.
 //!
 //! [1]: https://developers.google.com/protocol-buffers/
 #![deny(warnings)]
@@ -31,6 +37,10 @@ extern crate serde;
 pub mod de;
 pub mod descriptor;
 pub mod error;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod ser;
 pub mod value;
+pub mod wire;
 
 pub use crate::error::Error;
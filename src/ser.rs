@@ -0,0 +1,748 @@
+//! Serialization of runtime values into binary protocol buffer encoded data.
+//!
+//! This is the inverse of decoding into a [`value::Message`](../value/struct.Message.html): all
+//! known fields are encoded according to their descriptors, followed by the extensions and the
+//! unknown fields of the message.
+//!
+//! ```
+//! use serde_protobuf::descriptor::{Descriptors, FieldDescriptor, MessageDescriptor};
+//! use serde_protobuf::{de, ser, value};
+//!
+//! let mut message = MessageDescriptor::new(".pkg.Message");
+//! message.add_field(FieldDescriptor::builder("id").number(1).build());
+//! let mut descriptors = Descriptors::new();
+//! descriptors.add_message(message);
+//! let message = descriptors.message_by_name(".pkg.Message").unwrap();
+//!
+//! let mut value = value::Message::new(message);
+//! value.fields.insert(1, value::Field::Singular(Some(value::Value::I32(7))));
+//! let value = value::Value::Message(value);
+//!
+//! let mut stream = Vec::new();
+//! ser::write_delimited(&descriptors, message, &value, &mut stream).unwrap();
+//! ser::write_delimited(&descriptors, message, &value, &mut stream).unwrap();
+//! assert_eq!(stream, vec![2, 8, 7, 2, 8, 7]);
+//!
+//! let mut reader = stream.as_slice();
+//! assert!(de::read_delimited(&descriptors, message, &mut reader).is_some());
+//! assert!(de::read_delimited(&descriptors, message, &mut reader).is_some());
+//! assert!(de::read_delimited(&descriptors, message, &mut reader).is_none());
+//! ```
+//!
+//! By default, known fields are written in the order that they are declared in the message
+//! descriptor.  For reproducible output, e.g. for signing or for use as cache keys, use
+//! `EncodeOptions::deterministic`.
+use std::io;
+
+use protobuf;
+use protobuf::rt::WireType;
+
+use crate::descriptor;
+use crate::error;
+use crate::value;
+
+/// Options that control how protocol buffer data is encoded.
+#[derive(Clone, Debug, Default)]
+pub struct EncodeOptions {
+    deterministic: bool,
+}
+
+impl EncodeOptions {
+    /// Creates encode options with the default settings.
+    pub fn new() -> EncodeOptions {
+        EncodeOptions::default()
+    }
+
+    /// Sets whether the output is deterministic.
+    ///
+    /// Deterministic output follows protobuf's documented deterministic serialization, so that
+    /// messages with equal contents are encoded to identical bytes: known fields and extensions
+    /// are written in ascending field number order, the entries of map fields in ascending key
+    /// order, and unknown fields after them in ascending field number order.  By default, known
+    /// fields are written in the order that they are declared in the message descriptor, and map
+    /// entries and unknown fields in the order that they are stored.
+    ///
+    /// As with other protobuf implementations, deterministic output isn't canonical across
+    /// schema versions or implementations, so it shouldn't be relied upon across them.
+    pub fn deterministic(mut self, deterministic: bool) -> EncodeOptions {
+        self.deterministic = deterministic;
+        self
+    }
+}
+
+/// Encodes a message value of the specified message type into a byte vector.
+pub fn to_bytes(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    value: &value::Value,
+) -> error::Result<Vec<u8>> {
+    to_bytes_with_options(descriptors, descriptor, value, &EncodeOptions::default())
+}
+
+/// Encodes a message value of the specified message type into a byte vector, using the specified
+/// encode options.
+pub fn to_bytes_with_options(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    value: &value::Value,
+    options: &EncodeOptions,
+) -> error::Result<Vec<u8>> {
+    match *value {
+        value::Value::Message(ref message) => {
+            message_to_bytes(descriptors, descriptor, message, options)
+        }
+        _ => Err(error::Error::BadValue {
+            name: descriptor.name().to_owned(),
+            reason: "expected a message".to_owned(),
+        }),
+    }
+}
+
+/// Encodes a message of the specified named type from its canonical JSON form into a byte vector.
+///
+/// The message type name must be fully qualified.  The JSON is parsed like by `json::from_value`,
+/// so fields may be keyed by their JSON or original names, 64-bit integers may be strings, bytes
+/// are base64 encoded and enums may be value names.  This is the inverse of `de::to_json`, and
+/// requires the `json` feature.
+#[cfg(feature = "json")]
+pub fn from_json(
+    descriptors: &descriptor::Descriptors,
+    message_name: &str,
+    json: &serde_json::Value,
+) -> error::Result<Vec<u8>> {
+    let descriptor =
+        descriptors
+            .message_by_name(message_name)
+            .ok_or_else(|| error::Error::UnknownMessage {
+                name: message_name.to_owned(),
+            })?;
+    let value = crate::json::from_value(descriptors, descriptor, json)?;
+    to_bytes(descriptors, descriptor, &value)
+}
+
+/// Writes a message value of the specified message type as a single frame of a delimited stream,
+/// where each message is prefixed with its length as a varint.
+///
+/// Frames written this way can be read back one at a time using `de::read_delimited`.
+pub fn write_delimited<W>(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    value: &value::Value,
+    writer: &mut W,
+) -> error::Result<()>
+where
+    W: io::Write,
+{
+    let bytes = to_bytes(descriptors, descriptor, value)?;
+    let mut output = protobuf::CodedOutputStream::new(writer);
+    output.write_raw_varint64(bytes.len() as u64)?;
+    output.write_raw_bytes(&bytes)?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Writes the fields of a message value of the specified message type, without any framing.
+pub fn write_message(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    message: &value::Message,
+    output: &mut protobuf::CodedOutputStream,
+) -> error::Result<()> {
+    write_message_with_options(
+        descriptors,
+        descriptor,
+        message,
+        output,
+        &EncodeOptions::default(),
+    )
+}
+
+/// Writes the fields of a message value of the specified message type, without any framing,
+/// using the specified encode options.
+pub fn write_message_with_options(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    message: &value::Message,
+    output: &mut protobuf::CodedOutputStream,
+    options: &EncodeOptions,
+) -> error::Result<()> {
+    if let Some(&number) = message
+        .fields
+        .keys()
+        .find(|&&number| descriptor.field_by_number(number).is_none())
+    {
+        return Err(error::Error::BadValue {
+            name: format!("{}.{}", descriptor.name(), number),
+            reason: "the message type has no such field".to_owned(),
+        });
+    }
+
+    let mut extensions = message.extensions.iter().peekable();
+    if options.deterministic {
+        for (&number, field) in &message.fields {
+            // Interleave the extensions by field number
+            while let Some((&extension_number, value)) = extensions.next_if(|&(&n, _)| n < number) {
+                write_extension(
+                    descriptors,
+                    descriptor,
+                    extension_number,
+                    value,
+                    output,
+                    options,
+                )?;
+            }
+            let field_descriptor = descriptor.field_by_number(number).unwrap();
+            write_field(descriptors, field_descriptor, field, output, options)?;
+        }
+    } else {
+        for field_descriptor in descriptor.fields() {
+            if let Some(field) = message.fields.get(&field_descriptor.number()) {
+                write_field(descriptors, field_descriptor, field, output, options)?;
+            }
+        }
+    }
+    for (&number, value) in extensions {
+        write_extension(descriptors, descriptor, number, value, output, options)?;
+    }
+
+    let mut unknown = message.unknown.iter().collect::<Vec<_>>();
+    if options.deterministic {
+        unknown.sort_by_key(|&(number, _)| number);
+    }
+    for (number, value) in unknown {
+        match value {
+            // Items of unregistered extensions hold the contents of the item group
+            protobuf::UnknownValueRef::LengthDelimited(item)
+                if descriptor.is_message_set() && number == value::MESSAGE_SET_ITEM_NUMBER =>
+            {
+                output.write_tag(value::MESSAGE_SET_ITEM_NUMBER, WireType::StartGroup)?;
+                output.write_raw_bytes(item)?;
+                output.write_tag(value::MESSAGE_SET_ITEM_NUMBER, WireType::EndGroup)?;
+            }
+            value => output.write_unknown(number, value)?,
+        }
+    }
+    Ok(())
+}
+
+fn write_extension(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    number: i32,
+    value: &value::Value,
+    output: &mut protobuf::CodedOutputStream,
+    options: &EncodeOptions,
+) -> error::Result<()> {
+    let extension = match descriptors.extension(descriptor.name(), number) {
+        Some(e) => e,
+        None => {
+            return Err(error::Error::BadValue {
+                name: format!("{}.{}", descriptor.name(), number),
+                reason: "the extension is not registered".to_owned(),
+            })
+        }
+    };
+    if descriptor.is_message_set() {
+        write_message_set_item(descriptors, extension, value, output, options)
+    } else {
+        write_value(descriptors, extension, value, output, options)
+    }
+}
+
+fn message_to_bytes(
+    descriptors: &descriptor::Descriptors,
+    descriptor: &descriptor::MessageDescriptor,
+    message: &value::Message,
+    options: &EncodeOptions,
+) -> error::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut output = protobuf::CodedOutputStream::vec(&mut bytes);
+    write_message_with_options(descriptors, descriptor, message, &mut output, options)?;
+    output.flush()?;
+    drop(output);
+    Ok(bytes)
+}
+
+fn write_field(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    value: &value::Field,
+    output: &mut protobuf::CodedOutputStream,
+    options: &EncodeOptions,
+) -> error::Result<()> {
+    match *value {
+        value::Field::Singular(None) => Ok(()),
+        // Fields without presence are only written if they differ from their zero value
+        value::Field::Singular(Some(ref v)) if !field.has_presence() && is_zero(v) => Ok(()),
+        value::Field::Singular(Some(ref v)) => write_value(descriptors, field, v, output, options),
+        value::Field::Repeated(ref vs) if vs.is_empty() => Ok(()),
+        value::Field::Repeated(ref vs) if field.is_packed() => {
+            let mut bytes = Vec::new();
+            let mut packed = protobuf::CodedOutputStream::vec(&mut bytes);
+            for v in vs {
+                write_value_no_tag(descriptors, field, v, &mut packed, options)?;
+            }
+            packed.flush()?;
+            drop(packed);
+            output.write_bytes(field.number() as u32, &bytes)?;
+            Ok(())
+        }
+        value::Field::Repeated(ref vs) => match field.field_type(descriptors) {
+            descriptor::FieldType::Message(entry)
+                if options.deterministic && entry.is_map_entry() =>
+            {
+                let mut entries = vs.iter().collect::<Vec<_>>();
+                entries
+                    .sort_by(|a, b| value::compare_map_keys(value::map_key(a), value::map_key(b)));
+                for v in entries {
+                    write_value(descriptors, field, v, output, options)?;
+                }
+                Ok(())
+            }
+            _ => {
+                for v in vs {
+                    write_value(descriptors, field, v, output, options)?;
+                }
+                Ok(())
+            }
+        },
+    }
+}
+
+/// Whether a scalar value is the implicit zero value of its type, which `proto3` fields without
+/// presence don't write.
+///
+/// Negative zero is not the zero value, since it has a different encoding.
+fn is_zero(value: &value::Value) -> bool {
+    match *value {
+        value::Value::Bool(v) => !v,
+        value::Value::I32(v) | value::Value::Enum(v) => v == 0,
+        value::Value::I64(v) => v == 0,
+        value::Value::U32(v) => v == 0,
+        value::Value::U64(v) => v == 0,
+        value::Value::F32(v) => v.to_bits() == 0,
+        value::Value::F64(v) => v.to_bits() == 0,
+        value::Value::Bytes(ref v) => v.is_empty(),
+        value::Value::String(ref v) => v.is_empty(),
+        value::Value::Message(_) => false,
+    }
+}
+
+fn write_value(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    value: &value::Value,
+    output: &mut protobuf::CodedOutputStream,
+    options: &EncodeOptions,
+) -> error::Result<()> {
+    let number = field.number() as u32;
+    match (field.field_type(descriptors), value) {
+        (descriptor::FieldType::Group(m), value::Value::Message(v)) => {
+            output.write_tag(number, WireType::StartGroup)?;
+            write_message_with_options(descriptors, m, v, output, options)?;
+            output.write_tag(number, WireType::EndGroup)?;
+            Ok(())
+        }
+        (field_type, _) => {
+            output.write_tag(number, value::wire_type(&field_type))?;
+            write_value_no_tag(descriptors, field, value, output, options)
+        }
+    }
+}
+
+/// Writes a registered extension as an item of a message using the `MessageSet` wire format.
+fn write_message_set_item(
+    descriptors: &descriptor::Descriptors,
+    extension: &descriptor::FieldDescriptor,
+    value: &value::Value,
+    output: &mut protobuf::CodedOutputStream,
+    options: &EncodeOptions,
+) -> error::Result<()> {
+    output.write_tag(value::MESSAGE_SET_ITEM_NUMBER, WireType::StartGroup)?;
+    output.write_int32(value::MESSAGE_SET_TYPE_ID_NUMBER, extension.number())?;
+    output.write_tag(value::MESSAGE_SET_MESSAGE_NUMBER, WireType::LengthDelimited)?;
+    write_value_no_tag(descriptors, extension, value, output, options)?;
+    output.write_tag(value::MESSAGE_SET_ITEM_NUMBER, WireType::EndGroup)?;
+    Ok(())
+}
+
+fn write_value_no_tag(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    value: &value::Value,
+    output: &mut protobuf::CodedOutputStream,
+    options: &EncodeOptions,
+) -> error::Result<()> {
+    use crate::descriptor::FieldType as T;
+    use crate::value::Value as V;
+
+    match (field.field_type(descriptors), value) {
+        (T::Double, &V::F64(v)) => output.write_double_no_tag(v)?,
+        (T::Float, &V::F32(v)) => output.write_float_no_tag(v)?,
+        (T::Int64, &V::I64(v)) => output.write_int64_no_tag(v)?,
+        (T::UInt64, &V::U64(v)) => output.write_uint64_no_tag(v)?,
+        (T::Int32, &V::I32(v)) => output.write_int32_no_tag(v)?,
+        (T::Fixed64, &V::U64(v)) => output.write_fixed64_no_tag(v)?,
+        (T::Fixed32, &V::U32(v)) => output.write_fixed32_no_tag(v)?,
+        (T::Bool, &V::Bool(v)) => output.write_bool_no_tag(v)?,
+        (T::String, V::String(v)) => output.write_string_no_tag(v)?,
+        (T::Bytes, V::Bytes(v)) => output.write_bytes_no_tag(v)?,
+        (T::UInt32, &V::U32(v)) => output.write_uint32_no_tag(v)?,
+        (T::Enum(_), &V::Enum(v)) | (T::UnresolvedEnum(_), &V::Enum(v)) => {
+            output.write_enum_no_tag(v)?
+        }
+        (T::SFixed32, &V::I32(v)) => output.write_sfixed32_no_tag(v)?,
+        (T::SFixed64, &V::I64(v)) => output.write_sfixed64_no_tag(v)?,
+        (T::SInt32, &V::I32(v)) => output.write_sint32_no_tag(v)?,
+        (T::SInt64, &V::I64(v)) => output.write_sint64_no_tag(v)?,
+        (T::Message(m), V::Message(v)) => {
+            output.write_bytes_no_tag(&message_to_bytes(descriptors, m, v, options)?)?
+        }
+        (T::UnresolvedMessage(name), _) | (T::UnresolvedGroup(name), _) => {
+            return Err(error::Error::UnknownMessage {
+                name: name.to_owned(),
+            })
+        }
+        (field_type, value) => {
+            return Err(error::Error::BadValue {
+                name: field.name().to_owned(),
+                reason: format!("{:?} is not a valid {:?} value", value, field_type.tag()),
+            })
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::descriptor::test::load_descriptors;
+    use crate::descriptor::{
+        Descriptors, FieldDescriptor, FieldLabel, InternalFieldType, MessageDescriptor, Syntax,
+    };
+
+    fn descriptors() -> Descriptors {
+        let mut inner = MessageDescriptor::new(".test.Inner");
+        inner.add_field(
+            FieldDescriptor::builder("label")
+                .number(1)
+                .field_type(InternalFieldType::String)
+                .build(),
+        );
+
+        let mut outer = MessageDescriptor::new(".test.Outer");
+        outer.set_syntax(Syntax::Proto3);
+        outer.add_field(
+            FieldDescriptor::builder("id")
+                .number(1)
+                .field_type(InternalFieldType::SInt64)
+                .build(),
+        );
+        outer.add_field(
+            FieldDescriptor::builder("values")
+                .number(2)
+                .label(FieldLabel::Repeated)
+                .field_type(InternalFieldType::Fixed32)
+                .build(),
+        );
+        outer.add_field(
+            FieldDescriptor::builder("inners")
+                .number(3)
+                .label(FieldLabel::Repeated)
+                .field_type(InternalFieldType::UnresolvedMessage(".test.Inner".into()))
+                .build(),
+        );
+
+        let mut d = Descriptors::new();
+        d.add_message(inner);
+        d.add_message(outer);
+        d.resolve_refs();
+        d
+    }
+
+    #[test]
+    fn round_trip() {
+        let d = descriptors();
+        let outer = d.message_by_name(".test.Outer").unwrap();
+        let inner = d.message_by_name(".test.Inner").unwrap();
+        let data = [
+            8, 3, // id: -2
+            18, 8, 1, 0, 0, 0, 2, 0, 0, 0, // values: [1, 2] (packed)
+            26, 3, 10, 1, b'a', // inners: [{ label: "a" }]
+            26, 0, // inners: [{}]
+            64, 5, // unknown field 8: 5
+        ];
+        let mut message = value::Message::new(outer);
+        let mut input = protobuf::CodedInputStream::from_bytes(&data);
+        message.merge_from(&d, outer, &mut input).unwrap();
+        let value = value::Value::Message(message);
+        assert_eq!(to_bytes(&d, outer, &value).unwrap(), &data[..]);
+
+        assert!(to_bytes(&d, inner, &value::Value::I32(1)).is_err());
+    }
+
+    #[test]
+    fn round_trip_defaults() {
+        let d = load_descriptors();
+        let m = d
+            .message_by_name(".protobuf_unittest.TestAllTypes")
+            .unwrap();
+        let data = [
+            8, 1, // optional_int32: 1
+            114, 2, b'h', b'i', // optional_string: "hi"
+            248, 1, 5, // repeated_int32: [5]
+            232, 3, 41, // default_int32: 41 (its declared default)
+        ];
+        let mut message = value::Message::new(m);
+        let mut input = protobuf::CodedInputStream::from_bytes(&data);
+        message.merge_from(&d, m, &mut input).unwrap();
+        // Fields with a declared default that weren't on the wire aren't written
+        let value = value::Value::Message(message);
+        assert_eq!(to_bytes(&d, m, &value).unwrap(), &data[..]);
+    }
+
+    #[test]
+    fn proto3_presence() {
+        let field = |name: &str, number, field_type| {
+            FieldDescriptor::builder(name)
+                .number(number)
+                .field_type(field_type)
+        };
+        let mut m = MessageDescriptor::new(".test.Presence");
+        m.set_syntax(Syntax::Proto3);
+        m.add_field(field("plain", 1, InternalFieldType::Int32).build());
+        m.add_field(
+            field("explicit", 2, InternalFieldType::Int32)
+                .proto3_optional(true)
+                .build(),
+        );
+        m.add_field(
+            field("chosen", 3, InternalFieldType::Int32)
+                .oneof("choice")
+                .build(),
+        );
+        m.add_field(field("plain_double", 4, InternalFieldType::Double).build());
+        m.add_field(field("plain_string", 5, InternalFieldType::String).build());
+        m.add_field(
+            field(
+                "inner",
+                6,
+                InternalFieldType::UnresolvedMessage(".test.Inner".into()),
+            )
+            .build(),
+        );
+        let mut d = descriptors();
+        d.add_message(m);
+        d.resolve_refs();
+        let m = d.message_by_name(".test.Presence").unwrap();
+        let inner = d.message_by_name(".test.Inner").unwrap();
+        assert!(!m.field_by_number(1).unwrap().has_presence());
+        assert!(m.field_by_number(2).unwrap().has_presence());
+        assert!(inner.field_by_number(1).unwrap().has_presence());
+
+        let mut message = value::Message::new(m);
+        let mut inner_message = value::Message::new(inner);
+        inner_message.fields.insert(
+            1,
+            value::Field::Singular(Some(value::Value::String(String::new()))),
+        );
+        for (number, value) in [
+            (1, value::Value::I32(0)),
+            (2, value::Value::I32(0)),
+            (3, value::Value::I32(0)),
+            (4, value::Value::F64(0.0)),
+            (5, value::Value::String(String::new())),
+            (6, value::Value::Message(inner_message)),
+        ] {
+            message
+                .fields
+                .insert(number, value::Field::Singular(Some(value)));
+        }
+        let bytes = to_bytes(&d, m, &value::Value::Message(message.clone())).unwrap();
+        // explicit: 0, chosen: 0, inner { label: "" }
+        assert_eq!(bytes, [16, 0, 24, 0, 50, 2, 10, 0]);
+
+        // Non-zero values and negative zero are written
+        message
+            .fields
+            .insert(1, value::Field::Singular(Some(value::Value::I32(7))));
+        message
+            .fields
+            .insert(4, value::Field::Singular(Some(value::Value::F64(-0.0))));
+        let bytes = to_bytes(&d, m, &value::Value::Message(message)).unwrap();
+        assert_eq!(&bytes[..2], [8, 7]);
+        assert_eq!(&bytes[6..15], [33, 0, 0, 0, 0, 0, 0, 0, 0x80]);
+
+        // Decoding the written message keeps the explicitly present field
+        let mut decoded = value::Message::new(m);
+        let mut input = protobuf::CodedInputStream::from_bytes(&[16, 0]);
+        decoded.merge_from(&d, m, &mut input).unwrap();
+        assert!(matches!(
+            decoded.fields.get(&2),
+            Some(value::Field::Singular(Some(value::Value::I32(0))))
+        ));
+        assert!(matches!(
+            decoded.fields.get(&1),
+            Some(value::Field::Singular(None))
+        ));
+    }
+
+    #[test]
+    fn bad_values() {
+        let d = descriptors();
+        let outer = d.message_by_name(".test.Outer").unwrap();
+
+        let mut message = value::Message::new(outer);
+        message
+            .fields
+            .insert(1, value::Field::Singular(Some(value::Value::I32(1))));
+        match to_bytes(&d, outer, &value::Value::Message(message)) {
+            Err(error::Error::BadValue { name, .. }) => assert_eq!(name, "id"),
+            r => panic!("Expected a bad value error, got {:?}", r),
+        }
+
+        let mut message = value::Message::new(outer);
+        message
+            .fields
+            .insert(9, value::Field::Singular(Some(value::Value::I32(1))));
+        match to_bytes(&d, outer, &value::Value::Message(message)) {
+            Err(error::Error::BadValue { name, .. }) => assert_eq!(name, ".test.Outer.9"),
+            r => panic!("Expected a bad value error, got {:?}", r),
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn from_json() {
+        let d = load_descriptors();
+        let name = ".protobuf_unittest.TestAllTypes";
+        let json = serde_json::json!({
+            "optional_int32": 1,
+            "optionalInt64": "-9007199254740993",
+            "optional_uint64": "18446744073709551615",
+            "optionalBytes": "+/8=",
+            "optional_nested_enum": "BAR",
+            "optionalNestedMessage": {"bb": 3},
+            "repeatedString": ["a", "b"],
+            "repeated_foreign_enum": ["FOREIGN_BAZ", 5],
+        });
+
+        let bytes = super::from_json(&d, name, &json).unwrap();
+        // optional_int32: 1 is written first, following the declaration order
+        assert_eq!(&bytes[..2], &[8, 1]);
+
+        let decoded = crate::de::to_json(&d, name, &bytes).unwrap();
+        assert_eq!(decoded["optionalInt32"], 1);
+        assert_eq!(decoded["optionalInt64"], "-9007199254740993");
+        assert_eq!(decoded["optionalUint64"], "18446744073709551615");
+        assert_eq!(decoded["optionalBytes"], "+/8=");
+        assert_eq!(decoded["optionalNestedEnum"], "BAR");
+        assert_eq!(
+            decoded["optionalNestedMessage"],
+            serde_json::json!({"bb": 3})
+        );
+        assert_eq!(decoded["repeatedString"], serde_json::json!(["a", "b"]));
+        assert_eq!(
+            decoded["repeatedForeignEnum"],
+            serde_json::json!(["FOREIGN_BAZ", "FOREIGN_BAR"])
+        );
+        // Converting the canonical form back gives the same bytes
+        assert_eq!(super::from_json(&d, name, &decoded).unwrap(), bytes);
+
+        match super::from_json(&d, ".protobuf_unittest.Missing", &json) {
+            Err(error::Error::UnknownMessage { name }) => {
+                assert_eq!(name, ".protobuf_unittest.Missing")
+            }
+            r => panic!("Expected an unknown message error, got {:?}", r),
+        }
+        assert!(super::from_json(&d, name, &serde_json::json!({"optionalInt32": "x"})).is_err());
+
+        // Fields with declared default values are only encoded when they are given
+        assert!(super::from_json(&d, name, &serde_json::json!({}))
+            .unwrap()
+            .is_empty());
+        let bytes = super::from_json(&d, name, &serde_json::json!({"defaultInt32": 41})).unwrap();
+        assert_eq!(bytes, vec![232, 3, 41]);
+    }
+
+    #[test]
+    fn deterministic() {
+        let mut entry = MessageDescriptor::new(".test.Tags.LabelsEntry");
+        entry.set_map_entry(true);
+        entry.add_field(
+            FieldDescriptor::builder("key")
+                .number(1)
+                .field_type(InternalFieldType::String)
+                .build(),
+        );
+        entry.add_field(FieldDescriptor::builder("value").number(2).build());
+        let mut tags = MessageDescriptor::new(".test.Tags");
+        tags.add_field(
+            FieldDescriptor::builder("labels")
+                .number(3)
+                .label(FieldLabel::Repeated)
+                .field_type(InternalFieldType::UnresolvedMessage(
+                    ".test.Tags.LabelsEntry".into(),
+                ))
+                .build(),
+        );
+        tags.add_field(FieldDescriptor::builder("id").number(1).build());
+        let mut d = Descriptors::new();
+        d.add_message(entry);
+        d.add_message(tags);
+        d.resolve_refs();
+        let entry = d.message_by_name(".test.Tags.LabelsEntry").unwrap();
+        let tags = d.message_by_name(".test.Tags").unwrap();
+
+        let message = |labels: &[(&str, i32)], unknown: &[(u32, u64)]| {
+            let mut message = value::Message::new(tags);
+            message
+                .fields
+                .insert(1, value::Field::Singular(Some(value::Value::I32(1))));
+            let entries = labels
+                .iter()
+                .map(|&(k, v)| {
+                    let mut e = value::Message::new(entry);
+                    e.fields.insert(1, value::Field::Singular(Some(k.into())));
+                    e.fields.insert(2, value::Field::Singular(Some(v.into())));
+                    value::Value::Message(e)
+                })
+                .collect();
+            message.fields.insert(3, value::Field::Repeated(entries));
+            for &(number, v) in unknown {
+                message.unknown.add_varint(number, v);
+            }
+            value::Value::Message(message)
+        };
+        let a = message(&[("b", 2), ("a", 1)], &[(9, 1), (8, 2)]);
+        let b = message(&[("a", 1), ("b", 2)], &[(8, 2), (9, 1)]);
+
+        let options = EncodeOptions::new().deterministic(true);
+        let expected = [
+            8, 1, // id: 1
+            26, 5, 10, 1, b'a', 16, 1, // labels: { a: 1 }
+            26, 5, 10, 1, b'b', 16, 2, // labels: { b: 2 }
+            64, 2, // unknown field 8: 2
+            72, 1, // unknown field 9: 1
+        ];
+        assert_eq!(
+            to_bytes_with_options(&d, tags, &a, &options).unwrap(),
+            &expected[..]
+        );
+        assert_eq!(
+            to_bytes_with_options(&d, tags, &b, &options).unwrap(),
+            &expected[..]
+        );
+
+        // Otherwise, fields are written in declaration order and map entries as stored
+        assert_eq!(
+            &to_bytes(&d, tags, &a).unwrap()[..16],
+            &[
+                26, 5, 10, 1, b'b', 16, 2, // labels: { b: 2 }
+                26, 5, 10, 1, b'a', 16, 1, // labels: { a: 1 }
+                8, 1, // id: 1
+            ]
+        );
+    }
+}
@@ -1,16 +1,51 @@
 //! Types for representing runtime Protobuf values.
+use std::cell;
+use std::cmp;
 use std::collections;
+use std::fmt;
+use std::ops;
+use std::sync;
 
 use protobuf;
 use protobuf::rt as wire_format;
 
 use crate::descriptor;
 use crate::error;
+use crate::wire;
 
-const TAG_TYPE_BITS: u32 = 3;
-const TAG_TYPE_MASK: u32 = (1u32 << TAG_TYPE_BITS as usize) - 1;
+#[cfg(feature = "arena")]
+mod arena;
+mod borrowed;
+
+#[cfg(feature = "arena")]
+pub use self::arena::{ArenaField, ArenaMessage, ArenaValue};
+pub use self::borrowed::{BorrowedField, BorrowedMessage, BorrowedValue};
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Field numbers of the group layout used by the legacy `MessageSet` wire format
+pub(crate) const MESSAGE_SET_ITEM_NUMBER: u32 = 1;
+pub(crate) const MESSAGE_SET_TYPE_ID_NUMBER: u32 = 2;
+pub(crate) const MESSAGE_SET_MESSAGE_NUMBER: u32 = 3;
+
+/// The maximum number of values of a packed fixed-width field that space is reserved for before
+/// they are read, since the length of the field may be bogus.
+const MAX_RESERVED_VALUES: usize = 1 << 16;
 
 /// Any protobuf value.
+///
+/// Values can be created from the corresponding Rust primitive types using `From`: `bool` becomes
+/// `Bool`, `i32` becomes `I32`, `i64` becomes `I64`, `u32` becomes `U32`, `u64` becomes `U64`,
+/// `f32` becomes `F32`, `f64` becomes `F64`, `Vec<u8>` becomes `Bytes`, and both `String` and
+/// `&str` become `String`.
+///
+/// ```
+/// use serde_protobuf::value::Value;
+///
+/// let value = Value::from(42i32);
+/// assert!(matches!(value, Value::I32(42)));
+/// ```
 #[derive(Clone, Debug)]
 pub enum Value {
     /// A boolean value.
@@ -44,6 +79,77 @@ pub struct Message {
     pub fields: collections::BTreeMap<i32, Field>,
     /// Unknown fields on the message.
     pub unknown: protobuf::UnknownFields,
+    /// Known extensions on the message, by field number.
+    ///
+    /// These are currently only decoded from the items of messages using the `MessageSet` wire
+    /// format, using the message typed extensions registered in the descriptor registry.
+    pub extensions: collections::BTreeMap<i32, Value>,
+}
+
+/// A schema-aware read-only view of a message value.
+///
+/// Field values are looked up and presented together with their field descriptors, without
+/// exposing the internal representation of the message.
+#[derive(Clone, Copy, Debug)]
+pub struct MessageView<'a> {
+    descriptor: &'a descriptor::MessageDescriptor,
+    message: &'a Message,
+}
+
+/// Options that control how protocol buffer data is decoded.
+#[derive(Clone, Debug, Default)]
+pub struct DecodeOptions {
+    max_field_size: Option<u64>,
+    pub(crate) max_total_fields: Option<u64>,
+    allow_missing_required: bool,
+    string_validation: StringValidation,
+    fill_defaults: bool,
+    pub(crate) stringify_scalars: bool,
+    reject_duplicate_scalars: bool,
+    schemaless_unresolved_messages: bool,
+    field_hooks: FieldHooks,
+}
+
+/// The state of a single decode operation: its options, the number of field occurrences that
+/// may still be decoded according to `DecodeOptions::max_total_fields`, and the `required` fields
+/// that are missing from nested messages.
+///
+/// A context is created for every top-level decode, and passed on to all nested messages, so that
+/// the limit applies to the message as a whole.  It dereferences to the decode options.
+pub(crate) struct DecodeContext<'a> {
+    options: &'a DecodeOptions,
+    remaining_fields: cell::Cell<Option<u64>>,
+    /// The path from the top-level message to the message that is being decoded, as the number of
+    /// each enclosing field together with `0` for singular fields, or a distinct occurrence number
+    /// for each element of a repeated field
+    path: cell::RefCell<MessagePath>,
+    occurrences: cell::Cell<u64>,
+    /// The `required` fields that were missing from every occurrence of each nested message with
+    /// `required` fields so far, by path
+    missing_required: cell::RefCell<collections::BTreeMap<MessagePath, Vec<(i32, String)>>>,
+    /// The numbers of the fields of the top-level message that occurred on the wire, if they are
+    /// recorded
+    presence: Option<cell::RefCell<collections::BTreeSet<i32>>>,
+}
+
+/// The path of a nested message within a decode operation; see `DecodeContext`.
+type MessagePath = Vec<(i32, u64)>;
+
+/// A callback that transforms each decoded value of a field; see `DecodeOptions::field_hook`.
+pub type FieldHook = dyn Fn(Value) -> error::Result<Value> + Send + Sync;
+
+/// The registered field hooks, by message name and field number.
+#[derive(Clone, Default)]
+struct FieldHooks(collections::HashMap<String, collections::HashMap<i32, sync::Arc<FieldHook>>>);
+
+/// How the contents of `string` fields are validated while decoding.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum StringValidation {
+    /// Fail with `Error::InvalidUtf8` if a string isn't valid UTF-8.
+    #[default]
+    Strict,
+    /// Replace invalid UTF-8 sequences with U+FFFD REPLACEMENT CHARACTER.
+    Lossy,
 }
 
 /// A message field value.
@@ -55,24 +161,95 @@ pub enum Field {
     Repeated(Vec<Value>),
 }
 
+/// Implements `From` for `Value` variants that wrap a single primitive type.
+macro_rules! value_from {
+    ($($t:ty => $variant:ident),*) => {
+        $(
+            impl From<$t> for Value {
+                #[doc = concat!("Converts the value into a `Value::", stringify!($variant), "`.")]
+                #[inline]
+                fn from(v: $t) -> Value {
+                    Value::$variant(v)
+                }
+            }
+        )*
+    };
+}
+
+value_from! {
+    bool => Bool,
+    i32 => I32,
+    i64 => I64,
+    u32 => U32,
+    u64 => U64,
+    f32 => F32,
+    f64 => F64,
+    Vec<u8> => Bytes,
+    String => String
+}
+
+impl<'a> From<&'a str> for Value {
+    /// Converts the string slice into an owned `Value::String`.
+    #[inline]
+    fn from(v: &'a str) -> Value {
+        Value::String(v.to_owned())
+    }
+}
+
+#[cfg(feature = "json")]
+impl<'a> From<&'a Value> for serde_json::Value {
+    /// Converts the value into a JSON value using a straightforward structural mapping, without
+    /// needing a descriptor.
+    ///
+    /// This is a best-effort conversion meant for debugging and logging, and does not apply the
+    /// protobuf JSON mapping: messages become objects keyed by field number, repeated fields become
+    /// arrays, bytes become arrays of numbers and enums become their numbers.  Unset and unknown
+    /// fields are left out, and non-finite floats become `null`.
+    fn from(v: &'a Value) -> serde_json::Value {
+        match *v {
+            Value::Bool(v) => serde_json::Value::Bool(v),
+            Value::I32(v) | Value::Enum(v) => v.into(),
+            Value::I64(v) => v.into(),
+            Value::U32(v) => v.into(),
+            Value::U64(v) => v.into(),
+            Value::F32(v) => f64::from(v).into(),
+            Value::F64(v) => v.into(),
+            Value::Bytes(ref v) => v.as_slice().into(),
+            Value::String(ref v) => v.as_str().into(),
+            Value::Message(ref m) => serde_json::Value::Object(
+                m.fields
+                    .iter()
+                    .filter_map(|(number, field)| {
+                        let value = match *field {
+                            Field::Singular(ref v) => serde_json::Value::from(v.as_ref()?),
+                            Field::Repeated(ref vs) => {
+                                vs.iter().map(serde_json::Value::from).collect()
+                            }
+                        };
+                        Some((number.to_string(), value))
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
+
 impl Message {
     /// Creates a message given a Protobuf descriptor.
+    ///
+    /// All fields start out unset, including fields with a declared default value, so that
+    /// decoding only sets the fields that occur on the wire; use `fill_defaults` to fill in
+    /// default values.
     #[inline]
     pub fn new(message: &descriptor::MessageDescriptor) -> Message {
         let mut m = Message {
             fields: collections::BTreeMap::new(),
             unknown: protobuf::UnknownFields::new(),
+            extensions: collections::BTreeMap::new(),
         };
 
         for field in message.fields() {
-            m.fields.insert(
-                field.number(),
-                if field.is_repeated() {
-                    Field::Repeated(Vec::new())
-                } else {
-                    Field::Singular(field.default_value().cloned())
-                },
-            );
+            m.fields.insert(field.number(), Field::new(field));
         }
 
         m
@@ -86,24 +263,386 @@ impl Message {
         message: &descriptor::MessageDescriptor,
         input: &mut protobuf::CodedInputStream,
     ) -> error::Result<()> {
-        while !input.eof()? {
-            let (number, wire_type) = {
-                let v = input.read_raw_varint32()?;
-                (v >> TAG_TYPE_BITS, protobuf::rt::WireType::new(v & TAG_TYPE_MASK)
-                    .expect("bad wire type"))
-            };
+        self.merge_from_with_options(descriptors, message, input, &DecodeOptions::default())
+    }
 
-            if let Some(field) = message.field_by_number(number as i32) {
-                let value = self.ensure_field(field);
-                value.merge_from(descriptors, field, input, wire_type)?;
-            } else {
-                use protobuf::rt::read_unknown_or_skip_group as u;
-                u(number, input, &mut self.unknown)?;
+    /// Merge data from the given input stream into this message, using the specified decode
+    /// options.
+    #[inline]
+    pub fn merge_from_with_options(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        input: &mut protobuf::CodedInputStream,
+        options: &DecodeOptions,
+    ) -> error::Result<()> {
+        let context = DecodeContext::new(options);
+        self.merge_fields(descriptors, message, input, &context, None, None)
+    }
+
+    /// Merge data from the given input stream into this message, returning the numbers of all
+    /// fields that occurred on the wire, including unknown fields.
+    pub(crate) fn merge_with_presence(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        input: &mut protobuf::CodedInputStream,
+    ) -> error::Result<collections::BTreeSet<i32>> {
+        let options = DecodeOptions::default();
+        let context = DecodeContext::with_presence(&options);
+        self.merge_fields(descriptors, message, input, &context, None, None)?;
+        Ok(context.into_presence())
+    }
+
+    /// Merge only the fields with the specified numbers from the given input stream into this
+    /// message, skipping over all other fields without decoding them.
+    ///
+    /// Fields that aren't wanted are removed from the message afterwards, and are never reported
+    /// as missing required fields.
+    pub(crate) fn merge_wanted_fields(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        input: &mut protobuf::CodedInputStream,
+        options: &DecodeOptions,
+        wanted: &collections::BTreeSet<i32>,
+    ) -> error::Result<()> {
+        let context = DecodeContext::new(options);
+        self.merge_fields(descriptors, message, input, &context, Some(wanted), None)?;
+        self.fields.retain(|number, _| wanted.contains(number));
+        Ok(())
+    }
+
+    /// Merges fields until the end of the input stream or its current limit, or if `end_group` is
+    /// specified, until the end tag of the group with that field number.
+    fn merge_fields(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        input: &mut protobuf::CodedInputStream,
+        options: &DecodeContext,
+        wanted: Option<&collections::BTreeSet<i32>>,
+        end_group: Option<u32>,
+    ) -> error::Result<()> {
+        let mut seen = unwanted_fields(message, wanted);
+        loop {
+            if input.eof()? {
+                match end_group {
+                    Some(_) => return Err(error::Error::EndOfStream),
+                    None => break,
+                }
+            }
+            let offset = input.pos();
+            let ended = read_tag(input)
+                .and_then(|(number, wire_type)| {
+                    if wire_format::WireType::EndGroup == wire_type && end_group == Some(number) {
+                        return Ok(true);
+                    }
+                    options.count_field()?;
+                    if wanted.is_some_and(|wanted| !wanted.contains(&(number as i32))) {
+                        skip_field(input, wire_type, options)?;
+                    } else {
+                        self.merge_field(
+                            descriptors,
+                            message,
+                            input,
+                            options,
+                            (number, wire_type),
+                            &mut seen,
+                        )?;
+                        options.record_presence(number);
+                    }
+                    Ok(false)
+                })
+                .map_err(|e| decode_at(offset, e))?;
+            if ended {
+                break;
+            }
+        }
+        if options.fill_defaults {
+            self.fill_own_defaults(descriptors, message);
+        }
+        if options.stringify_scalars {
+            self.stringify_scalars(descriptors, message);
+        }
+        options.finish_message(message, &seen)
+    }
+
+    /// Merge data from the given input stream into this message, skipping over every field that
+    /// can't be decoded instead of failing.
+    ///
+    /// Returns the errors encountered along the way.  Each field value is read off the wire before
+    /// it is decoded, so a malformed value only affects its own field, but decoding stops at the
+    /// first error that leaves the stream at an unknown position (for example a malformed tag or
+    /// group).  If `wanted` is specified, all other fields are skipped like in
+    /// `merge_wanted_fields`.
+    pub(crate) fn merge_lenient(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        input: &mut protobuf::CodedInputStream,
+        options: &DecodeOptions,
+        wanted: Option<&collections::BTreeSet<i32>>,
+    ) -> Vec<error::Error> {
+        let options = &DecodeContext::new(options);
+        let mut errors = Vec::new();
+        let mut seen = unwanted_fields(message, wanted);
+        loop {
+            let offset = input.pos();
+            let result = match input.eof() {
+                Ok(true) => break,
+                Ok(false) => read_tag(input),
+                Err(e) => Err(e.into()),
+            }
+            .and_then(|tag| {
+                options.count_field()?;
+                Ok(tag)
+            })
+            .and_then(|tag| match tag {
+                (number, wire_type) if wanted.is_some_and(|w| !w.contains(&(number as i32))) => {
+                    skip_field(input, wire_type, options)
+                }
+                (number, wire_type)
+                    if wire_format::WireType::StartGroup != wire_type
+                        && message.field_by_number(number as i32).is_some() =>
+                {
+                    let bytes = read_raw_field(input, number, wire_type, options)?;
+                    // Errors while decoding the isolated value don't affect the outer stream
+                    let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+                    let tag = read_tag(&mut input)?;
+                    if let Err(e) =
+                        self.merge_field(descriptors, message, &mut input, options, tag, &mut seen)
+                    {
+                        // Offsets within the isolated value are relative to its re-encoded tag
+                        errors.push(decode_at(offset, shift_offsets(e, offset)));
+                    }
+                    Ok(())
+                }
+                tag => self.merge_field(descriptors, message, input, options, tag, &mut seen),
+            });
+            if let Err(e) = result {
+                errors.push(decode_at(offset, e));
+                break;
+            }
+        }
+        if options.fill_defaults {
+            self.fill_own_defaults(descriptors, message);
+        }
+        if options.stringify_scalars {
+            self.stringify_scalars(descriptors, message);
+        }
+        if let Err(e) = options.finish_message(message, &seen) {
+            errors.push(e);
+        }
+        if let Some(wanted) = wanted {
+            self.fields.retain(|number, _| wanted.contains(number));
+        }
+        errors
+    }
+
+    /// Merges the value of a single field, whose tag has already been read, into this message.
+    fn merge_field(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        input: &mut protobuf::CodedInputStream,
+        options: &DecodeContext,
+        (number, wire_type): (u32, wire_format::WireType),
+        seen: &mut collections::BTreeSet<i32>,
+    ) -> error::Result<()> {
+        if message.is_message_set() && number == MESSAGE_SET_ITEM_NUMBER {
+            // Other values of the item field would be written back as items, see
+            // `merge_message_set_item`
+            if wire_format::WireType::StartGroup != wire_type {
+                return Err(error::Error::BadWireType { wire_type });
+            }
+            self.merge_message_set_item(descriptors, message, input, options)?;
+        } else if let Some(field) = message.field_by_number(number as i32) {
+            options.mark_seen(descriptors, field, seen)?;
+            match options.field_hook_for(message, field) {
+                Some(hook) => {
+                    // Decode the values separately, so that the hook sees every value exactly once
+                    let mut decoded = Field::new(field);
+                    decoded.merge_value(descriptors, field, input, wire_type, options)?;
+                    let values = match decoded {
+                        Field::Singular(v) => v.into_iter().collect(),
+                        Field::Repeated(vs) => vs,
+                    };
+                    let value = self.ensure_field(field);
+                    for v in values {
+                        value.put(hook(v)?);
+                    }
+                }
+                None => {
+                    let value = self.ensure_field(field);
+                    value.merge_value(descriptors, field, input, wire_type, options)?;
+                }
+            }
+        } else if wire_format::WireType::LengthDelimited == wire_type {
+            let len = options.read_length(input)?;
+            let bytes = input.read_raw_bytes(len)?;
+            self.unknown.add_length_delimited(number, bytes);
+        } else if wire_format::WireType::StartGroup == wire_type {
+            skip_field(input, wire_type, options)?;
+        } else {
+            let value = input.read_unknown(wire_type)?;
+            self.unknown.add_value(number, value);
+        }
+        Ok(())
+    }
+
+    /// Merges a single `{ type_id, message }` item of a message using the `MessageSet` wire format.
+    ///
+    /// Items of registered extensions are decoded into `extensions`, and all other items, including
+    /// items without a type ID, are kept in `unknown` as length-delimited values of the item field
+    /// holding the contents of the item group, so that they are written back as they were.  Groups
+    /// within items are skipped.
+    fn merge_message_set_item(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        input: &mut protobuf::CodedInputStream,
+        options: &DecodeContext,
+    ) -> error::Result<()> {
+        let mut type_id = None;
+        let mut bytes = Vec::new();
+        let mut bytes_offset = 0;
+        let mut item = Vec::new();
+        loop {
+            let (number, wire_type) = read_tag(input)?;
+            match (number, wire_type) {
+                (MESSAGE_SET_ITEM_NUMBER, wire_format::WireType::EndGroup) => break,
+                (MESSAGE_SET_TYPE_ID_NUMBER, wire_format::WireType::Varint) => {
+                    let id = input.read_int32()?;
+                    let mut output = protobuf::CodedOutputStream::vec(&mut item);
+                    output.write_int32(number, id)?;
+                    output.flush()?;
+                    type_id = Some(id);
+                }
+                (MESSAGE_SET_MESSAGE_NUMBER, wire_format::WireType::LengthDelimited) => {
+                    let len = options.read_length(input)?;
+                    bytes_offset = input.pos();
+                    bytes = input.read_raw_bytes(len)?;
+                    let mut output = protobuf::CodedOutputStream::vec(&mut item);
+                    output.write_bytes(number, &bytes)?;
+                    output.flush()?;
+                }
+                (_, wire_format::WireType::StartGroup) => skip_field(input, wire_type, options)?,
+                (number, wire_type) => {
+                    item.extend(read_raw_field(input, number, wire_type, options)?);
+                }
+            }
+        }
+
+        let extension = match type_id {
+            Some(type_id)
+                if !(descriptor::MIN_FIELD_NUMBER..=descriptor::MAX_FIELD_NUMBER)
+                    .contains(&type_id) =>
+            {
+                return Err(error::Error::BadFieldNumber {
+                    field: format!("{}.type_id", message.name()),
+                    number: type_id,
+                });
             }
+            Some(type_id) => descriptors
+                .message_extension(message.name(), type_id)
+                .map(|extension| (type_id, extension)),
+            None => None,
+        };
+        match extension {
+            Some((type_id, extension)) => {
+                let mut value = match self.extensions.remove(&type_id) {
+                    Some(Value::Message(m)) => m,
+                    _ => Message::new(extension),
+                };
+                let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+                options
+                    .nested(type_id, false, || {
+                        value.merge_fields(descriptors, extension, &mut input, options, None, None)
+                    })
+                    // Offsets within the message are relative to its contents
+                    .map_err(|e| shift_offsets(e, bytes_offset))?;
+                self.extensions.insert(type_id, Value::Message(value));
+            }
+            None => self
+                .unknown
+                .add_length_delimited(MESSAGE_SET_ITEM_NUMBER, item),
         }
         Ok(())
     }
 
+    /// Sets every absent singular scalar field of this message, and of all nested messages that
+    /// are present, to its default value.
+    ///
+    /// Fields with a declared default value get that value.  Fields without one get the implicit
+    /// zero default value in `proto3` messages, and are left absent in `proto2` messages, where
+    /// the syntax of each nested message decides the defaults of its own fields.  Absent message
+    /// fields are never synthesized, since that would make them present.
+    ///
+    /// Decoding with `DecodeOptions::fill_defaults` has the same effect, because every nested
+    /// message is decoded with the same options; this is useful for values that were decoded
+    /// without that option or built by hand.
+    pub fn fill_defaults(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+    ) {
+        self.fill_own_defaults(descriptors, message);
+        for field in message.fields() {
+            let nested = match field.field_type(descriptors) {
+                descriptor::FieldType::Message(m) | descriptor::FieldType::Group(m) => m,
+                _ => continue,
+            };
+            let values = match self.fields.get_mut(&field.number()) {
+                Some(Field::Singular(v)) => v.as_mut_slice(),
+                Some(Field::Repeated(vs)) => vs.as_mut_slice(),
+                None => continue,
+            };
+            for value in values {
+                if let Value::Message(m) = value {
+                    m.fill_defaults(descriptors, nested);
+                }
+            }
+        }
+    }
+
+    /// Sets every absent singular scalar field of this message, but not of its nested messages,
+    /// to its default value.
+    fn fill_own_defaults(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+    ) {
+        for field in message.fields() {
+            if field.is_repeated() {
+                continue;
+            }
+            if let Field::Singular(ref mut value @ None) = *self.ensure_field(field) {
+                *value = match field.default_value() {
+                    Some(v) => Some(v.clone()),
+                    None if message.syntax() == descriptor::Syntax::Proto3 => {
+                        scalar_default(descriptors, field)
+                    }
+                    None => None,
+                };
+            }
+        }
+    }
+
+    /// Replaces the values of all scalar fields with their string representations; see
+    /// `DecodeOptions::stringify_scalars`.
+    fn stringify_scalars(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+    ) {
+        for field in message.fields() {
+            if let Some(value) = self.fields.get_mut(&field.number()) {
+                value.stringify_scalars(descriptors, field);
+            }
+        }
+    }
+
     #[inline]
     fn ensure_field(&mut self, field: &descriptor::FieldDescriptor) -> &mut Field {
         self.fields
@@ -131,16 +670,51 @@ impl Field {
         field: &descriptor::FieldDescriptor,
         input: &mut protobuf::CodedInputStream,
         wire_type: protobuf::rt::WireType,
+    ) -> error::Result<()> {
+        self.merge_from_with_options(
+            descriptors,
+            field,
+            input,
+            wire_type,
+            &DecodeOptions::default(),
+        )
+    }
+
+    /// Merge data from the given input stream into this field, using the specified decode
+    /// options.
+    #[inline]
+    pub fn merge_from_with_options(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        input: &mut protobuf::CodedInputStream,
+        wire_type: protobuf::rt::WireType,
+        options: &DecodeOptions,
+    ) -> error::Result<()> {
+        let context = DecodeContext::new(options);
+        self.merge_value(descriptors, field, input, wire_type, &context)?;
+        context.check_nested_required()
+    }
+
+    /// Merges the value of a single field, whose tag has already been read, into this field as
+    /// part of a larger decode operation.
+    pub(crate) fn merge_value(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        input: &mut protobuf::CodedInputStream,
+        wire_type: protobuf::rt::WireType,
+        options: &DecodeContext,
     ) -> error::Result<()> {
         // Make the type dispatch below more compact
         use crate::descriptor::FieldType::*;
         use protobuf::rt::WireType;
         use protobuf::CodedInputStream as I;
 
-        // Singular scalar
-        macro_rules! ss {
-            ($expected_wire_type:expr, $visit_func:expr, $reader:expr) => {
-                self.merge_scalar(input, wire_type, $expected_wire_type, $visit_func, $reader)
+        // Length delimited scalar
+        macro_rules! ls {
+            ($visit_func:expr) => {
+                self.merge_length_delimited(input, wire_type, options, $visit_func)
             };
         }
 
@@ -151,22 +725,26 @@ impl Field {
                     input,
                     wire_type,
                     $expected_wire_type,
+                    None,
+                    options,
                     $visit_func,
-                    $reader,
+                    |i: &mut I| $reader(i),
                 )
             };
             ($expected_wire_type:expr, $size:expr, $visit_func:expr, $reader:expr) => {
-                // TODO: use size to pre-allocate buffer space
                 self.merge_packable_scalar(
                     input,
                     wire_type,
                     $expected_wire_type,
+                    Some($size),
+                    options,
                     $visit_func,
-                    $reader,
+                    |i: &mut I| $reader(i),
                 )
             };
         }
 
+        // The fixed-width readers decode little-endian values regardless of the host byte order
         match field.field_type(descriptors) {
             Bool => ps!(WireType::Varint, Value::Bool, I::read_bool),
             Int32 => ps!(WireType::Varint, Value::I32, I::read_int32),
@@ -181,20 +759,41 @@ impl Field {
             SFixed64 => ps!(WireType::Fixed64, 8, Value::I64, I::read_sfixed64),
             Float => ps!(WireType::Fixed32, 4, Value::F32, I::read_float),
             Double => ps!(WireType::Fixed64, 8, Value::F64, I::read_double),
-            Bytes => ss!(WireType::LengthDelimited, Value::Bytes, I::read_bytes),
-            String => ss!(WireType::LengthDelimited, Value::String, I::read_string),
-            Enum(_) => self.merge_enum(input, wire_type),
-            Message(ref m) => self.merge_message(input, descriptors, m, wire_type),
-            Group => unimplemented!(),
+            Bytes => ls!(|v| Ok(Value::Bytes(v))),
+            String => ls!(|v| options.decode_string(field, v).map(Value::String)),
+            Enum(_) => ps!(WireType::Varint, Value::Enum, I::read_int32),
+            Message(m) | Group(m) => match *self {
+                Field::Repeated(ref vs) => {
+                    let index = vs.len();
+                    options
+                        .nested(field.number(), true, || {
+                            self.merge_message(input, descriptors, field, m, wire_type, options)
+                        })
+                        .map_err(|e| error::Error::InField {
+                            field: field.name().to_owned(),
+                            index,
+                            source: Box::new(e),
+                        })
+                }
+                Field::Singular(_) => options.nested(field.number(), false, || {
+                    self.merge_message(input, descriptors, field, m, wire_type, options)
+                }),
+            },
             UnresolvedEnum(e) => Err(error::Error::UnknownEnum { name: e.to_owned() }),
-            UnresolvedMessage(m) => Err(error::Error::UnknownMessage { name: m.to_owned() }),
+            UnresolvedMessage(_) if options.schemaless_unresolved_messages => {
+                let schemaless = schemaless_message();
+                self.merge_message(input, descriptors, field, schemaless, wire_type, options)
+            }
+            UnresolvedMessage(m) | UnresolvedGroup(m) => {
+                Err(error::Error::UnknownMessage { name: m.to_owned() })
+            }
         }
     }
 
     #[inline]
-    fn merge_scalar<'a, A, V, R>(
+    fn merge_scalar<A, V, R>(
         &mut self,
-        input: &mut protobuf::CodedInputStream<'a>,
+        input: &mut protobuf::CodedInputStream,
         actual_wire_type: wire_format::WireType,
         expected_wire_type: wire_format::WireType,
         value_ctor: V,
@@ -202,7 +801,7 @@ impl Field {
     ) -> error::Result<()>
     where
         V: Fn(A) -> Value,
-        R: Fn(&mut protobuf::CodedInputStream<'a>) -> protobuf::Result<A>,
+        R: Fn(&mut protobuf::CodedInputStream) -> protobuf::Result<A>,
     {
         if expected_wire_type == actual_wire_type {
             self.put(value_ctor(reader(input)?));
@@ -215,26 +814,76 @@ impl Field {
     }
 
     #[inline]
-    fn merge_packable_scalar<'a, A, V, R>(
+    fn merge_length_delimited<V>(
+        &mut self,
+        input: &mut protobuf::CodedInputStream,
+        actual_wire_type: wire_format::WireType,
+        options: &DecodeOptions,
+        value_ctor: V,
+    ) -> error::Result<()>
+    where
+        V: Fn(Vec<u8>) -> error::Result<Value>,
+    {
+        if wire_format::WireType::LengthDelimited == actual_wire_type {
+            let len = options.read_length(input)?;
+            self.put(value_ctor(input.read_raw_bytes(len)?)?);
+            Ok(())
+        } else {
+            Err(error::Error::BadWireType {
+                wire_type: actual_wire_type,
+            })
+        }
+    }
+
+    /// Merges a scalar that may be packed, where `element_size` is the encoded size of each value
+    /// for fixed-width types, and `None` for varint types.
+    ///
+    /// The number of values in a packed field is determined up front, so that repeated fields
+    /// can reserve space for them at once: for fixed-width types it follows from the length of
+    /// the field, up to `MAX_RESERVED_VALUES`, and for varint types it is the number of bytes
+    /// without the continuation bit, which requires reading the payload first.
+    #[inline]
+    #[allow(clippy::too_many_arguments)]
+    fn merge_packable_scalar<A, V, R>(
         &mut self,
-        input: &mut protobuf::CodedInputStream<'a>,
+        input: &mut protobuf::CodedInputStream,
         actual_wire_type: wire_format::WireType,
         expected_wire_type: wire_format::WireType,
+        element_size: Option<u32>,
+        options: &DecodeContext,
         value_ctor: V,
         reader: R,
     ) -> error::Result<()>
     where
         V: Fn(A) -> Value,
-        R: Fn(&mut protobuf::CodedInputStream<'a>) -> protobuf::Result<A>,
+        R: Fn(&mut protobuf::CodedInputStream) -> protobuf::Result<A>,
     {
         if wire_format::WireType::LengthDelimited == actual_wire_type {
-            let len = input.read_raw_varint64()?;
-
-            let old_limit = input.push_limit(len)?;
-            while !input.eof()? {
-                self.put(value_ctor(reader(input)?));
+            let len = options.read_length(input)?;
+            match element_size {
+                Some(size) => {
+                    let old_limit = input.push_limit(u64::from(len))?;
+                    self.reserve(((len / size) as usize).min(MAX_RESERVED_VALUES));
+                    while !input.eof()? {
+                        options.count_field()?;
+                        self.put(value_ctor(reader(input)?));
+                    }
+                    // The end of the input may come before the end of the payload
+                    if input.bytes_until_limit() != 0 {
+                        return Err(error::Error::EndOfStream);
+                    }
+                    input.pop_limit(old_limit);
+                }
+                None => {
+                    let bytes = input.read_raw_bytes(len)?;
+                    self.reserve(bytes.iter().filter(|&&b| b & 0x80 == 0).count());
+                    let mut input = protobuf::CodedInputStream::from_bytes(&bytes);
+                    while !input.eof()? {
+                        options.count_field()?;
+                        self.put(value_ctor(reader(&mut input)?));
+                    }
+                }
             }
-            input.pop_limit(old_limit);
 
             Ok(())
         } else {
@@ -248,54 +897,68 @@ impl Field {
         }
     }
 
+    /// Merges a message value, which is either length-delimited or a group depending on the type
+    /// of the field.
     #[inline]
-    fn merge_enum(
+    fn merge_message(
         &mut self,
         input: &mut protobuf::CodedInputStream,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        message: &descriptor::MessageDescriptor,
         actual_wire_type: wire_format::WireType,
+        options: &DecodeContext,
     ) -> error::Result<()> {
-        if wire_format::WireType::Varint == actual_wire_type {
-            let v = input.read_raw_varint32()? as i32;
-            self.put(Value::Enum(v));
-            Ok(())
+        let is_group = matches!(
+            field.field_type(descriptors),
+            descriptor::FieldType::Group(_)
+        );
+        let expected_wire_type = if is_group {
+            wire_format::WireType::StartGroup
         } else {
-            Err(error::Error::BadWireType {
+            wire_format::WireType::LengthDelimited
+        };
+        if expected_wire_type != actual_wire_type {
+            return Err(error::Error::BadWireType {
                 wire_type: actual_wire_type,
-            })
+            });
         }
-    }
+        let len = if is_group {
+            None
+        } else {
+            Some(options.read_length(input)?)
+        };
 
-    #[inline]
-    fn merge_message(
-        &mut self,
-        input: &mut protobuf::CodedInputStream,
-        descriptors: &descriptor::Descriptors,
-        message: &descriptor::MessageDescriptor,
-        actual_wire_type: wire_format::WireType,
-    ) -> error::Result<()> {
-        if wire_format::WireType::LengthDelimited == actual_wire_type {
-            let len = input.read_raw_varint64()?;
-            let mut msg = match *self {
-                Field::Singular(ref mut o) => {
-                    if let Some(Value::Message(m)) = o.take() {
-                        m
-                    } else {
-                        Message::new(message)
-                    }
+        let mut msg = match *self {
+            Field::Singular(ref mut o) => {
+                if let Some(Value::Message(m)) = o.take() {
+                    m
+                } else {
+                    Message::new(message)
                 }
-                _ => Message::new(message),
-            };
+            }
+            _ => Message::new(message),
+        };
+        match len {
+            Some(len) => {
+                let old_limit = input.push_limit(u64::from(len))?;
+                msg.merge_fields(descriptors, message, input, options, None, None)?;
+                input.pop_limit(old_limit);
+            }
+            None => {
+                let end_group = Some(field.number() as u32);
+                msg.merge_fields(descriptors, message, input, options, None, end_group)?;
+            }
+        }
 
-            let old_limit = input.push_limit(len)?;
-            msg.merge_from(descriptors, message, input)?;
-            input.pop_limit(old_limit);
+        self.put(Value::Message(msg));
+        Ok(())
+    }
 
-            self.put(Value::Message(msg));
-            Ok(())
-        } else {
-            Err(error::Error::BadWireType {
-                wire_type: actual_wire_type,
-            })
+    #[inline]
+    fn reserve(&mut self, additional: usize) {
+        if let Field::Repeated(ref mut r) = *self {
+            r.reserve(additional);
         }
     }
 
@@ -306,4 +969,1818 @@ impl Field {
             Field::Repeated(ref mut r) => r.push(value),
         }
     }
+
+    /// Replaces all scalar values of this field with their string representations; see
+    /// `DecodeOptions::stringify_scalars`.
+    pub(crate) fn stringify_scalars(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+    ) {
+        let values = match *self {
+            Field::Singular(ref mut v) => v.as_mut_slice(),
+            Field::Repeated(ref mut vs) => vs.as_mut_slice(),
+        };
+        for value in values {
+            if let Some(s) = stringify_scalar(descriptors, field, value) {
+                *value = Value::String(s);
+            }
+        }
+    }
+}
+
+impl<'a> MessageView<'a> {
+    /// Creates a view of a message value with the specified message descriptor.
+    ///
+    /// Returns `None` if the value isn't a message.
+    pub fn new(
+        descriptor: &'a descriptor::MessageDescriptor,
+        value: &'a Value,
+    ) -> Option<MessageView<'a>> {
+        match *value {
+            Value::Message(ref message) => Some(MessageView {
+                descriptor,
+                message,
+            }),
+            _ => None,
+        }
+    }
+
+    /// The descriptor of the message.
+    #[inline]
+    pub fn descriptor(&self) -> &'a descriptor::MessageDescriptor {
+        self.descriptor
+    }
+
+    /// Looks up the value of the singular field with the specified name.
+    ///
+    /// Returns `None` if there is no such field, if the field is unset or if the field is
+    /// repeated; use `get_repeated` for repeated fields.
+    pub fn get(&self, field_name: &str) -> Option<&'a Value> {
+        self.descriptor
+            .field_by_name(field_name)
+            .and_then(|f| self.singular(f.number()))
+    }
+
+    /// Looks up the value of the singular field with the specified number.
+    ///
+    /// Returns `None` if there is no such field, if the field is unset or if the field is
+    /// repeated.
+    pub fn get_by_number(&self, number: i32) -> Option<&'a Value> {
+        self.descriptor
+            .field_by_number(number)
+            .and_then(|f| self.singular(f.number()))
+    }
+
+    /// Looks up the values of the repeated field with the specified name.
+    ///
+    /// Returns `None` if there is no such field or if the field is singular.
+    pub fn get_repeated(&self, field_name: &str) -> Option<&'a [Value]> {
+        let field = self.descriptor.field_by_name(field_name)?;
+        match self.message.fields.get(&field.number()) {
+            Some(Field::Repeated(values)) => Some(values),
+            None if field.is_repeated() => Some(&[]),
+            _ => None,
+        }
+    }
+
+    /// Iterates over all set field values of the message in field number order.
+    ///
+    /// Every value of a repeated field is yielded separately.  Unknown fields are skipped.
+    pub fn iter(&self) -> impl Iterator<Item = (&'a descriptor::FieldDescriptor, &'a Value)> + 'a {
+        let descriptor = self.descriptor;
+        self.message
+            .fields
+            .iter()
+            .filter_map(move |(&number, field)| {
+                descriptor.field_by_number(number).map(|f| (f, field))
+            })
+            .flat_map(|(f, field)| {
+                let values = match *field {
+                    Field::Singular(ref v) => v.as_slice(),
+                    Field::Repeated(ref vs) => vs.as_slice(),
+                };
+                values.iter().map(move |v| (f, v))
+            })
+    }
+
+    fn singular(&self, number: i32) -> Option<&'a Value> {
+        match self.message.fields.get(&number) {
+            Some(Field::Singular(v)) => v.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+impl DecodeOptions {
+    /// Creates decode options with the default settings.
+    pub fn new() -> DecodeOptions {
+        DecodeOptions::default()
+    }
+
+    /// Sets the maximum size in bytes of any single length-delimited field.
+    ///
+    /// Decoding fails with `Error::FieldTooLarge` if a field claims to be larger than this,
+    /// before any memory is allocated for the field.  By default, the size of a field is only
+    /// limited by the maximum size that the wire format can represent.
+    pub fn max_field_size(mut self, max_field_size: u32) -> DecodeOptions {
+        self.max_field_size = Some(u64::from(max_field_size));
+        self
+    }
+
+    /// Sets the maximum total number of field occurrences decoded from a single message,
+    /// including the occurrences in all nested messages, unknown fields, and every element of a
+    /// packed repeated field.
+    ///
+    /// Decoding fails with `Error::TooManyFields` once the limit is exceeded, which bounds the work
+    /// done for input with huge numbers of tiny fields that each stay below `max_field_size`.  By
+    /// default, the number of fields is not limited.
+    pub fn max_total_fields(mut self, max_total_fields: u64) -> DecodeOptions {
+        self.max_total_fields = Some(max_total_fields);
+        self
+    }
+
+    /// Sets whether messages that are missing `required` fields may be decoded.
+    ///
+    /// By default, decoding fails with `Error::MissingRequiredField` if a message doesn't contain
+    /// a value for one of its `required` fields.  If missing required fields are allowed, such
+    /// fields are instead left absent, as if they were `optional`.
+    pub fn allow_missing_required(mut self, allow_missing_required: bool) -> DecodeOptions {
+        self.allow_missing_required = allow_missing_required;
+        self
+    }
+
+    /// Sets how the contents of `string` fields are validated.
+    ///
+    /// By default, strings are validated strictly.
+    pub fn string_validation(mut self, string_validation: StringValidation) -> DecodeOptions {
+        self.string_validation = string_validation;
+        self
+    }
+
+    /// Sets whether absent singular scalar fields are filled in with their default values after
+    /// decoding a message.
+    ///
+    /// Fields with a declared default value get that value, and in `proto3` messages, all other
+    /// scalar fields get their implicit zero default value.  By default, absent fields are left
+    /// absent.
+    pub fn fill_defaults(mut self, fill_defaults: bool) -> DecodeOptions {
+        self.fill_defaults = fill_defaults;
+        self
+    }
+
+    /// Sets whether the values of all scalar fields are replaced with their string
+    /// representations, producing `Value::String` values, which is useful for displaying
+    /// messages without knowing their schema.
+    ///
+    /// Messages and the structure of repeated fields are preserved.  Integers are formatted in
+    /// decimal, floats use the shortest decimal representation that round-trips (with `NaN`,
+    /// `inf` and `-inf` for the non-finite values), bools become `"true"` or `"false"`, bytes
+    /// become standard base64 with padding, and enums become the names of their values, or the
+    /// decimal numbers of unknown values.  Field hooks see the values before they are
+    /// stringified.  By default, scalars keep their types.
+    pub fn stringify_scalars(mut self, stringify_scalars: bool) -> DecodeOptions {
+        self.stringify_scalars = stringify_scalars;
+        self
+    }
+
+    /// Sets whether decoding fails with `Error::DuplicateField` if a singular scalar field occurs
+    /// more than once in the same message on the wire.
+    ///
+    /// By default, the last occurrence wins, as required by the protocol buffer specification.
+    /// Repeated occurrences of singular message fields are still merged, because that is how
+    /// large messages are commonly split up.
+    pub fn reject_duplicate_scalars(mut self, reject_duplicate_scalars: bool) -> DecodeOptions {
+        self.reject_duplicate_scalars = reject_duplicate_scalars;
+        self
+    }
+
+    /// Sets whether fields whose message type isn't in the descriptor registry are decoded without
+    /// a schema, instead of failing with `Error::UnknownMessage`.
+    ///
+    /// Such a field is decoded into a `Value::Message` without any known fields, which keeps all
+    /// of the fields of the encoded message as unknown fields, keyed by their field numbers and
+    /// holding their raw wire-typed values.  Groups within such messages are skipped, like unknown
+    /// groups anywhere else.  Fields with unresolved group types still fail to decode.  By
+    /// default, unresolved message types are an error.
+    pub fn schemaless_unresolved_messages(
+        mut self,
+        schemaless_unresolved_messages: bool,
+    ) -> DecodeOptions {
+        self.schemaless_unresolved_messages = schemaless_unresolved_messages;
+        self
+    }
+
+    /// Registers a hook that is called with every decoded value of the specified field of the
+    /// specified message type, and returns the value to store in its place.
+    ///
+    /// The message type name must be fully qualified.  Hooks apply at any nesting depth, and are
+    /// called as soon as each value has been decoded, so they can for example decrypt a `bytes`
+    /// field or convert an `int64` field to a different representation.  Errors returned by a
+    /// hook abort decoding.  Each occurrence of a singular message field on the wire is decoded
+    /// and passed to the hook on its own, instead of being merged into the previous occurrence.
+    pub fn field_hook<S, F>(mut self, message_name: S, field_number: i32, hook: F) -> DecodeOptions
+    where
+        S: Into<String>,
+        F: Fn(Value) -> error::Result<Value> + Send + Sync + 'static,
+    {
+        self.field_hooks
+            .0
+            .entry(message_name.into())
+            .or_default()
+            .insert(field_number, sync::Arc::new(hook));
+        self
+    }
+
+    #[inline]
+    fn field_hook_for(
+        &self,
+        message: &descriptor::MessageDescriptor,
+        field: &descriptor::FieldDescriptor,
+    ) -> Option<&FieldHook> {
+        if self.field_hooks.0.is_empty() {
+            return None;
+        }
+        self.field_hooks
+            .0
+            .get(message.name())?
+            .get(&field.number())
+            .map(|h| &**h)
+    }
+
+    /// Decodes the contents of a `string` field according to the string validation policy.
+    #[inline]
+    fn decode_string(
+        &self,
+        field: &descriptor::FieldDescriptor,
+        bytes: Vec<u8>,
+    ) -> error::Result<String> {
+        match String::from_utf8(bytes) {
+            Ok(s) => Ok(s),
+            Err(e) => match self.string_validation {
+                StringValidation::Strict => Err(error::Error::InvalidUtf8 {
+                    field: field.name().to_owned(),
+                    source: e,
+                }),
+                StringValidation::Lossy => Ok(String::from_utf8_lossy(e.as_bytes()).into_owned()),
+            },
+        }
+    }
+
+    /// Records that a field of a message was seen while decoding it.
+    ///
+    /// Only the fields that are checked later are recorded: `required` fields, and singular
+    /// scalar fields if duplicates are rejected.
+    pub(crate) fn mark_seen(
+        &self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        seen: &mut collections::BTreeSet<i32>,
+    ) -> error::Result<()> {
+        let check_duplicate = self.reject_duplicate_scalars
+            && !field.is_repeated()
+            && !matches!(
+                field.field_type(descriptors),
+                descriptor::FieldType::Message(_) | descriptor::FieldType::Group(_)
+            );
+        if check_duplicate {
+            if !seen.insert(field.number()) {
+                return Err(error::Error::DuplicateField {
+                    name: field.name().to_owned(),
+                    number: field.number(),
+                });
+            }
+        } else if field.field_label() == descriptor::FieldLabel::Required {
+            seen.insert(field.number());
+        }
+        Ok(())
+    }
+
+    /// Checks that all of the `required` fields of a message were seen while decoding it, unless
+    /// missing required fields are allowed.
+    pub(crate) fn check_required(
+        &self,
+        message: &descriptor::MessageDescriptor,
+        seen: &collections::BTreeSet<i32>,
+    ) -> error::Result<()> {
+        if self.allow_missing_required {
+            return Ok(());
+        }
+
+        match message.fields().iter().find(|f| {
+            f.field_label() == descriptor::FieldLabel::Required && !seen.contains(&f.number())
+        }) {
+            Some(field) => Err(error::Error::MissingRequiredField {
+                field: field.name().to_owned(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// The maximum size of a length-delimited field.
+    #[inline]
+    fn field_size_limit(&self) -> u64 {
+        self.max_field_size.unwrap_or(u64::from(u32::MAX))
+    }
+
+    /// Reads the length prefix of a length-delimited field, and checks it against the maximum
+    /// field size.
+    #[inline]
+    pub(crate) fn read_length(&self, input: &mut protobuf::CodedInputStream) -> error::Result<u32> {
+        let size = input.read_raw_varint64()?;
+        let max_size = self.field_size_limit();
+        if size > max_size {
+            Err(error::Error::FieldTooLarge { size, max_size })
+        } else {
+            Ok(size as u32)
+        }
+    }
+}
+
+impl<'a> DecodeContext<'a> {
+    /// Creates a context for a new decode operation with the specified options.
+    #[inline]
+    pub(crate) fn new(options: &'a DecodeOptions) -> DecodeContext<'a> {
+        DecodeContext::resume(options, options.max_total_fields)
+    }
+
+    /// Creates a context for continuing a decode operation, where the specified number of field
+    /// occurrences may still be decoded.
+    #[inline]
+    pub(crate) fn resume(
+        options: &'a DecodeOptions,
+        remaining_fields: Option<u64>,
+    ) -> DecodeContext<'a> {
+        DecodeContext {
+            options,
+            remaining_fields: cell::Cell::new(remaining_fields),
+            path: cell::RefCell::new(Vec::new()),
+            occurrences: cell::Cell::new(0),
+            missing_required: cell::RefCell::new(collections::BTreeMap::new()),
+            presence: None,
+        }
+    }
+
+    /// Creates a context for a new decode operation with the specified options, which also
+    /// records the fields of the top-level message that occur on the wire; see `into_presence`.
+    pub(crate) fn with_presence(options: &'a DecodeOptions) -> DecodeContext<'a> {
+        DecodeContext {
+            presence: Some(cell::RefCell::new(collections::BTreeSet::new())),
+            ..DecodeContext::new(options)
+        }
+    }
+
+    /// Records that a field with the specified number occurred on the wire, if presence is
+    /// recorded and the field belongs to the top-level message.
+    #[inline]
+    fn record_presence(&self, number: u32) {
+        if let Some(ref presence) = self.presence {
+            if self.path.borrow().is_empty() {
+                presence.borrow_mut().insert(number as i32);
+            }
+        }
+    }
+
+    /// Returns the numbers of the fields of the top-level message that occurred on the wire, or
+    /// an empty set if presence isn't recorded.
+    pub(crate) fn into_presence(self) -> collections::BTreeSet<i32> {
+        self.presence
+            .map(cell::RefCell::into_inner)
+            .unwrap_or_default()
+    }
+
+    /// The number of field occurrences that may still be decoded, if limited.
+    #[inline]
+    pub(crate) fn remaining_fields(&self) -> Option<u64> {
+        self.remaining_fields.get()
+    }
+
+    /// Accounts for a single decoded field occurrence, failing if that exceeds the limit.
+    #[inline]
+    pub(crate) fn count_field(&self) -> error::Result<()> {
+        match self.remaining_fields.get() {
+            Some(0) => Err(error::Error::TooManyFields {
+                max_fields: self.options.max_total_fields.unwrap_or(0),
+            }),
+            Some(n) => {
+                self.remaining_fields.set(Some(n - 1));
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Decodes a nested message that is the value of the field with the specified number.
+    ///
+    /// All occurrences of a singular message field are merged, so they are tracked as the same
+    /// nested message, while every element of a repeated field is a message of its own.
+    pub(crate) fn nested<T, F>(&self, number: i32, repeated: bool, decode: F) -> error::Result<T>
+    where
+        F: FnOnce() -> error::Result<T>,
+    {
+        let occurrence = if repeated {
+            self.occurrences.set(self.occurrences.get() + 1);
+            self.occurrences.get()
+        } else {
+            0
+        };
+        self.path.borrow_mut().push((number, occurrence));
+        let result = decode();
+        self.path.borrow_mut().pop();
+        result
+    }
+
+    /// Checks the `required` fields of a message once an occurrence of it has been decoded, given
+    /// the fields that were seen in that occurrence.
+    ///
+    /// A nested message may be split into several occurrences that are merged, so its `required`
+    /// fields only have to be seen in one of them.  They are therefore checked when the top-level
+    /// message has been decoded, which also checks the nested messages.
+    pub(crate) fn finish_message(
+        &self,
+        message: &descriptor::MessageDescriptor,
+        seen: &collections::BTreeSet<i32>,
+    ) -> error::Result<()> {
+        if self.allow_missing_required {
+            return Ok(());
+        }
+        let path = self.path.borrow();
+        if path.is_empty() {
+            self.check_required(message, seen)?;
+            return self.check_nested_required();
+        }
+
+        let mut missing_required = self.missing_required.borrow_mut();
+        match missing_required.get_mut(&path[..]) {
+            Some(missing) => missing.retain(|(number, _)| !seen.contains(number)),
+            None => {
+                let required = message
+                    .fields()
+                    .iter()
+                    .filter(|f| f.field_label() == descriptor::FieldLabel::Required)
+                    .collect::<Vec<_>>();
+                if !required.is_empty() {
+                    let missing = required
+                        .into_iter()
+                        .filter(|f| !seen.contains(&f.number()))
+                        .map(|f| (f.number(), f.name().to_owned()))
+                        .collect();
+                    missing_required.insert(path.clone(), missing);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails if a `required` field was missing from all occurrences of a nested message.
+    pub(crate) fn check_nested_required(&self) -> error::Result<()> {
+        match self
+            .missing_required
+            .borrow()
+            .values()
+            .find_map(|missing| missing.first())
+        {
+            Some((_, field)) => Err(error::Error::MissingRequiredField {
+                field: field.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a> ops::Deref for DecodeContext<'a> {
+    type Target = DecodeOptions;
+
+    #[inline]
+    fn deref(&self) -> &DecodeOptions {
+        self.options
+    }
+}
+
+impl fmt::Debug for FieldHooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set()
+            .entries(
+                self.0
+                    .iter()
+                    .flat_map(|(name, hooks)| hooks.keys().map(move |number| (name, number))),
+            )
+            .finish()
+    }
+}
+
+/// Prunes a message value down to only the fields selected by a list of dotted field paths, in
+/// the style of a `google.protobuf.FieldMask`.
+///
+/// A path selecting a field keeps the whole field, including all values of a repeated field,
+/// while a path through a nested message field keeps only the selected fields of the nested
+/// message.  All other fields, and all unknown fields, are dropped.  Values that aren't messages
+/// are left untouched.
+///
+/// Returns an error without modifying the value if any of the paths can't be resolved against
+/// the message descriptor; see `Descriptors::resolve_field_path`.
+pub fn apply_field_mask(
+    descriptors: &descriptor::Descriptors,
+    message: &descriptor::MessageDescriptor,
+    value: &mut Value,
+    paths: &[&str],
+) -> error::Result<()> {
+    let paths = paths
+        .iter()
+        .map(|path| {
+            descriptors
+                .resolve_field_path(message.name(), path)
+                .map(|fields| fields.iter().map(|f| f.number()).collect::<Vec<_>>())
+        })
+        .collect::<error::Result<Vec<_>>>()?;
+
+    if let Value::Message(ref mut m) = *value {
+        let paths = paths.iter().map(Vec::as_slice).collect::<Vec<_>>();
+        prune_message(m, &paths);
+    }
+    Ok(())
+}
+
+fn prune_message(message: &mut Message, paths: &[&[i32]]) {
+    message.unknown = protobuf::UnknownFields::new();
+    message.fields.retain(|number, field| {
+        let mut sub_paths = Vec::new();
+        for path in paths {
+            match path.split_first() {
+                Some((n, [])) if n == number => return true,
+                Some((n, rest)) if n == number => sub_paths.push(rest),
+                _ => (),
+            }
+        }
+
+        if sub_paths.is_empty() {
+            return false;
+        }
+        let values = match *field {
+            Field::Singular(ref mut v) => v.iter_mut().collect::<Vec<_>>(),
+            Field::Repeated(ref mut vs) => vs.iter_mut().collect(),
+        };
+        for value in values {
+            if let Value::Message(ref mut m) = *value {
+                prune_message(m, &sub_paths);
+            }
+        }
+        true
+    });
+}
+
+/// Compares two message values for equality, using the protobuf equality semantics for the
+/// message type with the specified descriptor.
+///
+/// Unlike a structural comparison, unset singular scalar fields are considered equal to fields
+/// without presence that are set to the default value of the field, `NaN` floating point values
+/// are considered equal to each other, and unknown fields are ignored.  Repeated fields are
+/// compared element-wise in order, except for map fields, which are compared as sets of entries
+/// where the last entry for each key wins.  Nested messages are compared recursively.
+///
+/// Returns `false` if either of the values isn't a message.
+pub fn message_eq(
+    descriptors: &descriptor::Descriptors,
+    message: &descriptor::MessageDescriptor,
+    a: &Value,
+    b: &Value,
+) -> bool {
+    match (a, b) {
+        (Value::Message(a), Value::Message(b)) => message_fields_eq(descriptors, message, a, b),
+        _ => false,
+    }
+}
+
+fn message_fields_eq(
+    descriptors: &descriptor::Descriptors,
+    message: &descriptor::MessageDescriptor,
+    a: &Message,
+    b: &Message,
+) -> bool {
+    message.fields().iter().all(|field| {
+        let number = field.number();
+        match (a.fields.get(&number), b.fields.get(&number)) {
+            (Some(Field::Repeated(a)), Some(Field::Repeated(b)))
+                if field.map_entry_fields(descriptors).is_some() =>
+            {
+                let a = map_entries(a);
+                let b = map_entries(b);
+                a.len() == b.len()
+                    && a.iter()
+                        .zip(b.iter())
+                        .all(|(a, b)| value_eq(descriptors, field, a, b))
+            }
+            (Some(Field::Repeated(a)), Some(Field::Repeated(b))) => {
+                a.len() == b.len()
+                    && a.iter()
+                        .zip(b.iter())
+                        .all(|(a, b)| value_eq(descriptors, field, a, b))
+            }
+            (Some(Field::Repeated(v)), None) | (None, Some(Field::Repeated(v))) => v.is_empty(),
+            (a, b) => {
+                let a = singular(a);
+                let b = singular(b);
+                match (a, b) {
+                    (Some(a), Some(b)) => value_eq(descriptors, field, a, b),
+                    (None, None) => true,
+                    (Some(_), None) | (None, Some(_)) if field.has_presence() => false,
+                    (Some(v), None) | (None, Some(v)) => match scalar_default(descriptors, field) {
+                        Some(ref default) => value_eq(descriptors, field, v, default),
+                        None => false,
+                    },
+                }
+            }
+        }
+    })
+}
+
+/// The key of a map entry, if it is set.
+pub(crate) fn map_key(entry: &Value) -> Option<&Value> {
+    match *entry {
+        Value::Message(ref m) => match m.fields.get(&1) {
+            Some(Field::Singular(v)) => v.as_ref(),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Orders map keys, with absent keys first since they stand for the default value.
+pub(crate) fn compare_map_keys(a: Option<&Value>, b: Option<&Value>) -> cmp::Ordering {
+    use self::Value as V;
+
+    match (a, b) {
+        (Some(&V::Bool(a)), Some(&V::Bool(b))) => a.cmp(&b),
+        (Some(&V::I32(a)), Some(&V::I32(b))) => a.cmp(&b),
+        (Some(&V::I64(a)), Some(&V::I64(b))) => a.cmp(&b),
+        (Some(&V::U32(a)), Some(&V::U32(b))) => a.cmp(&b),
+        (Some(&V::U64(a)), Some(&V::U64(b))) => a.cmp(&b),
+        (Some(V::String(a)), Some(V::String(b))) => a.cmp(b),
+        (a, b) => a.is_some().cmp(&b.is_some()),
+    }
+}
+
+/// The entries of a map field ordered by key, keeping only the last entry for each key like
+/// decoding a map does.
+fn map_entries(entries: &[Value]) -> Vec<&Value> {
+    let mut sorted = entries.iter().collect::<Vec<_>>();
+    // The sort is stable, so the last entry for each key stays last
+    sorted.sort_by(|a, b| compare_map_keys(map_key(a), map_key(b)));
+    let mut result: Vec<&Value> = Vec::with_capacity(sorted.len());
+    for entry in sorted {
+        match result.last_mut() {
+            Some(last)
+                if compare_map_keys(map_key(last), map_key(entry)) == cmp::Ordering::Equal =>
+            {
+                *last = entry
+            }
+            _ => result.push(entry),
+        }
+    }
+    result
+}
+
+fn singular(field: Option<&Field>) -> Option<&Value> {
+    match field {
+        Some(Field::Singular(v)) => v.as_ref(),
+        _ => None,
+    }
+}
+
+fn value_eq(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    a: &Value,
+    b: &Value,
+) -> bool {
+    match (a, b) {
+        (Value::F32(a), Value::F32(b)) => a == b || (a.is_nan() && b.is_nan()),
+        (Value::F64(a), Value::F64(b)) => a == b || (a.is_nan() && b.is_nan()),
+        (Value::Bool(a), Value::Bool(b)) => a == b,
+        (Value::I32(a), Value::I32(b)) => a == b,
+        (Value::I64(a), Value::I64(b)) => a == b,
+        (Value::U32(a), Value::U32(b)) => a == b,
+        (Value::U64(a), Value::U64(b)) => a == b,
+        (Value::Bytes(a), Value::Bytes(b)) => a == b,
+        (Value::String(a), Value::String(b)) => a == b,
+        (Value::Enum(a), Value::Enum(b)) => a == b,
+        (Value::Message(a), Value::Message(b)) => match field.field_type(descriptors) {
+            descriptor::FieldType::Message(m) | descriptor::FieldType::Group(m) => {
+                message_fields_eq(descriptors, m, a, b)
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// The string representation of a scalar value, or `None` if the value is a message or already a
+/// string.
+fn stringify_scalar(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+    value: &Value,
+) -> Option<String> {
+    match *value {
+        Value::Bool(v) => Some(v.to_string()),
+        Value::I32(v) => Some(v.to_string()),
+        Value::I64(v) => Some(v.to_string()),
+        Value::U32(v) => Some(v.to_string()),
+        Value::U64(v) => Some(v.to_string()),
+        Value::F32(v) => Some(v.to_string()),
+        Value::F64(v) => Some(v.to_string()),
+        Value::Bytes(ref v) => Some(base64_encode(v)),
+        Value::Enum(v) => Some(match field.field_type(descriptors) {
+            descriptor::FieldType::Enum(e) => e.value_display(v),
+            _ => v.to_string(),
+        }),
+        Value::String(_) | Value::Message(_) => None,
+    }
+}
+
+/// Encodes bytes using the standard base64 alphabet, with padding.
+pub(crate) fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// The value that an unset singular scalar field is considered to have.
+pub(crate) fn scalar_default(
+    descriptors: &descriptor::Descriptors,
+    field: &descriptor::FieldDescriptor,
+) -> Option<Value> {
+    use crate::descriptor::FieldType;
+
+    if let Some(v) = field.default_value() {
+        return Some(v.clone());
+    }
+
+    match field.field_type(descriptors) {
+        FieldType::Bool => Some(Value::Bool(false)),
+        FieldType::Int32 | FieldType::SInt32 | FieldType::SFixed32 => Some(Value::I32(0)),
+        FieldType::Int64 | FieldType::SInt64 | FieldType::SFixed64 => Some(Value::I64(0)),
+        FieldType::UInt32 | FieldType::Fixed32 => Some(Value::U32(0)),
+        FieldType::UInt64 | FieldType::Fixed64 => Some(Value::U64(0)),
+        FieldType::Float => Some(Value::F32(0.0)),
+        FieldType::Double => Some(Value::F64(0.0)),
+        FieldType::String => Some(Value::String(String::new())),
+        FieldType::Bytes => Some(Value::Bytes(Vec::new())),
+        FieldType::Enum(e) => e.values().first().map(|v| Value::Enum(v.number())),
+        FieldType::Message(_)
+        | FieldType::Group(_)
+        | FieldType::UnresolvedGroup(_)
+        | FieldType::UnresolvedMessage(_)
+        | FieldType::UnresolvedEnum(_) => None,
+    }
+}
+
+/// Skips over a field without decoding it, checking the length of length-delimited fields against
+/// the maximum field size.
+#[inline]
+pub(crate) fn skip_field(
+    input: &mut protobuf::CodedInputStream,
+    wire_type: wire_format::WireType,
+    options: &DecodeOptions,
+) -> error::Result<()> {
+    wire::skip_field_from(input, wire_type, options.field_size_limit())
+}
+
+/// Returns the descriptor that messages of an unresolved type are decoded with when
+/// `DecodeOptions::schemaless_unresolved_messages` is enabled.
+///
+/// A descriptor without any fields keeps all fields of the message as unknown fields.  Nothing
+/// else about the descriptor is used while decoding, so a single one is shared by all unresolved
+/// types.
+pub(crate) fn schemaless_message() -> &'static descriptor::MessageDescriptor {
+    static SCHEMALESS: sync::OnceLock<descriptor::MessageDescriptor> = sync::OnceLock::new();
+    SCHEMALESS.get_or_init(|| descriptor::MessageDescriptor::new(""))
+}
+
+/// Returns the numbers of the fields of a message that are not wanted, if only some are.
+fn unwanted_fields(
+    message: &descriptor::MessageDescriptor,
+    wanted: Option<&collections::BTreeSet<i32>>,
+) -> collections::BTreeSet<i32> {
+    match wanted {
+        Some(wanted) => message
+            .fields()
+            .iter()
+            .map(|f| f.number())
+            .filter(|number| !wanted.contains(number))
+            .collect(),
+        None => collections::BTreeSet::new(),
+    }
+}
+
+/// Attaches the offset of the field whose decoding failed to an error, unless the error already
+/// has a more precise offset from within a nested message.
+fn decode_at(offset: u64, e: error::Error) -> error::Error {
+    if e.offset().is_some() {
+        e
+    } else {
+        error::Error::DecodeAt {
+            offset: offset as usize,
+            source: Box::new(e),
+        }
+    }
+}
+
+/// Shifts the offsets attached to an error by the specified base offset.
+fn shift_offsets(e: error::Error, base: u64) -> error::Error {
+    match e {
+        error::Error::DecodeAt { offset, source } => error::Error::DecodeAt {
+            offset: offset + base as usize,
+            source,
+        },
+        error::Error::InField {
+            field,
+            index,
+            source,
+        } => error::Error::InField {
+            field,
+            index,
+            source: Box::new(shift_offsets(*source, base)),
+        },
+        e => e,
+    }
+}
+
+/// Reads the value of a non-group field from the input stream, and re-encodes it together with its
+/// tag so that it can be decoded in isolation.
+fn read_raw_field(
+    input: &mut protobuf::CodedInputStream,
+    number: u32,
+    wire_type: wire_format::WireType,
+    options: &DecodeOptions,
+) -> error::Result<Vec<u8>> {
+    let value = if wire_format::WireType::LengthDelimited == wire_type {
+        let len = options.read_length(input)?;
+        protobuf::UnknownValue::LengthDelimited(input.read_raw_bytes(len)?)
+    } else {
+        input.read_unknown(wire_type)?
+    };
+
+    let mut bytes = Vec::new();
+    let mut output = protobuf::CodedOutputStream::vec(&mut bytes);
+    output.write_unknown(number, value.get_ref())?;
+    output.flush()?;
+    drop(output);
+    Ok(bytes)
+}
+
+/// The wire type of a single, unpacked value of a field with the specified type.
+pub(crate) fn wire_type(field_type: &descriptor::FieldType) -> wire_format::WireType {
+    use crate::descriptor::FieldType as T;
+
+    match *field_type {
+        T::Double | T::Fixed64 | T::SFixed64 => wire_format::WireType::Fixed64,
+        T::Float | T::Fixed32 | T::SFixed32 => wire_format::WireType::Fixed32,
+        T::Int64
+        | T::UInt64
+        | T::Int32
+        | T::Bool
+        | T::UInt32
+        | T::SInt32
+        | T::SInt64
+        | T::Enum(_)
+        | T::UnresolvedEnum(_) => wire_format::WireType::Varint,
+        T::String | T::Bytes | T::Message(_) | T::UnresolvedMessage(_) => {
+            wire_format::WireType::LengthDelimited
+        }
+        T::Group(_) | T::UnresolvedGroup(_) => wire_format::WireType::StartGroup,
+    }
+}
+
+/// Decodes a zig-zag encoded 32-bit integer, as used by the `sint32` type.
+#[inline]
+pub fn zigzag_decode_32(n: u32) -> i32 {
+    ((n >> 1) as i32) ^ -((n & 1) as i32)
+}
+
+/// Decodes a zig-zag encoded 64-bit integer, as used by the `sint64` type.
+#[inline]
+pub fn zigzag_decode_64(n: u64) -> i64 {
+    ((n >> 1) as i64) ^ -((n & 1) as i64)
+}
+
+/// Zig-zag encodes a 32-bit integer, as used by the `sint32` type, so that integers with a small
+/// absolute value have a short varint encoding.
+#[inline]
+pub fn zigzag_encode_32(n: i32) -> u32 {
+    ((n << 1) ^ (n >> 31)) as u32
+}
+
+/// Zig-zag encodes a 64-bit integer, as used by the `sint64` type, so that integers with a small
+/// absolute value have a short varint encoding.
+#[inline]
+pub fn zigzag_encode_64(n: i64) -> u64 {
+    ((n << 1) ^ (n >> 63)) as u64
+}
+
+/// Reads a field tag from the input stream, returning the field number and wire type.
+#[inline]
+pub(crate) fn read_tag(
+    input: &mut protobuf::CodedInputStream,
+) -> error::Result<(u32, wire_format::WireType)> {
+    wire::read_tag_from(input)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::descriptor::test::load_descriptors;
+    use crate::descriptor::{
+        Descriptors, FieldDescriptor, FieldLabel, InternalFieldType, MessageDescriptor, Syntax,
+    };
+
+    fn descriptors() -> Descriptors {
+        let mut inner = MessageDescriptor::new(".test.Inner");
+        inner.set_syntax(Syntax::Proto3);
+        inner.add_field(
+            FieldDescriptor::builder("ratio")
+                .number(1)
+                .field_type(InternalFieldType::Double)
+                .build(),
+        );
+        inner.add_field(
+            FieldDescriptor::builder("label")
+                .number(2)
+                .field_type(InternalFieldType::String)
+                .build(),
+        );
+
+        let mut outer = MessageDescriptor::new(".test.Outer");
+        outer.add_field(FieldDescriptor::builder("count").number(1).build());
+        outer.add_field(
+            FieldDescriptor::builder("name")
+                .number(2)
+                .field_type(InternalFieldType::String)
+                .default_value("anonymous".into())
+                .build(),
+        );
+        outer.add_field(
+            FieldDescriptor::builder("inners")
+                .number(3)
+                .label(FieldLabel::Repeated)
+                .field_type(InternalFieldType::UnresolvedMessage(".test.Inner".into()))
+                .build(),
+        );
+
+        let mut descriptors = Descriptors::new();
+        descriptors.add_message(inner);
+        descriptors.add_message(outer);
+        descriptors.resolve_refs();
+        descriptors
+    }
+
+    fn decode(descriptors: &Descriptors, data: &[u8]) -> Value {
+        let descriptor = descriptors.message_by_name(".test.Outer").unwrap();
+        let mut message = Message::new(descriptor);
+        let mut input = protobuf::CodedInputStream::from_bytes(data);
+        message
+            .merge_from(descriptors, descriptor, &mut input)
+            .unwrap();
+        Value::Message(message)
+    }
+
+    fn eq(a: &[u8], b: &[u8]) -> bool {
+        let d = descriptors();
+        let descriptor = d.message_by_name(".test.Outer").unwrap();
+        message_eq(&d, descriptor, &decode(&d, a), &decode(&d, b))
+    }
+
+    #[test]
+    fn packed_fields_reserve_capacity() {
+        let d = Descriptors::new();
+        let field = |field_type| {
+            FieldDescriptor::builder("values")
+                .number(1)
+                .label(FieldLabel::Repeated)
+                .field_type(field_type)
+                .build()
+        };
+        let decode = |field: &FieldDescriptor, payload: &[u8]| {
+            let mut data = vec![10];
+            let mut output = protobuf::CodedOutputStream::vec(&mut data);
+            output.write_raw_varint32(payload.len() as u32).unwrap();
+            output.write_raw_bytes(payload).unwrap();
+            output.flush().unwrap();
+            drop(output);
+
+            let mut value = Field::new(field);
+            let mut input = protobuf::CodedInputStream::from_bytes(&data);
+            let (_, wire_type) = read_tag(&mut input).unwrap();
+            value.merge_from(&d, field, &mut input, wire_type).unwrap();
+            match value {
+                Field::Repeated(vs) => vs,
+                f => panic!("Expected a repeated field, got {:?}", f),
+            }
+        };
+
+        // 100000 values of mixed varint lengths; growing the vector one value at a time would
+        // end up with a capacity of 131072 after 17 reallocations
+        let mut payload = Vec::new();
+        let mut output = protobuf::CodedOutputStream::vec(&mut payload);
+        for i in 0..100_000 {
+            output.write_int32_no_tag(i - 50_000).unwrap();
+        }
+        output.flush().unwrap();
+        drop(output);
+        let vs = decode(&field(InternalFieldType::Int32), &payload);
+        assert_eq!(vs.len(), 100_000);
+        assert_eq!(vs.capacity(), 100_000);
+        assert!(matches!(vs[0], Value::I32(-50_000)));
+        assert!(matches!(vs[99_999], Value::I32(49_999)));
+
+        let vs = decode(&field(InternalFieldType::Fixed32), &[0; 4 * 1000]);
+        assert_eq!(vs.len(), 1000);
+        assert_eq!(vs.capacity(), 1000);
+
+        // Beyond the limit, the vector grows as usual
+        let count = MAX_RESERVED_VALUES + 1;
+        let vs = decode(&field(InternalFieldType::Fixed32), &vec![0; 4 * count]);
+        assert_eq!(vs.len(), count);
+        assert!(vs.capacity() >= count);
+    }
+
+    #[test]
+    fn schemaless_message_is_shared() {
+        let schemaless = schemaless_message();
+        assert!(schemaless.fields().is_empty());
+        assert!(std::ptr::eq(schemaless, schemaless_message()));
+    }
+
+    #[test]
+    fn packed_field_length_beyond_input() {
+        let mut d = load_descriptors();
+        d.resolve_refs();
+        let descriptor = d
+            .message_by_name(".protobuf_unittest.TestAllTypes")
+            .unwrap();
+        // repeated_sfixed32 with a length of almost 4 GiB, but no payload
+        let data = [186, 2, 0xf0, 0xff, 0xff, 0xff, 0x0f];
+        let mut message = Message::new(descriptor);
+        let mut input = protobuf::CodedInputStream::from_bytes(&data);
+        assert!(message.merge_from(&d, descriptor, &mut input).is_err());
+    }
+
+    #[test]
+    fn negative_int32_ten_byte_varint() {
+        let d = Descriptors::new();
+        let field = FieldDescriptor::builder("value")
+            .number(1)
+            .field_type(InternalFieldType::Int32)
+            .build();
+        // Negative values are sign-extended to 64 bits, as the reference implementation encodes them
+        let data = [
+            8, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01, // value: -1
+            8, 0x80, 0x80, 0x80, 0x80, 0xf8, 0xff, 0xff, 0xff, 0xff, 0x01, // value: i32::MIN
+        ];
+        let mut input = protobuf::CodedInputStream::from_bytes(&data);
+
+        for expected in &[-1, i32::MIN] {
+            let mut value = Field::new(&field);
+            let (_, wire_type) = read_tag(&mut input).unwrap();
+            value.merge_from(&d, &field, &mut input, wire_type).unwrap();
+            match value {
+                Field::Singular(Some(Value::I32(v))) => assert_eq!(v, *expected),
+                f => panic!("Expected an int32 value, got {:?}", f),
+            }
+        }
+        assert!(input.eof().unwrap());
+    }
+
+    #[test]
+    fn zigzag() {
+        for &(decoded, encoded) in &[(0, 0), (-1, 1), (1, 2), (-2, 3), (i32::MIN, u32::MAX)] {
+            assert_eq!(zigzag_encode_32(decoded), encoded);
+            assert_eq!(zigzag_decode_32(encoded), decoded);
+        }
+        for &(decoded, encoded) in &[
+            (0, 0),
+            (-1, 1),
+            (i64::MAX, u64::MAX - 1),
+            (i64::MIN, u64::MAX),
+        ] {
+            assert_eq!(zigzag_encode_64(decoded), encoded);
+            assert_eq!(zigzag_decode_64(encoded), decoded);
+        }
+    }
+
+    #[test]
+    fn negative_sint_values() {
+        let d = Descriptors::new();
+        let field = |name, field_type| {
+            FieldDescriptor::builder(name)
+                .number(1)
+                .label(FieldLabel::Repeated)
+                .field_type(field_type)
+                .build()
+        };
+        let decode = |field: &FieldDescriptor, data: &[u8]| {
+            let mut value = Field::new(field);
+            let mut input = protobuf::CodedInputStream::from_bytes(data);
+            while !input.eof().unwrap() {
+                let (_, wire_type) = read_tag(&mut input).unwrap();
+                value.merge_from(&d, field, &mut input, wire_type).unwrap();
+            }
+            value
+        };
+
+        let sint32 = field("sint32", InternalFieldType::SInt32);
+        let mut data = Vec::new();
+        for &n in &[-1, -64, i32::MIN] {
+            data.push(8);
+            let mut output = protobuf::CodedOutputStream::vec(&mut data);
+            output.write_raw_varint32(zigzag_encode_32(n)).unwrap();
+            output.flush().unwrap();
+        }
+        match decode(&sint32, &data) {
+            Field::Repeated(vs) => match vs.as_slice() {
+                [Value::I32(-1), Value::I32(-64), Value::I32(i32::MIN)] => (),
+                vs => panic!("Unexpected values {:?}", vs),
+            },
+            f => panic!("Expected a repeated field, got {:?}", f),
+        }
+
+        let sint64 = field("sint64", InternalFieldType::SInt64);
+        let mut data = Vec::new();
+        for &n in &[-1, -8192, i64::MIN] {
+            data.push(8);
+            let mut output = protobuf::CodedOutputStream::vec(&mut data);
+            output.write_raw_varint64(zigzag_encode_64(n)).unwrap();
+            output.flush().unwrap();
+        }
+        match decode(&sint64, &data) {
+            Field::Repeated(vs) => match vs.as_slice() {
+                [Value::I64(-1), Value::I64(-8192), Value::I64(i64::MIN)] => (),
+                vs => panic!("Unexpected values {:?}", vs),
+            },
+            f => panic!("Expected a repeated field, got {:?}", f),
+        }
+    }
+
+    #[test]
+    fn decode_error_offset() {
+        let d = descriptors();
+        let descriptor = d.message_by_name(".test.Outer").unwrap();
+        let decode = |data: &[u8]| {
+            let mut message = Message::new(descriptor);
+            let mut input = protobuf::CodedInputStream::from_bytes(data);
+            message.merge_from(&d, descriptor, &mut input)
+        };
+
+        // count: 1, then a tag with the invalid wire type 7
+        match decode(&[8, 1, 15]) {
+            Err(e @ error::Error::DecodeAt { .. }) => assert_eq!(e.offset(), Some(2)),
+            r => panic!("Expected an error with an offset, got {:?}", r),
+        }
+
+        // count: 1, inners: [{ label: "ok" }, { label: "\xff" }]
+        let data = [8, 1, 26, 4, 18, 2, b'o', b'k', 26, 3, 18, 1, 0xff];
+        match decode(&data) {
+            Err(e) => {
+                assert_eq!(e.offset(), Some(10));
+                match e {
+                    error::Error::InField {
+                        index: 1, source, ..
+                    } => match *source {
+                        error::Error::DecodeAt { offset: 10, source } => {
+                            assert!(matches!(*source, error::Error::InvalidUtf8 { .. }))
+                        }
+                        e => panic!("Expected an error with an offset, got {:?}", e),
+                    },
+                    e => panic!("Expected an error in a field, got {:?}", e),
+                }
+            }
+            r => panic!("Expected an error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn string_validation() {
+        let d = descriptors();
+        let descriptor = d.message_by_name(".test.Outer").unwrap();
+        // name: "a\xffb"
+        let data = [18, 3, b'a', 0xff, b'b'];
+        let decode = |options: DecodeOptions| {
+            let mut message = Message::new(descriptor);
+            let mut input = protobuf::CodedInputStream::from_bytes(&data);
+            message
+                .merge_from_with_options(&d, descriptor, &mut input, &options)
+                .map(|()| message)
+        };
+
+        match decode(DecodeOptions::new()) {
+            Err(error::Error::DecodeAt { offset: 0, source }) => match *source {
+                error::Error::InvalidUtf8 { field, .. } => assert_eq!(field, "name"),
+                e => panic!("Expected invalid UTF-8 error, got {:?}", e),
+            },
+            r => panic!("Expected invalid UTF-8 error, got {:?}", r),
+        }
+
+        let message = decode(DecodeOptions::new().string_validation(StringValidation::Lossy));
+        match message.unwrap().fields.get(&2) {
+            Some(Field::Singular(Some(Value::String(s)))) => assert_eq!(s, "a\u{fffd}b"),
+            f => panic!("Expected a string value, got {:?}", f),
+        }
+    }
+
+    #[test]
+    fn message_set_items() {
+        let mut d = Descriptors::new();
+        let mut set = MessageDescriptor::new(".test.Set");
+        set.set_message_set(true);
+        d.add_message(set);
+        let mut item = MessageDescriptor::new(".test.Item");
+        item.add_field(
+            FieldDescriptor::builder("text")
+                .number(1)
+                .field_type(InternalFieldType::String)
+                .build(),
+        );
+        d.add_message(item);
+        d.add_extension(
+            ".test.Set",
+            FieldDescriptor::builder("item")
+                .number(100)
+                .field_type(InternalFieldType::UnresolvedMessage(".test.Item".into()))
+                .build(),
+        );
+
+        let descriptor = d.message_by_name(".test.Set").unwrap();
+        // item {type_id: 100, message: {text: "x"}}, item {message: {}, type_id: 7}
+        let data = [11, 16, 100, 26, 3, 10, 1, b'x', 12, 11, 26, 0, 16, 7, 12];
+        let mut message = Message::new(descriptor);
+        let mut input = protobuf::CodedInputStream::from_bytes(&data);
+        message.merge_from(&d, descriptor, &mut input).unwrap();
+
+        match message.extensions.get(&100) {
+            Some(Value::Message(m)) => match m.fields.get(&1) {
+                Some(Field::Singular(Some(Value::String(s)))) => assert_eq!(s, "x"),
+                f => panic!("Expected a string field, got {:?}", f),
+            },
+            v => panic!("Expected a message extension, got {:?}", v),
+        }
+        assert_eq!(message.extensions.len(), 1);
+        // The item of the unregistered extension is kept as is
+        assert_eq!(
+            message.unknown.iter().collect::<Vec<_>>(),
+            vec![(
+                1,
+                protobuf::UnknownValueRef::LengthDelimited(&[26, 0, 16, 7])
+            )]
+        );
+        let value = Value::Message(message);
+        assert_eq!(crate::ser::to_bytes(&d, descriptor, &value).unwrap(), data);
+
+        // Items without a type ID are kept as well
+        let data = [11, 26, 1, 0, 12];
+        let mut message = Message::new(descriptor);
+        let mut input = protobuf::CodedInputStream::from_bytes(&data);
+        message.merge_from(&d, descriptor, &mut input).unwrap();
+        let value = Value::Message(message);
+        assert_eq!(crate::ser::to_bytes(&d, descriptor, &value).unwrap(), data);
+
+        // item {type_id: 100, message: {text: "\xff"}}
+        let data = [11, 16, 100, 26, 3, 10, 1, 0xff, 12];
+        let mut message = Message::new(descriptor);
+        let mut input = protobuf::CodedInputStream::from_bytes(&data);
+        match message.merge_from(&d, descriptor, &mut input) {
+            Err(e) => assert_eq!(e.offset(), Some(5)),
+            r => panic!("Expected an error, got {:?}", r),
+        }
+
+        // item {type_id: 0}, and an item field that isn't a group
+        for data in &[&[11, 16, 0, 12][..], &[10, 0]] {
+            let mut message = Message::new(descriptor);
+            let mut input = protobuf::CodedInputStream::from_bytes(data);
+            match message.merge_from(&d, descriptor, &mut input) {
+                Err(error::Error::DecodeAt { offset: 0, source }) => assert!(matches!(
+                    *source,
+                    error::Error::BadFieldNumber { number: 0, .. }
+                        | error::Error::BadWireType { .. }
+                )),
+                r => panic!("Expected a decode error, got {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn repeated_message_element_errors() {
+        let d = descriptors();
+        let descriptor = d.message_by_name(".test.Outer").unwrap();
+        // inners: [{label: "a"}, {label: "b"} truncated to 3 of 5 bytes]
+        let data = [26, 3, 18, 1, b'a', 26, 5, 18, 3, b'b'];
+        let mut message = Message::new(descriptor);
+        let mut input = protobuf::CodedInputStream::from_bytes(&data);
+
+        match message.merge_from(&d, descriptor, &mut input) {
+            Err(error::Error::InField { field, index, .. }) => {
+                assert_eq!(field, "inners");
+                assert_eq!(index, 1);
+            }
+            r => panic!("Expected an element error, got {:?}", r),
+        }
+        match message.fields.get(&3) {
+            Some(Field::Repeated(vs)) => assert_eq!(vs.len(), 1),
+            f => panic!("Expected a repeated field, got {:?}", f),
+        }
+    }
+
+    #[test]
+    fn apply_field_mask() {
+        let d = descriptors();
+        let descriptor = d.message_by_name(".test.Outer").unwrap();
+        // count: 5, name: "x", inners: [{ratio: 1.0, label: "y"}], unknown field 15: 7
+        let mut data = vec![8, 5, 18, 1, b'x', 26, 12, 9];
+        data.extend_from_slice(&1f64.to_le_bytes());
+        data.extend_from_slice(&[18, 1, b'y', 120, 7]);
+
+        let numbers = |m: &Message| m.fields.keys().cloned().collect::<Vec<_>>();
+        let inner = |v: &Value| match *v {
+            Value::Message(ref m) => match m.fields.get(&3) {
+                Some(Field::Repeated(vs)) => match vs.as_slice() {
+                    [Value::Message(m)] => m.clone(),
+                    vs => panic!("Expected a single inner message, got {:?}", vs),
+                },
+                f => panic!("Expected a repeated field, got {:?}", f),
+            },
+            ref v => panic!("Expected a message, got {:?}", v),
+        };
+
+        let mut value = decode(&d, &data);
+        super::apply_field_mask(&d, descriptor, &mut value, &["count", "inners.ratio"]).unwrap();
+        match value {
+            Value::Message(ref m) => {
+                assert_eq!(numbers(m), vec![1, 3]);
+                assert!(m.unknown.iter().next().is_none());
+            }
+            ref v => panic!("Expected a message, got {:?}", v),
+        }
+        assert_eq!(numbers(&inner(&value)), vec![1]);
+
+        let mut value = decode(&d, &data);
+        super::apply_field_mask(&d, descriptor, &mut value, &["inners"]).unwrap();
+        assert_eq!(numbers(&inner(&value)), vec![1, 2]);
+
+        let mut value = decode(&d, &data);
+        match super::apply_field_mask(&d, descriptor, &mut value, &["name", "count.x"]) {
+            Err(error::Error::BadFieldPath { path, .. }) => assert_eq!(path, "count.x"),
+            r => panic!("Expected bad field path error, got {:?}", r),
+        }
+        assert!(message_eq(&d, descriptor, &value, &decode(&d, &data)));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn value_to_json() {
+        let d = descriptors();
+        // count: 5, inners: [{ratio: NaN, label: "y"}], unknown field 15: 7
+        let mut data = vec![8, 5, 26, 12, 9];
+        data.extend_from_slice(&f64::NAN.to_le_bytes());
+        data.extend_from_slice(&[18, 1, b'y', 120, 7]);
+
+        let json = serde_json::Value::from(&decode(&d, &data));
+        assert_eq!(
+            json,
+            serde_json::json!({"1": 5, "3": [{"1": null, "2": "y"}]})
+        );
+        assert_eq!(
+            serde_json::Value::from(&Value::Bytes(vec![1, 2])),
+            serde_json::json!([1, 2])
+        );
+    }
+
+    #[test]
+    fn value_from_primitives() {
+        assert!(matches!(Value::from(true), Value::Bool(true)));
+        assert!(matches!(Value::from(-1i32), Value::I32(-1)));
+        assert!(matches!(Value::from(-1i64), Value::I64(-1)));
+        assert!(matches!(Value::from(1u32), Value::U32(1)));
+        assert!(matches!(Value::from(1u64), Value::U64(1)));
+        assert!(matches!(Value::from(0.5f32), Value::F32(v) if v == 0.5));
+        assert!(matches!(Value::from(0.5f64), Value::F64(v) if v == 0.5));
+        assert!(matches!(Value::from(vec![1u8, 2]), Value::Bytes(ref v) if v == &[1, 2]));
+        assert!(matches!(Value::from("a".to_owned()), Value::String(ref v) if v == "a"));
+        assert!(matches!(Value::from("a"), Value::String(ref v) if v == "a"));
+    }
+
+    #[test]
+    fn fixed_width_little_endian() {
+        let types = vec![
+            InternalFieldType::Fixed32,
+            InternalFieldType::Fixed64,
+            InternalFieldType::SFixed32,
+            InternalFieldType::SFixed64,
+            InternalFieldType::Float,
+            InternalFieldType::Double,
+        ];
+        let mut m = MessageDescriptor::new(".test.Fixed");
+        for (i, field_type) in types.into_iter().enumerate() {
+            m.add_field(
+                FieldDescriptor::builder(format!("f{}", i + 1))
+                    .number(i as i32 + 1)
+                    .field_type(field_type)
+                    .build(),
+            );
+        }
+        m.add_field(
+            FieldDescriptor::builder("packed")
+                .number(7)
+                .label(FieldLabel::Repeated)
+                .field_type(InternalFieldType::Fixed32)
+                .build(),
+        );
+        let mut d = Descriptors::new();
+        d.add_message(m);
+        let descriptor = d.message_by_name(".test.Fixed").unwrap();
+
+        #[rustfmt::skip]
+        let data = [
+            13, 0x01, 0x02, 0x03, 0x04, // f1: 0x04030201
+            17, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, // f2: 0x0807060504030201
+            29, 0xfe, 0xff, 0xff, 0xff, // f3: -2
+            33, 0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, // f4: -2
+            45, 0x00, 0x00, 0xc0, 0x3f, // f5: 1.5
+            49, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xf8, 0x3f, // f6: 1.5
+            58, 8, 0x01, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, // packed: [1, 256]
+        ];
+        let mut message = Message::new(descriptor);
+        let mut input = protobuf::CodedInputStream::from_bytes(&data);
+        message.merge_from(&d, descriptor, &mut input).unwrap();
+        let value = Value::Message(message);
+        let view = MessageView::new(descriptor, &value).unwrap();
+
+        assert!(matches!(view.get("f1"), Some(Value::U32(0x0403_0201))));
+        assert!(matches!(
+            view.get("f2"),
+            Some(Value::U64(0x0807_0605_0403_0201))
+        ));
+        assert!(matches!(view.get("f3"), Some(Value::I32(-2))));
+        assert!(matches!(view.get("f4"), Some(Value::I64(-2))));
+        assert!(matches!(view.get("f5"), Some(Value::F32(v)) if *v == 1.5));
+        assert!(matches!(view.get("f6"), Some(Value::F64(v)) if *v == 1.5));
+        assert!(matches!(
+            view.get_repeated("packed"),
+            Some([Value::U32(1), Value::U32(256)])
+        ));
+    }
+
+    #[test]
+    fn message_view() {
+        let mut d = load_descriptors();
+        d.resolve_refs();
+        let descriptor = d
+            .message_by_name(".protobuf_unittest.TestAllTypes")
+            .unwrap();
+        // optional_int32: 42, optional_string: "hi", repeated_int32: [5, 6]
+        let data = [8, 42, 114, 2, b'h', b'i', 248, 1, 5, 248, 1, 6];
+        let mut message = Message::new(descriptor);
+        let mut input = protobuf::CodedInputStream::from_bytes(&data);
+        message.merge_from(&d, descriptor, &mut input).unwrap();
+        let value = Value::Message(message);
+
+        let view = MessageView::new(descriptor, &value).unwrap();
+        assert!(matches!(view.get("optional_int32"), Some(Value::I32(42))));
+        assert!(matches!(view.get_by_number(14), Some(Value::String(s)) if s == "hi"));
+        // Declared defaults aren't filled in for fields that weren't on the wire
+        assert!(view.get("default_int32").is_none());
+        assert!(view.get("optional_int64").is_none());
+        assert!(view.get("repeated_int32").is_none());
+        assert!(view.get("missing").is_none());
+        assert!(matches!(
+            view.get_repeated("repeated_int32"),
+            Some([Value::I32(5), Value::I32(6)])
+        ));
+        assert!(matches!(view.get_repeated("repeated_int64"), Some([])));
+        assert!(view.get_repeated("optional_int32").is_none());
+
+        let fields = view.iter().map(|(f, _)| f.name()).collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            vec![
+                "optional_int32",
+                "optional_string",
+                "repeated_int32",
+                "repeated_int32"
+            ]
+        );
+
+        assert!(MessageView::new(descriptor, &Value::I32(1)).is_none());
+    }
+
+    #[test]
+    fn fill_defaults() {
+        let d = descriptors();
+        let descriptor = d.message_by_name(".test.Outer").unwrap();
+        // inners: [{}]
+        let data = [26, 0];
+        let decode = |options: DecodeOptions| {
+            let mut message = Message::new(descriptor);
+            let mut input = protobuf::CodedInputStream::from_bytes(&data);
+            message
+                .merge_from_with_options(&d, descriptor, &mut input, &options)
+                .unwrap();
+            message
+        };
+        let singular = |m: &Message, n| match m.fields.get(&n) {
+            Some(Field::Singular(v)) => v.clone(),
+            None => None,
+            f => panic!("Expected a singular field, got {:?}", f),
+        };
+        let inner = |m: &Message| match m.fields.get(&3) {
+            Some(Field::Repeated(vs)) => match vs.as_slice() {
+                [Value::Message(m)] => m.clone(),
+                vs => panic!("Expected a single inner message, got {:?}", vs),
+            },
+            f => panic!("Expected a repeated field, got {:?}", f),
+        };
+
+        let m = decode(DecodeOptions::new());
+        assert!(singular(&m, 1).is_none());
+        assert!(singular(&m, 2).is_none());
+        assert!(singular(&inner(&m), 1).is_none());
+
+        let m = decode(DecodeOptions::new().fill_defaults(true));
+        assert!(singular(&m, 1).is_none());
+        assert!(matches!(singular(&m, 2), Some(Value::String(ref s)) if s == "anonymous"));
+        let inner = inner(&m);
+        assert!(matches!(singular(&inner, 1), Some(Value::F64(v)) if v == 0.0));
+        assert!(matches!(singular(&inner, 2), Some(Value::String(ref s)) if s.is_empty()));
+    }
+
+    #[test]
+    fn fill_defaults_recursively() {
+        let mut leaf = MessageDescriptor::new(".test.Leaf");
+        leaf.add_field(
+            FieldDescriptor::builder("label")
+                .number(1)
+                .field_type(InternalFieldType::String)
+                .default_value("leaf".into())
+                .build(),
+        );
+        leaf.add_field(FieldDescriptor::builder("size").number(2).build());
+        let mut middle = MessageDescriptor::new(".test.Middle");
+        middle.set_syntax(Syntax::Proto3);
+        middle.add_field(
+            FieldDescriptor::builder("leaf")
+                .number(1)
+                .field_type(InternalFieldType::UnresolvedMessage(".test.Leaf".into()))
+                .build(),
+        );
+        middle.add_field(FieldDescriptor::builder("count").number(2).build());
+        let mut top = MessageDescriptor::new(".test.Top");
+        for (name, number) in &[("middle", 1), ("absent", 2)] {
+            top.add_field(
+                FieldDescriptor::builder(*name)
+                    .number(*number)
+                    .field_type(InternalFieldType::UnresolvedMessage(".test.Middle".into()))
+                    .build(),
+            );
+        }
+        let mut d = Descriptors::new();
+        d.add_message(leaf);
+        d.add_message(middle);
+        d.add_message(top);
+        d.resolve_refs();
+        let descriptor = d.message_by_name(".test.Top").unwrap();
+
+        let empty = || Message {
+            fields: collections::BTreeMap::new(),
+            unknown: protobuf::UnknownFields::new(),
+            extensions: collections::BTreeMap::new(),
+        };
+        let nested = |m: &Message, n| match m.fields.get(&n) {
+            Some(Field::Singular(Some(Value::Message(m)))) => m.clone(),
+            f => panic!("Expected a nested message, got {:?}", f),
+        };
+        let check = |m: &Message| {
+            assert!(matches!(
+                m.fields.get(&2),
+                None | Some(Field::Singular(None))
+            ));
+            let middle = nested(m, 1);
+            assert!(matches!(
+                middle.fields.get(&2),
+                Some(Field::Singular(Some(Value::I32(0))))
+            ));
+            let leaf = nested(&middle, 1);
+            assert!(matches!(
+                leaf.fields.get(&1),
+                Some(Field::Singular(Some(Value::String(ref s)))) if s == "leaf"
+            ));
+            assert!(matches!(
+                leaf.fields.get(&2),
+                None | Some(Field::Singular(None))
+            ));
+        };
+
+        // middle: { leaf: {} }, built by hand without any pre-populated defaults
+        let mut middle = empty();
+        middle
+            .fields
+            .insert(1, Field::Singular(Some(Value::Message(empty()))));
+        let mut m = empty();
+        m.fields
+            .insert(1, Field::Singular(Some(Value::Message(middle))));
+        m.fill_defaults(&d, descriptor);
+        check(&m);
+
+        // The same message decoded with defaults filled in
+        let mut m = Message::new(descriptor);
+        let mut input = protobuf::CodedInputStream::from_bytes(&[10, 2, 10, 0]);
+        let options = DecodeOptions::new().fill_defaults(true);
+        m.merge_from_with_options(&d, descriptor, &mut input, &options)
+            .unwrap();
+        check(&m);
+    }
+
+    #[test]
+    fn merge_keeps_unknown_fields() {
+        let d = descriptors();
+        let value = decode(&d, &[8, 1, 120, 7, 122, 2, b'h', b'i']);
+        match value {
+            Value::Message(m) => {
+                let unknown = m.unknown.iter().collect::<Vec<_>>();
+                assert_eq!(
+                    unknown,
+                    vec![
+                        (15, protobuf::UnknownValueRef::Varint(7)),
+                        (15, protobuf::UnknownValueRef::LengthDelimited(b"hi")),
+                    ]
+                );
+            }
+            v => panic!("Expected a message, got {:?}", v),
+        }
+    }
+
+    #[test]
+    fn encode_and_decode_groups() {
+        let mut group = MessageDescriptor::new(".test.Message.Group");
+        group.add_field(FieldDescriptor::builder("a").number(1).build());
+        let group_type = || InternalFieldType::UnresolvedGroup(".test.Message.Group".into());
+        let mut message = MessageDescriptor::new(".test.Message");
+        message.add_field(
+            FieldDescriptor::builder("group")
+                .number(1)
+                .field_type(group_type())
+                .build(),
+        );
+        message.add_field(
+            FieldDescriptor::builder("groups")
+                .number(2)
+                .label(FieldLabel::Repeated)
+                .field_type(group_type())
+                .build(),
+        );
+        message.add_field(FieldDescriptor::builder("after").number(3).build());
+        let mut d = Descriptors::new();
+        d.add_message(group);
+        d.add_message(message);
+        d.resolve_refs();
+        let message = d.message_by_name(".test.Message").unwrap();
+        let group = d.message_by_name(".test.Message.Group").unwrap();
+
+        let group_value = |a| {
+            let mut m = Message::new(group);
+            m.fields.insert(1, Field::Singular(Some(Value::I32(a))));
+            Value::Message(m)
+        };
+        let mut m = Message::new(message);
+        m.fields.insert(1, Field::Singular(Some(group_value(5))));
+        m.fields
+            .insert(2, Field::Repeated(vec![group_value(6), group_value(7)]));
+        m.fields.insert(3, Field::Singular(Some(Value::I32(8))));
+        let value = Value::Message(m);
+
+        let bytes = crate::ser::to_bytes(&d, message, &value).unwrap();
+        assert_eq!(
+            bytes,
+            [11, 8, 5, 12, 19, 8, 6, 20, 19, 8, 7, 20, 24, 8].to_vec()
+        );
+        let decode = |data: &[u8]| {
+            let mut decoded = Message::new(message);
+            let mut input = protobuf::CodedInputStream::from_bytes(data);
+            decoded
+                .merge_from(&d, message, &mut input)
+                .map(|()| Value::Message(decoded))
+        };
+        assert!(message_eq(&d, message, &decode(&bytes).unwrap(), &value));
+
+        // The occurrences of a singular group are merged, and unknown fields are kept
+        match decode(&[11, 16, 5, 12, 11, 8, 1, 12]).unwrap() {
+            Value::Message(m) => match m.fields.get(&1) {
+                Some(Field::Singular(Some(Value::Message(g)))) => {
+                    assert!(matches!(
+                        g.fields.get(&1),
+                        Some(Field::Singular(Some(Value::I32(1))))
+                    ));
+                    assert_eq!(g.unknown.iter().count(), 1);
+                }
+                f => panic!("Expected a group, got {:?}", f),
+            },
+            v => panic!("Expected a message, got {:?}", v),
+        }
+
+        // A group without its end tag, or a group field that isn't encoded as a group
+        assert!(decode(&[11, 8, 5]).is_err());
+        assert!(decode(&[10, 2, 8, 5]).is_err());
+    }
+
+    #[test]
+    fn message_eq_ignores_field_order() {
+        assert!(eq(&[8, 1, 18, 1, b'a'], &[18, 1, b'a', 8, 1]));
+        assert!(!eq(&[8, 1, 18, 1, b'a'], &[18, 1, b'b', 8, 1]));
+    }
+
+    #[test]
+    fn message_eq_default_equals_unset() {
+        // The proto3 inner message doesn't track presence
+        assert!(eq(&[26, 0], &[26, 2, 18, 0]));
+        assert!(!eq(&[26, 0], &[26, 3, 18, 1, b'a']));
+
+        // The proto2 outer message does, so a field set to its declared default isn't unset
+        assert!(!eq(&[], &[8, 0]));
+        assert!(!eq(&[], &[8, 1]));
+        assert!(!eq(
+            &[],
+            &[18, 9, b'a', b'n', b'o', b'n', b'y', b'm', b'o', b'u', b's']
+        ));
+        assert!(!eq(&[], &[18, 0]));
+    }
+
+    #[test]
+    fn message_eq_maps() {
+        let mut entry = MessageDescriptor::new(".test.Labels.ValuesEntry");
+        entry.set_map_entry(true);
+        entry.set_syntax(Syntax::Proto3);
+        entry.add_field(
+            FieldDescriptor::builder("key")
+                .number(1)
+                .field_type(InternalFieldType::String)
+                .build(),
+        );
+        entry.add_field(FieldDescriptor::builder("value").number(2).build());
+        let mut labels = MessageDescriptor::new(".test.Labels");
+        labels.add_field(
+            FieldDescriptor::builder("values")
+                .number(1)
+                .label(FieldLabel::Repeated)
+                .field_type(InternalFieldType::UnresolvedMessage(
+                    ".test.Labels.ValuesEntry".into(),
+                ))
+                .build(),
+        );
+        let mut d = Descriptors::new();
+        d.add_message(entry);
+        d.add_message(labels);
+        d.resolve_refs();
+        let descriptor = d.message_by_name(".test.Labels").unwrap();
+        let eq = |a: &[u8], b: &[u8]| {
+            let decode = |data| {
+                let mut message = Message::new(descriptor);
+                let mut input = protobuf::CodedInputStream::from_bytes(data);
+                message.merge_from(&d, descriptor, &mut input).unwrap();
+                Value::Message(message)
+            };
+            message_eq(&d, descriptor, &decode(a), &decode(b))
+        };
+
+        let a1 = [10, 5, 10, 1, b'a', 16, 1];
+        let a9 = [10, 5, 10, 1, b'a', 16, 9];
+        let b2 = [10, 5, 10, 1, b'b', 16, 2];
+        assert!(eq(&[&a1[..], &b2].concat(), &[&b2[..], &a1].concat()));
+        assert!(eq(&[&a1[..], &b2].concat(), &[&a9[..], &b2, &a1].concat()));
+        assert!(!eq(&[&a1[..], &b2].concat(), &[&a1[..], &b2, &a9].concat()));
+        assert!(!eq(&[&a1[..], &b2].concat(), &a1));
+    }
+
+    #[test]
+    fn message_eq_repeated_and_nested() {
+        let nan = f64::NAN.to_le_bytes();
+        let mut a = vec![26, 9, 9];
+        a.extend_from_slice(&nan);
+        assert!(eq(&a, &a));
+        assert!(!eq(&a, &[]));
+        assert!(!eq(&a, &[26, 0]));
+        assert!(eq(
+            &[26, 0, 26, 0],
+            &[26, 9, 9, 0, 0, 0, 0, 0, 0, 0, 0, 26, 0]
+        ));
+    }
 }
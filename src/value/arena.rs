@@ -0,0 +1,497 @@
+//! Values whose collections are allocated in a bump arena.
+use std::str;
+
+use bumpalo::collections::Vec as BumpVec;
+use bumpalo::Bump;
+
+use super::borrowed::read_length;
+use super::*;
+
+/// A protobuf value decoded from a byte slice into a bump arena.
+///
+/// The contents of `bytes` and `string` values are borrowed from the input like for
+/// `BorrowedValue`, and all collections are allocated in the arena, so that decoding doesn't
+/// allocate anything on the heap once the arena has grown large enough.  This is meant for
+/// decoding many small messages in a loop, resetting the arena between messages:
+///
+/// ```
+/// use bumpalo::Bump;
+/// use serde_protobuf::descriptor::{Descriptors, FieldDescriptor, MessageDescriptor};
+/// use serde_protobuf::value::ArenaMessage;
+///
+/// let mut message = MessageDescriptor::new(".pkg.Message");
+/// message.add_field(FieldDescriptor::builder("id").number(1).build());
+/// let mut descriptors = Descriptors::new();
+/// descriptors.add_message(message);
+/// let message = descriptors.message_by_name(".pkg.Message").unwrap();
+///
+/// let mut bump = Bump::new();
+/// for data in &[[8, 1], [8, 2]] {
+///     let decoded = ArenaMessage::decode(&bump, &descriptors, message, data).unwrap();
+///     assert!(decoded.get(1).is_some());
+///     drop(decoded);
+///     bump.reset();
+/// }
+/// ```
+#[derive(Debug)]
+pub enum ArenaValue<'b> {
+    /// A boolean value.
+    Bool(bool),
+    /// A 32-bit signed integer.
+    I32(i32),
+    /// A 64-bit signed integer.
+    I64(i64),
+    /// A 32-bit unsigned integer.
+    U32(u32),
+    /// A 64-bit unsigned integer.
+    U64(u64),
+    /// A 32-bit floating point value.
+    F32(f32),
+    /// A 64-bit floating point value.
+    F64(f64),
+    /// A byte vector, borrowed from the input.
+    Bytes(&'b [u8]),
+    /// A string, borrowed from the input, or allocated in the arena if it was not valid UTF-8
+    /// and was decoded lossily.
+    String(&'b str),
+    /// An enum value.
+    Enum(i32),
+    /// A message.
+    Message(ArenaMessage<'b>),
+}
+
+/// A message value decoded into a bump arena; see `ArenaValue`.
+///
+/// Like for `BorrowedMessage`, only the fields that occur in the input are present.
+#[derive(Debug)]
+pub struct ArenaMessage<'b> {
+    /// Known fields on the message by field number, in the order that they first occurred in the
+    /// input.
+    pub fields: BumpVec<'b, (i32, ArenaField<'b>)>,
+    /// The raw encodings of the unknown fields on the message, including their tags, in the order
+    /// that they occurred in the input.
+    pub unknown: BumpVec<'b, &'b [u8]>,
+}
+
+/// A message field value decoded into a bump arena; see `ArenaValue`.
+#[derive(Debug)]
+pub enum ArenaField<'b> {
+    /// A field with a single value.
+    Singular(Option<ArenaValue<'b>>),
+    /// A field with several (repeated) values.
+    Repeated(BumpVec<'b, ArenaValue<'b>>),
+}
+
+impl<'b> ArenaValue<'b> {
+    /// Converts this value into an owned value, copying it out of the arena.
+    pub fn into_owned(self) -> Value {
+        match self {
+            ArenaValue::Bool(v) => Value::Bool(v),
+            ArenaValue::I32(v) => Value::I32(v),
+            ArenaValue::I64(v) => Value::I64(v),
+            ArenaValue::U32(v) => Value::U32(v),
+            ArenaValue::U64(v) => Value::U64(v),
+            ArenaValue::F32(v) => Value::F32(v),
+            ArenaValue::F64(v) => Value::F64(v),
+            ArenaValue::Bytes(v) => Value::Bytes(v.to_vec()),
+            ArenaValue::String(v) => Value::String(v.to_owned()),
+            ArenaValue::Enum(v) => Value::Enum(v),
+            ArenaValue::Message(m) => Value::Message(m.into_owned()),
+        }
+    }
+}
+
+impl<'b> ArenaMessage<'b> {
+    /// Creates an empty message in the specified arena.
+    #[inline]
+    pub fn new_in(bump: &'b Bump) -> ArenaMessage<'b> {
+        ArenaMessage {
+            fields: BumpVec::new_in(bump),
+            unknown: BumpVec::new_in(bump),
+        }
+    }
+
+    /// Decodes a message of the specified type from a byte slice, allocating all collections in
+    /// the specified arena.
+    #[inline]
+    pub fn decode(
+        bump: &'b Bump,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        bytes: &'b [u8],
+    ) -> error::Result<ArenaMessage<'b>> {
+        ArenaMessage::decode_with_options(
+            bump,
+            descriptors,
+            message,
+            bytes,
+            &DecodeOptions::default(),
+        )
+    }
+
+    /// Decodes a message of the specified type from a byte slice into the specified arena, using
+    /// the specified decode options.
+    ///
+    /// The options apply like for `BorrowedMessage::decode_with_options`.
+    pub fn decode_with_options(
+        bump: &'b Bump,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        bytes: &'b [u8],
+        options: &DecodeOptions,
+    ) -> error::Result<ArenaMessage<'b>> {
+        let mut result = ArenaMessage::new_in(bump);
+        let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+        result.merge(
+            bump,
+            descriptors,
+            message,
+            bytes,
+            &mut input,
+            &DecodeContext::new(options),
+        )?;
+        Ok(result)
+    }
+
+    /// Looks up the value of the field with the specified number.
+    pub fn get(&self, number: i32) -> Option<&ArenaField<'b>> {
+        self.fields
+            .iter()
+            .find(|&&(n, _)| n == number)
+            .map(|(_, field)| field)
+    }
+
+    /// Converts this message into an owned message, copying it out of the arena and decoding the
+    /// unknown fields.
+    pub fn into_owned(self) -> Message {
+        let mut message = BorrowedMessage {
+            fields: collections::BTreeMap::new(),
+            unknown: self.unknown.into_iter().collect(),
+        }
+        .into_owned();
+        message.fields = self
+            .fields
+            .into_iter()
+            .map(|(number, field)| (number, field.into_owned()))
+            .collect();
+        message
+    }
+
+    /// Merges fields from the input stream, which reads from `bytes` starting at its beginning,
+    /// until the end of the stream or its current limit.
+    fn merge(
+        &mut self,
+        bump: &'b Bump,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        bytes: &'b [u8],
+        input: &mut protobuf::CodedInputStream,
+        options: &DecodeContext,
+    ) -> error::Result<()> {
+        let mut seen = collections::BTreeSet::new();
+        while !input.eof()? {
+            let offset = input.pos();
+            read_tag(input)
+                .and_then(|(number, wire_type)| {
+                    options.count_field()?;
+                    match message.field_by_number(number as i32) {
+                        Some(field) => {
+                            options.mark_seen(descriptors, field, &mut seen)?;
+                            let value = self.field_mut(bump, field);
+                            value.merge(bump, descriptors, field, bytes, input, wire_type, options)
+                        }
+                        None => {
+                            skip_field(input, wire_type, options)?;
+                            self.unknown
+                                .push(&bytes[offset as usize..input.pos() as usize]);
+                            Ok(())
+                        }
+                    }
+                })
+                .map_err(|e| decode_at(offset, e))?;
+        }
+        options.finish_message(message, &seen)
+    }
+
+    fn field_mut(
+        &mut self,
+        bump: &'b Bump,
+        field: &descriptor::FieldDescriptor,
+    ) -> &mut ArenaField<'b> {
+        let number = field.number();
+        let index = match self.fields.iter().position(|&(n, _)| n == number) {
+            Some(index) => index,
+            None => {
+                self.fields.push((number, ArenaField::new_in(bump, field)));
+                self.fields.len() - 1
+            }
+        };
+        &mut self.fields[index].1
+    }
+}
+
+impl<'b> ArenaField<'b> {
+    /// Creates an empty field in the specified arena given a Protobuf descriptor.
+    #[inline]
+    pub fn new_in(bump: &'b Bump, field: &descriptor::FieldDescriptor) -> ArenaField<'b> {
+        if field.is_repeated() {
+            ArenaField::Repeated(BumpVec::new_in(bump))
+        } else {
+            ArenaField::Singular(None)
+        }
+    }
+
+    /// Converts this field into an owned field, copying it out of the arena.
+    pub fn into_owned(self) -> Field {
+        match self {
+            ArenaField::Singular(v) => Field::Singular(v.map(ArenaValue::into_owned)),
+            ArenaField::Repeated(vs) => {
+                Field::Repeated(vs.into_iter().map(ArenaValue::into_owned).collect())
+            }
+        }
+    }
+
+    /// Merges the value of a single field, whose tag has already been read, into this field.
+    #[allow(clippy::too_many_arguments)]
+    fn merge(
+        &mut self,
+        bump: &'b Bump,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        bytes: &'b [u8],
+        input: &mut protobuf::CodedInputStream,
+        wire_type: wire_format::WireType,
+        options: &DecodeContext,
+    ) -> error::Result<()> {
+        use crate::descriptor::FieldType;
+        use protobuf::rt::WireType;
+
+        let field_type = field.field_type(descriptors);
+        match field_type {
+            FieldType::Message(m) if WireType::LengthDelimited == wire_type => {
+                let index = match *self {
+                    ArenaField::Singular(_) => None,
+                    ArenaField::Repeated(ref vs) => Some(vs.len()),
+                };
+                options
+                    .nested(field.number(), index.is_some(), || {
+                        self.merge_message(bump, descriptors, m, bytes, input, options)
+                    })
+                    .map_err(|e| match index {
+                        Some(index) => error::Error::InField {
+                            field: field.name().to_owned(),
+                            index,
+                            source: Box::new(e),
+                        },
+                        None => e,
+                    })
+            }
+            FieldType::Bytes | FieldType::String if WireType::LengthDelimited == wire_type => {
+                let len = options.read_length(input)?;
+                let start = input.pos() as usize;
+                input.skip_raw_bytes(len)?;
+                let contents = &bytes[start..start + len as usize];
+                self.put(match field_type {
+                    FieldType::Bytes => ArenaValue::Bytes(contents),
+                    _ => ArenaValue::String(match str::from_utf8(contents) {
+                        Ok(s) => s,
+                        Err(_) => bump.alloc_str(&options.decode_string(field, contents.to_vec())?),
+                    }),
+                });
+                Ok(())
+            }
+            FieldType::Message(_) | FieldType::Bytes | FieldType::String => {
+                Err(error::Error::BadWireType { wire_type })
+            }
+            FieldType::Group(_) => Err(error::Error::BadField {
+                field: field.name().to_owned(),
+                reason: "groups are not supported".to_owned(),
+            }),
+            FieldType::UnresolvedMessage(_) if options.schemaless_unresolved_messages => {
+                if WireType::LengthDelimited != wire_type {
+                    return Err(error::Error::BadWireType { wire_type });
+                }
+                let schemaless = schemaless_message();
+                self.merge_message(bump, descriptors, schemaless, bytes, input, options)
+            }
+            FieldType::UnresolvedEnum(e) => Err(error::Error::UnknownEnum { name: e.to_owned() }),
+            FieldType::UnresolvedMessage(m) | FieldType::UnresolvedGroup(m) => {
+                Err(error::Error::UnknownMessage { name: m.to_owned() })
+            }
+            _ if WireType::LengthDelimited == wire_type => {
+                let len = read_length(input, bytes, options)?;
+                let old_limit = input.push_limit(u64::from(len))?;
+                while !input.eof()? {
+                    options.count_field()?;
+                    self.put(read_scalar(input, &field_type)?);
+                }
+                input.pop_limit(old_limit);
+                Ok(())
+            }
+            _ if wire_type == super::wire_type(&field_type) => {
+                self.put(read_scalar(input, &field_type)?);
+                Ok(())
+            }
+            _ => Err(error::Error::BadWireType { wire_type }),
+        }
+    }
+
+    /// Merges a length-delimited message value, whose length hasn't been read yet, into this
+    /// field.
+    fn merge_message(
+        &mut self,
+        bump: &'b Bump,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        bytes: &'b [u8],
+        input: &mut protobuf::CodedInputStream,
+        options: &DecodeContext,
+    ) -> error::Result<()> {
+        let len = read_length(input, bytes, options)?;
+        let mut msg = match *self {
+            ArenaField::Singular(ref mut o) => match o.take() {
+                Some(ArenaValue::Message(m)) => m,
+                _ => ArenaMessage::new_in(bump),
+            },
+            ArenaField::Repeated(_) => ArenaMessage::new_in(bump),
+        };
+
+        let old_limit = input.push_limit(u64::from(len))?;
+        msg.merge(bump, descriptors, message, bytes, input, options)?;
+        input.pop_limit(old_limit);
+
+        self.put(ArenaValue::Message(msg));
+        Ok(())
+    }
+
+    #[inline]
+    fn put(&mut self, value: ArenaValue<'b>) {
+        match *self {
+            ArenaField::Singular(ref mut s) => *s = Some(value),
+            ArenaField::Repeated(ref mut r) => r.push(value),
+        }
+    }
+}
+
+/// Reads a single value of a scalar type that isn't length-delimited.
+#[inline]
+fn read_scalar<'b>(
+    input: &mut protobuf::CodedInputStream,
+    field_type: &descriptor::FieldType,
+) -> error::Result<ArenaValue<'b>> {
+    use crate::descriptor::FieldType;
+
+    Ok(match *field_type {
+        FieldType::Bool => ArenaValue::Bool(input.read_bool()?),
+        FieldType::Int32 => ArenaValue::I32(input.read_int32()?),
+        FieldType::Int64 => ArenaValue::I64(input.read_int64()?),
+        FieldType::SInt32 => ArenaValue::I32(input.read_sint32()?),
+        FieldType::SInt64 => ArenaValue::I64(input.read_sint64()?),
+        FieldType::UInt32 => ArenaValue::U32(input.read_uint32()?),
+        FieldType::UInt64 => ArenaValue::U64(input.read_uint64()?),
+        FieldType::Fixed32 => ArenaValue::U32(input.read_fixed32()?),
+        FieldType::Fixed64 => ArenaValue::U64(input.read_fixed64()?),
+        FieldType::SFixed32 => ArenaValue::I32(input.read_sfixed32()?),
+        FieldType::SFixed64 => ArenaValue::I64(input.read_sfixed64()?),
+        FieldType::Float => ArenaValue::F32(input.read_float()?),
+        FieldType::Double => ArenaValue::F64(input.read_double()?),
+        FieldType::Enum(_) => ArenaValue::Enum(input.read_int32()?),
+        _ => unreachable!("not a scalar type: {:?}", field_type),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_into_arena() {
+        let field = |name: &str, number, label, field_type| {
+            descriptor::FieldDescriptor::builder(name)
+                .number(number)
+                .label(label)
+                .field_type(field_type)
+                .build()
+        };
+        let mut message = descriptor::MessageDescriptor::new(".test.Node");
+        message.add_field(field(
+            "name",
+            1,
+            descriptor::FieldLabel::Optional,
+            descriptor::InternalFieldType::String,
+        ));
+        message.add_field(field(
+            "values",
+            2,
+            descriptor::FieldLabel::Repeated,
+            descriptor::InternalFieldType::SInt32,
+        ));
+        message.add_field(field(
+            "child",
+            3,
+            descriptor::FieldLabel::Optional,
+            descriptor::InternalFieldType::UnresolvedMessage(".test.Node".into()),
+        ));
+        let mut descriptors = descriptor::Descriptors::new();
+        descriptors.add_message(message);
+        descriptors.resolve_refs();
+        let node = descriptors.message_by_name(".test.Node").unwrap();
+
+        let data = [
+            10, 2, b'h', b'i', // name: "hi"
+            18, 2, 1, 4, // values: [-1, 2] (packed)
+            16, 5, // values: -3 (unpacked)
+            26, 2, 16, 2, // child { values: [1] }
+            26, 3, 10, 1, b'c', // child { name: "c" } (merged)
+            40, 7, // unknown field 5: 7
+        ];
+
+        let bump = Bump::new();
+        let message = ArenaMessage::decode(&bump, &descriptors, node, &data).unwrap();
+        match message.get(1) {
+            Some(ArenaField::Singular(Some(ArenaValue::String(s)))) => {
+                assert_eq!(*s, "hi");
+                assert_eq!(s.as_ptr(), data[2..].as_ptr());
+            }
+            f => panic!("Expected a borrowed string, got {:?}", f),
+        }
+        match message.get(2) {
+            Some(ArenaField::Repeated(vs)) => match vs.as_slice() {
+                [ArenaValue::I32(-1), ArenaValue::I32(2), ArenaValue::I32(-3)] => {}
+                vs => panic!("Expected [-1, 2, -3], got {:?}", vs),
+            },
+            f => panic!("Expected values, got {:?}", f),
+        }
+        match message.get(3) {
+            Some(ArenaField::Singular(Some(ArenaValue::Message(m)))) => {
+                assert!(matches!(
+                    m.get(1),
+                    Some(ArenaField::Singular(Some(ArenaValue::String("c"))))
+                ));
+                assert!(matches!(m.get(2), Some(ArenaField::Repeated(vs)) if vs.len() == 1));
+            }
+            f => panic!("Expected a child, got {:?}", f),
+        }
+        assert_eq!(message.unknown.as_slice(), &[&[40, 7][..]]);
+
+        // Converting to an owned value gives the same result as decoding one
+        let mut owned = Message::new(node);
+        let mut input = protobuf::CodedInputStream::from_bytes(&data);
+        owned.merge_from(&descriptors, node, &mut input).unwrap();
+        let converted = message.into_owned();
+        assert!(message_eq(
+            &descriptors,
+            node,
+            &Value::Message(converted.clone()),
+            &Value::Message(owned.clone())
+        ));
+        assert_eq!(converted.unknown, owned.unknown);
+
+        // Errors carry the offset of the field that failed, like for owned values
+        let bad = [10, 2, b'h', b'i', 18, 5, 1];
+        match ArenaMessage::decode(&bump, &descriptors, node, &bad) {
+            Err(error::Error::DecodeAt { offset: 4, .. }) => {}
+            r => panic!("Expected an error at offset 4, got {:?}", r),
+        };
+    }
+}
@@ -0,0 +1,489 @@
+//! Values that borrow the contents of their `bytes` and `string` fields from the decoded input.
+use std::borrow::Cow;
+use std::str;
+
+use super::*;
+
+/// A protobuf value decoded from a byte slice, borrowing the contents of `bytes` and `string`
+/// values from that slice instead of copying them.
+///
+/// Apart from the borrowed contents, this mirrors `Value`, and can be converted into one using
+/// `into_owned`.
+#[derive(Clone, Debug)]
+pub enum BorrowedValue<'a> {
+    /// A boolean value.
+    Bool(bool),
+    /// A 32-bit signed integer.
+    I32(i32),
+    /// A 64-bit signed integer.
+    I64(i64),
+    /// A 32-bit unsigned integer.
+    U32(u32),
+    /// A 64-bit unsigned integer.
+    U64(u64),
+    /// A 32-bit floating point value.
+    F32(f32),
+    /// A 64-bit floating point value.
+    F64(f64),
+    /// A byte vector, usually borrowed from the input.
+    Bytes(Cow<'a, [u8]>),
+    /// A string, usually borrowed from the input.
+    ///
+    /// The string is only owned if it was not valid UTF-8 and was decoded lossily; see
+    /// `StringValidation::Lossy`.
+    String(Cow<'a, str>),
+    /// An enum value.
+    Enum(i32),
+    /// A message.
+    Message(BorrowedMessage<'a>),
+}
+
+/// A message value decoded from a byte slice; see `BorrowedValue`.
+///
+/// Unlike `Message::new`, decoding doesn't add entries for unset fields, so only the fields that
+/// occur in the input are present.
+#[derive(Clone, Debug, Default)]
+pub struct BorrowedMessage<'a> {
+    /// Known fields on the message.
+    pub fields: collections::BTreeMap<i32, BorrowedField<'a>>,
+    /// The raw encodings of the unknown fields on the message, including their tags, in the order
+    /// that they occurred in the input.
+    pub unknown: Vec<&'a [u8]>,
+}
+
+/// A message field value decoded from a byte slice; see `BorrowedValue`.
+#[derive(Clone, Debug)]
+pub enum BorrowedField<'a> {
+    /// A field with a single value.
+    Singular(Option<BorrowedValue<'a>>),
+    /// A field with several (repeated) values.
+    Repeated(Vec<BorrowedValue<'a>>),
+}
+
+impl<'a> BorrowedValue<'a> {
+    /// Converts this value into an owned value, copying any borrowed contents.
+    pub fn into_owned(self) -> Value {
+        match self {
+            BorrowedValue::Bool(v) => Value::Bool(v),
+            BorrowedValue::I32(v) => Value::I32(v),
+            BorrowedValue::I64(v) => Value::I64(v),
+            BorrowedValue::U32(v) => Value::U32(v),
+            BorrowedValue::U64(v) => Value::U64(v),
+            BorrowedValue::F32(v) => Value::F32(v),
+            BorrowedValue::F64(v) => Value::F64(v),
+            BorrowedValue::Bytes(v) => Value::Bytes(v.into_owned()),
+            BorrowedValue::String(v) => Value::String(v.into_owned()),
+            BorrowedValue::Enum(v) => Value::Enum(v),
+            BorrowedValue::Message(m) => Value::Message(m.into_owned()),
+        }
+    }
+
+    /// Converts a decoded scalar value, which never borrows anything.
+    fn from_scalar(value: Value) -> BorrowedValue<'a> {
+        match value {
+            Value::Bool(v) => BorrowedValue::Bool(v),
+            Value::I32(v) => BorrowedValue::I32(v),
+            Value::I64(v) => BorrowedValue::I64(v),
+            Value::U32(v) => BorrowedValue::U32(v),
+            Value::U64(v) => BorrowedValue::U64(v),
+            Value::F32(v) => BorrowedValue::F32(v),
+            Value::F64(v) => BorrowedValue::F64(v),
+            Value::Bytes(v) => BorrowedValue::Bytes(Cow::Owned(v)),
+            Value::String(v) => BorrowedValue::String(Cow::Owned(v)),
+            Value::Enum(v) => BorrowedValue::Enum(v),
+            Value::Message(_) => unreachable!("messages are never decoded as owned values"),
+        }
+    }
+}
+
+impl<'a> BorrowedMessage<'a> {
+    /// Decodes a message of the specified type from a byte slice, borrowing the contents of all
+    /// `bytes` and `string` values from it.
+    #[inline]
+    pub fn decode(
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        bytes: &'a [u8],
+    ) -> error::Result<BorrowedMessage<'a>> {
+        BorrowedMessage::decode_with_options(descriptors, message, bytes, &DecodeOptions::default())
+    }
+
+    /// Decodes a message of the specified type from a byte slice, using the specified decode
+    /// options.
+    ///
+    /// The maximum field size, the handling of missing required fields, the string validation
+    /// policy and the rejection of duplicate scalars apply like they do for owned values.  The
+    /// other options transform owned values after they have been decoded, so they are ignored.
+    /// Groups aren't supported, and items of messages using the `MessageSet` wire format are kept
+    /// as unknown fields.
+    pub fn decode_with_options(
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        bytes: &'a [u8],
+        options: &DecodeOptions,
+    ) -> error::Result<BorrowedMessage<'a>> {
+        let mut result = BorrowedMessage::default();
+        let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+        result.merge(
+            descriptors,
+            message,
+            bytes,
+            &mut input,
+            &DecodeContext::new(options),
+        )?;
+        Ok(result)
+    }
+
+    /// Converts this message into an owned message, copying any borrowed contents and decoding
+    /// the unknown fields.
+    pub fn into_owned(self) -> Message {
+        let mut unknown = protobuf::UnknownFields::new();
+        for bytes in self.unknown {
+            // The raw encodings were already validated while decoding
+            let mut input = protobuf::CodedInputStream::from_bytes(bytes);
+            match read_tag(&mut input) {
+                Ok((number, wire_format::WireType::LengthDelimited)) => {
+                    if let Ok(bytes) = input.read_bytes() {
+                        unknown.add_length_delimited(number, bytes);
+                    }
+                }
+                Ok((_, wire_format::WireType::StartGroup)) | Err(_) => (),
+                Ok((number, wire_type)) => {
+                    if let Ok(value) = input.read_unknown(wire_type) {
+                        unknown.add_value(number, value);
+                    }
+                }
+            }
+        }
+
+        Message {
+            fields: self
+                .fields
+                .into_iter()
+                .map(|(number, field)| (number, field.into_owned()))
+                .collect(),
+            unknown,
+            extensions: collections::BTreeMap::new(),
+        }
+    }
+
+    /// Merges fields from the input stream, which reads from `bytes` starting at its beginning,
+    /// until the end of the stream or its current limit.
+    fn merge(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        bytes: &'a [u8],
+        input: &mut protobuf::CodedInputStream,
+        options: &DecodeContext,
+    ) -> error::Result<()> {
+        let mut seen = collections::BTreeSet::new();
+        while !input.eof()? {
+            let offset = input.pos();
+            read_tag(input)
+                .and_then(|(number, wire_type)| {
+                    options.count_field()?;
+                    match message.field_by_number(number as i32) {
+                        Some(field) => {
+                            options.mark_seen(descriptors, field, &mut seen)?;
+                            self.merge_field(descriptors, field, bytes, input, wire_type, options)
+                        }
+                        None => {
+                            skip_field(input, wire_type, options)?;
+                            self.unknown
+                                .push(&bytes[offset as usize..input.pos() as usize]);
+                            Ok(())
+                        }
+                    }
+                })
+                .map_err(|e| decode_at(offset, e))?;
+        }
+        options.finish_message(message, &seen)
+    }
+
+    /// Merges the value of a single field, whose tag has already been read, into this message.
+    fn merge_field(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        field: &descriptor::FieldDescriptor,
+        bytes: &'a [u8],
+        input: &mut protobuf::CodedInputStream,
+        wire_type: wire_format::WireType,
+        options: &DecodeContext,
+    ) -> error::Result<()> {
+        use crate::descriptor::FieldType;
+        use protobuf::rt::WireType;
+
+        let value = self
+            .fields
+            .entry(field.number())
+            .or_insert_with(|| BorrowedField::new(field));
+        match field.field_type(descriptors) {
+            FieldType::Message(m) if WireType::LengthDelimited == wire_type => {
+                let index = match *value {
+                    BorrowedField::Singular(_) => None,
+                    BorrowedField::Repeated(ref vs) => Some(vs.len()),
+                };
+                options
+                    .nested(field.number(), index.is_some(), || {
+                        value.merge_message(descriptors, m, bytes, input, options)
+                    })
+                    .map_err(|e| match index {
+                        Some(index) => error::Error::InField {
+                            field: field.name().to_owned(),
+                            index,
+                            source: Box::new(e),
+                        },
+                        None => e,
+                    })
+            }
+            field_type @ (FieldType::Bytes | FieldType::String)
+                if WireType::LengthDelimited == wire_type =>
+            {
+                let len = options.read_length(input)?;
+                let start = input.pos() as usize;
+                input.skip_raw_bytes(len)?;
+                let contents = &bytes[start..start + len as usize];
+                value.put(match field_type {
+                    FieldType::Bytes => BorrowedValue::Bytes(Cow::Borrowed(contents)),
+                    _ => BorrowedValue::String(match str::from_utf8(contents) {
+                        Ok(s) => Cow::Borrowed(s),
+                        Err(_) => Cow::Owned(options.decode_string(field, contents.to_vec())?),
+                    }),
+                });
+                Ok(())
+            }
+            FieldType::UnresolvedMessage(_)
+                if options.schemaless_unresolved_messages
+                    && WireType::LengthDelimited == wire_type =>
+            {
+                let schemaless = schemaless_message();
+                value.merge_message(descriptors, schemaless, bytes, input, options)
+            }
+            FieldType::Group(_) => Err(error::Error::BadField {
+                field: field.name().to_owned(),
+                reason: "groups are not supported".to_owned(),
+            }),
+            _ => {
+                // Scalars never borrow anything, so they are decoded like owned values, which also
+                // takes care of packed encodings and reports bad wire types
+                let mut decoded = Field::new(field);
+                decoded.merge_value(descriptors, field, input, wire_type, options)?;
+                let values = match decoded {
+                    Field::Singular(v) => v.into_iter().collect(),
+                    Field::Repeated(vs) => vs,
+                };
+                for v in values {
+                    value.put(BorrowedValue::from_scalar(v));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<'a> BorrowedField<'a> {
+    /// Creates an empty field given a Protobuf descriptor.
+    #[inline]
+    pub fn new(field: &descriptor::FieldDescriptor) -> BorrowedField<'a> {
+        if field.is_repeated() {
+            BorrowedField::Repeated(Vec::new())
+        } else {
+            BorrowedField::Singular(None)
+        }
+    }
+
+    /// Converts this field into an owned field, copying any borrowed contents.
+    pub fn into_owned(self) -> Field {
+        match self {
+            BorrowedField::Singular(v) => Field::Singular(v.map(BorrowedValue::into_owned)),
+            BorrowedField::Repeated(vs) => {
+                Field::Repeated(vs.into_iter().map(BorrowedValue::into_owned).collect())
+            }
+        }
+    }
+
+    /// Merges a length-delimited message value, whose length hasn't been read yet, into this
+    /// field.
+    fn merge_message(
+        &mut self,
+        descriptors: &descriptor::Descriptors,
+        message: &descriptor::MessageDescriptor,
+        bytes: &'a [u8],
+        input: &mut protobuf::CodedInputStream,
+        options: &DecodeContext,
+    ) -> error::Result<()> {
+        let len = read_length(input, bytes, options)?;
+        let mut msg = match *self {
+            BorrowedField::Singular(ref mut o) => match o.take() {
+                Some(BorrowedValue::Message(m)) => m,
+                _ => BorrowedMessage::default(),
+            },
+            BorrowedField::Repeated(_) => BorrowedMessage::default(),
+        };
+
+        let old_limit = input.push_limit(u64::from(len))?;
+        msg.merge(descriptors, message, bytes, input, options)?;
+        input.pop_limit(old_limit);
+
+        self.put(BorrowedValue::Message(msg));
+        Ok(())
+    }
+
+    #[inline]
+    fn put(&mut self, value: BorrowedValue<'a>) {
+        match *self {
+            BorrowedField::Singular(ref mut s) => *s = Some(value),
+            BorrowedField::Repeated(ref mut r) => r.push(value),
+        }
+    }
+}
+
+/// Reads the length of a length-delimited value, making sure that the value doesn't extend past
+/// the end of the input, since pushing a limit past it would silently truncate the value.
+#[inline]
+pub(super) fn read_length(
+    input: &mut protobuf::CodedInputStream,
+    bytes: &[u8],
+    options: &DecodeContext,
+) -> error::Result<u32> {
+    let len = options.read_length(input)?;
+    if input.pos() + u64::from(len) > bytes.len() as u64 {
+        Err(error::Error::EndOfStream)
+    } else {
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn blob_descriptors() -> descriptor::Descriptors {
+        let field = |name: &str, number, field_type| {
+            descriptor::FieldDescriptor::builder(name)
+                .number(number)
+                .field_type(field_type)
+                .build()
+        };
+        let mut message = descriptor::MessageDescriptor::new(".test.Blob");
+        message.add_field(field("data", 1, descriptor::InternalFieldType::Bytes));
+        message.add_field(field("name", 2, descriptor::InternalFieldType::String));
+        message.add_field(
+            descriptor::FieldDescriptor::builder("children")
+                .number(3)
+                .label(descriptor::FieldLabel::Repeated)
+                .field_type(descriptor::InternalFieldType::UnresolvedMessage(
+                    ".test.Blob".into(),
+                ))
+                .build(),
+        );
+        message.add_field(field("id", 4, descriptor::InternalFieldType::Int32));
+        let mut descriptors = descriptor::Descriptors::new();
+        descriptors.add_message(message);
+        descriptors.resolve_refs();
+        descriptors
+    }
+
+    #[test]
+    fn decode_borrowed() {
+        let descriptors = blob_descriptors();
+        let blob = descriptors.message_by_name(".test.Blob").unwrap();
+        let data = [
+            10, 3, 1, 2, 3, // data: "\x01\x02\x03"
+            26, 6, 18, 2, b'h', b'i', 32, 5, // children { name: "hi", id: 5 }
+            40, 7, // unknown field 5: 7
+            32, 9, // id: 9
+        ];
+
+        let message = BorrowedMessage::decode(&descriptors, blob, &data).unwrap();
+        match message.fields.get(&1) {
+            Some(BorrowedField::Singular(Some(BorrowedValue::Bytes(Cow::Borrowed(b))))) => {
+                assert_eq!(*b, &[1, 2, 3]);
+                assert_eq!(b.as_ptr(), data[2..].as_ptr());
+            }
+            f => panic!("Expected borrowed bytes, got {:?}", f),
+        }
+        match message.fields.get(&3) {
+            Some(BorrowedField::Repeated(vs)) => match vs.as_slice() {
+                [BorrowedValue::Message(m)] => match m.fields.get(&2) {
+                    Some(BorrowedField::Singular(Some(BorrowedValue::String(Cow::Borrowed(
+                        s,
+                    ))))) => {
+                        assert_eq!(*s, "hi")
+                    }
+                    f => panic!("Expected a borrowed string, got {:?}", f),
+                },
+                vs => panic!("Expected a single child, got {:?}", vs),
+            },
+            f => panic!("Expected children, got {:?}", f),
+        }
+        assert!(matches!(
+            message.fields.get(&4),
+            Some(BorrowedField::Singular(Some(BorrowedValue::I32(9))))
+        ));
+        assert_eq!(message.unknown, vec![&[40, 7][..]]);
+
+        // Converting to an owned value gives the same result as decoding one
+        let mut owned = Message::new(blob);
+        let mut input = protobuf::CodedInputStream::from_bytes(&data);
+        owned.merge_from(&descriptors, blob, &mut input).unwrap();
+        let converted = message.into_owned();
+        assert!(message_eq(
+            &descriptors,
+            blob,
+            &Value::Message(converted.clone()),
+            &Value::Message(owned.clone())
+        ));
+        assert_eq!(converted.unknown, owned.unknown);
+    }
+
+    #[test]
+    fn decode_borrowed_errors() {
+        let descriptors = blob_descriptors();
+        let blob = descriptors.message_by_name(".test.Blob").unwrap();
+
+        // name: "\xff", which is only decoded into an owned string when decoding lossily
+        let data = [18, 1, 0xff];
+        match BorrowedMessage::decode(&descriptors, blob, &data) {
+            Err(error::Error::DecodeAt { offset: 0, source }) => {
+                assert!(matches!(*source, error::Error::InvalidUtf8 { .. }))
+            }
+            r => panic!("Expected invalid UTF-8 error, got {:?}", r),
+        }
+        let options = DecodeOptions::new().string_validation(StringValidation::Lossy);
+        let message =
+            BorrowedMessage::decode_with_options(&descriptors, blob, &data, &options).unwrap();
+        match message.fields.get(&2) {
+            Some(BorrowedField::Singular(Some(BorrowedValue::String(Cow::Owned(s))))) => {
+                assert_eq!(s, "\u{fffd}")
+            }
+            f => panic!("Expected an owned string, got {:?}", f),
+        }
+
+        // children { data: truncated }, which fails like decoding an owned value
+        let data = [26, 4, 10, 5, 1, 2];
+        let mut input = protobuf::CodedInputStream::from_bytes(&data);
+        let owned = Message::new(blob).merge_from(&descriptors, blob, &mut input);
+        match BorrowedMessage::decode(&descriptors, blob, &data) {
+            Err(e @ error::Error::InField { index: 0, .. }) => {
+                assert_eq!(format!("{:?}", e), format!("{:?}", owned.unwrap_err()))
+            }
+            r => panic!("Expected an element error, got {:?}", r),
+        }
+
+        // children: { name: "hi" }, with a length that claims more bytes than there are
+        let data = [26, 10, 18, 2, b'h', b'i'];
+        match BorrowedMessage::decode(&descriptors, blob, &data) {
+            Err(error::Error::DecodeAt { offset: 0, source }) => match *source {
+                error::Error::InField {
+                    index: 0, source, ..
+                } => {
+                    assert!(matches!(*source, error::Error::EndOfStream))
+                }
+                e => panic!("Expected an element error, got {:?}", e),
+            },
+            r => panic!("Expected an end of stream error, got {:?}", r),
+        }
+    }
+}
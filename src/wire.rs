@@ -0,0 +1,351 @@
+//! Low-level primitives for reading the protocol buffer wire format.
+//!
+//! These read directly from any `io::Read`, one value at a time and without buffering past the
+//! end of the value, so they can be used to build custom partial parsers around the descriptor
+//! model, for example to find a field in a large message before decoding it.  They don't consult
+//! any descriptors, so they know nothing about field types: a length-delimited value might be a
+//! string, a nested message or a packed repeated field.
+//!
+//! ```
+//! use serde_protobuf::wire::{self, WireType};
+//!
+//! // field 1: 150 (varint), field 2: "hi" (length-delimited)
+//! let mut data: &[u8] = &[8, 150, 1, 18, 2, b'h', b'i'];
+//! assert_eq!(wire::read_tag(&mut data).unwrap(), (1, WireType::Varint));
+//! wire::skip_field(&mut data, WireType::Varint).unwrap();
+//! assert_eq!(wire::read_tag(&mut data).unwrap(), (2, WireType::LengthDelimited));
+//! assert_eq!(wire::read_length_delimited(&mut data).unwrap(), b"hi");
+//! ```
+use std::convert::TryFrom;
+use std::io;
+
+use protobuf;
+
+use crate::error;
+
+pub use protobuf::rt::WireType;
+
+// The maximum number of bytes in the encoding of a 64-bit varint
+const MAX_VARINT_LEN: usize = 10;
+
+/// The maximum depth of nested groups that `skip_field` skips over, which is the default
+/// recursion limit of the `protobuf` crate.
+pub const MAX_GROUP_DEPTH: u32 = 100;
+
+/// A source of wire format values, so that tags are read and fields are skipped by the same code
+/// for any `io::Read` and for a `protobuf::CodedInputStream`.
+pub(crate) trait Source {
+    /// Reads a varint-encoded unsigned integer.
+    fn read_varint(&mut self) -> error::Result<u64>;
+
+    /// Skips exactly the specified number of bytes.
+    fn skip_bytes(&mut self, len: u64) -> error::Result<()>;
+}
+
+/// An `io::Read` as a source of wire format values.
+struct Reader<'a, R>(&'a mut R);
+
+impl<R> Source for Reader<'_, R>
+where
+    R: io::Read,
+{
+    fn read_varint(&mut self) -> error::Result<u64> {
+        read_varint(self.0)
+    }
+
+    fn skip_bytes(&mut self, len: u64) -> error::Result<()> {
+        let skipped = io::copy(&mut io::Read::take(&mut *self.0, len), &mut io::sink())
+            .map_err(protobuf::Error::from)?;
+        if skipped < len {
+            return Err(error::Error::EndOfStream);
+        }
+        Ok(())
+    }
+}
+
+impl Source for protobuf::CodedInputStream<'_> {
+    #[inline]
+    fn read_varint(&mut self) -> error::Result<u64> {
+        Ok(self.read_raw_varint64()?)
+    }
+
+    #[inline]
+    fn skip_bytes(&mut self, len: u64) -> error::Result<()> {
+        let len = u32::try_from(len).map_err(|_| error::Error::FieldTooLarge {
+            size: len,
+            max_size: u64::from(u32::MAX),
+        })?;
+        Ok(self.skip_raw_bytes(len)?)
+    }
+}
+
+/// Reads a varint-encoded unsigned integer.
+///
+/// Returns an `Error::EndOfStream` error if the reader is at the end of the stream.
+pub fn read_varint<R>(reader: &mut R) -> error::Result<u64>
+where
+    R: io::Read,
+{
+    try_read_varint(reader).unwrap_or(Err(error::Error::EndOfStream))
+}
+
+/// Reads a length-delimited value, returning its contents without the length prefix.
+///
+/// Returns an `Error::EndOfStream` error if the stream ends before the end of the value.
+pub fn read_length_delimited<R>(reader: &mut R) -> error::Result<Vec<u8>>
+where
+    R: io::Read,
+{
+    let len = read_varint(reader)?;
+    read_bytes(reader, len)
+}
+
+/// Reads a field tag, returning the field number and the wire type.
+///
+/// Returns an `Error::BadTag` error if the tag doesn't fit in 32 bits or has an unknown wire
+/// type.
+pub fn read_tag<R>(reader: &mut R) -> error::Result<(u32, WireType)>
+where
+    R: io::Read,
+{
+    read_tag_from(&mut Reader(reader))
+}
+
+/// Skips over the value of a field whose tag, with the specified wire type, has already been
+/// read.
+///
+/// Groups are skipped up to and including their end tag, along with any nested groups.  Returns
+/// an `Error::BadWireType` error for an end group tag without a matching start group tag, and an
+/// `Error::GroupTooDeep` error for groups nested more than `MAX_GROUP_DEPTH` levels deep.
+pub fn skip_field<R>(reader: &mut R, wire_type: WireType) -> error::Result<()>
+where
+    R: io::Read,
+{
+    skip_field_from(&mut Reader(reader), wire_type, u64::MAX)
+}
+
+/// Reads a field tag from any source; see `read_tag`.
+#[inline]
+pub(crate) fn read_tag_from<S>(source: &mut S) -> error::Result<(u32, WireType)>
+where
+    S: Source,
+{
+    let tag = source.read_varint()?;
+    let tag = u32::try_from(tag).map_err(|_| error::Error::BadTag { tag: tag as u32 })?;
+    match WireType::new(tag & 7) {
+        Some(wire_type) => Ok((tag >> 3, wire_type)),
+        None => Err(error::Error::BadTag { tag }),
+    }
+}
+
+/// Skips over the value of a field in any source, failing with an `Error::FieldTooLarge` error
+/// for length-delimited values longer than the specified maximum size; see `skip_field`.
+pub(crate) fn skip_field_from<S>(
+    source: &mut S,
+    wire_type: WireType,
+    max_size: u64,
+) -> error::Result<()>
+where
+    S: Source,
+{
+    skip_nested_field(source, wire_type, max_size, 0)
+}
+
+fn skip_nested_field<S>(
+    source: &mut S,
+    wire_type: WireType,
+    max_size: u64,
+    depth: u32,
+) -> error::Result<()>
+where
+    S: Source,
+{
+    match wire_type {
+        WireType::Varint => source.read_varint().map(|_| ()),
+        WireType::Fixed64 => source.skip_bytes(8),
+        WireType::Fixed32 => source.skip_bytes(4),
+        WireType::LengthDelimited => {
+            let len = source.read_varint()?;
+            if len > max_size {
+                return Err(error::Error::FieldTooLarge {
+                    size: len,
+                    max_size,
+                });
+            }
+            source.skip_bytes(len)
+        }
+        WireType::StartGroup => {
+            if depth >= MAX_GROUP_DEPTH {
+                return Err(error::Error::GroupTooDeep {
+                    max_depth: MAX_GROUP_DEPTH,
+                });
+            }
+            loop {
+                match read_tag_from(source)? {
+                    (_, WireType::EndGroup) => return Ok(()),
+                    (_, wire_type) => skip_nested_field(source, wire_type, max_size, depth + 1)?,
+                }
+            }
+        }
+        WireType::EndGroup => Err(error::Error::BadWireType { wire_type }),
+    }
+}
+
+/// Reads exactly the specified number of bytes, failing with an `Error::EndOfStream` error if the
+/// stream ends before that.
+pub(crate) fn read_bytes<R>(reader: &mut R, len: u64) -> error::Result<Vec<u8>>
+where
+    R: io::Read,
+{
+    let mut bytes = Vec::new();
+    io::Read::read_to_end(&mut io::Read::take(reader, len), &mut bytes)
+        .map_err(protobuf::Error::from)?;
+    if (bytes.len() as u64) < len {
+        return Err(error::Error::EndOfStream);
+    }
+    Ok(bytes)
+}
+
+/// Reads a varint-encoded unsigned integer, or returns `None` if the reader is at the end of the
+/// stream before the first byte of the varint.
+pub(crate) fn try_read_varint<R>(reader: &mut R) -> Option<error::Result<u64>>
+where
+    R: io::Read,
+{
+    let mut bytes = Vec::with_capacity(MAX_VARINT_LEN);
+    loop {
+        let mut byte = [0];
+        match reader.read(&mut byte) {
+            Ok(0) if bytes.is_empty() => return None,
+            Ok(0) => return Some(Err(error::Error::EndOfStream)),
+            Ok(_) => bytes.push(byte[0]),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Some(Err(protobuf::Error::from(e).into())),
+        }
+        // A varint ends with the first byte without the continuation bit
+        if byte[0] & 0x80 == 0 || bytes.len() == MAX_VARINT_LEN {
+            break;
+        }
+    }
+    let result = protobuf::CodedInputStream::from_bytes(&bytes).read_raw_varint64();
+    Some(result.map_err(Into::into))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn varints() {
+        for (bytes, value) in &[
+            (&[0][..], 0),
+            (&[1][..], 1),
+            (&[150, 1][..], 150),
+            (&[0xff, 0xff, 0xff, 0xff, 0x0f][..], u64::from(u32::MAX)),
+            (
+                &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01][..],
+                u64::MAX,
+            ),
+        ] {
+            let mut input = *bytes;
+            assert_eq!(read_varint(&mut input).unwrap(), *value);
+            assert!(input.is_empty());
+        }
+
+        assert!(matches!(
+            read_varint(&mut &[][..]),
+            Err(error::Error::EndOfStream)
+        ));
+        assert!(matches!(
+            read_varint(&mut &[0x80][..]),
+            Err(error::Error::EndOfStream)
+        ));
+        assert!(read_varint(&mut &[0xff; 11][..]).is_err());
+    }
+
+    #[test]
+    fn tags_and_values() {
+        let mut input: &[u8] = &[10, 3, b'a', b'b', b'c', 0x7f];
+        assert_eq!(
+            read_tag(&mut input).unwrap(),
+            (1, WireType::LengthDelimited)
+        );
+        assert_eq!(read_length_delimited(&mut input).unwrap(), b"abc");
+        assert!(matches!(
+            read_tag(&mut input),
+            Err(error::Error::BadTag { tag: 0x7f })
+        ));
+
+        let mut input: &[u8] = &[10, 3, b'a'];
+        read_tag(&mut input).unwrap();
+        assert!(matches!(
+            read_length_delimited(&mut input),
+            Err(error::Error::EndOfStream)
+        ));
+        assert!(matches!(
+            read_tag(&mut &[0x80, 0x80, 0x80, 0x80, 0x10][..]),
+            Err(error::Error::BadTag { .. })
+        ));
+    }
+
+    #[test]
+    fn skip_fields() {
+        let data: &[u8] = &[
+            8, 150, 1, // field 1: 150
+            17, 1, 2, 3, 4, 5, 6, 7, 8, // field 2: fixed64
+            29, 1, 2, 3, 4, // field 3: fixed32
+            34, 2, 0, 0, // field 4: length-delimited
+            43, 8, 1, 51, 56, 1, 52,
+            44, // field 5: group containing a varint and a nested group
+            48, 7, // field 6: 7
+        ];
+        let mut input = data;
+        let mut numbers = Vec::new();
+        while !input.is_empty() {
+            let (number, wire_type) = read_tag(&mut input).unwrap();
+            numbers.push(number);
+            if number == 6 {
+                assert_eq!(read_varint(&mut input).unwrap(), 7);
+            } else {
+                skip_field(&mut input, wire_type).unwrap();
+            }
+        }
+        assert_eq!(numbers, vec![1, 2, 3, 4, 5, 6]);
+
+        assert!(matches!(
+            skip_field(&mut &[][..], WireType::EndGroup),
+            Err(error::Error::BadWireType { .. })
+        ));
+        assert!(matches!(
+            skip_field(&mut &[1, 2, 3][..], WireType::Fixed32),
+            Err(error::Error::EndOfStream)
+        ));
+    }
+
+    #[test]
+    fn skip_deeply_nested_groups() {
+        // Start group tags of field 1, nested far deeper than any stack could handle
+        let data = vec![11u8; 1_000_000];
+        let mut input = &data[..];
+        read_tag(&mut input).unwrap();
+        assert!(matches!(
+            skip_field(&mut input, WireType::StartGroup),
+            Err(error::Error::GroupTooDeep { max_depth: 100 })
+        ));
+
+        let mut input = protobuf::CodedInputStream::from_bytes(&data);
+        read_tag_from(&mut input).unwrap();
+        assert!(matches!(
+            skip_field_from(&mut input, WireType::StartGroup, u64::MAX),
+            Err(error::Error::GroupTooDeep { max_depth: 100 })
+        ));
+
+        // Groups up to the maximum depth are fine
+        let depth = MAX_GROUP_DEPTH as usize;
+        let mut data = vec![11u8; depth];
+        data.extend(vec![12u8; depth]);
+        let mut input = &data[1..];
+        skip_field(&mut input, WireType::StartGroup).unwrap();
+        assert!(input.is_empty());
+    }
+}